@@ -0,0 +1,60 @@
+//! Encoding and framing a stream of inserts, as a client connection does:
+//! with fresh buffers for every command against buffers reused across them.
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use rust_db::commands::DbCommand;
+use rust_db::db_types::Value;
+use rust_db::protocol::{self, FrameOptions};
+
+mod common;
+
+#[global_allocator]
+static ALLOC: common::CountingAlloc = common::CountingAlloc;
+
+const INSERTS: i64 = 1_000;
+
+fn inserts() -> Vec<DbCommand> {
+    (0..INSERTS)
+        .map(|n| DbCommand::InsertRow {
+            table: "t".into(),
+            values: vec![Value::Int(n), Value::Text(format!("row {}", n))],
+            idempotency_key: None,
+        })
+        .collect()
+}
+
+async fn fresh_buffers(commands: &[DbCommand], options: FrameOptions) {
+    let mut sink = tokio::io::sink();
+    for cmd in commands {
+        let mut buf = Vec::new();
+        protocol::encode_command_into(&mut buf, cmd);
+        protocol::write_frame_with(&mut sink, &buf, options).await.unwrap();
+    }
+}
+
+async fn reused_buffers(commands: &[DbCommand], options: FrameOptions) {
+    let mut sink = tokio::io::sink();
+    let mut buf = Vec::new();
+    let mut write_buf = Vec::new();
+    for cmd in commands {
+        buf.clear();
+        protocol::encode_command_into(&mut buf, cmd);
+        protocol::write_frame_buffered(&mut sink, &buf, options, &mut write_buf).await.unwrap();
+    }
+}
+
+fn insert_loop(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let commands = inserts();
+    let options = FrameOptions { checksum: true, ..FrameOptions::default() };
+    common::report_allocations("insert_loop/fresh_buffers", || runtime.block_on(fresh_buffers(&commands, options)));
+    common::report_allocations("insert_loop/reused_buffers", || runtime.block_on(reused_buffers(&commands, options)));
+
+    let mut group = c.benchmark_group("insert_loop");
+    group.bench_function("fresh_buffers", |b| b.iter(|| runtime.block_on(fresh_buffers(&commands, options))));
+    group.bench_function("reused_buffers", |b| b.iter(|| runtime.block_on(reused_buffers(&commands, options))));
+    group.finish();
+}
+
+criterion_group!(benches, insert_loop);
+criterion_main!(benches);
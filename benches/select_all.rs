@@ -0,0 +1,51 @@
+//! SelectAll on a 100k-row table: encoding straight from the table, as the
+//! server does, against cloning the rows into a `DbResult` and encoding that.
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use rust_db::commands::DbCommand;
+use rust_db::db::Database;
+use rust_db::protocol;
+use std::hint::black_box;
+
+mod common;
+
+#[global_allocator]
+static ALLOC: common::CountingAlloc = common::CountingAlloc;
+
+const ROWS: i64 = 100_000;
+
+fn database() -> Database {
+    let mut db = Database::default();
+    let create = r#"{"type":"createTable","table":"t","columns":[["n","int"],["s","text"]]}"#;
+    db.execute(serde_json::from_str(create).unwrap()).unwrap();
+    for n in 0..ROWS {
+        let insert = format!(r#"{{"type":"insert","table":"t","values":[{},"row {}"]}}"#, n, n);
+        db.execute(serde_json::from_str::<DbCommand>(&insert).unwrap()).unwrap();
+    }
+    db
+}
+
+fn cloned(db: &Database) -> Vec<u8> {
+    let result = db.select_all("t".into(), false, None, None, false).unwrap();
+    protocol::encode_result(&result)
+}
+
+fn from_table(db: &Database) -> Vec<u8> {
+    protocol::encode_table(&db.tables["t"], db.row_cap(), false, None, None, true).unwrap()
+}
+
+fn select_all(c: &mut Criterion) {
+    let db = database();
+    let a = common::report_allocations("select_all/cloned", || cloned(&db));
+    let b = common::report_allocations("select_all/from_table", || from_table(&db));
+    assert_eq!(a, b);
+
+    let mut group = c.benchmark_group("select_all");
+    group.sample_size(20);
+    group.bench_function("cloned", |b| b.iter(|| black_box(cloned(&db))));
+    group.bench_function("from_table", |b| b.iter(|| black_box(from_table(&db))));
+    group.finish();
+}
+
+criterion_group!(benches, select_all);
+criterion_main!(benches);
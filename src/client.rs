@@ -1,118 +1,990 @@
 use axum::{
+    extract::{Path, State},
     extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    response::IntoResponse,
-    routing::get,
-    Router,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    body::Body,
+    routing::{get, post},
+    Json, Router,
 };
+use futures_util::StreamExt;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpStream;
+use tokio::time::{self, MissedTickBehavior};
 use tower_http::services::ServeDir;
+use tracing::{debug, info, warn};
 
-use crate::config::{CLIENT_ADDRESS, CLIENT_SERVER, DB_ADDRESS};
-use crate::db_types::Value;
-use crate::commands::{DbCommand, DbResult};
+use crate::config::{
+    CASE_INSENSITIVE_TABLES, CLIENT_ADDRESS, CLIENT_SERVER, DB_ADDRESS, DB_POOL_SIZE, DB_POOL_WAIT,
+    DB_RECONNECT_ATTEMPTS, DB_RECONNECT_DELAY, EXPORT_PAGE_ROWS, WS_PING_INTERVAL,
+};
+use crate::db_types::{ColumnType, Value};
+use crate::commands::{DbCommand, DbResult, JsonRow, error_code};
+use crate::pool::{Pool, PooledConn};
 use crate::protocol;
 
+// Backoff between reconnect attempts stops growing here
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// An insert with its values keyed by column name, sent with `"inferSchema": true`.
+/// If the table doesn't exist it's created from the values' JSON types, so
+/// tables can be prototyped from the browser without a CreateTable first.
+#[derive(Deserialize)]
+struct ObjectInsert {
+    #[serde(rename = "type")]
+    kind: String,
+    table: String,
+    values: serde_json::Map<String, serde_json::Value>,
+    #[serde(rename = "idempotencyKey")]
+    idempotency_key: Option<String>,
+    #[serde(rename = "inferSchema")]
+    infer_schema: bool,
+}
+
 pub async fn run() {
-    let app = Router::new()
+    let pool = Pool::new(DB_ADDRESS, DB_POOL_SIZE, DB_POOL_WAIT);
+    let listener = tokio::net::TcpListener::bind(CLIENT_SERVER).await.unwrap();
+    info!("Web client at {}", CLIENT_ADDRESS);
+    axum::serve(listener, app(pool)).await.unwrap();
+}
+
+fn app(pool: Arc<Pool>) -> Router {
+    Router::new()
         .route("/ws", get(ws_handler))
-        .nest_service("/", ServeDir::new("web"));
+        .route("/tables/:name/schema.json", get(table_schema))
+        .route("/tables/:name/export.ndjson", get(export_ndjson))
+        .route("/tables/:name/import", post(import_ndjson))
+        .route("/openapi.json", get(openapi))
+        .nest_service("/", ServeDir::new("web"))
+        .with_state(pool)
+}
 
-    let listener = tokio::net::TcpListener::bind(CLIENT_SERVER).await.unwrap();
-    println!("Web client at {}", CLIENT_ADDRESS);
-    axum::serve(listener, app).await.unwrap();
+async fn ws_handler(ws: WebSocketUpgrade, State(pool): State<Arc<Pool>>) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle_socket(socket, pool))
+}
+
+/// JSON Schema for the rows of one table, as the web API returns them.
+async fn table_schema(Path(name): Path<String>, State(pool): State<Arc<Pool>>) -> impl IntoResponse {
+    let name = if CASE_INSENSITIVE_TABLES { name.to_lowercase() } else { name };
+
+    let columns = match table_columns(&pool, &name).await {
+        Ok(columns) => columns,
+        Err(e) => return (StatusCode::BAD_GATEWAY, Json(error_json(&e))),
+    };
+    if columns.is_empty() {
+        return (StatusCode::NOT_FOUND, Json(error_json("Table not found")));
+    }
+    (StatusCode::OK, Json(json_schema(&name, &columns)))
 }
 
-async fn ws_handler(ws: WebSocketUpgrade) -> impl IntoResponse {
-    ws.on_upgrade(handle_socket)
+/// The table as newline-delimited JSON, one object per row in id order, shaped
+/// like the rows of a SelectAll reply. Rows are fetched a page at a time, so
+/// no single database response has to fit in a frame.
+async fn export_ndjson(Path(name): Path<String>, State(pool): State<Arc<Pool>>) -> Response {
+    let name = if CASE_INSENSITIVE_TABLES { name.to_lowercase() } else { name };
+
+    let mut body = String::new();
+    let mut cursor = None;
+    loop {
+        let page = DbCommand::SelectAll {
+            table: name.clone(),
+            with_types: false,
+            limit: Some(EXPORT_PAGE_ROWS),
+            cursor: cursor.take(),
+            exclude_metadata: false,
+        };
+        let (columns, rows, next_cursor) = match query(&pool, &page).await {
+            Ok(DbResult::Rows { columns, rows, next_cursor, .. }) => (columns, rows, next_cursor),
+            Ok(_) => return (StatusCode::BAD_GATEWAY, Json(error_json("Unexpected response"))).into_response(),
+            Err(e) if e == "Table not found" => return (StatusCode::NOT_FOUND, Json(error_json(&e))).into_response(),
+            Err(e) => return (StatusCode::BAD_GATEWAY, Json(error_json(&e))).into_response(),
+        };
+        for (id, values) in &rows {
+            body.push_str(&serde_json::to_string(&JsonRow(*id, &columns, values)).expect("rows serialize to JSON"));
+            body.push('\n');
+        }
+        match next_cursor {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    ([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response()
 }
 
-async fn handle_socket(mut socket: WebSocket) {
-    let mut tcp = match TcpStream::connect(DB_ADDRESS).await {
-        Ok(s) => s,
-        Err(e) => {
-            let _ = send_error(&mut socket, format!("Failed to connect to database: {}", e)).await;
+/// Inserts each line of an NDJSON body as a row, with the values keyed by
+/// column name as in an export. `_id` and `_version` are ignored, so rows get
+/// new ids. The body is read as it arrives, and a failed line doesn't stop
+/// the import: the reply counts the inserted rows and lists the failed lines.
+async fn import_ndjson(Path(name): Path<String>, State(pool): State<Arc<Pool>>, body: Body) -> Response {
+    let name = if CASE_INSENSITIVE_TABLES { name.to_lowercase() } else { name };
+
+    let columns = match table_columns(&pool, &name).await {
+        Ok(columns) if columns.is_empty() => {
+            return (StatusCode::NOT_FOUND, Json(error_json("Table not found"))).into_response();
+        }
+        Ok(columns) => columns,
+        Err(e) => return (StatusCode::BAD_GATEWAY, Json(error_json(&e))).into_response(),
+    };
+
+    let mut inserted = 0;
+    let mut failed = Vec::new();
+    let mut line_number = 0;
+    let mut import_line = async |line: &[u8]| {
+        line_number += 1;
+        if line.trim_ascii().is_empty() {
             return;
         }
+        match import_row(&pool, &name, &columns, line).await {
+            Ok(()) => inserted += 1,
+            Err(e) => failed.push(serde_json::json!({"line": line_number, "code": error_code(&e), "error": e})),
+        }
     };
 
-    while let Some(Ok(msg)) = socket.recv().await {
-        let Message::Text(text) = msg else { continue };
-   println!("Received command: {}", text);
-        // Parse JSON directly to DbCommand
-        let db_cmd: DbCommand = match serde_json::from_str(&text) {
-         
-            Ok(cmd) => cmd,
+    let mut stream = body.into_data_stream();
+    let mut pending = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        match chunk {
+            Ok(chunk) => pending.extend_from_slice(&chunk),
             Err(e) => {
-                let _ = send_error(&mut socket, format!("Invalid JSON: {}", e)).await;
-                continue;
+                let error = error_json(&format!("Failed to read request body: {}", e));
+                return (StatusCode::BAD_REQUEST, Json(error)).into_response();
+            }
+        }
+        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            import_line(&line).await;
+        }
+    }
+    // The last line may not end in a newline
+    if !pending.is_empty() {
+        import_line(&pending).await;
+    }
+
+    Json(serde_json::json!({"ok": true, "inserted": inserted, "failed": failed})).into_response()
+}
+
+async fn import_row(pool: &Arc<Pool>, table: &str, columns: &[(String, ColumnType)], line: &[u8]) -> Result<(), String> {
+    let mut values: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(line).map_err(|e| format!("Invalid JSON: {}", e))?;
+    values.remove("_id");
+    values.remove("_version");
+    let values = order_values(columns, values)?;
+    query(pool, &DbCommand::InsertRow { table: table.to_string(), values, idempotency_key: None }).await?;
+    Ok(())
+}
+
+/// The columns of table `name`, or none if there's no such table.
+async fn table_columns(pool: &Arc<Pool>, name: &str) -> Result<Vec<(String, ColumnType)>, String> {
+    let rows = match query(pool, &DbCommand::GetTables {}).await? {
+        DbResult::Rows { rows, .. } => rows,
+        _ => return Err("Unexpected response".into()),
+    };
+
+    // GetTables lists one row per column: table, column, type, schema version
+    Ok(rows
+        .into_iter()
+        .filter_map(|(_, values)| match values.as_slice() {
+            [Value::Text(table), Value::Text(column), Value::Text(col_type), ..] if table == name => {
+                Some((column.clone(), ColumnType::from_name(col_type)?))
             }
+            _ => None,
+        })
+        .collect())
+}
+
+fn json_schema(table: &str, columns: &[(String, ColumnType)]) -> serde_json::Value {
+    let mut properties = serde_json::Map::new();
+    properties.insert("_id".into(), serde_json::json!({"type": "integer", "readOnly": true}));
+    for (name, col_type) in columns {
+        let json_type = match col_type {
+            ColumnType::Int => "integer",
+            ColumnType::Text => "string",
+            ColumnType::Bool => "boolean",
         };
+        properties.insert(name.clone(), serde_json::json!({"type": json_type}));
+    }
+    properties.insert("_version".into(), serde_json::json!({"type": "integer", "readOnly": true}));
 
-        let binary = protocol::encode_command(&db_cmd);
-        if let Err(e) = protocol::write_frame(&mut tcp, &binary).await {
-            let _ = send_error(&mut socket, format!("TCP send error: {}", e)).await;
-            return;
+    let mut required = vec!["_id"];
+    required.extend(columns.iter().map(|(name, _)| name.as_str()));
+    required.push("_version");
+
+    serde_json::json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": table,
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false
+    })
+}
+
+/// OpenAPI description of the HTTP routes. Table commands go over the `/ws`
+/// socket, which OpenAPI can't describe, so only the plain HTTP routes are listed.
+async fn openapi() -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "rust_db web API",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Table commands are sent as JSON over the WebSocket at /ws."
+        },
+        "paths": {
+            "/tables/{name}/schema.json": {
+                "get": {
+                    "summary": "JSON Schema for the rows of a table",
+                    "parameters": [{
+                        "name": "name",
+                        "in": "path",
+                        "required": true,
+                        "schema": {"type": "string"}
+                    }],
+                    "responses": {
+                        "200": {
+                            "description": "The table's row schema",
+                            "content": {"application/json": {"schema": {"type": "object"}}}
+                        },
+                        "404": {
+                            "description": "No such table",
+                            "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Error"}}}
+                        }
+                    }
+                }
+            },
+            "/tables/{name}/export.ndjson": {
+                "get": {
+                    "summary": "Every row of a table as newline-delimited JSON, in id order",
+                    "parameters": [{
+                        "name": "name",
+                        "in": "path",
+                        "required": true,
+                        "schema": {"type": "string"}
+                    }],
+                    "responses": {
+                        "200": {
+                            "description": "One JSON object per line, with the row's _id and _version",
+                            "content": {"application/x-ndjson": {"schema": {"type": "string"}}}
+                        },
+                        "404": {
+                            "description": "No such table",
+                            "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Error"}}}
+                        }
+                    }
+                }
+            },
+            "/tables/{name}/import": {
+                "post": {
+                    "summary": "Insert each line of a newline-delimited JSON body as a row",
+                    "parameters": [{
+                        "name": "name",
+                        "in": "path",
+                        "required": true,
+                        "schema": {"type": "string"}
+                    }],
+                    "requestBody": {
+                        "content": {"application/x-ndjson": {"schema": {"type": "string"}}}
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "How many rows were inserted, and the lines that failed with their errors",
+                            "content": {"application/json": {"schema": {"type": "object"}}}
+                        },
+                        "404": {
+                            "description": "No such table",
+                            "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Error"}}}
+                        }
+                    }
+                }
+            },
+            "/openapi.json": {
+                "get": {
+                    "summary": "This document",
+                    "responses": {"200": {"description": "OpenAPI document"}}
+                }
+            }
+        },
+        "components": {
+            "schemas": {
+                "Error": {
+                    "type": "object",
+                    "properties": {
+                        "ok": {"type": "boolean", "const": false},
+                        "code": {"type": "string"},
+                        "error": {"type": "string"}
+                    },
+                    "required": ["ok", "code", "error"]
+                }
+            }
         }
+    }))
+}
+
+/// Runs one command on a pooled database connection.
+async fn query(pool: &Arc<Pool>, command: &DbCommand) -> Result<DbResult, String> {
+    let mut conn = pool.get().await?;
+
+    let mut data = Vec::new();
+    protocol::encode_command_into(&mut data, command);
+    let frame_opts = conn.frame_opts;
+    let response = round_trip(&mut conn.tcp, &data, frame_opts, &mut Vec::new()).await;
+    if response.is_err() {
+        conn.retire();
+    }
+    protocol::decode_response(&response?)
+}
+
+async fn handle_socket(mut socket: WebSocket, pool: Arc<Pool>) {
+    // Session options live on the database connection, so a socket that sets
+    // any keeps that connection to itself, counting against the pool's size
+    // until the socket closes
+    let mut pinned: Option<PooledConn> = None;
 
-        // Read and decode response
-        let response_bytes = match protocol::read_frame(&mut tcp).await {
-            Ok(Some(b)) => b,
-            Ok(None) => {
-                let _ = send_error(&mut socket, "Connection closed".into()).await;
-                return;
+    // Reused across messages on this socket
+    let mut cmd_buf = Vec::new();
+    let mut write_buf = Vec::new();
+
+    let mut ping = WS_PING_INTERVAL.map(|period| {
+        let mut interval = time::interval_at(time::Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
+    let mut awaiting_pong = false;
+
+    loop {
+        let msg = tokio::select! {
+            msg = socket.recv() => match msg {
+                Some(Ok(msg)) => msg,
+                _ => return,
+            },
+            _ = next_tick(&mut ping) => {
+                if awaiting_pong {
+                    debug!("No pong from browser, closing socket");
+                    return;
+                }
+                if socket.send(Message::Ping(Vec::new())).await.is_err() {
+                    return;
+                }
+                awaiting_pong = true;
+                continue;
             }
+        };
+        let text = match msg {
+            Message::Text(text) => text,
+            Message::Pong(_) => {
+                awaiting_pong = false;
+                continue;
+            }
+            // Binary messages already hold an encoded command and get the raw response back
+            Message::Binary(data) => {
+                // A handshake, replicate or log subscription would repurpose the pooled
+                // connection for everyone who borrows it after this socket
+                if protocol::is_connection_request(&data) {
+                    let error = protocol::encode_error("Connection-level requests can't be sent through the web client");
+                    if socket.send(Message::Binary(error)).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                let pin = protocol::parse_set(&data).is_some();
+                let reply = match send_command(&mut socket, &pool, &mut pinned, &data, pin, &mut write_buf).await {
+                    Sent::Response(response) => Message::Binary(response),
+                    Sent::Failed => {
+                        awaiting_pong = false;
+                        continue;
+                    }
+                    Sent::Closed => return,
+                };
+                if socket.send(reply).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        debug!(command = %text, "Received command");
+        // Parse JSON directly to DbCommand, except for object inserts that may create their table
+        let parsed = match serde_json::from_str::<ObjectInsert>(&text) {
+            Ok(insert) if insert.kind == "insert" && insert.infer_schema => infer_insert(&pool, insert).await,
+            _ => serde_json::from_str(&text).map_err(|e| format!("Invalid JSON: {}", e)),
+        };
+        let db_cmd: DbCommand = match parsed {
+            Ok(cmd) => cmd,
             Err(e) => {
-                let _ = send_error(&mut socket, format!("TCP read error: {}", e)).await;
-                return;
+                let _ = send_error(&mut socket, e).await;
+                continue;
             }
         };
 
+        cmd_buf.clear();
+        protocol::encode_command_into(&mut cmd_buf, &db_cmd);
+        let pin = matches!(db_cmd, DbCommand::Set { .. });
+        let response_bytes = match send_command(&mut socket, &pool, &mut pinned, &cmd_buf, pin, &mut write_buf).await {
+            Sent::Response(response) => response,
+            Sent::Failed => {
+                awaiting_pong = false;
+                continue;
+            }
+            Sent::Closed => return,
+        };
+
         let json = match protocol::decode_response(&response_bytes) {
-            Ok(result) => result_to_json(&result),
-            Err(e) => serde_json::json!({"ok": false, "error": e}),
+            Ok(result) => serde_json::to_string(&result).expect("results serialize to JSON"),
+            Err(e) => error_json(&e).to_string(),
         };
 
-        if socket.send(Message::Text(json.to_string().into())).await.is_err() {
+        if socket.send(Message::Text(json)).await.is_err() {
             return;
         }
     }
 }
 
-fn result_to_json(result: &DbResult) -> serde_json::Value {
-    match result {
-        DbResult::Ok => serde_json::json!({"ok": true}),
-   
-        DbResult::Rows { columns, rows } => {
-            let json_rows: Vec<_> = rows
-                .iter()
-                .map(|(id, values)| {
-                    let mut obj = serde_json::Map::new();
-                    obj.insert("_id".into(), serde_json::json!(id));
-                    for (col, val) in columns.iter().zip(values) {
-                        obj.insert(col.clone(), value_to_json(val));
+/// Turns an object insert into a plain one, with the values in column order.
+/// A missing table is created first, with a column per field; a table that
+/// exists, inferred earlier or not, is checked against as it is.
+async fn infer_insert(pool: &Arc<Pool>, insert: ObjectInsert) -> Result<DbCommand, String> {
+    let ObjectInsert { table, values, idempotency_key, .. } = insert;
+    let table = if CASE_INSENSITIVE_TABLES { table.to_lowercase() } else { table };
+
+    let mut columns = table_columns(pool, &table).await?;
+    if columns.is_empty() {
+        let inferred = values
+            .iter()
+            .map(|(name, value)| Ok((name.clone(), infer_type(name, value)?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        let create = inferred
+            .iter()
+            .fold(DbCommand::create_table(&table), |create, (name, col_type)| create.column(name, col_type.clone()))
+            .build()?;
+        columns = match query(pool, &create).await {
+            Ok(_) => inferred,
+            // Another socket got there first, and its schema is the one to follow
+            Err(e) if e.starts_with("Table already exists") => table_columns(pool, &table).await?,
+            Err(e) => return Err(e),
+        };
+    }
+
+    let values = order_values(&columns, values)?;
+    Ok(DbCommand::InsertRow { table, values, idempotency_key })
+}
+
+/// Values keyed by column name as a row, in column order. Every column needs
+/// a value, and every value a column.
+fn order_values(
+    columns: &[(String, ColumnType)],
+    mut values: serde_json::Map<String, serde_json::Value>,
+) -> Result<Vec<Value>, String> {
+    let mut ordered = Vec::with_capacity(columns.len());
+    for (name, _) in columns {
+        let value = values.remove(name).ok_or_else(|| format!("Expected a value for column {}", name))?;
+        ordered.push(serde_json::from_value(value).map_err(|e| format!("Invalid JSON: {}", e))?);
+    }
+    if let Some(name) = values.keys().next() {
+        return Err(format!("Column not found: {}", name));
+    }
+    Ok(ordered)
+}
+
+fn infer_type(column: &str, value: &serde_json::Value) -> Result<ColumnType, String> {
+    match value {
+        serde_json::Value::Number(_) => Ok(ColumnType::Int),
+        serde_json::Value::String(_) => Ok(ColumnType::Text),
+        serde_json::Value::Bool(_) => Ok(ColumnType::Bool),
+        other => Err(format!("Type mismatch for column {}: can't infer a type from {}", column, other)),
+    }
+}
+
+/// What became of a command sent on behalf of a socket.
+enum Sent {
+    Response(Vec<u8>),
+    /// The command failed and the browser has been told
+    Failed,
+    /// The socket should be closed
+    Closed,
+}
+
+/// Sends one encoded command on the socket's pinned connection, or else a
+/// pooled one. With `pin` set the connection stays with the socket afterwards.
+/// Pinned connections never go back to the pool, as they carry session options.
+async fn send_command(
+    socket: &mut WebSocket,
+    pool: &Arc<Pool>,
+    pinned: &mut Option<PooledConn>,
+    command: &[u8],
+    pin: bool,
+    write_buf: &mut Vec<u8>,
+) -> Sent {
+    let keep = pin || pinned.is_some();
+    let conn = match pinned.take() {
+        Some(conn) => Ok(conn),
+        None => pool.get().await,
+    };
+    let error = match conn {
+        Ok(mut conn) => {
+            let frame_opts = conn.frame_opts;
+            match round_trip(&mut conn.tcp, command, frame_opts, write_buf).await {
+                Ok(response) => {
+                    if keep {
+                        conn.retire();
+                        *pinned = Some(conn);
                     }
-                    serde_json::Value::Object(obj)
-                })
-                .collect();
-       
-            serde_json::json!({
-                "ok": true,
-                "columns": columns,
-                "rows": json_rows
-            })
+                    return Sent::Response(response);
+                }
+                Err(e) => {
+                    conn.retire();
+                    e
+                }
+            }
         }
+        Err(e) => e,
+    };
+
+    // The other idle connections most likely broke the same way
+    pool.clear_idle();
+    if recover(socket, pool, error).await { Sent::Failed } else { Sent::Closed }
+}
+
+/// Called when a command failed to reach the database: waits until it can be
+/// reached again, then answers the command with `error`. It isn't retried,
+/// since it may have run before the connection broke. Returns false if the
+/// socket should be closed.
+async fn recover(socket: &mut WebSocket, pool: &Arc<Pool>, error: String) -> bool {
+    warn!(error = %error, "Lost database connection");
+    let reconnected = reconnect(socket, pool).await;
+    send_error(socket, error).await.is_ok() && reconnected
+}
+
+/// Retries with exponential backoff, sending the browser a status message
+/// before each attempt and once connected. Returns false once it gives up.
+async fn reconnect(socket: &mut WebSocket, pool: &Arc<Pool>) -> bool {
+    let mut delay = DB_RECONNECT_DELAY;
+    for attempt in 1..=DB_RECONNECT_ATTEMPTS {
+        let status = serde_json::json!({"status": "reconnecting", "attempt": attempt});
+        if socket.send(Message::Text(status.to_string())).await.is_err() {
+            return false;
+        }
+        time::sleep(delay).await;
+
+        // The fresh connection goes back into the pool for the next command
+        match pool.get().await {
+            Ok(_) => {
+                info!(attempt, "Reconnected to database");
+                let status = serde_json::json!({"status": "connected"});
+                return socket.send(Message::Text(status.to_string())).await.is_ok();
+            }
+            Err(e) => debug!(error = %e, attempt, "Reconnect failed"),
+        }
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+    false
+}
+
+/// Waits for the next tick, or forever when pings are disabled.
+async fn next_tick(interval: &mut Option<time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
     }
 }
 
-fn value_to_json(v: &Value) -> serde_json::Value {
-    match v {
-        Value::Int(i) => serde_json::json!(i),
-        Value::Text(s) => serde_json::json!(s),
-        Value::Bool(b) => serde_json::json!(b),
+/// Sends one encoded command to the database and reads back its encoded response.
+async fn round_trip(
+    tcp: &mut TcpStream,
+    command: &[u8],
+    frame_opts: protocol::FrameOptions,
+    write_buf: &mut Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    if let Err(e) = protocol::write_frame_buffered(tcp, command, frame_opts, write_buf).await {
+        return Err(format!("TCP send error: {}", e));
+    }
+
+    match protocol::read_frame_with(tcp, frame_opts).await {
+        Ok(Some(b)) => Ok(b),
+        Ok(None) => Err("Connection closed".into()),
+        Err(e) => Err(format!("TCP read error: {}", e)),
     }
 }
 
+fn error_json(error: &str) -> serde_json::Value {
+    serde_json::json!({"ok": false, "code": error_code(error), "error": error})
+}
+
 async fn send_error(socket: &mut WebSocket, error: String) -> Result<(), axum::Error> {
-    let json = serde_json::json!({"ok": false, "error": error});
-    socket.send(Message::Text(json.to_string().into())).await
+    let json = error_json(&error);
+    socket.send(Message::Text(json.to_string())).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::listener::tests::database;
+    use futures_util::SinkExt;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+    type Browser = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>;
+
+    /// Starts a database server and the web client in front of it, each on a
+    /// free local port, and returns the web client's address.
+    async fn web() -> SocketAddr {
+        web_for(&database().await).await
+    }
+
+    /// Starts the web client in front of the database at `db_address`.
+    async fn web_for(db_address: &str) -> SocketAddr {
+        let web_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let web_address = web_listener.local_addr().unwrap();
+        let pool = Pool::new(db_address, 2, Duration::from_secs(1));
+        tokio::spawn(async move { axum::serve(web_listener, app(pool)).await });
+        web_address
+    }
+
+    /// Opens a WebSocket to a fresh web client.
+    async fn browser() -> Browser {
+        browser_at(web().await).await
+    }
+
+    async fn browser_at(addr: SocketAddr) -> Browser {
+        let (socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+        socket
+    }
+
+    /// Makes one HTTP/1.1 request and returns the status code and body.
+    async fn http(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            addr,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (head, mut body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut chunks = String::new();
+        if head.to_ascii_lowercase().contains("transfer-encoding: chunked") {
+            while let Some((size, rest)) = body.split_once("\r\n") {
+                let size = usize::from_str_radix(size, 16).unwrap();
+                chunks.push_str(&rest[..size]);
+                body = &rest[size + 2..];
+            }
+            return (status, chunks);
+        }
+        (status, body.to_string())
+    }
+
+    #[tokio::test]
+    async fn table_schemas_map_column_types_to_json_types() {
+        let addr = web().await;
+        let (status, _) = http(addr, "GET", "/tables/t/schema.json", "").await;
+        assert_eq!(status, 404);
+
+        let mut socket = browser_at(addr).await;
+        socket
+            .send(WsMessage::Text(r#"{"type":"createTable","table":"t","columns":[["n","int"],["s","text"],["b","bool"]]}"#.into()))
+            .await
+            .unwrap();
+        reply(&mut socket).await;
+
+        let (status, body) = http(addr, "GET", "/tables/t/schema.json", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({
+                "$schema": "https://json-schema.org/draft/2020-12/schema",
+                "title": "t",
+                "type": "object",
+                "properties": {
+                    "_id": {"type": "integer", "readOnly": true},
+                    "n": {"type": "integer"},
+                    "s": {"type": "string"},
+                    "b": {"type": "boolean"},
+                    "_version": {"type": "integer", "readOnly": true}
+                },
+                "required": ["_id", "n", "s", "b", "_version"],
+                "additionalProperties": false
+            })
+        );
+    }
+
+    /// The next data message, skipping pings.
+    async fn reply(socket: &mut Browser) -> WsMessage {
+        loop {
+            match socket.next().await.unwrap().unwrap() {
+                WsMessage::Ping(_) | WsMessage::Pong(_) => continue,
+                msg => return msg,
+            }
+        }
+    }
+
+    #[test]
+    fn missing_tables_reply_with_their_error_code() {
+        let mut db = Database::default();
+        let cmd: DbCommand = serde_json::from_str(r#"{"type":"selectAll","table":"nope"}"#).unwrap();
+        let error = db.execute(cmd).unwrap_err();
+        assert_eq!(
+            error_json(&error),
+            serde_json::json!({"ok": false, "code": "table_not_found", "error": "Table not found"})
+        );
+
+        // Failed commands inside a batch carry their code too
+        let batch = DbResult::Batch { results: vec![Ok(DbResult::Ok), Err(error)] };
+        assert_eq!(
+            serde_json::to_value(&batch).unwrap()["results"][1],
+            serde_json::json!({"ok": false, "code": "table_not_found", "error": "Table not found"})
+        );
+    }
+
+    #[tokio::test]
+    async fn binary_messages_get_binary_replies() {
+        let mut socket = browser().await;
+        let create: DbCommand = serde_json::from_str(r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).unwrap();
+        let mut data = Vec::new();
+        protocol::encode_command_into(&mut data, &create);
+        socket.send(WsMessage::Binary(data)).await.unwrap();
+        assert_eq!(reply(&mut socket).await, WsMessage::Binary(protocol::encode_result(&DbResult::Ok)));
+
+        // Text messages on the same socket still get JSON
+        socket.send(WsMessage::Text(r#"{"type":"insert","table":"t","values":[1]}"#.into())).await.unwrap();
+        let WsMessage::Text(json) = reply(&mut socket).await else { panic!("expected text") };
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&json).unwrap(), serde_json::json!({"ok": true, "rowId": 1}));
+    }
+
+    #[tokio::test]
+    async fn connection_level_frames_are_refused_over_websockets() {
+        let mut socket = browser().await;
+        let refused = WsMessage::Binary(protocol::encode_error("Connection-level requests can't be sent through the web client"));
+        for frame in [
+            protocol::encode_handshake(protocol::PROTOCOL_VERSION, protocol::FrameOptions::default()),
+            protocol::encode_replicate_request(""),
+            protocol::encode_subscribe_logs_request(),
+        ] {
+            socket.send(WsMessage::Binary(frame)).await.unwrap();
+            assert_eq!(reply(&mut socket).await, refused);
+        }
+
+        // The pooled connection still runs commands
+        socket.send(WsMessage::Text(r#"{"type":"ping"}"#.into())).await.unwrap();
+        let WsMessage::Text(json) = reply(&mut socket).await else { panic!("expected text") };
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&json).unwrap()["ok"], true);
+    }
+
+    #[tokio::test]
+    async fn openapi_lists_every_http_route() {
+        let (status, body) = http(web().await, "GET", "/openapi.json", "").await;
+        assert_eq!(status, 200);
+        let spec: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(spec["openapi"], "3.1.0");
+        let paths: Vec<&str> = spec["paths"].as_object().unwrap().keys().map(String::as_str).collect();
+        assert_eq!(
+            paths,
+            ["/openapi.json", "/tables/{name}/export.ndjson", "/tables/{name}/import", "/tables/{name}/schema.json"]
+        );
+        assert!(spec["paths"]["/tables/{name}/import"]["post"].is_object());
+    }
+
+    #[tokio::test]
+    async fn out_of_range_and_decimal_numbers_get_their_own_codes() {
+        let mut socket = browser().await;
+        socket.send(WsMessage::Text(r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#.into())).await.unwrap();
+        reply(&mut socket).await;
+
+        for (number, code) in [("9223372036854775808", "integer_out_of_range"), ("2.5", "not_an_integer")] {
+            let insert = format!(r#"{{"type":"insert","table":"t","values":[{}]}}"#, number);
+            socket.send(WsMessage::Text(insert)).await.unwrap();
+            let WsMessage::Text(json) = reply(&mut socket).await else { panic!("expected text") };
+            let response: serde_json::Value = serde_json::from_str(&json).unwrap();
+            assert_eq!(response["code"], code, "{}", json);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_sockets_are_pinged_and_kept_open_while_pongs_come_back() {
+        let period = WS_PING_INTERVAL.unwrap();
+        let mut socket = browser().await;
+        let started = time::Instant::now();
+        // The client answers each ping with a pong as it reads on
+        for tick in 1..=2 {
+            assert!(matches!(socket.next().await.unwrap().unwrap(), WsMessage::Ping(_)));
+            assert!(started.elapsed() >= period * tick);
+        }
+
+        socket.send(WsMessage::Text(r#"{"type":"ping"}"#.into())).await.unwrap();
+        assert_eq!(reply(&mut socket).await, WsMessage::Text(r#"{"ok":true}"#.into()));
+    }
+
+    /// Forwards connections to `target` until told to cut the ones open so far.
+    async fn proxy(target: String) -> (String, tokio::sync::watch::Sender<u32>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (cut, cuts) = tokio::sync::watch::channel(0);
+        tokio::spawn(async move {
+            while let Ok((mut inbound, _)) = listener.accept().await {
+                let mut outbound = TcpStream::connect(&target).await.unwrap();
+                let mut cuts = cuts.clone();
+                cuts.mark_unchanged();
+                tokio::spawn(async move {
+                    tokio::select! {
+                        _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound) => {}
+                        _ = cuts.changed() => {}
+                    }
+                });
+            }
+        });
+        (address, cut)
+    }
+
+    async fn text_reply(socket: &mut Browser) -> serde_json::Value {
+        let WsMessage::Text(json) = reply(socket).await else { panic!("expected text") };
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn sockets_reconnect_after_the_database_connection_drops() {
+        let (db_address, cut) = proxy(database().await).await;
+        let mut socket = browser_at(web_for(&db_address).await).await;
+        let ping = || WsMessage::Text(r#"{"type":"ping"}"#.into());
+        socket.send(ping()).await.unwrap();
+        assert_eq!(text_reply(&mut socket).await, serde_json::json!({"ok": true}));
+
+        // The pooled connection breaks under the next command
+        cut.send_modify(|n| *n += 1);
+        socket.send(ping()).await.unwrap();
+        assert_eq!(text_reply(&mut socket).await, serde_json::json!({"status": "reconnecting", "attempt": 1}));
+        assert_eq!(text_reply(&mut socket).await, serde_json::json!({"status": "connected"}));
+        // The command isn't retried, since it may have run
+        assert_eq!(text_reply(&mut socket).await["ok"], false);
+
+        socket.send(ping()).await.unwrap();
+        assert_eq!(text_reply(&mut socket).await, serde_json::json!({"ok": true}));
+    }
+
+    #[tokio::test]
+    async fn concurrent_sockets_share_the_pool() {
+        let addr = web().await;
+        let mut socket = browser_at(addr).await;
+        socket.send(WsMessage::Text(r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#.into())).await.unwrap();
+        reply(&mut socket).await;
+
+        // More sockets than pooled connections, each sending as fast as it can
+        let sessions = (0..6).map(|session| {
+            tokio::spawn(async move {
+                let mut socket = browser_at(addr).await;
+                for i in 0..20 {
+                    let insert = format!(r#"{{"type":"insert","table":"t","values":[{}]}}"#, session * 100 + i);
+                    socket.send(WsMessage::Text(insert)).await.unwrap();
+                    assert_eq!(text_reply(&mut socket).await["ok"], true);
+                }
+            })
+        });
+        for session in sessions.collect::<Vec<_>>() {
+            session.await.unwrap();
+        }
+
+        socket.send(WsMessage::Text(r#"{"type":"listIds","table":"t"}"#.into())).await.unwrap();
+        assert_eq!(text_reply(&mut socket).await["rows"].as_array().unwrap().len(), 120);
+    }
+
+    #[tokio::test]
+    async fn inserts_can_create_their_table_with_inferred_types() {
+        let mut socket = browser().await;
+        let mut send = async |json: &str| {
+            socket.send(WsMessage::Text(json.into())).await.unwrap();
+            text_reply(&mut socket).await
+        };
+
+        let insert = r#"{"type":"insert","table":"people","inferSchema":true,"values":{"name":"ann","age":30,"admin":false}}"#;
+        assert_eq!(send(insert).await, serde_json::json!({"ok": true, "rowId": 1}));
+        let tables = send(r#"{"type":"getTables"}"#).await;
+        let schema: Vec<(&str, &str)> = tables["rows"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| (row["column_name"].as_str().unwrap(), row["column_type"].as_str().unwrap()))
+            .collect();
+        assert_eq!(schema, [("admin", "bool"), ("age", "int"), ("name", "text")]);
+
+        // Later inserts are checked against what was inferred
+        let insert = r#"{"type":"insert","table":"people","inferSchema":true,"values":{"name":"bob","age":"old","admin":true}}"#;
+        assert_eq!(send(insert).await["code"], "type_mismatch");
+        let insert = r#"{"type":"insert","table":"people","inferSchema":true,"values":{"name":"bob","age":41,"admin":true}}"#;
+        assert_eq!(send(insert).await["rowId"], 2);
+        // Without the flag a missing table stays missing
+        let insert = r#"{"type":"insert","table":"pets","values":{"name":"rex"}}"#;
+        assert_eq!(send(insert).await["ok"], false);
+        let insert = r#"{"type":"insert","table":"pets","inferSchema":true,"values":{"tags":["a"]}}"#;
+        assert_eq!(send(insert).await["code"], "type_mismatch");
+    }
+
+    #[tokio::test]
+    async fn exports_write_one_json_object_per_row() {
+        let addr = web().await;
+        assert_eq!(http(addr, "GET", "/tables/t/export.ndjson", "").await.0, 404);
+
+        let mut socket = browser_at(addr).await;
+        for json in [
+            r#"{"type":"createTable","table":"t","columns":[["n","int"],["s","text"],["b","bool"]]}"#,
+            r#"{"type":"insert","table":"t","values":[1,"plain",true]}"#,
+            r#"{"type":"insert","table":"t","values":[2,"gone",true]}"#,
+            r#"{"type":"insert","table":"t","values":[-3,"line\nbreak \"quoted\"",false]}"#,
+            r#"{"type":"deleteRow","table":"t","rowId":2}"#,
+        ] {
+            socket.send(WsMessage::Text(json.into())).await.unwrap();
+            assert_eq!(text_reply(&mut socket).await["ok"], true);
+        }
+
+        let (status, body) = http(addr, "GET", "/tables/t/export.ndjson", "").await;
+        assert_eq!(status, 200);
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"_id":1,"n":1,"s":"plain","b":true,"_version":1}"#,
+                r#"{"_id":3,"n":-3,"s":"line\nbreak \"quoted\"","b":false,"_version":1}"#,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn imports_insert_each_line_and_report_failures() {
+        let addr = web().await;
+        assert_eq!(http(addr, "POST", "/tables/t/import", "{}\n").await.0, 404);
+
+        let mut socket = browser_at(addr).await;
+        socket.send(WsMessage::Text(r#"{"type":"createTable","table":"t","columns":[["n","int"],["s","text"]]}"#.into())).await.unwrap();
+        text_reply(&mut socket).await;
+
+        // An exported row, a blank line, two bad lines and a last line with no newline
+        let body = concat!(
+            "{\"_id\":7,\"n\":1,\"s\":\"a\",\"_version\":3}\n",
+            "\n",
+            "{\"n\":\"two\",\"s\":\"b\"}\n",
+            "not json\n",
+            "{\"n\":4,\"s\":\"d\"}",
+        );
+        let (status, reply) = http(addr, "POST", "/tables/t/import", body).await;
+        assert_eq!(status, 200);
+        let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(reply["inserted"], 2);
+        let failed: Vec<(u64, &str)> = reply["failed"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| (f["line"].as_u64().unwrap(), f["code"].as_str().unwrap()))
+            .collect();
+        assert_eq!(failed, [(3, "type_mismatch"), (4, "invalid_json")]);
+
+        socket.send(WsMessage::Text(r#"{"type":"listIds","table":"t"}"#.into())).await.unwrap();
+        assert_eq!(text_reply(&mut socket).await["rows"].as_array().unwrap().len(), 2);
+    }
 }
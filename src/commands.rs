@@ -1,21 +1,45 @@
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::ser::{SerializeMap, SerializeSeq};
+use serde::{Deserialize, Serialize, Serializer};
+use tracing::warn;
 
-use std::collections::HashMap;
-use serde::{Deserialize, Serialize};
-
+use crate::config::{MAX_NAME_LENGTH, STRICT_NAMES};
 use crate::db::Database;
-use crate::db_types::{Column, ColumnType, Table, Value};
+use crate::db_types::{
+    Acl, ChangeLog, check_row_width, Column, ColumnType, ForeignKey, IdempotencyCache, MAX_COLUMNS, OnDelete, RowCursor, SortedIndex, Table,
+    TextIndex, Value,
+};
+use crate::filter::Filter;
+use crate::migration::MigrationStep;
+use crate::row_store::RowStore;
+use crate::{protocol, snapshot};
+
+// Cursors are only freed once fully fetched, so cap how many can pile up
+const MAX_OPEN_CURSORS: usize = 1024;
+// Number of recent insert idempotency keys remembered across all tables
+const IDEMPOTENCY_CACHE_SIZE: usize = 10_000;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum DbCommand {
     CreateTable {
         table: String,
         columns: Vec<(String, ColumnType)>,
+        /// Columns whose combined values must be unique across rows
+        #[serde(default)]
+        key: Vec<String>,
+        #[serde(default, rename = "foreignKeys")]
+        foreign_keys: Vec<ForeignKeyDef>,
     },
     #[serde(rename = "insert")]
     InsertRow {
         table: String,
         values: Vec<Value>,
+        #[serde(default, rename = "idempotencyKey")]
+        idempotency_key: Option<String>,
     },
     #[serde(rename = "update")]
     UpdateRow {
@@ -23,25 +47,833 @@ pub enum DbCommand {
         #[serde(rename = "rowId")]
         row_id: u64,
         updates: HashMap<String, Value>,
+        #[serde(default, rename = "expectedVersion")]
+        expected_version: Option<u64>,
     },
+    /// With a limit, returns one page of rows and a cursor for the next one.
+    /// Pages continue after the last row id seen, so rows inserted while
+    /// paging show up on a later page instead of shifting earlier ones.
     SelectAll {
         table: String,
+        #[serde(default, rename = "withTypes")]
+        with_types: bool,
+        #[serde(default)]
+        limit: Option<u32>,
+        #[serde(default)]
+        cursor: Option<String>,
+        /// Leaves out the server-managed columns like `_version`, which
+        /// otherwise follow the table's own columns
+        #[serde(default, rename = "excludeMetadata")]
+        exclude_metadata: bool,
     },
        GetTables {
       
     },
+    Ping {},
+    SelectCursor {
+        table: String,
+    },
+    Fetch {
+        #[serde(rename = "cursorId")]
+        cursor_id: u64,
+        n: u32,
+    },
+    SelectWhere {
+        table: String,
+        filter: Filter,
+        #[serde(default)]
+        limit: Option<u32>,
+        #[serde(default)]
+        offset: Option<u32>,
+        #[serde(default, rename = "orderBy")]
+        order_by: Option<OrderBy>,
+        #[serde(default, rename = "excludeMetadata")]
+        exclude_metadata: bool,
+    },
+    CreateTextIndex {
+        table: String,
+        column: String,
+    },
+    SearchText {
+        table: String,
+        column: String,
+        query: String,
+    },
+    Explain {
+        inner: Box<DbCommand>,
+    },
+    Join {
+        left: String,
+        right: String,
+        #[serde(rename = "leftCol")]
+        left_col: String,
+        #[serde(rename = "rightCol")]
+        right_col: String,
+    },
+    GroupCount {
+        table: String,
+        column: String,
+    },
+    Batch {
+        commands: Vec<DbCommand>,
+        #[serde(default, rename = "ignoreErrors")]
+        ignore_errors: bool,
+    },
+    ListIds {
+        table: String,
+    },
+    SelectByIds {
+        table: String,
+        ids: Vec<u64>,
+        #[serde(default, rename = "ignoreMissing")]
+        ignore_missing: bool,
+    },
+    Reset {},
+    Migrate {
+        table: String,
+        steps: Vec<MigrationStep>,
+    },
+    Snapshot {},
+    DeleteRow {
+        table: String,
+        #[serde(rename = "rowId")]
+        row_id: u64,
+    },
+    CreateIndex {
+        table: String,
+        column: String,
+    },
+    ServerInfo {},
+    /// Sets a session option for the rest of the connection. Handled by the
+    /// connection itself, so it can't appear inside a batch.
+    Set {
+        key: String,
+        value: String,
+    },
+    /// Checks `inner` as if running it, without changing anything.
+    Validate {
+        inner: Box<DbCommand>,
+    },
+    /// Renumbers the table's rows from 1. Changes row ids.
+    Compact {
+        table: String,
+    },
+    /// The schema of every table with real column types, unlike GetTables' text rows.
+    GetSchema {},
+    /// Hands out the next `count` row ids so clients can insert them later with InsertWithId.
+    ReserveIds {
+        table: String,
+        count: u64,
+    },
+    /// Inserts a row under an id the client chose, usually one from ReserveIds.
+    InsertWithId {
+        table: String,
+        #[serde(rename = "rowId")]
+        row_id: u64,
+        values: Vec<Value>,
+    },
+    /// Creates `to` with the schema, indexes and rows of `from`. Rows are renumbered
+    /// from 1 unless `preserve_ids` is set.
+    CopyTable {
+        from: String,
+        to: String,
+        #[serde(default, rename = "preserveIds")]
+        preserve_ids: bool,
+    },
+    /// A hash of the table's column names and types, to detect schema changes cheaply.
+    SchemaHash {
+        table: String,
+    },
+    /// Keeps at most `max_rows` of the table's rows in memory and spills the
+    /// least recently used ones to disk. `None` brings every row back into memory.
+    SetMemoryLimit {
+        table: String,
+        #[serde(default, rename = "maxRows")]
+        max_rows: Option<u64>,
+    },
+    /// Distinct values of `column` with their counts, most frequent first.
+    /// At most `limit` values are returned; the rest are dropped and `truncated` is set.
+    ColumnHistogram {
+        table: String,
+        column: String,
+        #[serde(default)]
+        limit: Option<u32>,
+    },
+    /// Adds `by` to an int column and returns the new value. Runs in the
+    /// database loop, so concurrent increments can't lose updates.
+    Increment {
+        table: String,
+        #[serde(rename = "rowId")]
+        row_id: u64,
+        column: String,
+        by: i64,
+    },
+    /// Sets `column` to `new` only if it currently equals `expected`.
+    CompareAndSwap {
+        table: String,
+        #[serde(rename = "rowId")]
+        row_id: u64,
+        column: String,
+        expected: Value,
+        new: Value,
+    },
+    /// The `n` newest rows, highest row id first.
+    Tail {
+        table: String,
+        n: u32,
+    },
+    /// Restricts who may read and write the table. `None` opens it to everyone.
+    SetAcl {
+        table: String,
+        #[serde(default)]
+        acl: Option<Acl>,
+    },
+    /// Makes the next inserted row get id `start`. Every existing row must
+    /// have a lower id. Ids handed out by ReserveIds aren't tracked, so
+    /// resetting below them lets later inserts reuse them.
+    ResetSequence {
+        table: String,
+        start: u64,
+    },
+    /// The lowest and highest row id in use, as one row of `min_id` and
+    /// `max_id`. An empty table gives no row, as there's no null value.
+    IdBounds {
+        table: String,
+    },
+    /// Runs `commands` as one unit: if any fails, none of them take effect
+    /// and the error is that command's.
+    Atomic {
+        commands: Vec<DbCommand>,
+    },
+    /// Whether the table exists, or with `row_id`, whether that row does.
+    /// A missing table isn't an error; its rows just don't exist.
+    Exists {
+        table: String,
+        #[serde(default, rename = "rowId")]
+        row_id: Option<u64>,
+    },
+    /// Rows inserted or updated after change number `since_version`, and the
+    /// ids of rows deleted after it. Start from 0 to get every row, then pass
+    /// back the `version` of each reply to get only what changed since.
+    ChangesSince {
+        table: String,
+        #[serde(default, rename = "sinceVersion")]
+        since_version: u64,
+    },
+    /// Creates every table in `tables`, or none of them if any can't be created.
+    CreateTables {
+        tables: Vec<(String, Vec<(String, ColumnType)>)>,
+    },
+    /// Up to `n` rows picked at random, in row id order. The same `seed`
+    /// picks the same rows from the same table.
+    Sample {
+        table: String,
+        n: u32,
+        #[serde(default)]
+        seed: Option<u64>,
+    },
+    /// The table's column names in creation order, one per row under
+    /// `column_name`. Server-managed columns like `_version` aren't listed.
+    Columns {
+        table: String,
+    },
+    /// Runs `inner` and adds how long it took to execute, in microseconds,
+    /// to its result. Failed commands come back as plain errors.
+    Timed {
+        inner: Box<DbCommand>,
+    },
+    /// Deletes rows that repeat an earlier row's values in `columns`.
+    Dedup {
+        table: String,
+        #[serde(default)]
+        columns: Vec<String>,
+    },
+    SwapIds {
+        table: String,
+        a: u64,
+        b: u64,
+    },
+    /// Moves a row to another table with the same columns. The row gets a new id there.
+    MoveRow {
+        from: String,
+        to: String,
+        #[serde(rename = "rowId")]
+        row_id: u64,
+    },
+    /// The server's version and the range of protocol versions it speaks.
+    Version {},
+    /// The table's indexes, one per row under `column` and `kind`: `btree`
+    /// for CreateIndex, `fulltext` for CreateTextIndex and `unique` for the
+    /// table's key, whose columns are listed comma-separated.
+    ListIndexes {
+        table: String,
+    },
+}
+
+/// Sorts results by one column, ties broken by row id. Descending reverses both.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OrderBy {
+    pub column: String,
+    #[serde(default)]
+    pub descending: bool,
+}
+
+/// Declares that `column` may only hold values present in the key of `parent`,
+/// which must be a single column of the same type.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ForeignKeyDef {
+    pub column: String,
+    pub parent: String,
+    #[serde(default, rename = "onDelete")]
+    pub on_delete: OnDelete,
+}
+
+/// Builds a CreateTable command, e.g.
+/// `DbCommand::create_table("users").column("name", ColumnType::Text).build()`.
+pub struct CreateTableBuilder {
+    table: String,
+    columns: Vec<(String, ColumnType)>,
+}
+
+impl CreateTableBuilder {
+    pub fn column(mut self, name: impl Into<String>, col_type: ColumnType) -> Self {
+        self.columns.push((name.into(), col_type));
+        self
+    }
+
+    /// Fails on a column added twice, which the server would reject anyway.
+    pub fn build(self) -> Result<DbCommand, String> {
+        let mut seen = HashSet::new();
+        if let Some((name, _)) = self.columns.iter().find(|(name, _)| !seen.insert(name)) {
+            return Err(format!("Duplicate column name {}", name));
+        }
+        Ok(DbCommand::CreateTable { table: self.table, columns: self.columns, key: Vec::new(), foreign_keys: Vec::new() })
+    }
+}
+
+impl DbCommand {
+    pub fn create_table(table: impl Into<String>) -> CreateTableBuilder {
+        CreateTableBuilder { table: table.into(), columns: Vec::new() }
+    }
+
+    /// Lowercases every table name the command refers to, including nested commands.
+    pub fn lowercase_table_names(&mut self) {
+        self.map_table_names(&mut |t| *t = t.to_lowercase());
+    }
+
+    /// Calls `f` on every table name the command refers to, including nested commands.
+    pub fn map_table_names(&mut self, f: &mut dyn FnMut(&mut String)) {
+        match self {
+            // Foreign keys name their parent tables too
+            DbCommand::CreateTable { table, foreign_keys, .. } => {
+                f(table);
+                foreign_keys.iter_mut().for_each(|fk| f(&mut fk.parent));
+            }
+            DbCommand::InsertRow { table, .. }
+            | DbCommand::UpdateRow { table, .. }
+            | DbCommand::SelectAll { table, .. }
+            | DbCommand::SelectCursor { table }
+            | DbCommand::SelectWhere { table, .. }
+            | DbCommand::CreateTextIndex { table, .. }
+            | DbCommand::SearchText { table, .. }
+            | DbCommand::GroupCount { table, .. }
+            | DbCommand::ListIds { table }
+            | DbCommand::SelectByIds { table, .. }
+            | DbCommand::Migrate { table, .. }
+            | DbCommand::DeleteRow { table, .. }
+            | DbCommand::Compact { table }
+            | DbCommand::ReserveIds { table, .. }
+            | DbCommand::InsertWithId { table, .. }
+            | DbCommand::SchemaHash { table }
+            | DbCommand::SetMemoryLimit { table, .. }
+            | DbCommand::ColumnHistogram { table, .. }
+            | DbCommand::Increment { table, .. }
+            | DbCommand::CompareAndSwap { table, .. }
+            | DbCommand::Tail { table, .. }
+            | DbCommand::SetAcl { table, .. }
+            | DbCommand::ResetSequence { table, .. }
+            | DbCommand::IdBounds { table }
+            | DbCommand::Exists { table, .. }
+            | DbCommand::ChangesSince { table, .. }
+            | DbCommand::Sample { table, .. }
+            | DbCommand::Columns { table }
+            | DbCommand::Dedup { table, .. }
+            | DbCommand::SwapIds { table, .. }
+            | DbCommand::ListIndexes { table }
+            | DbCommand::CreateIndex { table, .. } => f(table),
+            DbCommand::Join { left, right, .. }
+            | DbCommand::CopyTable { from: left, to: right, .. }
+            | DbCommand::MoveRow { from: left, to: right, .. } => {
+                f(left);
+                f(right);
+            }
+            DbCommand::CreateTables { tables } => tables.iter_mut().for_each(|(table, _)| f(table)),
+            DbCommand::Explain { inner } | DbCommand::Validate { inner } | DbCommand::Timed { inner } => {
+                inner.map_table_names(f)
+            }
+            DbCommand::Batch { commands, .. } | DbCommand::Atomic { commands } => {
+                commands.iter_mut().for_each(|c| c.map_table_names(f))
+            }
+            DbCommand::GetTables {}
+            | DbCommand::Ping {}
+            | DbCommand::ServerInfo {}
+            | DbCommand::Set { .. }
+            | DbCommand::Fetch { .. }
+            | DbCommand::Reset {}
+            | DbCommand::GetSchema { .. }
+            | DbCommand::Version {}
+            | DbCommand::Snapshot {} => {}
+        }
+    }
+
+    /// The command's `type` in JSON, for logging.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DbCommand::CreateTable { .. } => "createTable",
+            DbCommand::InsertRow { .. } => "insert",
+            DbCommand::UpdateRow { .. } => "update",
+            DbCommand::SelectAll { .. } => "selectAll",
+            DbCommand::GetTables {} => "getTables",
+            DbCommand::Ping {} => "ping",
+            DbCommand::SelectCursor { .. } => "selectCursor",
+            DbCommand::Fetch { .. } => "fetch",
+            DbCommand::SelectWhere { .. } => "selectWhere",
+            DbCommand::CreateTextIndex { .. } => "createTextIndex",
+            DbCommand::SearchText { .. } => "searchText",
+            DbCommand::Explain { .. } => "explain",
+            DbCommand::Join { .. } => "join",
+            DbCommand::GroupCount { .. } => "groupCount",
+            DbCommand::Batch { .. } => "batch",
+            DbCommand::ListIds { .. } => "listIds",
+            DbCommand::SelectByIds { .. } => "selectByIds",
+            DbCommand::Reset {} => "reset",
+            DbCommand::Migrate { .. } => "migrate",
+            DbCommand::Snapshot {} => "snapshot",
+            DbCommand::DeleteRow { .. } => "deleteRow",
+            DbCommand::CreateIndex { .. } => "createIndex",
+            DbCommand::ServerInfo {} => "serverInfo",
+            DbCommand::Set { .. } => "set",
+            DbCommand::Validate { .. } => "validate",
+            DbCommand::ListIndexes { .. } => "listIndexes",
+            DbCommand::Version {} => "version",
+            DbCommand::MoveRow { .. } => "moveRow",
+            DbCommand::SwapIds { .. } => "swapIds",
+            DbCommand::Dedup { .. } => "dedup",
+            DbCommand::Timed { .. } => "timed",
+            DbCommand::Columns { .. } => "columns",
+            DbCommand::Sample { .. } => "sample",
+            DbCommand::CreateTables { .. } => "createTables",
+            DbCommand::ChangesSince { .. } => "changesSince",
+            DbCommand::Exists { .. } => "exists",
+            DbCommand::Atomic { .. } => "atomic",
+            DbCommand::IdBounds { .. } => "idBounds",
+            DbCommand::ResetSequence { .. } => "resetSequence",
+            DbCommand::SetAcl { .. } => "setAcl",
+            DbCommand::Tail { .. } => "tail",
+            DbCommand::CompareAndSwap { .. } => "compareAndSwap",
+            DbCommand::Increment { .. } => "increment",
+            DbCommand::ColumnHistogram { .. } => "columnHistogram",
+            DbCommand::SetMemoryLimit { .. } => "setMemoryLimit",
+            DbCommand::SchemaHash { .. } => "schemaHash",
+            DbCommand::CopyTable { .. } => "copyTable",
+            DbCommand::InsertWithId { .. } => "insertWithId",
+            DbCommand::ReserveIds { .. } => "reserveIds",
+            DbCommand::GetSchema { .. } => "getSchema",
+            DbCommand::Compact { .. } => "compact",
+        }
+    }
+
+    /// The table the command runs against, for logging. A join reports its left table.
+    pub fn table(&self) -> Option<&str> {
+        match self {
+            DbCommand::CreateTable { table, .. }
+            | DbCommand::InsertRow { table, .. }
+            | DbCommand::UpdateRow { table, .. }
+            | DbCommand::SelectAll { table, .. }
+            | DbCommand::SelectCursor { table }
+            | DbCommand::SelectWhere { table, .. }
+            | DbCommand::CreateTextIndex { table, .. }
+            | DbCommand::SearchText { table, .. }
+            | DbCommand::GroupCount { table, .. }
+            | DbCommand::ListIds { table }
+            | DbCommand::SelectByIds { table, .. }
+            | DbCommand::Migrate { table, .. }
+            | DbCommand::DeleteRow { table, .. }
+            | DbCommand::Compact { table }
+            | DbCommand::ReserveIds { table, .. }
+            | DbCommand::InsertWithId { table, .. }
+            | DbCommand::CopyTable { to: table, .. }
+            | DbCommand::MoveRow { to: table, .. }
+            | DbCommand::SchemaHash { table }
+            | DbCommand::SetMemoryLimit { table, .. }
+            | DbCommand::ColumnHistogram { table, .. }
+            | DbCommand::Increment { table, .. }
+            | DbCommand::CompareAndSwap { table, .. }
+            | DbCommand::Tail { table, .. }
+            | DbCommand::SetAcl { table, .. }
+            | DbCommand::ResetSequence { table, .. }
+            | DbCommand::IdBounds { table }
+            | DbCommand::Exists { table, .. }
+            | DbCommand::ChangesSince { table, .. }
+            | DbCommand::Sample { table, .. }
+            | DbCommand::Columns { table }
+            | DbCommand::Dedup { table, .. }
+            | DbCommand::SwapIds { table, .. }
+            | DbCommand::ListIndexes { table }
+            | DbCommand::CreateIndex { table, .. }
+            | DbCommand::Join { left: table, .. } => Some(table),
+            DbCommand::Explain { inner } | DbCommand::Validate { inner } | DbCommand::Timed { inner } => inner.table(),
+            DbCommand::Batch { .. }
+            | DbCommand::Atomic { .. }
+            | DbCommand::GetTables {}
+            | DbCommand::Ping {}
+            | DbCommand::ServerInfo {}
+            | DbCommand::Set { .. }
+            | DbCommand::Fetch { .. }
+            | DbCommand::Reset {}
+            | DbCommand::GetSchema { .. }
+            | DbCommand::CreateTables { .. }
+            | DbCommand::Version {}
+            | DbCommand::Snapshot {} => None,
+        }
+    }
+
+    /// Looks through wrappers like Timed, which run the command they hold.
+    fn unwrapped(&self) -> &DbCommand {
+        match self {
+            DbCommand::Timed { inner } | DbCommand::Explain { inner } | DbCommand::Validate { inner } => inner.unwrapped(),
+            _ => self,
+        }
+    }
+
+    /// Whether the command changes stored data. These are the commands that get
+    /// replicated to followers and that a read-only server refuses.
+    pub fn is_write(&self) -> bool {
+        match self {
+            DbCommand::CreateTable { .. }
+            | DbCommand::InsertRow { .. }
+            | DbCommand::UpdateRow { .. }
+            | DbCommand::CreateTextIndex { .. }
+            | DbCommand::Reset {}
+            | DbCommand::Migrate { .. }
+            | DbCommand::DeleteRow { .. }
+            | DbCommand::Compact { .. }
+            | DbCommand::ReserveIds { .. }
+            | DbCommand::InsertWithId { .. }
+            | DbCommand::CopyTable { .. }
+            | DbCommand::Increment { .. }
+            | DbCommand::CompareAndSwap { .. }
+            | DbCommand::SetAcl { .. }
+            | DbCommand::ResetSequence { .. }
+            | DbCommand::CreateTables { .. }
+            | DbCommand::Dedup { .. }
+            | DbCommand::SwapIds { .. }
+            | DbCommand::MoveRow { .. }
+            | DbCommand::SetMemoryLimit { .. }
+            | DbCommand::CreateIndex { .. } => true,
+            DbCommand::Batch { commands, .. } | DbCommand::Atomic { commands } => {
+                commands.iter().any(DbCommand::is_write)
+            }
+            DbCommand::Timed { inner } => inner.is_write(),
+            DbCommand::SelectAll { .. }
+            | DbCommand::GetTables {}
+            | DbCommand::Ping {}
+            | DbCommand::ServerInfo {}
+            | DbCommand::Set { .. }
+            | DbCommand::SelectCursor { .. }
+            | DbCommand::Fetch { .. }
+            | DbCommand::SelectWhere { .. }
+            | DbCommand::SearchText { .. }
+            | DbCommand::Explain { .. }
+            | DbCommand::Validate { .. }
+            | DbCommand::Join { .. }
+            | DbCommand::GroupCount { .. }
+            | DbCommand::ListIds { .. }
+            | DbCommand::SelectByIds { .. }
+            | DbCommand::GetSchema { .. }
+            | DbCommand::SchemaHash { .. }
+            | DbCommand::ColumnHistogram { .. }
+            | DbCommand::Tail { .. }
+            | DbCommand::IdBounds { .. }
+            | DbCommand::Exists { .. }
+            | DbCommand::ChangesSince { .. }
+            | DbCommand::Sample { .. }
+            | DbCommand::Columns { .. }
+            | DbCommand::Version {}
+            | DbCommand::ListIndexes { .. }
+            | DbCommand::Snapshot {} => false,
+        }
+    }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug)]
+pub struct TableSchema {
+    pub name: String,
+    pub schema_version: u64,
+    pub columns: Vec<Column>,
+}
+
+/// Serializes to the JSON the web client replies with: `ok` plus the
+/// result's fields, with rows as objects (see `JsonRow`).
+#[derive(Debug)]
 pub enum DbResult {
     Ok,
     Rows {
         columns: Vec<String>,
         rows: Vec<(u64, Vec<Value>)>,
+        /// Set when the row cap cut the result short.
+        truncated: bool,
+        /// Type of each entry in `columns`, when the command asked for them.
+        column_types: Option<Vec<ColumnType>>,
+        /// Passed back as SelectAll's `cursor` to get the next page. Only set
+        /// when paging with a limit and more rows are left.
+        next_cursor: Option<String>,
+    },
+    Inserted {
+        row_id: u64,
+    },
+    CursorOpened {
+        cursor_id: u64,
+    },
+    /// Number of rows a write touched.
+    Affected(u64),
+    /// Tables in listing order, as GetTables lists them.
+    Schema {
+        tables: Vec<TableSchema>,
+    },
+    Batch {
+        results: Vec<Result<DbResult, String>>,
+    },
+    /// Reply to ChangesSince. `rows` are current values of inserted and
+    /// updated rows, `deleted` the ids of removed ones. `version` is the
+    /// change number to poll from next.
+    Changes {
+        columns: Vec<String>,
+        rows: Vec<(u64, Vec<Value>)>,
+        deleted: Vec<u64>,
+        version: u64,
+        /// Set when the row cap cut the reply short; polling from `version` picks up the rest.
+        truncated: bool,
     },
+    /// Reply to Timed: the inner command's result and its execution time.
+    Timed {
+        result: Box<DbResult>,
+        micros: u64,
+    },
+}
+impl Serialize for DbResult {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("ok", &true)?;
+        self.serialize_fields(&mut map)?;
+        map.end()
+    }
+}
+
+impl DbResult {
+    fn serialize_fields<M: SerializeMap>(&self, map: &mut M) -> Result<(), M::Error> {
+        match self {
+            DbResult::Ok => {}
+            DbResult::Inserted { row_id } => map.serialize_entry("rowId", row_id)?,
+            DbResult::CursorOpened { cursor_id } => map.serialize_entry("cursorId", cursor_id)?,
+            DbResult::Affected(count) => map.serialize_entry("affected", count)?,
+            DbResult::Schema { tables } => map.serialize_entry("tables", tables)?,
+            DbResult::Rows { columns, rows, truncated, column_types, next_cursor } => {
+                map.serialize_entry("columns", columns)?;
+                map.serialize_entry("rows", &JsonRows(columns, rows))?;
+                map.serialize_entry("truncated", truncated)?;
+                if let Some(types) = column_types {
+                    map.serialize_entry("columnTypes", types)?;
+                }
+                if let Some(cursor) = next_cursor {
+                    map.serialize_entry("nextCursor", cursor)?;
+                }
+            }
+            DbResult::Changes { columns, rows, deleted, version, truncated } => {
+                map.serialize_entry("columns", columns)?;
+                map.serialize_entry("rows", &JsonRows(columns, rows))?;
+                map.serialize_entry("deleted", deleted)?;
+                map.serialize_entry("version", version)?;
+                map.serialize_entry("truncated", truncated)?;
+            }
+            DbResult::Batch { results } => {
+                let results: Vec<_> = results.iter().map(JsonBatchItem).collect();
+                map.serialize_entry("results", &results)?;
+            }
+            DbResult::Timed { result, micros } => {
+                result.serialize_fields(map)?;
+                map.serialize_entry("durationMicros", micros)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for TableSchema {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(3))?;
+        map.serialize_entry("name", &self.name)?;
+        map.serialize_entry("schemaVersion", &self.schema_version)?;
+        let columns: Vec<_> = self.columns.iter().map(|c| JsonColumn { name: &c.name, r#type: c.col_type.name() }).collect();
+        map.serialize_entry("columns", &columns)?;
+        map.end()
+    }
+}
+
+#[derive(Serialize)]
+struct JsonColumn<'a> {
+    name: &'a str,
+    r#type: &'a str,
+}
+
+/// A failed batch item comes out as an error reply of its own.
+struct JsonBatchItem<'a>(&'a Result<DbResult, String>);
+
+impl Serialize for JsonBatchItem<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            Ok(result) => result.serialize(serializer),
+            Err(e) => {
+                let mut map = serializer.serialize_map(Some(3))?;
+                map.serialize_entry("ok", &false)?;
+                map.serialize_entry("code", error_code(e))?;
+                map.serialize_entry("error", e)?;
+                map.end()
+            }
+        }
+    }
+}
+
+/// A row as a JSON object: `_id`, then the values keyed by column name in column order.
+pub struct JsonRow<'a>(pub u64, pub &'a [String], pub &'a [Value]);
+
+impl Serialize for JsonRow<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let JsonRow(id, columns, values) = self;
+        let mut map = serializer.serialize_map(Some(columns.len() + 1))?;
+        map.serialize_entry("_id", id)?;
+        for (column, value) in columns.iter().zip(values.iter()) {
+            map.serialize_entry(column, value)?;
+        }
+        map.end()
+    }
+}
+
+struct JsonRows<'a>(&'a [String], &'a [(u64, Vec<Value>)]);
+
+impl Serialize for JsonRows<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.1.len()))?;
+        for (id, values) in self.1 {
+            seq.serialize_element(&JsonRow(*id, self.0, values))?;
+        }
+        seq.end()
+    }
+}
+
+/// Stable code for an error message, so the frontend can branch on it without
+/// parsing the human readable text. The database only reports errors as strings,
+/// so the code is recovered from the message here, by matching it against the
+/// full messages below. `{}` stands for whatever the message fills in.
+/// Messages not listed get the code "error".
+const ERROR_CODES: &[(&str, &str)] = &[
+    ("Table not found", "table_not_found"),
+    ("Table already exists", "table_exists"),
+    ("Column not found", "column_not_found"),
+    ("Column not found: {}", "column_not_found"),
+    ("Row ids exhausted for table", "row_ids_exhausted"),
+    ("Row id {} is already in use", "row_id_in_use"),
+    ("Row id {} is already in use, the sequence must start past it", "row_id_in_use"),
+    ("Row not found", "row_not_found"),
+    ("Row {} not found", "row_not_found"),
+    ("Cursor not found", "cursor_not_found"),
+    ("Invalid cursor", "invalid_cursor"),
+    ("Type mismatch for column {}: expected {}, got {}", "type_mismatch"),
+    ("Type mismatch for column {}: can't infer a type from {}", "type_mismatch"),
+    ("Expected {} columns, got {}", "column_count_mismatch"),
+    ("Expected a value for column {}", "column_count_mismatch"),
+    ("Version conflict", "version_conflict"),
+    ("Duplicate key ({})", "duplicate_key"),
+    ("Foreign key violation: {}.{} = {} has no match in {}", "foreign_key_violation"),
+    ("Cannot delete {} row {}: referenced by {} row {}", "foreign_key_violation"),
+    ("Protocol error: {}", "protocol_error"),
+    ("Internal error: {}", "internal_error"),
+    ("Access denied to table {}", "access_denied"),
+    ("Access denied: only admins can set ACLs", "access_denied"),
+    ("Access denied: only admins can run {}", "access_denied"),
+    ("Access denied: only admins can subscribe to logs", "access_denied"),
+    ("Access denied: invalid replication token", "access_denied"),
+    ("Authentication failed", "authentication_failed"),
+    // serde_json appends the position to its messages
+    ("Invalid JSON: Integer {} is too large for i64{}", "integer_out_of_range"),
+    ("Invalid JSON: Integer {} is out of range for i64{}", "integer_out_of_range"),
+    ("Invalid JSON: Expected an integer, got the decimal {}", "not_an_integer"),
+    ("Invalid JSON: {}", "invalid_json"),
+    ("Invalid table name {}", "invalid_name"),
+    ("Invalid column name {}", "invalid_name"),
+    ("timeout", "timeout"),
+    ("Server busy, try again later", "server_busy"),
+    ("Server is read-only", "read_only"),
+    ("Connection pool exhausted", "server_busy"),
+    ("Failed to connect to database: {}", "connection_failed"),
+    ("Handshake failed: {}", "connection_failed"),
+    ("TCP read error: {}", "connection_failed"),
+    ("TCP send error: {}", "connection_failed"),
+    ("Connection closed", "connection_failed"),
+];
+
+pub fn error_code(error: &str) -> &'static str {
+    ERROR_CODES
+        .iter()
+        .find(|(message, _)| matches_message(message, error))
+        .map_or("error", |(_, code)| code)
+}
+
+/// Whether `error` is `message` with each `{}` filled in by some text.
+fn matches_message(message: &str, error: &str) -> bool {
+    let mut parts = message.split("{}");
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = error.strip_prefix(first) else { return false };
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+pub fn format_value(value: &Value) -> String {
+    match value {
+        Value::Int(i) => i.to_string(),
+        Value::Text(t) => format!("{:?}", t),
+        Value::Bool(b) => b.to_string(),
+    }
+}
+
+fn format_key(key: &[Value]) -> String {
+    let parts: Vec<String> = key.iter().map(format_value).collect();
+    format!("({})", parts.join(", "))
+}
 
+/// Small seedable generator for Sample; not for anything security related.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
 }
-fn value_matches_type(value: &Value, col_type: &ColumnType) -> bool {
+
+pub fn value_matches_type(value: &Value, col_type: &ColumnType) -> bool {
     matches!(
         (value, col_type),
         (Value::Int(_), ColumnType::Int)
@@ -50,35 +882,155 @@ fn value_matches_type(value: &Value, col_type: &ColumnType) -> bool {
     )
 }
 
+/// Row ids SelectAll returns, in id order: those after `cursor`, at most
+/// `limit` and never more than `cap`. Also returns whether the cap cut the
+/// result short, and the cursor for the next page when paging with rows left.
+pub fn select_page(
+    table: &Table,
+    cursor: Option<&str>,
+    limit: Option<u32>,
+    cap: usize,
+) -> Result<(Vec<u64>, bool, Option<String>), String> {
+    if limit == Some(0) {
+        return Err("Limit must be at least 1".into());
+    }
+    let after = cursor.map(protocol::decode_page_cursor).transpose()?;
+
+    let mut ids: Vec<u64> = table.rows.keys().copied().filter(|id| after.is_none_or(|a| *id > a)).collect();
+    ids.sort_unstable();
+
+    let take = limit.map_or(cap, |l| (l as usize).min(cap));
+    let more = ids.len() > take;
+    ids.truncate(take);
+
+    // A page the cap cut short is flagged, but can still be continued
+    let truncated = more && limit.is_none_or(|l| l as usize > cap);
+    let next_cursor = if more && limit.is_some() {
+        ids.last().map(|id| protocol::encode_page_cursor(*id))
+    } else {
+        None
+    };
+    Ok((ids, truncated, next_cursor))
+}
+
+/// Drops the server-managed columns, which follow the table's own, from a
+/// result of `table`'s rows.
+fn strip_metadata(table: &Table, result: &mut DbResult) {
+    if let DbResult::Rows { columns, rows, column_types, .. } = result {
+        let width = table.columns.len();
+        columns.truncate(width);
+        rows.iter_mut().for_each(|(_, values)| values.truncate(width));
+        if let Some(types) = column_types {
+            types.truncate(width);
+        }
+    }
+}
+
+/// Checks a new table or column name against the configured naming rule.
+/// `kind` is "Table" or "Column", for the error message.
+pub fn check_name(kind: &str, name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err(format!("{} name cannot be empty", kind));
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(format!(
+            "Invalid {} name {:?}: longer than {} characters",
+            kind.to_lowercase(),
+            name,
+            MAX_NAME_LENGTH
+        ));
+    }
+    let allowed = |c: char| {
+        if STRICT_NAMES {
+            c.is_ascii_alphanumeric() || c == '_'
+        } else {
+            !c.is_control() && !matches!(c, ',' | '"' | '\'')
+        }
+    };
+    if !name.chars().all(allowed) {
+        return Err(format!("Invalid {} name {:?}", kind.to_lowercase(), name));
+    }
+    Ok(())
+}
+
 impl Database {
+  pub fn schema_hash(&self, table: String) -> Result<DbResult, String> {
+      let t = self.tables.get(&table).ok_or("Table not found")?;
+      Ok(DbResult::Rows {
+          columns: vec!["schema_hash".into()],
+          rows: vec![(1, vec![Value::Int(t.schema_hash() as i64)])],
+          truncated: false,
+          column_types: None,
+          next_cursor: None,
+      })
+  }
+
+  pub fn get_schema(&self) -> Result<DbResult, String> {
+      let tables = self
+          .listed_tables()
+          .into_iter()
+          .map(|t| TableSchema {
+              name: t.name.clone(),
+              schema_version: t.schema_version,
+              columns: t.columns.clone(),
+          })
+          .collect();
+      Ok(DbResult::Schema { tables })
+  }
+
+  /// Tables in the order GetTables and GetSchema list them: by name, or by
+  /// creation with ties broken by name.
+  fn listed_tables(&self) -> Vec<&Table> {
+      let mut tables: Vec<&Table> = self.tables.values().collect();
+      if self.tables_in_creation_order {
+          tables.sort_by(|a, b| (a.created, &a.name).cmp(&(b.created, &b.name)));
+      } else {
+          tables.sort_by(|a, b| a.name.cmp(&b.name));
+      }
+      tables
+  }
+
+  fn next_created(&self) -> u64 {
+      self.tables.values().map(|t| t.created).max().unwrap_or(0) + 1
+  }
+
   pub fn get_tables(&self) -> Result<DbResult, String> {
       let mut rows = Vec::new();
       let mut id = 1;
 
-      for (table_name, table) in &self.tables {
+      for table in self.listed_tables() {
           for col in &table.columns {
               rows.push((id, vec![
-                  Value::Text(table_name.clone()),
+                  Value::Text(table.name.clone()),
                   Value::Text(col.name.clone()),
-                  Value::Text(format!("{:?}", col.col_type).to_lowercase()),
+                  Value::Text(col.col_type.name().into()),
+                  Value::Int(table.schema_version as i64),
               ]));
               id += 1;
           }
       }
 
       Ok(DbResult::Rows {
-          columns: vec!["table_name".into(), "column_name".into(), "column_type".into()],
+          columns: vec![
+              "table_name".into(),
+              "column_name".into(),
+              "column_type".into(),
+              "schema_version".into(),
+          ],
           rows,
+          truncated: false,
+          column_types: None,
+          next_cursor: None,
       })
   }
     pub fn create_table(
         &mut self,
         table: String,
         columns: Vec<(String, ColumnType)>,
+        key: Vec<String>,
+        foreign_keys: Vec<ForeignKeyDef>,
     ) -> Result<DbResult, String> {
-        if self.tables.contains_key(&table) {
-            return Err("Table already exists".into());
-        }
+        let (key_columns, foreign_keys) = self.check_create_table(&table, &columns, &key, &foreign_keys)?;
 
         let columns = columns
             .into_iter()
@@ -88,80 +1040,3003 @@ impl Database {
         let table_obj = Table {
             name: table.clone(),
             columns,
-            rows: HashMap::new(),
+            rows: RowStore::default(),
             next_row_id: 1,
+            text_indexes: HashMap::new(),
+            sorted_indexes: HashMap::new(),
+            versions: HashMap::new(),
+            schema_version: 1,
+            key_columns,
+            keys: HashSet::new(),
+            foreign_keys,
+            acl: None,
+            created: self.next_created(),
+            changes: ChangeLog::default(),
         };
 
         self.tables.insert(table, table_obj);
         Ok(DbResult::Ok)
     }
 
-    pub fn insert_row(
-        &mut self,
-        table: String,
-        values: Vec<Value>,
-    ) -> Result<DbResult, String> {
-        let table = self.tables.get_mut(&table).ok_or("Table not found")?;
+    /// Checks every table before creating any, so a bad one leaves the others uncreated too.
+    pub fn create_tables(&mut self, tables: Vec<(String, Vec<(String, ColumnType)>)>) -> Result<DbResult, String> {
+        let mut seen = HashSet::new();
+        for (table, columns) in &tables {
+            if !seen.insert(table.as_str()) {
+                return Err(format!("Duplicate table name {}", table));
+            }
+            self.check_create_table(table, columns, &[], &[]).map_err(|e| format!("{} (table {})", e, table))?;
+        }
+        for (table, columns) in tables {
+            self.create_table(table, columns, Vec::new(), Vec::new())?;
+        }
+        Ok(DbResult::Ok)
+    }
 
-        if values.len() != table.columns.len() {
-            return Err("Column count mismatch".into());
+    /// Everything create_table checks before creating anything. Returns the key
+    /// column indices and the resolved foreign keys.
+    fn check_create_table(
+        &self,
+        table: &str,
+        columns: &[(String, ColumnType)],
+        key: &[String],
+        foreign_keys: &[ForeignKeyDef],
+    ) -> Result<(Vec<usize>, Vec<ForeignKey>), String> {
+        check_name("Table", table)?;
+        if self.tables.contains_key(table) {
+            return Err("Table already exists".into());
+        }
+        if columns.is_empty() {
+            return Err("Table must have at least one column".into());
+        }
+        if columns.len() > MAX_COLUMNS {
+            return Err(format!("Table can't have more than {} columns", MAX_COLUMNS));
         }
 
-        for (value, column) in values.iter().zip(&table.columns) {
-            if !value_matches_type(value, &column.col_type) {
-                return Err(format!("Type mismatch for column {}", column.name));
+        let mut seen = HashSet::new();
+        for (name, _) in columns {
+            check_name("Column", name)?;
+            if !seen.insert(name.as_str()) {
+                return Err(format!("Duplicate column name {}", name));
             }
         }
 
-        let row_id = table.next_row_id;
-        table.next_row_id += 1;
-        table.rows.insert(row_id, values);
-
-        Ok(DbResult::Ok)
-    }
-
-    pub fn update_row(
-        &mut self,
-        table: String,
-        row_id: u64,
-        updates: HashMap<String, Value>,
-    ) -> Result<DbResult, String> {
-        let table = self.tables.get_mut(&table).ok_or("Table not found")?;
-        let row = table.rows.get_mut(&row_id).ok_or("Row not found")?;
+        let mut key_columns = Vec::with_capacity(key.len());
+        for name in key {
+            let index = columns
+                .iter()
+                .position(|(c, _)| c == name)
+                .ok_or_else(|| format!("Key column not found: {}", name))?;
+            if key_columns.contains(&index) {
+                return Err(format!("Duplicate key column {}", name));
+            }
+            key_columns.push(index);
+        }
 
-        for (col_name, new_value) in updates.into_iter() {
-            let index = table
-                .columns
+        let mut resolved_fks = Vec::with_capacity(foreign_keys.len());
+        for def in foreign_keys {
+            let index = columns
                 .iter()
-                .position(|c| c.name == col_name)
-                .ok_or("Column not found")?;
+                .position(|(c, _)| *c == def.column)
+                .ok_or_else(|| format!("Foreign key column not found: {}", def.column))?;
 
-            if !value_matches_type(&new_value, &table.columns[index].col_type) {
-                return Err(format!("Type mismatch for column {}", col_name));
+            // A table may reference its own key
+            let parent_key_type = if def.parent == table {
+                match key_columns.as_slice() {
+                    [k] => Some(&columns[*k].1),
+                    _ => None,
+                }
+            } else {
+                let parent = self.tables.get(&def.parent).ok_or_else(|| format!("Parent table not found: {}", def.parent))?;
+                match parent.key_columns.as_slice() {
+                    [k] => Some(&parent.columns[*k].col_type),
+                    _ => None,
+                }
+            };
+            let parent_key_type = parent_key_type
+                .ok_or_else(|| format!("Table {} needs a single-column key to be referenced", def.parent))?;
+            if *parent_key_type != columns[index].1 {
+                return Err(format!(
+                    "Foreign key {} is {} but the key of {} is {}",
+                    def.column,
+                    columns[index].1.name(),
+                    def.parent,
+                    parent_key_type.name()
+                ));
             }
 
-            row[index] = new_value;
+            resolved_fks.push(ForeignKey { column: index, parent: def.parent.clone(), on_delete: def.on_delete });
         }
 
-        Ok(DbResult::Ok)
+        Ok((key_columns, resolved_fks))
     }
 
-    pub fn select_all(
-        &self,
+    /// A repeated `idempotency_key` returns the row id from the first insert instead of inserting again.
+    pub fn insert_row(
+        &mut self,
         table: String,
+        values: Vec<Value>,
+        idempotency_key: Option<String>,
     ) -> Result<DbResult, String> {
-        let table = self.tables.get(&table).ok_or("Table not found")?;
+        if let Some(key) = &idempotency_key
+            && let Some((seen_table, row_id)) = self.idempotency_keys.get(key)
+        {
+            if *seen_table != table {
+                return Err("Idempotency key already used for a different table".into());
+            }
+            return Ok(DbResult::Inserted { row_id: *row_id });
+        }
 
-        let columns = table.columns.iter().map(|c| c.name.clone()).collect();
+        let key = self.check_insert(&table, &values)?;
 
-        let mut rows: Vec<_> = table
+        let table_name = table;
+        let table = self.tables.get_mut(&table_name).ok_or("Table not found")?;
+        let row_id = table.next_row_id;
+        table.next_row_id = row_id.checked_add(1).ok_or("Row ids exhausted for table")?;
+        table.insert_at(row_id, values, key);
+
+        if let Some(key) = idempotency_key {
+            self.idempotency_keys.insert(key, table_name, row_id, IDEMPOTENCY_CACHE_SIZE);
+        }
+
+        Ok(DbResult::Inserted { row_id })
+    }
+
+    /// Deletes the row from `from` and inserts it into `to`, which needs the
+    /// same columns with the same types. Nothing changes unless both sides
+    /// succeed. The deletion cascades as with DeleteRow.
+    pub fn move_row(&mut self, from: String, to: String, row_id: u64) -> Result<DbResult, String> {
+        if from == to {
+            return Err("Can't move a row to the table it's in".into());
+        }
+        let source = self.tables.get(&from).ok_or("Table not found")?;
+        let target = self.tables.get(&to).ok_or("Table not found")?;
+        let same_columns = source.columns.len() == target.columns.len()
+            && source.columns.iter().zip(&target.columns).all(|(s, t)| s.name == t.name && s.col_type == t.col_type);
+        if !same_columns {
+            return Err(format!("Schema mismatch: {} doesn't have the columns of {}", to, from));
+        }
+        let values = source.rows.get(&row_id)?.ok_or("Row not found")?.to_vec();
+        if target.next_row_id == u64::MAX {
+            return Err("Row ids exhausted for table".into());
+        }
+        let key = self.check_insert(&to, &values)?;
+
+        self.delete_rows(from, vec![row_id])?;
+        let target = self.tables.get_mut(&to).ok_or("Table not found")?;
+        let new_id = target.next_row_id;
+        target.next_row_id += 1;
+        target.insert_at(new_id, values, key);
+        Ok(DbResult::Inserted { row_id: new_id })
+    }
+
+    /// Storage only: the rows themselves don't change. It's still a write, so
+    /// followers and snapshots keep the same limit as the primary.
+    pub fn set_memory_limit(&mut self, table: String, max_rows: Option<u64>) -> Result<DbResult, String> {
+        let t = self.tables.get_mut(&table).ok_or("Table not found")?;
+        t.rows.set_memory_limit(max_rows.map(|n| n as usize))?;
+        Ok(DbResult::Ok)
+    }
+
+    /// A self-referencing foreign key on `from` points at `to` in the copy;
+    /// other foreign keys keep their parents.
+    pub fn copy_table(&mut self, from: String, to: String, preserve_ids: bool) -> Result<DbResult, String> {
+        check_name("Table", &to)?;
+        if self.tables.contains_key(&to) {
+            return Err("Table already exists".into());
+        }
+        let source = self.tables.get(&from).ok_or("Table not found")?;
+
+        let mut ids: Vec<u64> = source.rows.keys().copied().collect();
+        ids.sort_unstable();
+        let new_id = |i: usize, id: u64| if preserve_ids { id } else { i as u64 + 1 };
+
+        // Spilling tables stay spilling, so a copy never has to fit in memory
+        let mut rows = RowStore::default();
+        rows.set_memory_limit(source.rows.memory_limit())?;
+        for (i, id) in ids.iter().enumerate() {
+            rows.insert(new_id(i, *id), source.rows.row(id)?.into_owned());
+        }
+
+        let mut copy = Table {
+            name: to.clone(),
+            columns: source.columns.clone(),
+            rows,
+            next_row_id: if preserve_ids { source.next_row_id } else { ids.len() as u64 + 1 },
+            text_indexes: source.text_indexes.keys().map(|c| (c.clone(), TextIndex::default())).collect(),
+            sorted_indexes: source.sorted_indexes.keys().map(|c| (c.clone(), SortedIndex::default())).collect(),
+            versions: ids.iter().enumerate().map(|(i, id)| (new_id(i, *id), source.versions.get(id).copied().unwrap_or(1))).collect(),
+            schema_version: source.schema_version,
+            key_columns: source.key_columns.clone(),
+            keys: source.keys.clone(),
+            foreign_keys: source
+                .foreign_keys
+                .iter()
+                .map(|fk| ForeignKey {
+                    parent: if fk.parent == from { to.clone() } else { fk.parent.clone() },
+                    ..fk.clone()
+                })
+                .collect(),
+            // Like any new table, the copy starts out open to everyone
+            acl: None,
+            created: self.next_created(),
+            changes: ChangeLog::default(),
+        };
+        copy.rebuild_indexes()?;
+        // Change feeds of the copy start over, with every row inserted
+        for (i, id) in ids.iter().enumerate() {
+            copy.changes.changed(new_id(i, *id));
+        }
+
+        self.tables.insert(to, copy);
+        Ok(DbResult::Ok)
+    }
+
+    /// Advances the table's id sequence past `count` ids and returns them as
+    /// one row of (first_id, last_id). Reserved ids that are never used stay
+    /// unused, until Compact renumbers the table.
+    pub fn reserve_ids(&mut self, table: String, count: u64) -> Result<DbResult, String> {
+        if count == 0 {
+            return Err("Count must be at least 1".into());
+        }
+        let t = self.tables.get_mut(&table).ok_or("Table not found")?;
+        let first_id = t.next_row_id;
+        let next_row_id = first_id.checked_add(count).ok_or("Row ids exhausted for table")?;
+        // The range goes back as Int values, so both ends must fit in an i64
+        let range = |id: u64| i64::try_from(id).map_err(|_| format!("Reserved ids would pass {}", i64::MAX));
+        let (first, last) = (range(first_id)?, range(next_row_id - 1)?);
+        t.next_row_id = next_row_id;
+
+        Ok(DbResult::Rows {
+            columns: vec!["first_id".into(), "last_id".into()],
+            rows: vec![(1, vec![Value::Int(first), Value::Int(last)])],
+            truncated: false,
+            column_types: None,
+            next_cursor: None,
+        })
+    }
+
+    pub fn exists(&self, table: String, row_id: Option<u64>) -> Result<DbResult, String> {
+        let table = self.tables.get(&table);
+        let exists = match row_id {
+            Some(id) => table.is_some_and(|t| t.rows.contains_key(&id)),
+            None => table.is_some(),
+        };
+        Ok(DbResult::Rows {
+            columns: vec!["exists".into()],
+            rows: vec![(1, vec![Value::Bool(exists)])],
+            truncated: false,
+            column_types: None,
+            next_cursor: None,
+        })
+    }
+
+    pub fn changes_since(&self, table: String, since_version: u64) -> Result<DbResult, String> {
+        let t = self.tables.get(&table).ok_or("Table not found")?;
+        // Starting from 0 there's nothing to delete, so lost tombstones don't matter
+        if since_version != 0 && since_version < t.changes.horizon {
+            return Err(format!(
+                "Deletes since version {} are no longer kept, sync again from version 0",
+                since_version
+            ));
+        }
+
+        // (change number, row id, deleted), oldest first
+        let mut changes: Vec<(u64, u64, bool)> = t
+            .changes
             .rows
             .iter()
-            .map(|(id, values)| (*id, values.clone()))
+            .filter(|(_, seq)| **seq > since_version)
+            .map(|(id, seq)| (*seq, *id, false))
+            .collect();
+        if since_version != 0 {
+            let start = t.changes.tombstones.partition_point(|(seq, _)| *seq <= since_version);
+            // Ids used again since, e.g. by Compact, are sent as rows instead
+            changes.extend(
+                t.changes
+                    .tombstones
+                    .range(start..)
+                    .filter(|(_, id)| !t.rows.contains_key(id))
+                    .map(|(seq, id)| (*seq, *id, true)),
+            );
+        }
+        changes.sort_unstable();
+
+        let truncated = changes.len() > self.row_cap();
+        changes.truncate(self.row_cap());
+        let version = match changes.last() {
+            Some((seq, _, _)) if truncated => *seq,
+            _ => t.changes.seq.max(since_version),
+        };
+
+        let mut rows = Vec::new();
+        let mut deleted = Vec::new();
+        for (_, id, is_delete) in changes {
+            if is_delete {
+                deleted.push(id);
+            } else if let Some(row) = t.result_row(id)? {
+                rows.push(row);
+            }
+        }
+        deleted.sort_unstable();
+        deleted.dedup();
+
+        Ok(DbResult::Changes { columns: t.result_columns(), rows, deleted, version, truncated })
+    }
+
+    pub fn id_bounds(&self, table: String) -> Result<DbResult, String> {
+        let t = self.tables.get(&table).ok_or("Table not found")?;
+        let bounds = t.rows.keys().fold(None, |bounds, &id| match bounds {
+            None => Some((id, id)),
+            Some((min, max)) => Some((id.min(min), id.max(max))),
+        });
+        Ok(DbResult::Rows {
+            columns: vec!["min_id".into(), "max_id".into()],
+            rows: bounds.map(|(min, max)| (1, vec![Value::Int(min as i64), Value::Int(max as i64)])).into_iter().collect(),
+            truncated: false,
+            column_types: None,
+            next_cursor: None,
+        })
+    }
+
+    pub fn reset_sequence(&mut self, table: String, start: u64) -> Result<DbResult, String> {
+        let t = self.tables.get_mut(&table).ok_or("Table not found")?;
+        if start == 0 {
+            return Err("Invalid row id 0, ids start at 1".into());
+        }
+        if let Some(highest) = t.rows.keys().copied().filter(|id| *id >= start).max() {
+            return Err(format!("Row id {} is already in use, the sequence must start past it", highest));
+        }
+        t.next_row_id = start;
+        Ok(DbResult::Ok)
+    }
+
+    /// Inserts under a client-chosen id. Ids past the table's sequence move
+    /// the sequence along, so later plain inserts can't collide with them.
+    pub fn insert_with_id(&mut self, table: String, row_id: u64, values: Vec<Value>) -> Result<DbResult, String> {
+        let key = self.check_insert(&table, &values)?;
+
+        let t = self.tables.get_mut(&table).ok_or("Table not found")?;
+        if row_id == 0 {
+            return Err("Invalid row id 0, ids start at 1".into());
+        }
+        if t.rows.contains_key(&row_id) {
+            return Err(format!("Row id {} is already in use", row_id));
+        }
+        if row_id >= t.next_row_id {
+            t.next_row_id = row_id.checked_add(1).ok_or("Row ids exhausted for table")?;
+        }
+        t.insert_at(row_id, values, key);
+
+        Ok(DbResult::Inserted { row_id })
+    }
+
+    /// Everything insert_row checks before inserting. Returns the row's key tuple, if the table has a key.
+    fn check_insert(&self, table: &str, values: &[Value]) -> Result<Option<Vec<Value>>, String> {
+        let table = self.tables.get(table).ok_or("Table not found")?;
+
+        if values.len() != table.columns.len() {
+            return Err(format!("Expected {} columns, got {}", table.columns.len(), values.len()));
+        }
+
+        for (value, column) in values.iter().zip(&table.columns) {
+            if !value_matches_type(value, &column.col_type) {
+                return Err(format!(
+                    "Type mismatch for column {}: expected {}, got {}",
+                    column.name,
+                    column.col_type.name(),
+                    value.type_name()
+                ));
+            }
+        }
+
+        let key = table.key_of(values);
+        if let Some(key) = &key
+            && table.keys.contains(key)
+        {
+            return Err(format!("Duplicate key {}", format_key(key)));
+        }
+
+        self.check_foreign_keys(table, values.iter().enumerate())?;
+        Ok(key)
+    }
+
+    pub fn set_acl(&mut self, table: String, acl: Option<Acl>) -> Result<DbResult, String> {
+        let t = self.tables.get_mut(&table).ok_or("Table not found")?;
+        t.acl = acl;
+        Ok(DbResult::Ok)
+    }
+
+    /// Goes through update_row, so key, foreign key and index handling is the
+    /// same as for any update. Returns the row with just the new value.
+    pub fn increment(&mut self, table: String, row_id: u64, column: String, by: i64) -> Result<DbResult, String> {
+        let t = self.tables.get(&table).ok_or("Table not found")?;
+        let index = t.columns.iter().position(|c| c.name == column).ok_or("Column not found")?;
+        let row = t.rows.get(&row_id)?.ok_or("Row not found")?;
+        check_row_width(row_id, &row, t.columns.len())?;
+        let Value::Int(current) = row[index] else {
+            return Err(format!(
+                "Type mismatch for column {}: expected int, got {}",
+                column,
+                t.columns[index].col_type.name()
+            ));
+        };
+        let new = current
+            .checked_add(by)
+            .ok_or_else(|| format!("Integer overflow incrementing column {}", column))?;
+
+        self.update_row(table, row_id, HashMap::from([(column.clone(), Value::Int(new))]), None)?;
+        Ok(DbResult::Rows {
+            columns: vec![column],
+            rows: vec![(row_id, vec![Value::Int(new)])],
+            truncated: false,
+            column_types: None,
+            next_cursor: None,
+        })
+    }
+
+    /// Returns one row of (swapped, value), where value is the column's value
+    /// after the command: `new` on a swap, the mismatching current value otherwise.
+    pub fn compare_and_swap(
+        &mut self,
+        table: String,
+        row_id: u64,
+        column: String,
+        expected: Value,
+        new: Value,
+    ) -> Result<DbResult, String> {
+        let t = self.tables.get(&table).ok_or("Table not found")?;
+        let index = t.columns.iter().position(|c| c.name == column).ok_or("Column not found")?;
+        let col_type = &t.columns[index].col_type;
+        for value in [&expected, &new] {
+            if !value_matches_type(value, col_type) {
+                return Err(format!(
+                    "Type mismatch for column {}: expected {}, got {}",
+                    column,
+                    col_type.name(),
+                    value.type_name()
+                ));
+            }
+        }
+        let row = t.rows.get(&row_id)?.ok_or("Row not found")?;
+        check_row_width(row_id, &row, t.columns.len())?;
+        let current = row[index].clone();
+
+        let swapped = current == expected;
+        let value = if swapped {
+            self.update_row(table, row_id, HashMap::from([(column.clone(), new.clone())]), None)?;
+            new
+        } else {
+            current
+        };
+        Ok(DbResult::Rows {
+            columns: vec!["swapped".into(), column],
+            rows: vec![(row_id, vec![Value::Bool(swapped), value])],
+            truncated: false,
+            column_types: None,
+            next_cursor: None,
+        })
+    }
+
+    /// Fails with "Version conflict" if `expected_version` is given and doesn't
+    /// match the row's current version. All updates are checked before any are applied.
+    pub fn update_row(
+        &mut self,
+        table: String,
+        row_id: u64,
+        updates: HashMap<String, Value>,
+        expected_version: Option<u64>,
+    ) -> Result<DbResult, String> {
+        // Checked up front, while the table can still be borrowed alongside its parents.
+        // Values of the wrong type are left for the type check below to report.
+        let t = self.tables.get(&table).ok_or("Table not found")?;
+        let updated = updates.iter().filter_map(|(name, value)| {
+            let index = t.columns.iter().position(|c| &c.name == name)?;
+            value_matches_type(value, &t.columns[index].col_type).then_some((index, value))
+        });
+        self.check_foreign_keys(t, updated)?;
+
+        let table = self.tables.get_mut(&table).ok_or("Table not found")?;
+        let row = table.rows.get_mut(&row_id)?.ok_or("Row not found")?;
+        check_row_width(row_id, row, table.columns.len())?;
+        let version = table.versions.entry(row_id).or_insert(1);
+
+        if expected_version.is_some_and(|v| v != *version) {
+            return Err("Version conflict".into());
+        }
+
+        let mut resolved = Vec::with_capacity(updates.len());
+        for (col_name, new_value) in updates.into_iter() {
+            let index = table
+                .columns
+                .iter()
+                .position(|c| c.name == col_name)
+                .ok_or("Column not found")?;
+
+            let col_type = &table.columns[index].col_type;
+            if !value_matches_type(&new_value, col_type) {
+                return Err(format!(
+                    "Type mismatch for column {}: expected {}, got {}",
+                    col_name,
+                    col_type.name(),
+                    new_value.type_name()
+                ));
+            }
+
+            resolved.push((index, col_name, new_value));
+        }
+
+        // Only check the key if the update actually changes it
+        let key_change = if table.key_columns.is_empty() {
+            None
+        } else {
+            let old_key: Vec<Value> = table.key_columns.iter().map(|&i| row[i].clone()).collect();
+            let new_key: Vec<Value> = table
+                .key_columns
+                .iter()
+                .map(|&i| {
+                    resolved
+                        .iter()
+                        .rev()
+                        .find(|(index, _, _)| *index == i)
+                        .map_or(&row[i], |(_, _, v)| v)
+                        .clone()
+                })
+                .collect();
+            if old_key == new_key {
+                None
+            } else if table.keys.contains(&new_key) {
+                return Err(format!("Duplicate key {}", format_key(&new_key)));
+            } else {
+                Some((old_key, new_key))
+            }
+        };
+
+        if let Some((old_key, new_key)) = key_change {
+            table.keys.remove(&old_key);
+            table.keys.insert(new_key);
+        }
+
+        for (index, col_name, new_value) in resolved {
+            if let Some(text_index) = table.text_indexes.get_mut(&col_name) {
+                if let Value::Text(old) = &row[index] {
+                    text_index.remove(row_id, old);
+                }
+                if let Value::Text(new) = &new_value {
+                    text_index.insert(row_id, new);
+                }
+            }
+            if let Some(sorted_index) = table.sorted_indexes.get_mut(&col_name) {
+                sorted_index.remove(row_id, &row[index]);
+                sorted_index.insert(row_id, &new_value);
+            }
+
+            row[index] = new_value;
+        }
+        *version += 1;
+        table.changes.changed(row_id);
+
+        Ok(DbResult::Affected(1))
+    }
+
+    /// Checks `(column index, value)` pairs against the table's foreign keys.
+    /// Violations are errors when foreign keys are enforced and only logged otherwise.
+    fn check_foreign_keys<'a>(
+        &self,
+        table: &Table,
+        values: impl IntoIterator<Item = (usize, &'a Value)>,
+    ) -> Result<(), String> {
+        if table.foreign_keys.is_empty() {
+            return Ok(());
+        }
+
+        for (index, value) in values {
+            for fk in table.foreign_keys.iter().filter(|fk| fk.column == index) {
+                let found = self
+                    .tables
+                    .get(&fk.parent)
+                    .is_some_and(|parent| parent.keys.contains(std::slice::from_ref(value)));
+                if found {
+                    continue;
+                }
+
+                let message = format!(
+                    "Foreign key violation: {}.{} = {} has no match in {}",
+                    table.name,
+                    table.columns[index].name,
+                    format_value(value),
+                    fk.parent
+                );
+                if self.enforce_foreign_keys {
+                    return Err(message);
+                }
+                warn!("{}", message);
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes a row and, following foreign keys, whatever references it.
+    /// Restrict references block the whole delete; cascade references are
+    /// deleted too. Rows already scheduled for deletion aren't visited again,
+    /// so reference cycles end instead of looping. Nothing is removed unless
+    /// every reference allows it.
+    pub fn delete_row(&mut self, table: String, row_id: u64) -> Result<DbResult, String> {
+        let t = self.tables.get(&table).ok_or("Table not found")?;
+        if !t.rows.contains_key(&row_id) {
+            return Err("Row not found".into());
+        }
+        self.delete_rows(table, vec![row_id])
+    }
+
+    /// Deletes rows whose values in `columns` repeat those of a lower id,
+    /// so the first of each group survives. No columns means all of them.
+    /// Deletes cascade as with DeleteRow and count towards the result.
+    pub fn dedup(&mut self, table: String, columns: Vec<String>) -> Result<DbResult, String> {
+        let t = self.tables.get(&table).ok_or("Table not found")?;
+        let indices = if columns.is_empty() {
+            (0..t.columns.len()).collect()
+        } else {
+            columns
+                .iter()
+                .map(|name| t.columns.iter().position(|c| &c.name == name).ok_or_else(|| format!("Column not found: {}", name)))
+                .collect::<Result<Vec<usize>, String>>()?
+        };
+
+        let mut ids: Vec<u64> = t.rows.keys().copied().collect();
+        ids.sort_unstable();
+        let mut seen = HashSet::new();
+        let mut duplicates = Vec::new();
+        for id in ids {
+            let row = t.rows.row(&id)?;
+            check_row_width(id, &row, t.columns.len())?;
+            let values: Vec<Value> = indices.iter().map(|&i| row[i].clone()).collect();
+            if !seen.insert(values) {
+                duplicates.push(id);
+            }
+        }
+
+        if duplicates.is_empty() {
+            return Ok(DbResult::Affected(0));
+        }
+        self.delete_rows(table, duplicates)
+    }
+
+    /// Deletes existing rows of one table along with whatever their foreign
+    /// keys cascade to. Nothing is deleted if a restricting reference stops any of them.
+    fn delete_rows(&mut self, table: String, row_ids: Vec<u64>) -> Result<DbResult, String> {
+        // Values of each row to delete, read before anything is deleted so a
+        // spilled row that can't be read leaves every table as it was
+        let mut doomed: HashMap<(String, u64), Vec<Value>> = HashMap::new();
+        let mut pending: Vec<(String, u64)> = row_ids.into_iter().map(|id| (table.clone(), id)).collect();
+        // Referencing values of each child table and foreign key column, read
+        // once rather than once per deleted row
+        let mut references: HashMap<(String, usize), Vec<(u64, Value)>> = HashMap::new();
+        while let Some((table_name, row_id)) = pending.pop() {
+            if doomed.contains_key(&(table_name.clone(), row_id)) {
+                continue;
+            }
+
+            let parent = &self.tables[&table_name];
+            let Some(row) = parent.rows.get(&row_id)? else { continue };
+            check_row_width(row_id, &row, parent.columns.len())?;
+            let key = parent.key_of(&row);
+            doomed.insert((table_name.clone(), row_id), row.into_owned());
+            let Some(key) = key else { continue };
+            // Only single-column keys can be referenced
+            let [key] = key.as_slice() else { continue };
+
+            for child in self.tables.values() {
+                for fk in child.foreign_keys.iter().filter(|fk| fk.parent == table_name) {
+                    let refs = match references.entry((child.name.clone(), fk.column)) {
+                        Entry::Occupied(refs) => refs.into_mut(),
+                        Entry::Vacant(entry) => entry.insert(
+                            child
+                                .rows
+                                .iter()
+                                .map(|row| {
+                                    let (id, values) = row?;
+                                    check_row_width(*id, &values, child.columns.len())?;
+                                    Ok::<_, String>((*id, values[fk.column].clone()))
+                                })
+                                .collect::<Result<_, _>>()?,
+                        ),
+                    };
+                    for (child_id, value) in refs.iter() {
+                        if value != key || doomed.contains_key(&(child.name.clone(), *child_id)) {
+                            continue;
+                        }
+                        match fk.on_delete {
+                            OnDelete::Cascade => pending.push((child.name.clone(), *child_id)),
+                            OnDelete::Restrict => {
+                                let message = format!(
+                                    "Cannot delete {} row {}: referenced by {} row {}",
+                                    table_name, row_id, child.name, child_id
+                                );
+                                if self.enforce_foreign_keys {
+                                    return Err(message);
+                                }
+                                warn!("{}", message);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let count = doomed.len() as u64;
+        for ((table_name, row_id), row) in doomed {
+            let Some(t) = self.tables.get_mut(&table_name) else { continue };
+            if !t.rows.delete(&row_id) {
+                continue;
+            }
+            t.versions.remove(&row_id);
+            t.changes.deleted(row_id);
+            if let Some(key) = t.key_of(&row) {
+                t.keys.remove(&key);
+            }
+            t.unindex_row(row_id, &row);
+        }
+
+        Ok(DbResult::Affected(count))
+    }
+
+    /// Most rows a single select may return; larger results come back truncated.
+    pub fn row_cap(&self) -> usize {
+        self.max_select_rows.unwrap_or(usize::MAX)
+    }
+
+    /// Cuts `rows` down to the row cap. Returns whether any were dropped.
+    fn cap_rows<T>(&self, rows: &mut Vec<T>) -> bool {
+        let truncated = rows.len() > self.row_cap();
+        rows.truncate(self.row_cap());
+        truncated
+    }
+
+    pub fn select_all(
+        &self,
+        table: String,
+        with_types: bool,
+        limit: Option<u32>,
+        cursor: Option<String>,
+        exclude_metadata: bool,
+    ) -> Result<DbResult, String> {
+        let table = self.tables.get(&table).ok_or("Table not found")?;
+
+        let columns = table.result_columns();
+        let (ids, truncated, next_cursor) = select_page(table, cursor.as_deref(), limit, self.row_cap())?;
+
+        let rows = ids.into_iter().filter_map(|id| table.result_row(id).transpose()).collect::<Result<_, _>>()?;
+        let column_types = with_types.then(|| table.result_column_types());
+
+        let mut result = DbResult::Rows { columns, rows, truncated, column_types, next_cursor };
+        if exclude_metadata {
+            strip_metadata(table, &mut result);
+        }
+        Ok(result)
+    }
+
+    /// Only the ids are sorted, and only the newest `n` of them. The row cap
+    /// still applies on top of `n`.
+    pub fn tail(&self, table: String, n: u32) -> Result<DbResult, String> {
+        let table = self.tables.get(&table).ok_or("Table not found")?;
+
+        let mut ids: Vec<u64> = table.rows.keys().copied().collect();
+        let take = (n as usize).min(self.row_cap());
+        let truncated = take < n as usize && ids.len() > take;
+        if ids.len() > take {
+            ids.select_nth_unstable_by(take, |a, b| b.cmp(a));
+            ids.truncate(take);
+        }
+        ids.sort_unstable_by(|a, b| b.cmp(a));
+
+        let rows = ids.into_iter().filter_map(|id| table.result_row(id).transpose()).collect::<Result<_, _>>()?;
+        Ok(DbResult::Rows { columns: table.result_columns(), rows, truncated, column_types: None, next_cursor: None })
+    }
+
+    pub fn column_names(&self, table: String) -> Result<DbResult, String> {
+        let t = self.tables.get(&table).ok_or("Table not found")?;
+        Ok(DbResult::Rows {
+            columns: vec!["column_name".into()],
+            rows: t.columns.iter().zip(1..).map(|(c, id)| (id, vec![Value::Text(c.name.clone())])).collect(),
+            truncated: false,
+            column_types: None,
+            next_cursor: None,
+        })
+    }
+
+    pub fn list_indexes(&self, table: String) -> Result<DbResult, String> {
+        let t = self.tables.get(&table).ok_or("Table not found")?;
+        let mut indexes: Vec<(String, &str)> = t
+            .sorted_indexes
+            .keys()
+            .map(|c| (c.clone(), "btree"))
+            .chain(t.text_indexes.keys().map(|c| (c.clone(), "fulltext")))
             .collect();
+        indexes.sort();
+        if !t.key_columns.is_empty() {
+            let key: Vec<&str> = t.key_columns.iter().map(|&i| t.columns[i].name.as_str()).collect();
+            indexes.push((key.join(","), "unique"));
+        }
+        Ok(DbResult::Rows {
+            columns: vec!["column".into(), "kind".into()],
+            rows: indexes
+                .into_iter()
+                .zip(1..)
+                .map(|((column, kind), id)| (id, vec![Value::Text(column), Value::Text(kind.into())]))
+                .collect(),
+            truncated: false,
+            column_types: None,
+            next_cursor: None,
+        })
+    }
+
+    pub fn sample(&self, table: String, n: u32, seed: Option<u64>) -> Result<DbResult, String> {
+        let table = self.tables.get(&table).ok_or("Table not found")?;
+
+        // Sorted so a seed picks the same rows however the store iterates
+        let mut ids: Vec<u64> = table.rows.keys().copied().collect();
+        ids.sort_unstable();
+        let take = (n as usize).min(self.row_cap()).min(ids.len());
+        let truncated = take < n as usize && ids.len() > take;
+
+        let seed = seed.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64));
+        let mut rng = SplitMix64(seed);
+        // Partial Fisher-Yates: the first `take` slots end up a uniform sample
+        for i in 0..take {
+            let j = i + (rng.next() % (ids.len() - i) as u64) as usize;
+            ids.swap(i, j);
+        }
+        ids.truncate(take);
+        ids.sort_unstable();
+
+        let rows = ids.into_iter().filter_map(|id| table.result_row(id).transpose()).collect::<Result<_, _>>()?;
+        Ok(DbResult::Rows { columns: table.result_columns(), rows, truncated, column_types: None, next_cursor: None })
+    }
+
+    /// Limit and offset apply after filtering, to the matches in id order or
+    /// in `order_by` order. Ordering uses the column's sorted index when there
+    /// is one and sorts in memory otherwise. The row cap still applies on top of `limit`.
+    pub fn select_where(
+        &self,
+        table: String,
+        filter: Filter,
+        limit: Option<u32>,
+        offset: Option<u32>,
+        order_by: Option<OrderBy>,
+        exclude_metadata: bool,
+    ) -> Result<DbResult, String> {
+        let table = self.tables.get(&table).ok_or("Table not found")?;
+        filter.validate(&table.columns)?;
+
+        let columns = table.result_columns();
+        // Every row is read once, since a spilled one comes from disk each time
+        type Read<'a> = Result<(u64, Cow<'a, [Value]>), String>;
+        let matches = |row: &Read| row.as_ref().map_or(true, |(_, values)| filter.matches(&table.columns, values));
+        let matching = || table.rows.iter().map(|row| row.map(|(id, values)| (*id, values))).filter(matches);
+
+        let rows: Box<dyn Iterator<Item = Read> + '_> = match &order_by {
+            Some(order) => {
+                let col = table
+                    .columns
+                    .iter()
+                    .position(|c| c.name == order.column)
+                    .ok_or_else(|| format!("Column not found: {}", order.column))?;
+                match table.sorted_indexes.get(&order.column) {
+                    Some(index) => Box::new(
+                        index
+                            .ids(order.descending)
+                            .map(|id| Ok((id, table.rows.row(&id)?)))
+                            .filter(matches),
+                    ),
+                    None => {
+                        let mut rows = matching().collect::<Result<Vec<_>, _>>()?;
+                        for (id, values) in &rows {
+                            check_row_width(*id, values, table.columns.len())?;
+                        }
+                        rows.sort_unstable_by(|(a, a_values), (b, b_values)| {
+                            a_values[col].cmp(&b_values[col]).then(a.cmp(b))
+                        });
+                        if order.descending {
+                            rows.reverse();
+                        }
+                        Box::new(rows.into_iter().map(Ok))
+                    }
+                }
+            }
+            None => {
+                let mut rows = matching().collect::<Result<Vec<_>, _>>()?;
+                rows.sort_unstable_by_key(|(id, _)| *id);
+                Box::new(rows.into_iter().map(Ok))
+            }
+        };
+
+        // Read one past the cap to learn whether the result was cut short
+        let offset = offset.unwrap_or(0) as usize;
+        let limit = limit.map_or(usize::MAX, |l| l as usize);
+        let mut page = rows
+            .skip(offset)
+            .take(limit.min(self.row_cap().saturating_add(1)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let truncated = page.len() > self.row_cap();
+        page.truncate(self.row_cap());
+
+        let rows = page.into_iter().map(|(id, values)| table.result_row_from(id, &values)).collect();
+
+        let mut result = DbResult::Rows { columns, rows, truncated, column_types: None, next_cursor: None };
+        if exclude_metadata {
+            strip_metadata(table, &mut result);
+        }
+        Ok(result)
+    }
+
+    pub fn open_cursor(&mut self, table: String) -> Result<DbResult, String> {
+        if self.cursors.len() >= MAX_OPEN_CURSORS {
+            return Err("Too many open cursors".into());
+        }
+
+        let table_obj = self.tables.get(&table).ok_or("Table not found")?;
+        let mut row_ids: Vec<u64> = table_obj.rows.keys().copied().collect();
+        row_ids.sort_unstable();
+
+        let cursor_id = self.next_cursor_id;
+        self.next_cursor_id += 1;
+        self.cursors.insert(cursor_id, RowCursor {
+            table,
+            row_ids: row_ids.into(),
+        });
+
+        Ok(DbResult::CursorOpened { cursor_id })
+    }
+
+    /// Returns up to `n` rows; an empty batch means the cursor is exhausted and has been closed.
+    /// `n` is capped at the row cap, with `truncated` set if rows were left for that reason.
+    pub fn fetch(&mut self, cursor_id: u64, n: u32) -> Result<DbResult, String> {
+        // An empty batch would read as the end of the cursor
+        if n == 0 {
+            return Err("Limit must be at least 1".into());
+        }
+        let cap = self.row_cap();
+        let cursor = self.cursors.get_mut(&cursor_id).ok_or("Cursor not found")?;
+
+        let Some(table) = self.tables.get(&cursor.table) else {
+            self.cursors.remove(&cursor_id);
+            return Err("Table not found".into());
+        };
+
+        let columns = table.result_columns();
+        let mut rows = Vec::new();
+
+        while rows.len() < (n as usize).min(cap) {
+            let Some(&id) = cursor.row_ids.front() else { break };
+            // Rows removed since the cursor was opened are skipped. A row that
+            // can't be read stays next, for the client to retry
+            if let Some(row) = table.result_row(id)? {
+                rows.push(row);
+            }
+            cursor.row_ids.pop_front();
+        }
+        let truncated = n as usize > cap && rows.len() == cap && !cursor.row_ids.is_empty();
+
+        if rows.is_empty() {
+            self.cursors.remove(&cursor_id);
+        }
+
+        Ok(DbResult::Rows { columns, rows, truncated, column_types: None, next_cursor: None })
+    }
 
-        rows.sort_by_key(|(id, _)| *id);
+    pub fn create_text_index(&mut self, table: String, column: String) -> Result<DbResult, String> {
+        let table = self.tables.get_mut(&table).ok_or("Table not found")?;
+        let col_index = table
+            .columns
+            .iter()
+            .position(|c| c.name == column)
+            .ok_or("Column not found")?;
+
+        if !matches!(table.columns[col_index].col_type, ColumnType::Text) {
+            return Err(format!("Cannot create text index on non-text column {}", column));
+        }
+        if table.text_indexes.contains_key(&column) {
+            return Err("Text index already exists".into());
+        }
+
+        let mut index = TextIndex::default();
+        for row in table.rows.iter() {
+            let (row_id, values) = row?;
+            check_row_width(*row_id, &values, table.columns.len())?;
+            if let Value::Text(text) = &values[col_index] {
+                index.insert(*row_id, text);
+            }
+        }
+
+        table.text_indexes.insert(column, index);
+        Ok(DbResult::Ok)
+    }
+
+    /// Builds an ordered index on `column`, used by selects ordered on it.
+    pub fn create_index(&mut self, table: String, column: String) -> Result<DbResult, String> {
+        let table = self.tables.get_mut(&table).ok_or("Table not found")?;
+        let col_index = table
+            .columns
+            .iter()
+            .position(|c| c.name == column)
+            .ok_or("Column not found")?;
+
+        if table.sorted_indexes.contains_key(&column) {
+            return Err("Index already exists".into());
+        }
 
-        Ok(DbResult::Rows { columns, rows })
+        let mut index = SortedIndex::default();
+        for row in table.rows.iter() {
+            let (row_id, values) = row?;
+            check_row_width(*row_id, &values, table.columns.len())?;
+            index.insert(*row_id, &values[col_index]);
+        }
+
+        table.sorted_indexes.insert(column, index);
+        Ok(DbResult::Ok)
+    }
+
+    /// Rows whose indexed column contains any token of `query`, in id order.
+    pub fn search_text(&self, table: String, column: String, query: String) -> Result<DbResult, String> {
+        let table = self.tables.get(&table).ok_or("Table not found")?;
+        let index = table
+            .text_indexes
+            .get(&column)
+            .ok_or_else(|| format!("No text index on column {}", column))?;
+
+        let mut ids: Vec<u64> = index.search(&query).into_iter().collect();
+        ids.sort_unstable();
+        let truncated = self.cap_rows(&mut ids);
+
+        let columns = table.result_columns();
+        let rows = ids.into_iter().filter_map(|id| table.result_row(id).transpose()).collect::<Result<_, _>>()?;
+
+        Ok(DbResult::Rows { columns, rows, truncated, column_types: None, next_cursor: None })
     }
-}
\ No newline at end of file
+
+    /// Describes how a query would run without executing it.
+    pub fn explain(&self, inner: DbCommand) -> Result<DbResult, String> {
+        let (plan, filter, estimated) = match inner {
+            DbCommand::SelectAll { table, .. } => {
+                let t = self.tables.get(&table).ok_or("Table not found")?;
+                (format!("full scan on {}", table), None, t.rows.len())
+            }
+            DbCommand::SelectWhere { table, filter, limit, order_by, .. } => {
+                let t = self.tables.get(&table).ok_or("Table not found")?;
+                filter.validate(&t.columns)?;
+                let estimated = limit.map_or(t.rows.len(), |l| t.rows.len().min(l as usize));
+                let plan = match &order_by {
+                    Some(order) if t.sorted_indexes.contains_key(&order.column) => {
+                        format!("index scan (ordered) using index on {}.{}", table, order.column)
+                    }
+                    Some(order) => format!(
+                        "full scan on {} then sort by {}{}",
+                        table,
+                        order.column,
+                        if order.descending { " desc" } else { "" }
+                    ),
+                    None => format!("full scan on {}", table),
+                };
+                (plan, Some(filter.to_string()), estimated)
+            }
+            DbCommand::SearchText { table, column, query } => {
+                let t = self.tables.get(&table).ok_or("Table not found")?;
+                let index = t
+                    .text_indexes
+                    .get(&column)
+                    .ok_or_else(|| format!("No text index on column {}", column))?;
+                (
+                    format!("index scan using text index on {}.{}", table, column),
+                    Some(format!("{} matches any of {:?}", column, query)),
+                    index.search(&query).len(),
+                )
+            }
+            _ => return Err("EXPLAIN only supports select commands".into()),
+        };
+
+        let mut lines = vec![plan];
+        if let Some(filter) = filter {
+            lines.push(format!("filter: {}", filter));
+        }
+        lines.push(format!("estimated rows: {}", estimated));
+
+        let rows = lines
+            .into_iter()
+            .enumerate()
+            .map(|(i, line)| (i as u64 + 1, vec![Value::Text(line)]))
+            .collect();
+
+        Ok(DbResult::Rows {
+            columns: vec!["plan".into()],
+            rows,
+            truncated: false,
+            column_types: None,
+            next_cursor: None,
+        })
+    }
+
+    /// Inner equi-join. Output columns are prefixed with their table name and
+    /// rows are numbered from 1 in left-then-right id order.
+    pub fn join(
+        &self,
+        left: String,
+        right: String,
+        left_col: String,
+        right_col: String,
+    ) -> Result<DbResult, String> {
+        let l = self.tables.get(&left).ok_or("Table not found")?;
+        let r = self.tables.get(&right).ok_or("Table not found")?;
+
+        let li = l.columns.iter().position(|c| c.name == left_col).ok_or("Column not found")?;
+        let ri = r.columns.iter().position(|c| c.name == right_col).ok_or("Column not found")?;
+
+        if l.columns[li].col_type != r.columns[ri].col_type {
+            return Err(format!("Join columns {}.{} and {}.{} have different types", left, left_col, right, right_col));
+        }
+        // Column counts go over the wire as a single byte
+        if l.columns.len() + r.columns.len() > u8::MAX as usize {
+            return Err(format!("Join of {} and {} would have more than {} columns", left, right, u8::MAX));
+        }
+
+        let columns = l
+            .columns
+            .iter()
+            .map(|c| format!("{}.{}", left, c.name))
+            .chain(r.columns.iter().map(|c| format!("{}.{}", right, c.name)))
+            .collect();
+
+        // Each side is read once up front, since a spilled row comes from disk each time
+        let mut left_rows = l.rows.iter().collect::<Result<Vec<_>, _>>()?;
+        let mut right_rows = r.rows.iter().collect::<Result<Vec<_>, _>>()?;
+        for (id, values) in &left_rows {
+            check_row_width(**id, values, l.columns.len())?;
+        }
+        for (id, values) in &right_rows {
+            check_row_width(**id, values, r.columns.len())?;
+        }
+        left_rows.sort_unstable_by_key(|(id, _)| **id);
+        right_rows.sort_unstable_by_key(|(id, _)| **id);
+
+        // Matches multiply, so stop one past the cap rather than build the whole product
+        let mut rows = Vec::new();
+        'outer: for (_, lrow) in &left_rows {
+            for (_, rrow) in &right_rows {
+                if lrow[li] == rrow[ri] {
+                    if rows.len() > self.row_cap() {
+                        break 'outer;
+                    }
+                    let combined = lrow.iter().chain(rrow.iter()).cloned().collect();
+                    rows.push((rows.len() as u64 + 1, combined));
+                }
+            }
+        }
+        let truncated = self.cap_rows(&mut rows);
+
+        Ok(DbResult::Rows { columns, rows, truncated, column_types: None, next_cursor: None })
+    }
+
+    /// Counts rows per distinct value of `column`, in no particular order.
+    fn count_values(&self, table: &str, column: &str) -> Result<Vec<(Value, i64)>, String> {
+        let t = self.tables.get(table).ok_or("Table not found")?;
+        let index = t.columns.iter().position(|c| c.name == column).ok_or("Column not found")?;
+
+        let mut counts: HashMap<Value, i64> = HashMap::new();
+        for row in t.rows.iter() {
+            let (row_id, values) = row?;
+            check_row_width(*row_id, &values, t.columns.len())?;
+            *counts.entry(values[index].clone()).or_insert(0) += 1;
+        }
+        Ok(counts.into_iter().collect())
+    }
+
+    /// Like group_count, but most frequent first with ties sorted by value, and capped at `limit`.
+    pub fn column_histogram(&self, table: String, column: String, limit: Option<u32>) -> Result<DbResult, String> {
+        let mut groups = self.count_values(&table, &column)?;
+        groups.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.compare(&b.0)));
+
+        let limit = limit.map_or(usize::MAX, |l| l as usize).min(self.row_cap());
+        let truncated = groups.len() > limit;
+        groups.truncate(limit);
+
+        let rows = groups
+            .into_iter()
+            .enumerate()
+            .map(|(i, (value, count))| (i as u64 + 1, vec![value, Value::Int(count)]))
+            .collect();
+
+        Ok(DbResult::Rows {
+            columns: vec![column, "count".into()],
+            rows,
+            truncated,
+            column_types: None,
+            next_cursor: None,
+        })
+    }
+
+    /// Counts rows per distinct value of `column`, sorted by value.
+    pub fn group_count(&self, table: String, column: String) -> Result<DbResult, String> {
+        let mut groups = self.count_values(&table, &column)?;
+        groups.sort_by(|a, b| a.0.compare(&b.0));
+        let truncated = self.cap_rows(&mut groups);
+
+        let rows = groups
+            .into_iter()
+            .enumerate()
+            .map(|(i, (value, count))| (i as u64 + 1, vec![value, Value::Int(count)]))
+            .collect();
+
+        Ok(DbResult::Rows {
+            columns: vec![column, "count".into()],
+            rows,
+            truncated,
+            column_types: None,
+            next_cursor: None,
+        })
+    }
+
+    /// Returns just the sorted row ids of `table` as a single `row_id` column.
+    pub fn list_ids(&self, table: String) -> Result<DbResult, String> {
+        let table = self.tables.get(&table).ok_or("Table not found")?;
+
+        let mut ids: Vec<u64> = table.rows.keys().copied().collect();
+        ids.sort_unstable();
+        let truncated = self.cap_rows(&mut ids);
+
+        Ok(DbResult::Rows {
+            columns: vec!["row_id".into()],
+            rows: ids.into_iter().map(|id| (id, vec![Value::Int(id as i64)])).collect(),
+            truncated,
+            column_types: None,
+            next_cursor: None,
+        })
+    }
+
+    /// Returns rows in the order the ids were given. Missing ids are an error
+    /// unless `ignore_missing` is set, in which case they're left out. Every id
+    /// is checked, even past the row cap.
+    pub fn select_by_ids(&self, table: String, ids: Vec<u64>, ignore_missing: bool) -> Result<DbResult, String> {
+        let table = self.tables.get(&table).ok_or("Table not found")?;
+
+        let mut rows = Vec::with_capacity(ids.len().min(self.row_cap()));
+        let mut truncated = false;
+        for id in ids {
+            if !table.rows.contains_key(&id) {
+                if ignore_missing {
+                    continue;
+                }
+                return Err(format!("Row {} not found", id));
+            }
+            if rows.len() == self.row_cap() {
+                truncated = true;
+            } else if let Some(row) = table.result_row(id)? {
+                rows.push(row);
+            }
+        }
+
+        Ok(DbResult::Rows { columns: table.result_columns(), rows, truncated, column_types: None, next_cursor: None })
+    }
+
+    fn check_migration(&self, table: &str, steps: &[MigrationStep]) -> Result<(), String> {
+        let table = self.tables.get(table).ok_or("Table not found")?;
+        if steps.is_empty() {
+            return Err("Migration must have at least one step".into());
+        }
+
+        let mut columns = table.columns.clone();
+        let mut key: Vec<String> = table.key_columns.iter().map(|&i| columns[i].name.clone()).collect();
+        for step in steps {
+            step.check(&mut columns, &mut key)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the checks of a schema change or insert without applying it.
+    pub fn validate(&self, cmd: DbCommand) -> Result<DbResult, String> {
+        match cmd {
+            DbCommand::CreateTable { table, columns, key, foreign_keys } => {
+                self.check_create_table(&table, &columns, &key, &foreign_keys)?;
+            }
+            DbCommand::InsertRow { table, values, .. } => {
+                self.check_insert(&table, &values)?;
+            }
+            DbCommand::Migrate { table, steps } => self.check_migration(&table, &steps)?,
+            _ => return Err("Validate supports createTable, insert and migrate".into()),
+        }
+        Ok(DbResult::Ok)
+    }
+
+    /// Exchanges the ids of two rows, versions included. Idempotency keys
+    /// follow their rows; open cursors keep their ids and so see the rows swapped.
+    pub fn swap_ids(&mut self, table: String, a: u64, b: u64) -> Result<DbResult, String> {
+        let t = self.tables.get_mut(&table).ok_or("Table not found")?;
+        for id in [a, b] {
+            if !t.rows.contains_key(&id) {
+                return Err(format!("Row {} not found", id));
+            }
+        }
+        if a == b {
+            return Ok(DbResult::Ok);
+        }
+
+        let row_a = t.rows.row(&a)?.into_owned();
+        let row_b = t.rows.row(&b)?.into_owned();
+        t.unindex_row(a, &row_a);
+        t.unindex_row(b, &row_b);
+        let swap = HashMap::from([(a, b), (b, a)]);
+        t.rows.renumber(&swap);
+        t.index_row(b, &row_a);
+        t.index_row(a, &row_b);
+
+        let version_a = t.versions.remove(&a).unwrap_or(1);
+        let version_b = t.versions.remove(&b).unwrap_or(1);
+        t.versions.insert(a, version_b);
+        t.versions.insert(b, version_a);
+        t.changes.changed(a);
+        t.changes.changed(b);
+
+        self.idempotency_keys.remap(&table, &swap);
+        Ok(DbResult::Ok)
+    }
+
+    /// Renumbers the rows of `table` to 1..=n, keeping their order, and resets
+    /// the id sequence to follow them. Returns the (old id, new id) pairs for
+    /// rows whose id changed. Row ids held by clients are stale afterwards;
+    /// open cursors and idempotency keys are remapped here.
+    pub fn compact(&mut self, table: String) -> Result<DbResult, String> {
+        let t = self.tables.get_mut(&table).ok_or("Table not found")?;
+
+        let mut ids: Vec<u64> = t.rows.keys().copied().collect();
+        ids.sort_unstable();
+        let new_ids: HashMap<u64, u64> = ids
+            .iter()
+            .zip(1..)
+            .filter(|(old, new)| **old != *new)
+            .map(|(old, new)| (*old, new))
+            .collect();
+
+        let mut versions = HashMap::with_capacity(ids.len());
+        for old in &ids {
+            let new = new_ids.get(old).copied().unwrap_or(*old);
+            versions.insert(new, t.versions.remove(old).unwrap_or(1));
+        }
+        t.rows.renumber(&new_ids);
+        t.versions = versions;
+        t.next_row_id = ids.len() as u64 + 1;
+        // Index entries are only ids, so they're renumbered without reading any row back
+        for index in t.text_indexes.values_mut() {
+            index.renumber(&new_ids);
+        }
+        for index in t.sorted_indexes.values_mut() {
+            index.renumber(&new_ids);
+        }
+        // To change feeds, a moved row is deleted under its old id and inserted under its new one
+        for old in new_ids.keys() {
+            t.changes.deleted(*old);
+        }
+        for new in new_ids.values() {
+            t.changes.changed(*new);
+        }
+
+        for cursor in self.cursors.values_mut().filter(|c| c.table == table) {
+            for id in cursor.row_ids.iter_mut() {
+                if let Some(new) = new_ids.get(id) {
+                    *id = *new;
+                }
+            }
+        }
+        self.idempotency_keys.remap(&table, &new_ids);
+
+        let mut changed: Vec<(u64, u64)> = new_ids.into_iter().collect();
+        changed.sort_unstable();
+        let rows = changed
+            .into_iter()
+            .zip(1..)
+            .map(|((old, new), i)| (i, vec![Value::Int(old as i64), Value::Int(new as i64)]))
+            .collect();
+
+        Ok(DbResult::Rows {
+            columns: vec!["old_id".into(), "new_id".into()],
+            rows,
+            truncated: false,
+            column_types: None,
+            next_cursor: None,
+        })
+    }
+
+    /// Server clock and uptime as a single row, so clients can use the server's time.
+    pub fn server_info(&self) -> Result<DbResult, String> {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| format!("System clock error: {}", e))?
+            .as_millis();
+        let uptime_ms = self.started_at.map_or(0, |t| t.elapsed().as_millis());
+
+        Ok(DbResult::Rows {
+            columns: vec!["now_ms".into(), "uptime_ms".into(), "table_count".into()],
+            rows: vec![(1, vec![
+                Value::Int(now_ms as i64),
+                Value::Int(uptime_ms as i64),
+                Value::Int(self.tables.len() as i64),
+            ])],
+            truncated: false,
+            column_types: None,
+            next_cursor: None,
+        })
+    }
+
+    pub fn version(&self) -> Result<DbResult, String> {
+        Ok(DbResult::Rows {
+            columns: vec!["server_version".into(), "protocol_version".into(), "min_protocol_version".into()],
+            rows: vec![(1, vec![
+                Value::Text(env!("CARGO_PKG_VERSION").into()),
+                Value::Int(protocol::PROTOCOL_VERSION as i64),
+                Value::Int(protocol::MIN_PROTOCOL_VERSION as i64),
+            ])],
+            truncated: false,
+            column_types: None,
+            next_cursor: None,
+        })
+    }
+
+    /// Saves every table to the configured snapshot file.
+    pub fn snapshot(&mut self) -> Result<DbResult, String> {
+        let path = self.snapshot_path.as_ref().ok_or("Snapshots are disabled on this server")?;
+        snapshot::save(&self.tables, path, self.compress_snapshots, &mut self.snapshot_state)?;
+        Ok(DbResult::Ok)
+    }
+
+    /// Like `snapshot`, but the files are written by a blocking task instead
+    /// of on the calling thread.
+    pub async fn snapshot_off_thread(&mut self) -> Result<DbResult, String> {
+        let path = self.snapshot_path.as_ref().ok_or("Snapshots are disabled on this server")?;
+        let job = snapshot::prepare(&self.tables, path, self.compress_snapshots, &self.snapshot_state)?;
+        let saved = tokio::task::spawn_blocking(move || job.write())
+            .await
+            .map_err(|e| format!("Snapshot write error: {}", e))??;
+        self.snapshot_state.saved(saved);
+        Ok(DbResult::Ok)
+    }
+
+    /// Applies all steps or none: every step is checked against the schema left
+    /// by the steps before it, and rows are only touched once all steps pass.
+    pub fn migrate(&mut self, table: String, steps: Vec<MigrationStep>) -> Result<DbResult, String> {
+        self.check_migration(&table, &steps)?;
+
+        let table = self.tables.get_mut(&table).ok_or("Table not found")?;
+        MigrationStep::apply_all(steps, table)?;
+        table.schema_version += 1;
+        // Every row changed shape
+        let ids: Vec<u64> = table.rows.keys().copied().collect();
+        for id in ids {
+            table.changes.changed(id);
+        }
+
+        Ok(DbResult::Ok)
+    }
+
+    /// Drops every table along with open cursors and remembered idempotency keys.
+    pub fn reset(&mut self) -> Result<DbResult, String> {
+        if !self.allow_reset {
+            return Err("Reset is disabled on this server".into());
+        }
+
+        self.tables.clear();
+        self.cursors.clear();
+        self.idempotency_keys = IdempotencyCache::default();
+
+        Ok(DbResult::Ok)
+    }
+
+    /// Runs commands in order, stopping after the first error unless `ignore_errors` is set.
+    /// Commands that ran before a failure are not rolled back.
+    pub fn batch(&mut self, commands: Vec<DbCommand>, ignore_errors: bool) -> Result<DbResult, String> {
+        // Checked up front, so a batch nested in an atomic block doesn't fail after the commands before it ran
+        fn nests_batch(cmd: &DbCommand) -> bool {
+            match cmd.unwrapped() {
+                DbCommand::Batch { .. } => true,
+                DbCommand::Atomic { commands } => commands.iter().any(nests_batch),
+                _ => false,
+            }
+        }
+        if commands.iter().any(nests_batch) {
+            return Err("Nested batches are not supported".into());
+        }
+
+        let mut results = Vec::with_capacity(commands.len());
+        for cmd in commands {
+            let result = self.execute(cmd);
+            let failed = result.is_err();
+            results.push(result);
+            if failed && !ignore_errors {
+                break;
+            }
+        }
+
+        Ok(DbResult::Batch { results })
+    }
+
+    /// Copies every table the commands could change before running them, and
+    /// puts the copies back if one fails. The cost grows with the size of
+    /// those tables, not with the work the commands do.
+    pub fn atomic(&mut self, commands: Vec<DbCommand>) -> Result<DbResult, String> {
+        for cmd in commands.iter().map(DbCommand::unwrapped) {
+            match cmd {
+                DbCommand::Atomic { .. } | DbCommand::Batch { .. } => {
+                    return Err("Nested atomic blocks are not supported".into());
+                }
+                // Neither can be rolled back: Reset reaches past the touched
+                // tables and Snapshot writes files
+                DbCommand::Reset {} => return Err("Reset can't run in an atomic block".into()),
+                DbCommand::Snapshot {} => return Err("Snapshot can't run in an atomic block".into()),
+                _ => {}
+            }
+        }
+
+        let mut touched = HashSet::new();
+        for cmd in &commands {
+            cmd.clone().map_table_names(&mut |t| {
+                touched.insert(t.clone());
+            });
+        }
+        // Cascading deletes reach into the tables referencing a touched one
+        loop {
+            let children: Vec<String> = self
+                .tables
+                .values()
+                .filter(|t| !touched.contains(&t.name) && t.foreign_keys.iter().any(|fk| touched.contains(&fk.parent)))
+                .map(|t| t.name.clone())
+                .collect();
+            if children.is_empty() {
+                break;
+            }
+            touched.extend(children);
+        }
+        // `None` marks a table created by the block, which is dropped on rollback
+        let saved = touched
+            .into_iter()
+            .map(|name| Ok((name.clone(), self.tables.get(&name).map(Table::try_clone).transpose()?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        let cursors = self.cursors.clone();
+        let idempotency_keys = self.idempotency_keys.clone();
+
+        let mut results = Vec::with_capacity(commands.len());
+        for cmd in commands {
+            match self.execute(cmd) {
+                Ok(result) => results.push(Ok(result)),
+                Err(e) => {
+                    for (name, table) in saved {
+                        match table {
+                            Some(table) => self.tables.insert(name, table),
+                            None => self.tables.remove(&name),
+                        };
+                    }
+                    self.cursors = cursors;
+                    self.idempotency_keys = idempotency_keys;
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(DbResult::Batch { results })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns_json(count: usize) -> String {
+        let columns: Vec<String> = (0..count).map(|i| format!(r#"["c{}","int"]"#, i)).collect();
+        format!("[{}]", columns.join(","))
+    }
+
+    fn plan(result: Result<DbResult, String>) -> Vec<String> {
+        match result {
+            Ok(DbResult::Rows { rows, .. }) => rows
+                .into_iter()
+                .map(|(_, values)| match &values[0] {
+                    Value::Text(line) => line.clone(),
+                    other => panic!("plan line {:?}", other),
+                })
+                .collect(),
+            other => panic!("expected a plan, got {:?}", other),
+        }
+    }
+
+    fn row_ids(result: Result<DbResult, String>) -> Vec<u64> {
+        match result {
+            Ok(DbResult::Rows { rows, .. }) => rows.into_iter().map(|(id, _)| id).collect(),
+            other => panic!("expected rows, got {:?}", other),
+        }
+    }
+
+    fn command(json: &str) -> DbCommand {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn db() -> Database {
+        Database { allow_reset: true, ..Database::default() }
+    }
+
+    fn run(db: &mut Database, json: &str) -> Result<DbResult, String> {
+        db.execute(command(json))
+    }
+
+    #[test]
+    fn create_table_rejects_bad_names() {
+        let mut db = db();
+        for (json, error) in [
+            (r#"{"type":"createTable","table":"","columns":[["a","int"]]}"#, "Table name cannot be empty"),
+            (r#"{"type":"createTable","table":"t","columns":[]}"#, "Table must have at least one column"),
+            (r#"{"type":"createTable","table":"t","columns":[["","int"]]}"#, "Column name cannot be empty"),
+            (r#"{"type":"createTable","table":"t","columns":[["a","int"],["a","text"]]}"#, "Duplicate column name a"),
+        ] {
+            assert_eq!(run(&mut db, json).unwrap_err(), error);
+        }
+        assert!(db.tables.is_empty());
+
+        // Names are compared case-sensitively
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["a","int"],["A","text"]]}"#).unwrap();
+        assert_eq!(db.tables["t"].columns.len(), 2);
+    }
+
+    #[test]
+    fn inserts_fail_cleanly_once_row_ids_run_out() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).unwrap();
+        db.tables.get_mut("t").unwrap().next_row_id = u64::MAX - 1;
+
+        let Ok(DbResult::Inserted { row_id }) = run(&mut db, r#"{"type":"insert","table":"t","values":[1]}"#) else {
+            panic!("insert failed")
+        };
+        assert_eq!(row_id, u64::MAX - 1);
+        assert_eq!(run(&mut db, r#"{"type":"insert","table":"t","values":[2]}"#).unwrap_err(), "Row ids exhausted for table");
+        assert_eq!(db.tables["t"].rows.len(), 1);
+        assert_eq!(db.tables["t"].next_row_id, u64::MAX);
+    }
+
+    #[test]
+    fn inserts_return_sequential_row_ids() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).unwrap();
+        for expected in 1..=3 {
+            let Ok(DbResult::Inserted { row_id }) = run(&mut db, r#"{"type":"insert","table":"t","values":[1]}"#) else {
+                panic!("insert failed")
+            };
+            assert_eq!(row_id, expected);
+        }
+
+        let json = serde_json::to_value(DbResult::Inserted { row_id: 3 }).unwrap();
+        assert_eq!(json, serde_json::json!({"ok": true, "rowId": 3}));
+    }
+
+    #[test]
+    fn cursors_page_through_a_table_in_batches() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).unwrap();
+        for a in 0..25 {
+            run(&mut db, &format!(r#"{{"type":"insert","table":"t","values":[{}]}}"#, a)).unwrap();
+        }
+        let Ok(DbResult::CursorOpened { cursor_id }) = run(&mut db, r#"{"type":"selectCursor","table":"t"}"#) else {
+            panic!("no cursor")
+        };
+        // Rows inserted after opening aren't part of the cursor
+        run(&mut db, r#"{"type":"insert","table":"t","values":[99]}"#).unwrap();
+
+        let empty = format!(r#"{{"type":"fetch","cursorId":{},"n":0}}"#, cursor_id);
+        assert_eq!(run(&mut db, &empty).unwrap_err(), "Limit must be at least 1");
+
+        let fetch = format!(r#"{{"type":"fetch","cursorId":{},"n":10}}"#, cursor_id);
+        let mut seen = Vec::new();
+        for expected in [10, 10, 5, 0] {
+            let batch = row_ids(run(&mut db, &fetch));
+            assert_eq!(batch.len(), expected);
+            seen.extend(batch);
+        }
+        assert_eq!(seen, (1..=25).collect::<Vec<_>>());
+        assert_eq!(run(&mut db, &fetch).unwrap_err(), "Cursor not found");
+    }
+
+    /// Table `t` with one int column `n` holding 1 to 10, each in the row of the same id.
+    fn db_with_numbers() -> Database {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["n","int"]]}"#).unwrap();
+        for n in 1..=10 {
+            run(&mut db, &format!(r#"{{"type":"insert","table":"t","values":[{}]}}"#, n)).unwrap();
+        }
+        db
+    }
+
+    fn select_range(db: &mut Database, op: &str, value: i64, paging: &str) -> Vec<u64> {
+        let json = format!(
+            r#"{{"type":"selectWhere","table":"t","filter":{{"kind":"range","column":"n","op":"{}","value":{}}}{}}}"#,
+            op, value, paging
+        );
+        row_ids(run(db, &json))
+    }
+
+    #[test]
+    fn range_filters_select_inclusive_and_exclusive_bounds() {
+        let mut db = db_with_numbers();
+        assert_eq!(select_range(&mut db, ">=", 8, ""), [8, 9, 10]);
+        assert_eq!(select_range(&mut db, ">", 8, ""), [9, 10]);
+        assert_eq!(select_range(&mut db, "<=", 2, ""), [1, 2]);
+        assert_eq!(select_range(&mut db, "<", 2, ""), [1]);
+        assert_eq!(select_range(&mut db, ">", 10, ""), [] as [u64; 0]);
+        // Paging applies to the matching rows
+        assert_eq!(select_range(&mut db, ">=", 3, r#","limit":2,"offset":1"#), [4, 5]);
+    }
+
+    #[test]
+    fn text_index_finds_rows_by_word_and_follows_writes() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["s","text"]]}"#).unwrap();
+        for s in ["The quick brown fox", "a lazy dog", "Quick thinking"] {
+            run(&mut db, &format!(r#"{{"type":"insert","table":"t","values":["{}"]}}"#, s)).unwrap();
+        }
+        run(&mut db, r#"{"type":"createTextIndex","table":"t","column":"s"}"#).unwrap();
+        let search = |db: &mut Database, query: &str| {
+            row_ids(run(db, &format!(r#"{{"type":"searchText","table":"t","column":"s","query":"{}"}}"#, query)))
+        };
+
+        assert_eq!(search(&mut db, "quick"), [1, 3]);
+        // Rows matching any token of the query
+        assert_eq!(search(&mut db, "DOG fox"), [1, 2]);
+        assert_eq!(search(&mut db, "cat"), [] as [u64; 0]);
+
+        run(&mut db, r#"{"type":"insert","table":"t","values":["quick cat"]}"#).unwrap();
+        run(&mut db, r#"{"type":"update","table":"t","rowId":1,"updates":{"s":"slow fox"}}"#).unwrap();
+        run(&mut db, r#"{"type":"deleteRow","table":"t","rowId":3}"#).unwrap();
+        assert_eq!(search(&mut db, "quick"), [4]);
+        assert_eq!(search(&mut db, "cat slow"), [1, 4]);
+    }
+
+    #[test]
+    fn explain_describes_text_index_searches_without_running_them() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["s","text"]]}"#).unwrap();
+        for s in ["red fox", "red hen", "blue jay"] {
+            run(&mut db, &format!(r#"{{"type":"insert","table":"t","values":["{}"]}}"#, s)).unwrap();
+        }
+        let explain = r#"{"type":"explain","inner":{"type":"searchText","table":"t","column":"s","query":"red"}}"#;
+        assert_eq!(run(&mut db, explain).unwrap_err(), "No text index on column s");
+
+        run(&mut db, r#"{"type":"createTextIndex","table":"t","column":"s"}"#).unwrap();
+        assert_eq!(
+            plan(run(&mut db, explain)),
+            ["index scan using text index on t.s", r#"filter: s matches any of "red""#, "estimated rows: 2"]
+        );
+
+        let explain = r#"{"type":"explain","inner":{"type":"selectWhere","table":"t","filter":{"kind":"eq","column":"s","value":"red fox"},"limit":1}}"#;
+        let lines = plan(run(&mut db, explain));
+        assert_eq!(lines[0], "full scan on t");
+        assert_eq!(lines[2], "estimated rows: 1");
+
+        let explain = r#"{"type":"explain","inner":{"type":"insert","table":"t","values":["x"]}}"#;
+        assert!(run(&mut db, explain).is_err());
+        assert_eq!(db.tables["t"].rows.len(), 3);
+    }
+
+    #[test]
+    fn joins_wider_than_the_wire_column_limit_fail() {
+        let mut db = db();
+        for (name, count) in [("a", 200), ("b", 100), ("c", 55)] {
+            let json = format!(r#"{{"type":"createTable","table":"{}","columns":{}}}"#, name, columns_json(count));
+            run(&mut db, &json).unwrap();
+        }
+        let join = |right: &str| format!(r#"{{"type":"join","left":"a","right":"{}","leftCol":"c0","rightCol":"c0"}}"#, right);
+        let err = run(&mut db, &join("b")).unwrap_err();
+        assert!(err.contains("more than 255 columns"), "{}", err);
+        let Ok(DbResult::Rows { columns, .. }) = run(&mut db, &join("c")) else { panic!("no rows") };
+        assert_eq!(columns.len(), 255);
+    }
+
+    #[test]
+    fn join_combines_matching_rows_under_prefixed_columns() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"users","columns":[["id","int"],["name","text"]]}"#).unwrap();
+        run(&mut db, r#"{"type":"createTable","table":"orders","columns":[["user","int"],["item","text"]]}"#).unwrap();
+        for (id, name) in [(1, "ann"), (2, "bob"), (3, "cy")] {
+            run(&mut db, &format!(r#"{{"type":"insert","table":"users","values":[{},"{}"]}}"#, id, name)).unwrap();
+        }
+        for (user, item) in [(2, "pen"), (1, "cup"), (2, "ink")] {
+            run(&mut db, &format!(r#"{{"type":"insert","table":"orders","values":[{},"{}"]}}"#, user, item)).unwrap();
+        }
+
+        let Ok(DbResult::Rows { columns, rows, .. }) =
+            run(&mut db, r#"{"type":"join","left":"users","right":"orders","leftCol":"id","rightCol":"user"}"#)
+        else {
+            panic!("join failed")
+        };
+        assert_eq!(columns, ["users.id", "users.name", "orders.user", "orders.item"]);
+        let rows: Vec<_> = rows.into_iter().map(|(id, values)| (id, values.iter().map(format_value).collect::<Vec<_>>())).collect();
+        assert_eq!(
+            rows,
+            [
+                (1, vec!["1".to_string(), "\"ann\"".into(), "1".into(), "\"cup\"".into()]),
+                (2, vec!["2".to_string(), "\"bob\"".into(), "2".into(), "\"pen\"".into()]),
+                (3, vec!["2".to_string(), "\"bob\"".into(), "2".into(), "\"ink\"".into()]),
+            ]
+        );
+
+        let mismatched = r#"{"type":"join","left":"users","right":"orders","leftCol":"name","rightCol":"user"}"#;
+        assert_eq!(
+            run(&mut db, mismatched).unwrap_err(),
+            "Join columns users.name and orders.user have different types"
+        );
+    }
+
+    /// The values of each returned row, in order.
+    fn values(result: Result<DbResult, String>) -> Vec<Vec<Value>> {
+        match result {
+            Ok(DbResult::Rows { rows, .. }) => rows.into_iter().map(|(_, values)| values).collect(),
+            other => panic!("expected rows, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn group_count_counts_each_value_in_key_order() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["color","text"]]}"#).unwrap();
+        for color in ["red", "blue", "red", "green", "red", "blue"] {
+            run(&mut db, &format!(r#"{{"type":"insert","table":"t","values":["{}"]}}"#, color)).unwrap();
+        }
+        let count = |color: &str, n: i64| vec![Value::Text(color.into()), Value::Int(n)];
+        assert_eq!(
+            values(run(&mut db, r#"{"type":"groupCount","table":"t","column":"color"}"#)),
+            [count("blue", 2), count("green", 1), count("red", 3)]
+        );
+        assert_eq!(run(&mut db, r#"{"type":"groupCount","table":"t","column":"size"}"#).unwrap_err(), "Column not found");
+    }
+
+    fn batch_results(result: Result<DbResult, String>) -> Vec<Result<DbResult, String>> {
+        match result {
+            Ok(DbResult::Batch { results }) => results,
+            other => panic!("expected a batch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn batches_run_in_order_and_stop_at_the_first_error() {
+        let mut db = db();
+        let ok = r#"{"type":"batch","commands":[
+            {"type":"createTable","table":"t","columns":[["a","int"]]},
+            {"type":"insert","table":"t","values":[1]},
+            {"type":"insert","table":"t","values":[2]}]}"#;
+        let results = batch_results(run(&mut db, ok));
+        assert_eq!(results.len(), 3);
+        assert!(matches!(results[2], Ok(DbResult::Inserted { row_id: 2 })));
+
+        let failing = r#"{"type":"batch","commands":[
+            {"type":"insert","table":"t","values":[3]},
+            {"type":"insert","table":"t","values":["wrong"]},
+            {"type":"insert","table":"t","values":[4]}]}"#;
+        let results = batch_results(run(&mut db, failing));
+        assert_eq!(results.len(), 2);
+        assert!(results[1].is_err());
+        // Commands before the error stay applied; a batch isn't a transaction
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"selectAll","table":"t"}"#)), [1, 2, 3]);
+
+        let ignoring = failing.replacen(r#""batch","#, r#""batch","ignoreErrors":true,"#, 1);
+        let results = batch_results(run(&mut db, &ignoring));
+        assert_eq!(results.iter().map(Result::is_ok).collect::<Vec<_>>(), [true, false, true]);
+    }
+
+    #[test]
+    fn batches_reject_batches_behind_wrappers() {
+        let mut db = db();
+        for inner in [
+            r#"{"type":"batch","commands":[]}"#,
+            r#"{"type":"timed","inner":{"type":"batch","commands":[]}}"#,
+            r#"{"type":"validate","inner":{"type":"batch","commands":[]}}"#,
+            r#"{"type":"atomic","commands":[{"type":"timed","inner":{"type":"batch","commands":[]}}]}"#,
+        ] {
+            let json = format!(
+                r#"{{"type":"batch","commands":[{{"type":"createTable","table":"t","columns":[["a","int"]]}},{}]}}"#,
+                inner
+            );
+            assert_eq!(run(&mut db, &json).unwrap_err(), "Nested batches are not supported", "{}", inner);
+        }
+        // Rejected before anything ran
+        assert!(run(&mut db, r#"{"type":"selectAll","table":"t"}"#).is_err());
+    }
+
+    #[test]
+    fn keyed_inserts_are_applied_once() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).unwrap();
+        run(&mut db, r#"{"type":"createTable","table":"u","columns":[["a","int"]]}"#).unwrap();
+        let keyed = r#"{"type":"insert","table":"t","values":[1],"idempotencyKey":"k1"}"#;
+        for _ in 0..2 {
+            assert!(matches!(run(&mut db, keyed), Ok(DbResult::Inserted { row_id: 1 })));
+        }
+        assert_eq!(db.tables["t"].rows.len(), 1);
+
+        // Another key, or none, inserts as usual
+        run(&mut db, r#"{"type":"insert","table":"t","values":[1],"idempotencyKey":"k2"}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"t","values":[1]}"#).unwrap();
+        assert_eq!(db.tables["t"].rows.len(), 3);
+
+        let other_table = r#"{"type":"insert","table":"u","values":[1],"idempotencyKey":"k1"}"#;
+        assert_eq!(run(&mut db, other_table).unwrap_err(), "Idempotency key already used for a different table");
+    }
+
+    #[test]
+    fn tables_are_capped_below_the_wire_column_limit() {
+        let mut db = db();
+        let create = |name: &str, count| {
+            format!(r#"{{"type":"createTable","table":"{}","columns":{}}}"#, name, columns_json(count))
+        };
+        let err = run(&mut db, &create("wide", MAX_COLUMNS + 1)).unwrap_err();
+        assert!(err.contains("more than"), "{}", err);
+        run(&mut db, &create("widest", MAX_COLUMNS)).unwrap();
+        // Results add _version, which still fits in the one-byte count
+        let Ok(DbResult::Rows { columns, .. }) = run(&mut db, r#"{"type":"selectAll","table":"widest"}"#) else {
+            panic!("no rows")
+        };
+        assert_eq!(columns.len(), u8::MAX as usize);
+    }
+
+    #[test]
+    fn updates_check_the_expected_row_version() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"t","values":[1]}"#).unwrap();
+        let select = r#"{"type":"selectAll","table":"t"}"#;
+        // Reads end each row with its version
+        assert_eq!(values(run(&mut db, select)), [vec![Value::Int(1), Value::Int(1)]]);
+
+        run(&mut db, r#"{"type":"update","table":"t","rowId":1,"updates":{"a":2},"expectedVersion":1}"#).unwrap();
+        assert_eq!(values(run(&mut db, select)), [vec![Value::Int(2), Value::Int(2)]]);
+
+        let stale = r#"{"type":"update","table":"t","rowId":1,"updates":{"a":3},"expectedVersion":1}"#;
+        assert_eq!(run(&mut db, stale).unwrap_err(), "Version conflict");
+        assert_eq!(values(run(&mut db, select)), [vec![Value::Int(2), Value::Int(2)]]);
+
+        // Without an expected version the update always applies
+        run(&mut db, r#"{"type":"update","table":"t","rowId":1,"updates":{"a":4}}"#).unwrap();
+        assert_eq!(values(run(&mut db, select)), [vec![Value::Int(4), Value::Int(3)]]);
+    }
+
+    #[test]
+    fn insert_and_update_errors_name_what_was_expected() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["a","int"],["b","text"]]}"#).unwrap();
+        assert_eq!(
+            run(&mut db, r#"{"type":"insert","table":"t","values":[1]}"#).unwrap_err(),
+            "Expected 2 columns, got 1"
+        );
+        assert_eq!(
+            run(&mut db, r#"{"type":"insert","table":"t","values":[1,"x",true]}"#).unwrap_err(),
+            "Expected 2 columns, got 3"
+        );
+        assert_eq!(
+            run(&mut db, r#"{"type":"insert","table":"t","values":["x","y"]}"#).unwrap_err(),
+            "Type mismatch for column a: expected int, got text"
+        );
+        run(&mut db, r#"{"type":"insert","table":"t","values":[1,"x"]}"#).unwrap();
+        assert_eq!(
+            run(&mut db, r#"{"type":"update","table":"t","rowId":1,"updates":{"b":false}}"#).unwrap_err(),
+            "Type mismatch for column b: expected text, got bool"
+        );
+    }
+
+    #[test]
+    fn list_ids_follows_inserts_and_deletes() {
+        let mut db = db();
+        assert_eq!(run(&mut db, r#"{"type":"listIds","table":"t"}"#).unwrap_err(), "Table not found");
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).unwrap();
+        for a in 0..5 {
+            run(&mut db, &format!(r#"{{"type":"insert","table":"t","values":[{}]}}"#, a)).unwrap();
+        }
+        run(&mut db, r#"{"type":"deleteRow","table":"t","rowId":2}"#).unwrap();
+        run(&mut db, r#"{"type":"deleteRow","table":"t","rowId":5}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"t","values":[9]}"#).unwrap();
+
+        let result = run(&mut db, r#"{"type":"listIds","table":"t"}"#);
+        let Ok(DbResult::Rows { columns, .. }) = &result else { panic!("expected rows") };
+        assert_eq!(columns, &["row_id"]);
+        assert_eq!(values(result), [1, 3, 4, 6].map(|id| vec![Value::Int(id)]));
+    }
+
+    #[test]
+    fn select_by_ids_returns_rows_in_request_order() {
+        let mut db = db_with_numbers();
+        let select = |db: &mut Database, ids: &str, ignore_missing: bool| {
+            let json = format!(r#"{{"type":"selectByIds","table":"t","ids":{},"ignoreMissing":{}}}"#, ids, ignore_missing);
+            run(db, &json)
+        };
+        assert_eq!(row_ids(select(&mut db, "[7,2,9]", false)), [7, 2, 9]);
+        assert_eq!(values(select(&mut db, "[7,2]", false))[0][0], Value::Int(7));
+
+        run(&mut db, r#"{"type":"deleteRow","table":"t","rowId":2}"#).unwrap();
+        assert_eq!(select(&mut db, "[7,2,9]", false).unwrap_err(), "Row 2 not found");
+        assert_eq!(row_ids(select(&mut db, "[7,2,11,9]", true)), [7, 9]);
+    }
+
+    fn truncated(result: Result<DbResult, String>) -> (usize, bool) {
+        match result {
+            Ok(DbResult::Rows { rows, truncated, .. }) => (rows.len(), truncated),
+            other => panic!("expected rows, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn every_row_listing_honors_the_row_cap() {
+        let mut db = Database { max_select_rows: Some(2), ..db() };
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["k","int"],["s","text"]]}"#).unwrap();
+        run(&mut db, r#"{"type":"createTextIndex","table":"t","column":"s"}"#).unwrap();
+        for k in 1..=3 {
+            run(&mut db, &format!(r#"{{"type":"insert","table":"t","values":[{},"x"]}}"#, k)).unwrap();
+        }
+        run(&mut db, r#"{"type":"insert","table":"t","values":[1,"x"]}"#).unwrap();
+
+        for json in [
+            r#"{"type":"join","left":"t","right":"t","leftCol":"s","rightCol":"s"}"#,
+            r#"{"type":"searchText","table":"t","column":"s","query":"x"}"#,
+            r#"{"type":"listIds","table":"t"}"#,
+            r#"{"type":"selectByIds","table":"t","ids":[3,2,1]}"#,
+            r#"{"type":"groupCount","table":"t","column":"k"}"#,
+        ] {
+            assert_eq!(truncated(run(&mut db, json)), (2, true), "{}", json);
+        }
+        assert_eq!(truncated(run(&mut db, r#"{"type":"selectByIds","table":"t","ids":[3,2]}"#)), (2, false));
+        assert!(run(&mut db, r#"{"type":"selectByIds","table":"t","ids":[1,2,3,9]}"#).is_err());
+
+        let Ok(DbResult::CursorOpened { cursor_id }) = run(&mut db, r#"{"type":"selectCursor","table":"t"}"#) else {
+            panic!("no cursor")
+        };
+        assert_eq!(truncated(db.fetch(cursor_id, 100)), (2, true));
+        assert_eq!(truncated(db.fetch(cursor_id, 100)), (2, false));
+    }
+
+    #[test]
+    fn selects_past_the_row_cap_are_flagged_truncated() {
+        let mut db = Database { max_select_rows: Some(4), ..db_with_numbers() };
+        assert_eq!(truncated(run(&mut db, r#"{"type":"selectAll","table":"t"}"#)), (4, true));
+        let filtered = r#"{"type":"selectWhere","table":"t","filter":{"kind":"range","column":"n","op":">","value":3}}"#;
+        assert_eq!(truncated(run(&mut db, filtered)), (4, true));
+        let few = r#"{"type":"selectWhere","table":"t","filter":{"kind":"range","column":"n","op":">","value":7}}"#;
+        assert_eq!(truncated(run(&mut db, few)), (3, false));
+        // An explicit limit under the cap isn't truncation
+        assert_eq!(truncated(run(&mut db, r#"{"type":"selectAll","table":"t","limit":2}"#)), (2, false));
+    }
+
+    #[test]
+    fn reset_drops_every_table_when_allowed() {
+        let mut db = db();
+        for table in ["a", "b", "c"] {
+            run(&mut db, &format!(r#"{{"type":"createTable","table":"{}","columns":[["n","int"]]}}"#, table)).unwrap();
+        }
+        run(&mut db, r#"{"type":"insert","table":"a","values":[1]}"#).unwrap();
+        assert!(matches!(run(&mut db, r#"{"type":"reset"}"#), Ok(DbResult::Ok)));
+        assert!(values(run(&mut db, r#"{"type":"getTables"}"#)).is_empty());
+        // Tables can be created again from scratch
+        run(&mut db, r#"{"type":"createTable","table":"a","columns":[["n","int"]]}"#).unwrap();
+
+        let mut guarded = Database::default();
+        run(&mut guarded, r#"{"type":"createTable","table":"a","columns":[["n","int"]]}"#).unwrap();
+        assert_eq!(run(&mut guarded, r#"{"type":"reset"}"#).unwrap_err(), "Reset is disabled on this server");
+        assert_eq!(guarded.tables.len(), 1);
+    }
+
+    fn affected(result: Result<DbResult, String>) -> u64 {
+        match result {
+            Ok(DbResult::Affected(count)) => count,
+            other => panic!("expected a row count, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn writes_report_the_rows_they_affected() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).unwrap();
+        for a in [1, 1, 2, 1] {
+            run(&mut db, &format!(r#"{{"type":"insert","table":"t","values":[{}]}}"#, a)).unwrap();
+        }
+        assert_eq!(affected(run(&mut db, r#"{"type":"update","table":"t","rowId":3,"updates":{"a":1}}"#)), 1);
+        assert_eq!(affected(run(&mut db, r#"{"type":"deleteRow","table":"t","rowId":4}"#)), 1);
+        // Every row but the first of each value is a duplicate
+        assert_eq!(affected(run(&mut db, r#"{"type":"dedup","table":"t"}"#)), 2);
+        assert_eq!(affected(run(&mut db, r#"{"type":"dedup","table":"t"}"#)), 0);
+    }
+
+    #[test]
+    fn composite_keys_reject_duplicate_tuples() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["a","int"],["b","text"],["c","int"]],"key":["a","b"]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"t","values":[1,"x",0]}"#).unwrap();
+        assert_eq!(
+            run(&mut db, r#"{"type":"insert","table":"t","values":[1,"x",5]}"#).unwrap_err(),
+            r#"Duplicate key (1, "x")"#
+        );
+        // Tuples sharing only part of the key are distinct
+        run(&mut db, r#"{"type":"insert","table":"t","values":[1,"y",0]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"t","values":[2,"x",0]}"#).unwrap();
+        assert_eq!(db.tables["t"].rows.len(), 3);
+
+        // Updates can't move a row onto another's key, but can change the rest of it
+        assert!(run(&mut db, r#"{"type":"update","table":"t","rowId":2,"updates":{"b":"x"}}"#).is_err());
+        run(&mut db, r#"{"type":"update","table":"t","rowId":1,"updates":{"c":9}}"#).unwrap();
+        run(&mut db, r#"{"type":"update","table":"t","rowId":2,"updates":{"b":"z"}}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"t","values":[1,"y",0]}"#).unwrap();
+    }
+
+    fn db_with_parent(enforce_foreign_keys: bool) -> Database {
+        let mut db = Database { enforce_foreign_keys, ..db() };
+        run(&mut db, r#"{"type":"createTable","table":"p","columns":[["k","int"]],"key":["k"]}"#).unwrap();
+        run(&mut db, r#"{"type":"createTable","table":"c","columns":[["p","int"]],"foreignKeys":[{"column":"p","parent":"p"}]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"p","values":[1]}"#).unwrap();
+        db
+    }
+
+    #[test]
+    fn foreign_keys_reject_dangling_references_when_enforced() {
+        let mut db = db_with_parent(true);
+        run(&mut db, r#"{"type":"insert","table":"c","values":[1]}"#).unwrap();
+        assert_eq!(
+            run(&mut db, r#"{"type":"insert","table":"c","values":[2]}"#).unwrap_err(),
+            "Foreign key violation: c.p = 2 has no match in p"
+        );
+        assert_eq!(
+            run(&mut db, r#"{"type":"update","table":"c","rowId":1,"updates":{"p":2}}"#).unwrap_err(),
+            "Foreign key violation: c.p = 2 has no match in p"
+        );
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"selectAll","table":"c"}"#)), vec![1]);
+
+        // Advisory mode only logs the violation
+        let mut db = db_with_parent(false);
+        assert!(run(&mut db, r#"{"type":"insert","table":"c","values":[2]}"#).is_ok());
+    }
+
+    #[test]
+    fn renaming_tables_renames_foreign_key_parents() {
+        let mut cmd: DbCommand = serde_json::from_str(
+            r#"{"type":"createTable","table":"C","columns":[["p","int"]],"foreignKeys":[{"column":"p","parent":"P"}]}"#,
+        )
+        .unwrap();
+        cmd.map_table_names(&mut |t| t.insert_str(0, "a_"));
+        cmd.lowercase_table_names();
+        let DbCommand::CreateTable { table, foreign_keys, .. } = cmd else { panic!("expected createTable") };
+        assert_eq!(table, "a_c");
+        assert_eq!(foreign_keys[0].parent, "a_p");
+    }
+
+    #[test]
+    fn deletes_cascade_to_children_or_are_restricted() {
+        let mut db = db_with_parent(true);
+        run(&mut db, r#"{"type":"createTable","table":"r","columns":[["p","int"]],"foreignKeys":[{"column":"p","parent":"p","onDelete":"restrict"}]}"#).unwrap();
+        run(&mut db, r#"{"type":"createTable","table":"g","columns":[["p","int"]],"foreignKeys":[{"column":"p","parent":"p","onDelete":"cascade"}]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"p","values":[2]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"g","values":[1]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"g","values":[2]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"r","values":[2]}"#).unwrap();
+
+        assert_eq!(affected(run(&mut db, r#"{"type":"deleteRow","table":"p","rowId":1}"#)), 2);
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"selectAll","table":"g"}"#)), vec![2]);
+
+        assert_eq!(
+            run(&mut db, r#"{"type":"deleteRow","table":"p","rowId":2}"#).unwrap_err(),
+            "Cannot delete p row 2: referenced by r row 1"
+        );
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"selectAll","table":"p"}"#)), vec![2]);
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"selectAll","table":"g"}"#)), vec![2]);
+    }
+
+    #[test]
+    fn explain_reports_an_index_scan_for_ordered_indexes() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["n","int"]]}"#).unwrap();
+        let explain = r#"{"type":"explain","inner":{"type":"selectWhere","table":"t","filter":{"kind":"range","column":"n","op":">","value":0},"orderBy":{"column":"n"}}}"#;
+        assert_eq!(plan(run(&mut db, explain))[0], "full scan on t then sort by n");
+        run(&mut db, r#"{"type":"createIndex","table":"t","column":"n"}"#).unwrap();
+        assert_eq!(plan(run(&mut db, explain))[0], "index scan (ordered) using index on t.n");
+    }
+
+    #[test]
+    fn cascades_through_reference_cycles_end() {
+        // Rows referencing ones inserted after them need advisory mode to go in
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"n","columns":[["k","int"],["next","int"]],"key":["k"],"foreignKeys":[{"column":"next","parent":"n","onDelete":"cascade"}]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"n","values":[1,2]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"n","values":[2,1]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"n","values":[3,3]}"#).unwrap();
+        db.enforce_foreign_keys = true;
+
+        assert_eq!(affected(run(&mut db, r#"{"type":"deleteRow","table":"n","rowId":1}"#)), 2);
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"selectAll","table":"n"}"#)), vec![3]);
+        assert_eq!(affected(run(&mut db, r#"{"type":"deleteRow","table":"n","rowId":3}"#)), 1);
+    }
+
+    #[test]
+    fn indexed_order_by_matches_the_in_memory_sort() {
+        let setup = |indexed: bool| {
+            let mut db = db();
+            run(&mut db, r#"{"type":"createTable","table":"t","columns":[["n","int"]]}"#).unwrap();
+            if indexed {
+                run(&mut db, r#"{"type":"createIndex","table":"t","column":"n"}"#).unwrap();
+            }
+            for n in [5, 3, 9, 3, 1, 7] {
+                run(&mut db, &format!(r#"{{"type":"insert","table":"t","values":[{}]}}"#, n)).unwrap();
+            }
+            run(&mut db, r#"{"type":"update","table":"t","rowId":3,"updates":{"n":0}}"#).unwrap();
+            run(&mut db, r#"{"type":"deleteRow","table":"t","rowId":6}"#).unwrap();
+            db
+        };
+        let (mut plain, mut indexed) = (setup(false), setup(true));
+
+        for (descending, paging) in [(false, ""), (true, ""), (false, r#","limit":2,"offset":1"#)] {
+            let select = format!(
+                r#"{{"type":"selectWhere","table":"t","filter":{{"kind":"range","column":"n","op":">=","value":1}},"orderBy":{{"column":"n","descending":{}}}{}}}"#,
+                descending, paging
+            );
+            assert_eq!(row_ids(run(&mut plain, &select)), row_ids(run(&mut indexed, &select)));
+        }
+        assert_eq!(
+            row_ids(run(&mut indexed, r#"{"type":"selectWhere","table":"t","filter":{"kind":"range","column":"n","op":">=","value":0},"orderBy":{"column":"n"}}"#)),
+            vec![3, 5, 2, 4, 1]
+        );
+    }
+
+    #[test]
+    fn server_info_reports_a_recent_time_and_the_table_count() {
+        let started = std::time::Instant::now() - std::time::Duration::from_secs(2);
+        let mut db = Database { started_at: Some(started), ..db() };
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["n","int"]]}"#).unwrap();
+
+        let Ok(DbResult::Rows { columns, rows, .. }) = run(&mut db, r#"{"type":"serverInfo"}"#) else { panic!("expected rows") };
+        assert_eq!(columns, ["now_ms", "uptime_ms", "table_count"]);
+        let [Value::Int(now_ms), Value::Int(uptime_ms), Value::Int(tables)] = rows[0].1[..] else { panic!("{:?}", rows) };
+        let wall_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        assert!((wall_ms - 5_000..=wall_ms).contains(&now_ms));
+        assert!(uptime_ms >= 2_000);
+        assert_eq!(tables, 1);
+    }
+
+    #[test]
+    fn validate_checks_commands_without_running_them() {
+        let mut db = db();
+        let create = r#"{"type":"createTable","table":"t","columns":[["n","int"]]}"#;
+        assert!(run(&mut db, &format!(r#"{{"type":"validate","inner":{}}}"#, create)).is_ok());
+        assert_eq!(run(&mut db, r#"{"type":"selectAll","table":"t"}"#).unwrap_err(), "Table not found");
+
+        run(&mut db, create).unwrap();
+        assert_eq!(
+            run(&mut db, &format!(r#"{{"type":"validate","inner":{}}}"#, create)).unwrap_err(),
+            "Table already exists"
+        );
+        assert!(run(&mut db, r#"{"type":"validate","inner":{"type":"insert","table":"t","values":[1]}}"#).is_ok());
+        assert_eq!(
+            run(&mut db, r#"{"type":"validate","inner":{"type":"insert","table":"t","values":["one"]}}"#).unwrap_err(),
+            "Type mismatch for column n: expected int, got text"
+        );
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"selectAll","table":"t"}"#)), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn compact_renumbers_rows_after_deletes() {
+        let mut db = db_with_numbers();
+        for id in [2, 3, 7] {
+            run(&mut db, &format!(r#"{{"type":"deleteRow","table":"t","rowId":{}}}"#, id)).unwrap();
+        }
+
+        let mapping = values(run(&mut db, r#"{"type":"compact","table":"t"}"#));
+        let expected: Vec<Vec<Value>> =
+            [(4, 2), (5, 3), (6, 4), (8, 5), (9, 6), (10, 7)].iter().map(|&(old, new)| vec![Value::Int(old), Value::Int(new)]).collect();
+        assert_eq!(mapping, expected);
+
+        // Rows keep their values under the new ids, and the next insert follows on
+        let rows = values(run(&mut db, r#"{"type":"selectAll","table":"t","excludeMetadata":true}"#));
+        assert_eq!(rows, [1, 4, 5, 6, 8, 9, 10].iter().map(|&n| vec![Value::Int(n)]).collect::<Vec<_>>());
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"selectAll","table":"t"}"#)), (1..=7).collect::<Vec<u64>>());
+        assert!(matches!(run(&mut db, r#"{"type":"insert","table":"t","values":[11]}"#), Ok(DbResult::Inserted { row_id: 8 })));
+    }
+
+    #[test]
+    fn set_memory_limit_is_a_write() {
+        assert!(command(r#"{"type":"setMemoryLimit","table":"t","maxRows":10}"#).is_write());
+    }
+
+    #[test]
+    fn selects_joins_and_cascades_work_on_spilled_rows() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"p","columns":[["k","int"]],"key":["k"]}"#).unwrap();
+        run(&mut db, r#"{"type":"createTable","table":"c","columns":[["p","int"]],"foreignKeys":[{"column":"p","parent":"p","onDelete":"cascade"}]}"#).unwrap();
+        for k in [3, 1, 2] {
+            run(&mut db, &format!(r#"{{"type":"insert","table":"p","values":[{}]}}"#, k)).unwrap();
+            run(&mut db, &format!(r#"{{"type":"insert","table":"c","values":[{}]}}"#, k)).unwrap();
+        }
+        run(&mut db, r#"{"type":"setMemoryLimit","table":"p","maxRows":1}"#).unwrap();
+        run(&mut db, r#"{"type":"setMemoryLimit","table":"c","maxRows":1}"#).unwrap();
+
+        let select = r#"{"type":"selectWhere","table":"p","filter":{"kind":"range","column":"k","op":">","value":1},"orderBy":{"column":"k","descending":true}}"#;
+        assert_eq!(row_ids(run(&mut db, select)), vec![1, 3]);
+        let join = r#"{"type":"join","left":"p","right":"c","leftCol":"k","rightCol":"p"}"#;
+        assert_eq!(row_ids(run(&mut db, join)), vec![1, 2, 3]);
+
+        run(&mut db, r#"{"type":"deleteRow","table":"p","rowId":1}"#).unwrap();
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"selectAll","table":"c"}"#)), vec![2, 3]);
+    }
+
+    #[test]
+    fn error_codes_match_whole_messages() {
+        let cases = [
+            ("Table not found", "table_not_found"),
+            ("Column not found: age", "column_not_found"),
+            ("Row ids exhausted for table", "row_ids_exhausted"),
+            ("Row id 4 is already in use", "row_id_in_use"),
+            ("Row id 4 is already in use, the sequence must start past it", "row_id_in_use"),
+            ("Row 4 not found", "row_not_found"),
+            ("Type mismatch for column a: expected int, got text", "type_mismatch"),
+            ("Expected 2 columns, got 3", "column_count_mismatch"),
+            ("Duplicate key (1)", "duplicate_key"),
+            ("Cannot delete p row 1: referenced by c row 2", "foreign_key_violation"),
+            ("Access denied to table secret", "access_denied"),
+            ("Invalid JSON: Integer 1e30 is out of range for i64 at line 1 column 9", "integer_out_of_range"),
+            ("Invalid JSON: Expected an integer, got the decimal 1.5 at line 1 column 9", "not_an_integer"),
+            ("Invalid JSON: expected value at line 1 column 1", "invalid_json"),
+            ("Invalid column name \"a b\"", "invalid_name"),
+            ("TCP read error: broken pipe", "connection_failed"),
+            // Messages that only share a prefix with a listed one
+            ("Expected an integer, got the decimal 1.5", "error"),
+            ("Duplicate key column a", "error"),
+            ("Row ids exhausted for table t and more", "error"),
+            ("Integer overflow incrementing column n", "error"),
+        ];
+        for (message, code) in cases {
+            assert_eq!(error_code(message), code, "{}", message);
+        }
+    }
+
+    #[test]
+    fn every_listed_message_maps_to_its_own_code() {
+        for (message, code) in ERROR_CODES {
+            let example = message.replace("{}", "x");
+            assert_eq!(error_code(&example), *code, "{}", message);
+        }
+    }
+
+    #[test]
+    fn reserved_ids_are_skipped_by_inserts_and_usable_once() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"t","values":[0]}"#).unwrap();
+
+        let block = values(run(&mut db, r#"{"type":"reserveIds","table":"t","count":5}"#));
+        assert_eq!(block, [vec![Value::Int(2), Value::Int(6)]]);
+        assert!(matches!(run(&mut db, r#"{"type":"insert","table":"t","values":[1]}"#), Ok(DbResult::Inserted { row_id: 7 })));
+
+        for id in [4, 2] {
+            let insert = format!(r#"{{"type":"insertWithId","table":"t","rowId":{},"values":[{}]}}"#, id, id);
+            assert!(matches!(run(&mut db, &insert), Ok(DbResult::Inserted { row_id }) if row_id == id));
+        }
+        assert_eq!(
+            run(&mut db, r#"{"type":"insertWithId","table":"t","rowId":4,"values":[9]}"#).unwrap_err(),
+            "Row id 4 is already in use"
+        );
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"selectAll","table":"t"}"#)), vec![1, 2, 4, 7]);
+        assert!(run(&mut db, r#"{"type":"reserveIds","table":"t","count":0}"#).is_err());
+
+        // A range past i64::MAX can't be reported, so nothing is reserved
+        let past = format!(r#"{{"type":"reserveIds","table":"t","count":{}}}"#, i64::MAX as u64);
+        assert_eq!(run(&mut db, &past).unwrap_err(), format!("Reserved ids would pass {}", i64::MAX));
+        assert!(matches!(run(&mut db, r#"{"type":"insert","table":"t","values":[1]}"#), Ok(DbResult::Inserted { row_id: 8 })));
+    }
+
+    #[test]
+    fn copied_tables_are_independent_of_their_source() {
+        let mut db = db_with_numbers();
+        run(&mut db, r#"{"type":"deleteRow","table":"t","rowId":2}"#).unwrap();
+        assert_eq!(run(&mut db, r#"{"type":"copyTable","from":"t","to":"t"}"#).unwrap_err(), "Table already exists");
+        assert_eq!(run(&mut db, r#"{"type":"copyTable","from":"nope","to":"u"}"#).unwrap_err(), "Table not found");
+
+        run(&mut db, r#"{"type":"copyTable","from":"t","to":"fresh"}"#).unwrap();
+        run(&mut db, r#"{"type":"copyTable","from":"t","to":"same","preserveIds":true}"#).unwrap();
+        let select = |table: &str| format!(r#"{{"type":"selectAll","table":"{}","excludeMetadata":true}}"#, table);
+        assert_eq!(values(run(&mut db, &select("fresh"))), values(run(&mut db, &select("t"))));
+        assert_eq!(row_ids(run(&mut db, &select("fresh"))), (1..=9).collect::<Vec<u64>>());
+        assert_eq!(row_ids(run(&mut db, &select("same"))), row_ids(run(&mut db, &select("t"))));
+
+        // Writes to either side stay on that side
+        run(&mut db, r#"{"type":"update","table":"t","rowId":1,"updates":{"n":100}}"#).unwrap();
+        run(&mut db, r#"{"type":"deleteRow","table":"fresh","rowId":9}"#).unwrap();
+        assert!(matches!(run(&mut db, r#"{"type":"insert","table":"fresh","values":[0]}"#), Ok(DbResult::Inserted { row_id: 10 })));
+        assert!(matches!(run(&mut db, r#"{"type":"insert","table":"same","values":[0]}"#), Ok(DbResult::Inserted { row_id: 11 })));
+        assert_eq!(values(run(&mut db, &select("fresh")))[0], [Value::Int(1)]);
+        assert_eq!(row_ids(run(&mut db, &select("t"))).len(), 9);
+    }
+
+    #[test]
+    fn schema_hash_changes_only_with_the_columns() {
+        let mut db = db_with_numbers();
+        let hash = |db: &mut Database, table: &str| values(run(db, &format!(r#"{{"type":"schemaHash","table":"{}"}}"#, table)));
+        let original = hash(&mut db, "t");
+
+        run(&mut db, r#"{"type":"insert","table":"t","values":[11]}"#).unwrap();
+        run(&mut db, r#"{"type":"createIndex","table":"t","column":"n"}"#).unwrap();
+        assert_eq!(hash(&mut db, "t"), original);
+        // Same columns, same hash, whatever the table holds
+        run(&mut db, r#"{"type":"createTable","table":"u","columns":[["n","int"]]}"#).unwrap();
+        assert_eq!(hash(&mut db, "u"), original);
+
+        run(&mut db, r#"{"type":"migrate","table":"t","steps":[{"op":"rename","from":"n","to":"m"}]}"#).unwrap();
+        let renamed = hash(&mut db, "t");
+        assert_ne!(renamed, original);
+        run(&mut db, r#"{"type":"migrate","table":"t","steps":[{"op":"add","name":"s","type":"text","default":""}]}"#).unwrap();
+        assert_ne!(hash(&mut db, "t"), renamed);
+        run(&mut db, r#"{"type":"migrate","table":"t","steps":[{"op":"drop","name":"s"},{"op":"rename","from":"m","to":"n"}]}"#).unwrap();
+        assert_eq!(hash(&mut db, "t"), original);
+    }
+
+    #[test]
+    fn names_are_limited_to_letters_digits_and_underscores() {
+        let mut db = db();
+        let create = |table: &str, column: &str| {
+            format!(r#"{{"type":"createTable","table":{},"columns":[[{},"int"]]}}"#, serde_json::json!(table), serde_json::json!(column))
+        };
+        let longest = "x".repeat(MAX_NAME_LENGTH);
+        for (table, column) in [("Orders_2024", "user_id"), (longest.as_str(), "_")] {
+            run(&mut db, &create(table, column)).unwrap();
+        }
+
+        for bad in ["a,b", "say\"hi\"", "it's", "tab\there", "new\nline", "with space", "é"] {
+            assert_eq!(run(&mut db, &create(bad, "a")).unwrap_err(), format!("Invalid table name {:?}", bad));
+            assert_eq!(run(&mut db, &create("ok", bad)).unwrap_err(), format!("Invalid column name {:?}", bad));
+        }
+        let too_long = "x".repeat(MAX_NAME_LENGTH + 1);
+        assert!(run(&mut db, &create(&too_long, "a")).unwrap_err().contains("longer than"));
+
+        // Columns added later follow the same rule
+        let add = r#"{"type":"migrate","table":"Orders_2024","steps":[{"op":"add","name":"a,b","type":"int","default":0}]}"#;
+        assert_eq!(run(&mut db, add).unwrap_err(), "Invalid column name \"a,b\"");
+        assert_eq!(db.tables.len(), 2);
+    }
+
+    #[test]
+    fn column_histogram_counts_values_most_frequent_first() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["color","text"]]}"#).unwrap();
+        for color in ["red", "blue", "red", "green", "blue", "red", "amber"] {
+            run(&mut db, &format!(r#"{{"type":"insert","table":"t","values":["{}"]}}"#, color)).unwrap();
+        }
+        let entry = |color: &str, count| vec![Value::Text(color.into()), Value::Int(count)];
+
+        let histogram = r#"{"type":"columnHistogram","table":"t","column":"color"}"#;
+        let Ok(DbResult::Rows { columns, .. }) = run(&mut db, histogram) else { panic!("expected rows") };
+        assert_eq!(columns, ["color", "count"]);
+        assert_eq!(truncated(run(&mut db, histogram)), (4, false));
+        // Equal counts are ordered by value
+        assert_eq!(values(run(&mut db, histogram)), [entry("red", 3), entry("blue", 2), entry("amber", 1), entry("green", 1)]);
+
+        let capped = r#"{"type":"columnHistogram","table":"t","column":"color","limit":2}"#;
+        assert_eq!(truncated(run(&mut db, capped)), (2, true));
+        assert_eq!(values(run(&mut db, capped)), [entry("red", 3), entry("blue", 2)]);
+
+        // The row cap applies with or without a limit of its own
+        db.max_select_rows = Some(1);
+        assert_eq!(truncated(run(&mut db, histogram)), (1, true));
+        assert_eq!(values(run(&mut db, capped)), [entry("red", 3)]);
+    }
+
+    #[test]
+    fn increments_add_to_int_columns_and_return_the_new_value() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["hits","int"],["name","text"]]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"t","values":[10,"a"]}"#).unwrap();
+        let increment = |db: &mut Database, column: &str, row_id: u64, by: i64| {
+            run(db, &format!(r#"{{"type":"increment","table":"t","rowId":{},"column":"{}","by":{}}}"#, row_id, column, by))
+        };
+
+        assert_eq!(values(increment(&mut db, "hits", 1, 5)), [[Value::Int(15)]]);
+        assert_eq!(values(increment(&mut db, "hits", 1, -20)), [[Value::Int(-5)]]);
+        assert_eq!(values(run(&mut db, r#"{"type":"selectAll","table":"t","excludeMetadata":true}"#))[0][0], Value::Int(-5));
+
+        assert_eq!(increment(&mut db, "hits", 2, 1).unwrap_err(), "Row not found");
+        assert_eq!(increment(&mut db, "name", 1, 1).unwrap_err(), "Type mismatch for column name: expected int, got text");
+        run(&mut db, r#"{"type":"update","table":"t","rowId":1,"updates":{"hits":9223372036854775807}}"#).unwrap();
+        assert_eq!(increment(&mut db, "hits", 1, 1).unwrap_err(), "Integer overflow incrementing column hits");
+    }
+
+    #[test]
+    fn compare_and_swap_updates_only_on_a_match() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"locks","columns":[["owner","text"]]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"locks","values":[""]}"#).unwrap();
+        let swap = |db: &mut Database, expected: &str, new: &str| {
+            run(db, &format!(r#"{{"type":"compareAndSwap","table":"locks","rowId":1,"column":"owner","expected":{},"new":{}}}"#, expected, new))
+        };
+        let owner = |db: &mut Database| values(run(db, r#"{"type":"selectAll","table":"locks","excludeMetadata":true}"#))[0][0].clone();
+
+        assert_eq!(values(swap(&mut db, r#""""#, r#""a""#)), [[Value::Bool(true), Value::Text("a".into())]]);
+        // The loser sees who holds it and changes nothing
+        assert_eq!(values(swap(&mut db, r#""""#, r#""b""#)), [[Value::Bool(false), Value::Text("a".into())]]);
+        assert_eq!(owner(&mut db), Value::Text("a".into()));
+
+        assert_eq!(swap(&mut db, "1", r#""b""#).unwrap_err(), "Type mismatch for column owner: expected text, got int");
+        assert_eq!(swap(&mut db, r#""a""#, "true").unwrap_err(), "Type mismatch for column owner: expected text, got bool");
+        assert_eq!(owner(&mut db), Value::Text("a".into()));
+    }
+
+    #[test]
+    fn page_cursors_neither_repeat_nor_skip_rows_across_inserts() {
+        let mut db = db_with_numbers();
+        let mut seen = Vec::new();
+        let mut cursor: Option<String> = None;
+        for page in 0.. {
+            let select = match &cursor {
+                Some(c) => format!(r#"{{"type":"selectAll","table":"t","limit":3,"cursor":"{}"}}"#, c),
+                None => r#"{"type":"selectAll","table":"t","limit":3}"#.to_string(),
+            };
+            let Ok(DbResult::Rows { rows, next_cursor, .. }) = run(&mut db, &select) else { panic!("expected rows") };
+            seen.extend(rows.iter().map(|(id, _)| *id));
+            // Rows arriving mid-way and rows deleted ahead of the cursor
+            if page == 1 {
+                run(&mut db, r#"{"type":"insert","table":"t","values":[11]}"#).unwrap();
+                run(&mut db, r#"{"type":"deleteRow","table":"t","rowId":8}"#).unwrap();
+                run(&mut db, r#"{"type":"deleteRow","table":"t","rowId":2}"#).unwrap();
+            }
+            match next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(seen, [1, 2, 3, 4, 5, 6, 7, 9, 10, 11]);
+        assert!(run(&mut db, r#"{"type":"selectAll","table":"t","limit":3,"cursor":"garbage"}"#).is_err());
+    }
+
+    #[test]
+    fn tail_returns_the_newest_rows_first() {
+        let mut db = db_with_numbers();
+        run(&mut db, r#"{"type":"deleteRow","table":"t","rowId":9}"#).unwrap();
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"tail","table":"t","n":3}"#)), vec![10, 8, 7]);
+        run(&mut db, r#"{"type":"insert","table":"t","values":[11]}"#).unwrap();
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"tail","table":"t","n":2}"#)), vec![11, 10]);
+
+        // Asking for more than there is returns everything
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"tail","table":"t","n":100}"#)).len(), 10);
+        assert_eq!(run(&mut db, r#"{"type":"tail","table":"nope","n":1}"#).unwrap_err(), "Table not found");
+    }
+
+    #[test]
+    fn sequences_reset_only_past_the_rows_in_use() {
+        let mut db = db_with_numbers();
+        let reset = |db: &mut Database, start: u64| run(db, &format!(r#"{{"type":"resetSequence","table":"t","start":{}}}"#, start));
+        let insert = r#"{"type":"insert","table":"t","values":[0]}"#;
+
+        assert_eq!(reset(&mut db, 3).unwrap_err(), "Row id 10 is already in use, the sequence must start past it");
+        assert_eq!(reset(&mut db, 10).unwrap_err(), "Row id 10 is already in use, the sequence must start past it");
+        reset(&mut db, 100).unwrap();
+        assert!(matches!(run(&mut db, insert), Ok(DbResult::Inserted { row_id: 100 })));
+
+        // Emptied out, the table can start again from 1
+        for id in (1..=10).chain([100]) {
+            run(&mut db, &format!(r#"{{"type":"deleteRow","table":"t","rowId":{}}}"#, id)).unwrap();
+        }
+        reset(&mut db, 1).unwrap();
+        assert!(matches!(run(&mut db, insert), Ok(DbResult::Inserted { row_id: 1 })));
+        assert!(reset(&mut db, 0).is_err());
+    }
+
+    #[test]
+    fn id_bounds_give_the_lowest_and_highest_id_or_no_row() {
+        let mut db = db_with_numbers();
+        run(&mut db, r#"{"type":"deleteRow","table":"t","rowId":1}"#).unwrap();
+        run(&mut db, r#"{"type":"deleteRow","table":"t","rowId":10}"#).unwrap();
+        let bounds = r#"{"type":"idBounds","table":"t"}"#;
+        let Ok(DbResult::Rows { columns, .. }) = run(&mut db, bounds) else { panic!("expected rows") };
+        assert_eq!(columns, ["min_id", "max_id"]);
+        assert_eq!(values(run(&mut db, bounds)), [[Value::Int(2), Value::Int(9)]]);
+
+        run(&mut db, r#"{"type":"createTable","table":"empty","columns":[["a","int"]]}"#).unwrap();
+        assert!(values(run(&mut db, r#"{"type":"idBounds","table":"empty"}"#)).is_empty());
+        assert_eq!(run(&mut db, r#"{"type":"idBounds","table":"nope"}"#).unwrap_err(), "Table not found");
+    }
+
+    #[test]
+    fn atomic_rejects_wrapped_reset_and_snapshot() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"keep","columns":[["a","int"]]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"keep","values":[1]}"#).unwrap();
+        for inner in [
+            r#"{"type":"timed","inner":{"type":"reset"}}"#,
+            r#"{"type":"timed","inner":{"type":"atomic","commands":[]}}"#,
+            r#"{"type":"explain","inner":{"type":"batch","commands":[]}}"#,
+            r#"{"type":"validate","inner":{"type":"snapshot"}}"#,
+            r#"{"type":"snapshot"}"#,
+        ] {
+            let json = format!(r#"{{"type":"atomic","commands":[{}]}}"#, inner);
+            let err = run(&mut db, &json).unwrap_err();
+            assert!(err.contains("atomic"), "{}: {}", inner, err);
+        }
+        // A failing command after a wrapped Reset must not leave the tables wiped
+        let json = r#"{"type":"atomic","commands":[{"type":"timed","inner":{"type":"reset"}},{"type":"insert","table":"missing","values":[1]}]}"#;
+        assert!(run(&mut db, json).is_err());
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"selectAll","table":"keep"}"#)), vec![1]);
+    }
+
+    #[test]
+    fn atomic_blocks_roll_back_when_a_later_command_fails() {
+        let mut db = db_with_numbers();
+        let json = r#"{"type":"atomic","commands":[
+            {"type":"insert","table":"t","values":[11]},
+            {"type":"update","table":"t","rowId":1,"updates":{"n":"one"}}
+        ]}"#;
+        assert_eq!(run(&mut db, json).unwrap_err(), "Type mismatch for column n: expected int, got text");
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"selectAll","table":"t"}"#)), (1..=10).collect::<Vec<u64>>());
+        // The rolled-back insert's id is free again
+        assert!(matches!(run(&mut db, r#"{"type":"insert","table":"t","values":[11]}"#), Ok(DbResult::Inserted { row_id: 11 })));
+
+        // Tables created in the block go too
+        let json = r#"{"type":"atomic","commands":[
+            {"type":"createTable","table":"u","columns":[["a","int"]]},
+            {"type":"deleteRow","table":"t","rowId":99}
+        ]}"#;
+        assert!(run(&mut db, json).is_err());
+        assert_eq!(run(&mut db, r#"{"type":"selectAll","table":"u"}"#).unwrap_err(), "Table not found");
+
+        let nested = r#"{"type":"atomic","commands":[{"type":"atomic","commands":[]}]}"#;
+        assert_eq!(run(&mut db, nested).unwrap_err(), "Nested atomic blocks are not supported");
+        let json = r#"{"type":"atomic","commands":[{"type":"deleteRow","table":"t","rowId":1},{"type":"deleteRow","table":"t","rowId":2}]}"#;
+        assert_eq!(batch_results(run(&mut db, json)).len(), 2);
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"selectAll","table":"t"}"#)).len(), 9);
+    }
+
+    #[test]
+    fn tables_are_listed_in_a_stable_order() {
+        let listing = |db: &mut Database| {
+            let mut names: Vec<String> = values(run(db, r#"{"type":"getTables"}"#))
+                .into_iter()
+                .map(|row| format_value(&row[0]))
+                .collect();
+            names.dedup();
+            names
+        };
+        for in_creation_order in [false, true] {
+            let mut db = Database { tables_in_creation_order: in_creation_order, ..db() };
+            for table in ["zebra", "apple", "mango"] {
+                run(&mut db, &format!(r#"{{"type":"createTable","table":"{}","columns":[["a","int"],["b","int"]]}}"#, table)).unwrap();
+            }
+            let first = listing(&mut db);
+            let expected = if in_creation_order { [r#""zebra""#, r#""apple""#, r#""mango""#] } else { [r#""apple""#, r#""mango""#, r#""zebra""#] };
+            assert_eq!(first, expected);
+            for _ in 0..5 {
+                assert_eq!(listing(&mut db), first);
+            }
+        }
+    }
+
+    #[test]
+    fn exists_checks_tables_and_rows() {
+        let mut db = db_with_numbers();
+        run(&mut db, r#"{"type":"deleteRow","table":"t","rowId":4}"#).unwrap();
+        for (json, expected) in [
+            (r#"{"type":"exists","table":"t"}"#, true),
+            (r#"{"type":"exists","table":"nope"}"#, false),
+            (r#"{"type":"exists","table":"t","rowId":3}"#, true),
+            (r#"{"type":"exists","table":"t","rowId":4}"#, false),
+            (r#"{"type":"exists","table":"t","rowId":11}"#, false),
+            (r#"{"type":"exists","table":"nope","rowId":1}"#, false),
+        ] {
+            assert_eq!(values(run(&mut db, json)), [[Value::Bool(expected)]], "{}", json);
+        }
+    }
+
+    #[test]
+    fn updates_of_short_rows_fail_instead_of_panicking() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"t","values":[1]}"#).unwrap();
+        run(&mut db, r#"{"type":"migrate","table":"t","steps":[{"op":"add","name":"b","type":"int","default":0}]}"#).unwrap();
+        // Rows that existed before the column was added take its default and can be updated
+        run(&mut db, r#"{"type":"update","table":"t","rowId":1,"updates":{"b":5}}"#).unwrap();
+        assert_eq!(values(run(&mut db, r#"{"type":"selectAll","table":"t","excludeMetadata":true}"#)), [[Value::Int(1), Value::Int(5)]]);
+
+        // A row stored without the new column is reported, not indexed past its end
+        db.tables.get_mut("t").unwrap().rows.get_mut(&1).unwrap().unwrap().truncate(1);
+        assert_eq!(
+            run(&mut db, r#"{"type":"update","table":"t","rowId":1,"updates":{"b":6}}"#).unwrap_err(),
+            "Stored row 1 has 1 values but the table has 2 columns"
+        );
+        assert!(run(&mut db, r#"{"type":"increment","table":"t","rowId":1,"column":"b","by":1}"#).is_err());
+    }
+
+    #[test]
+    fn reads_of_short_rows_fail_instead_of_panicking() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"p","columns":[["k","int"],["s","text"]],"key":["k"]}"#).unwrap();
+        run(&mut db, r#"{"type":"createTable","table":"c","columns":[["p","int"]],"foreignKeys":[{"column":"p","parent":"p"}]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"p","values":[1,"a"]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"p","values":[2,"b"]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"c","values":[2]}"#).unwrap();
+        // Copies rebuild every index, this one included
+        run(&mut db, r#"{"type":"createIndex","table":"p","column":"k"}"#).unwrap();
+        db.tables.get_mut("p").unwrap().rows.get_mut(&1).unwrap().unwrap().truncate(1);
+        db.tables.get_mut("c").unwrap().rows.get_mut(&1).unwrap().unwrap().clear();
+
+        let short = "Stored row 1 has 1 values but the table has 2 columns";
+        for json in [
+            r#"{"type":"dedup","table":"p","columns":["s"]}"#,
+            r#"{"type":"groupCount","table":"p","column":"s"}"#,
+            r#"{"type":"columnHistogram","table":"p","column":"s"}"#,
+            r#"{"type":"join","left":"p","right":"p","leftCol":"s","rightCol":"s"}"#,
+            r#"{"type":"selectWhere","table":"p","filter":{"kind":"range","column":"k","op":">","value":0},"orderBy":{"column":"s"}}"#,
+            r#"{"type":"createIndex","table":"p","column":"s"}"#,
+            r#"{"type":"createTextIndex","table":"p","column":"s"}"#,
+            r#"{"type":"copyTable","from":"p","to":"q"}"#,
+            r#"{"type":"deleteRow","table":"p","rowId":1}"#,
+        ] {
+            assert_eq!(run(&mut db, json).unwrap_err(), short, "{}", json);
+        }
+        // A child row too short to hold its foreign key is reported when its parent is deleted
+        assert_eq!(
+            run(&mut db, r#"{"type":"deleteRow","table":"p","rowId":2}"#).unwrap_err(),
+            "Stored row 1 has 0 values but the table has 1 columns"
+        );
+    }
+
+    #[test]
+    fn selects_include_metadata_columns_unless_excluded() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"t","values":[1]}"#).unwrap();
+        run(&mut db, r#"{"type":"update","table":"t","rowId":1,"updates":{"a":2}}"#).unwrap();
+        let filter = r#""filter":{"kind":"range","column":"a","op":">","value":0}"#;
+
+        for (json, metadata) in [
+            (r#"{"type":"selectAll","table":"t"}"#.to_string(), true),
+            (r#"{"type":"selectAll","table":"t","excludeMetadata":true}"#.to_string(), false),
+            (format!(r#"{{"type":"selectWhere","table":"t",{}}}"#, filter), true),
+            (format!(r#"{{"type":"selectWhere","table":"t",{},"excludeMetadata":true}}"#, filter), false),
+        ] {
+            let (columns, row) = if metadata { (vec!["a", "_version"], vec![Value::Int(2), Value::Int(2)]) } else { (vec!["a"], vec![Value::Int(2)]) };
+            let Ok(DbResult::Rows { columns: got, .. }) = run(&mut db, &json) else { panic!("expected rows") };
+            assert_eq!(got, columns, "{}", json);
+            assert_eq!(values(run(&mut db, &json)), [row], "{}", json);
+        }
+
+        // The loop encodes SelectAll straight from the table, with the same columns
+        for metadata in [true, false] {
+            let encoded = crate::protocol::encode_table(&db.tables["t"], db.row_cap(), false, None, None, metadata).unwrap();
+            let Ok(DbResult::Rows { columns, .. }) = crate::protocol::decode_response(&encoded) else { panic!("expected rows") };
+            assert_eq!(columns.len(), if metadata { 2 } else { 1 });
+        }
+    }
+
+    /// Ids of the changed rows, the deleted ids and the version to sync from next.
+    fn changes(db: &mut Database, since: u64) -> (Vec<u64>, Vec<u64>, u64) {
+        match run(db, &format!(r#"{{"type":"changesSince","table":"t","sinceVersion":{}}}"#, since)) {
+            Ok(DbResult::Changes { rows, deleted, version, .. }) => (rows.into_iter().map(|(id, _)| id).collect(), deleted, version),
+            other => panic!("expected changes, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn changes_since_returns_only_what_changed() {
+        let mut db = db_with_numbers();
+        let (rows, deleted, synced) = changes(&mut db, 0);
+        assert_eq!((rows, deleted), ((1..=10).collect(), vec![]));
+        assert_eq!(changes(&mut db, synced), (vec![], vec![], synced));
+
+        run(&mut db, r#"{"type":"update","table":"t","rowId":7,"updates":{"n":70}}"#).unwrap();
+        run(&mut db, r#"{"type":"update","table":"t","rowId":3,"updates":{"n":30}}"#).unwrap();
+        run(&mut db, r#"{"type":"insert","table":"t","values":[11]}"#).unwrap();
+        run(&mut db, r#"{"type":"deleteRow","table":"t","rowId":5}"#).unwrap();
+        // Inserted and deleted between two polls, so there's nothing of it to send but the delete
+        run(&mut db, r#"{"type":"insert","table":"t","values":[12]}"#).unwrap();
+        run(&mut db, r#"{"type":"deleteRow","table":"t","rowId":12}"#).unwrap();
+
+        let (rows, deleted, next) = changes(&mut db, synced);
+        assert_eq!(rows, [7, 3, 11]);
+        assert_eq!(deleted, [5, 12]);
+        assert!(next > synced);
+        assert_eq!(changes(&mut db, next), (vec![], vec![], next));
+    }
+
+    #[test]
+    fn built_create_tables_encode_like_hand_written_ones() {
+        let built = DbCommand::create_table("users")
+            .column("name", ColumnType::Text)
+            .column("age", ColumnType::Int)
+            .build()
+            .unwrap();
+        let manual = DbCommand::CreateTable {
+            table: "users".into(),
+            columns: vec![("name".into(), ColumnType::Text), ("age".into(), ColumnType::Int)],
+            key: Vec::new(),
+            foreign_keys: Vec::new(),
+        };
+        let encode = |cmd: &DbCommand| {
+            let mut buf = Vec::new();
+            crate::protocol::encode_command_into(&mut buf, cmd);
+            buf
+        };
+        assert_eq!(encode(&built), encode(&manual));
+
+        let duplicate = DbCommand::create_table("users").column("a", ColumnType::Int).column("a", ColumnType::Text).build();
+        assert_eq!(duplicate.unwrap_err(), "Duplicate column name a");
+    }
+
+    #[test]
+    fn results_serialize_to_row_objects_in_column_order() {
+        let rows = DbResult::Rows {
+            columns: vec!["zeta".into(), "alpha".into()],
+            rows: vec![(4, vec![Value::Int(1), Value::Text("x".into())]), (9, vec![Value::Int(-2), Value::Bool(true)])],
+            truncated: false,
+            column_types: None,
+            next_cursor: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&rows).unwrap(),
+            concat!(
+                r#"{"ok":true,"columns":["zeta","alpha"],"#,
+                r#""rows":[{"_id":4,"zeta":1,"alpha":"x"},{"_id":9,"zeta":-2,"alpha":true}],"truncated":false}"#,
+            )
+        );
+
+        let batch = DbResult::Batch { results: vec![Ok(DbResult::Inserted { row_id: 3 }), Ok(DbResult::Affected(2))] };
+        assert_eq!(
+            serde_json::to_string(&batch).unwrap(),
+            r#"{"ok":true,"results":[{"ok":true,"rowId":3},{"ok":true,"affected":2}]}"#
+        );
+        assert_eq!(serde_json::to_string(&DbResult::Ok).unwrap(), r#"{"ok":true}"#);
+    }
+
+    #[test]
+    fn create_tables_creates_all_or_none() {
+        let mut db = db();
+        let json = r#"{"type":"createTables","tables":[["a",[["x","int"]]],["b",[["y","text"]]],["c",[["z","bool"],["w","int"]]]]}"#;
+        // It goes over the wire like any other command
+        let mut frame = Vec::new();
+        crate::protocol::encode_command_into(&mut frame, &command(json));
+        db.execute(crate::protocol::parse_command(&frame).unwrap()).unwrap();
+        let mut names: Vec<&String> = db.tables.keys().collect();
+        names.sort();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert_eq!(db.tables["c"].columns.len(), 2);
+
+        for (json, error) in [
+            (r#"{"type":"createTables","tables":[["d",[["x","int"]]],["b",[["y","text"]]]]}"#, "Table already exists (table b)"),
+            (r#"{"type":"createTables","tables":[["d",[["x","int"]]],["d",[["y","text"]]]]}"#, "Duplicate table name d"),
+            (r#"{"type":"createTables","tables":[["d",[["x","int"]]],["e",[]]]}"#, "Table must have at least one column (table e)"),
+        ] {
+            assert_eq!(run(&mut db, json).unwrap_err(), error);
+            assert!(!db.tables.contains_key("d"));
+        }
+    }
+
+
+    #[test]
+    fn seeded_samples_are_reproducible_and_capped_at_the_table_size() {
+        let mut db = db_with_numbers();
+        let sample = r#"{"type":"sample","table":"t","n":4,"seed":42}"#;
+        let picked = row_ids(run(&mut db, sample));
+        assert_eq!(picked.len(), 4);
+        assert!(picked.windows(2).all(|w| w[0] < w[1]), "rows in id order without repeats: {:?}", picked);
+        assert_eq!(row_ids(run(&mut db, sample)), picked);
+
+        let everything = row_ids(run(&mut db, r#"{"type":"sample","table":"t","n":50,"seed":7}"#));
+        assert_eq!(everything, (1..=10).collect::<Vec<u64>>());
+        assert_eq!(
+            run(&mut db, r#"{"type":"sample","table":"missing","n":1}"#).unwrap_err(),
+            "Table not found"
+        );
+    }
+
+
+    #[test]
+    fn columns_are_listed_in_creation_order() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["zeta","int"],["alpha","text"],["mid","bool"]]}"#).unwrap();
+        let name = |n: &str| vec![Value::Text(n.into())];
+        assert_eq!(
+            values(run(&mut db, r#"{"type":"columns","table":"t"}"#)),
+            [name("zeta"), name("alpha"), name("mid")]
+        );
+        assert_eq!(run(&mut db, r#"{"type":"columns","table":"missing"}"#).unwrap_err(), "Table not found");
+    }
+
+
+    #[test]
+    fn dedup_keeps_the_first_row_of_each_duplicate_set() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["k","int"],["s","text"]]}"#).unwrap();
+        for (k, s) in [(1, "a"), (2, "b"), (1, "c"), (2, "b"), (1, "a")] {
+            run(&mut db, &format!(r#"{{"type":"insert","table":"t","values":[{},"{}"]}}"#, k, s)).unwrap();
+        }
+        // Rows 3 and 5 repeat row 1's k even though their text differs
+        assert_eq!(affected(run(&mut db, r#"{"type":"dedup","table":"t","columns":["k"]}"#)), 3);
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"selectAll","table":"t"}"#)), [1, 2]);
+        assert_eq!(
+            run(&mut db, r#"{"type":"dedup","table":"t","columns":["missing"]}"#).unwrap_err(),
+            "Column not found: missing"
+        );
+    }
+
+
+    #[test]
+    fn swapped_rows_exchange_ids_and_index_entries() {
+        let mut db = db_with_numbers();
+        run(&mut db, r#"{"type":"createIndex","table":"t","column":"n"}"#).unwrap();
+        run(&mut db, r#"{"type":"swapIds","table":"t","a":2,"b":7}"#).unwrap();
+
+        let n_of = |db: &mut Database, id: u64| {
+            values(run(db, &format!(r#"{{"type":"selectByIds","table":"t","ids":[{}]}}"#, id)))[0][0].clone()
+        };
+        assert_eq!(n_of(&mut db, 2), Value::Int(7));
+        assert_eq!(n_of(&mut db, 7), Value::Int(2));
+        // The index on n finds each value under its new id
+        let eq = |db: &mut Database, n: i64| {
+            row_ids(run(db, &format!(r#"{{"type":"selectWhere","table":"t","filter":{{"kind":"eq","column":"n","value":{}}}}}"#, n)))
+        };
+        assert_eq!(eq(&mut db, 7), [2]);
+        assert_eq!(eq(&mut db, 2), [7]);
+
+        assert_eq!(run(&mut db, r#"{"type":"swapIds","table":"t","a":2,"b":99}"#).unwrap_err(), "Row 99 not found");
+        assert_eq!(n_of(&mut db, 2), Value::Int(7));
+    }
+
+
+    #[test]
+    fn moved_rows_leave_the_source_for_a_fresh_id_in_the_target() {
+        let mut db = db();
+        for table in ["live", "archive"] {
+            let json = format!(r#"{{"type":"createTable","table":"{}","columns":[["k","int"],["s","text"]],"key":["k"]}}"#, table);
+            run(&mut db, &json).unwrap();
+        }
+        for (k, s) in [(1, "a"), (2, "b"), (3, "c")] {
+            run(&mut db, &format!(r#"{{"type":"insert","table":"live","values":[{},"{}"]}}"#, k, s)).unwrap();
+        }
+        run(&mut db, r#"{"type":"insert","table":"archive","values":[3,"old"]}"#).unwrap();
+
+        let moved = run(&mut db, r#"{"type":"moveRow","from":"live","to":"archive","rowId":2}"#);
+        assert!(matches!(moved, Ok(DbResult::Inserted { row_id: 2 })), "{:?}", moved);
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"selectAll","table":"live"}"#)), [1, 3]);
+        let row = |db: &mut Database, id: u64| {
+            values(run(db, &format!(r#"{{"type":"selectByIds","table":"archive","ids":[{}]}}"#, id)))[0][..2].to_vec()
+        };
+        assert_eq!(row(&mut db, 2), [Value::Int(2), Value::Text("b".into())]);
+
+        // A key already in the target stops the move before the source changes
+        assert_eq!(
+            run(&mut db, r#"{"type":"moveRow","from":"live","to":"archive","rowId":3}"#).unwrap_err(),
+            "Duplicate key (3)"
+        );
+        assert_eq!(row_ids(run(&mut db, r#"{"type":"selectAll","table":"live"}"#)), [1, 3]);
+
+        run(&mut db, r#"{"type":"createTable","table":"other","columns":[["k","text"],["s","text"]]}"#).unwrap();
+        assert_eq!(
+            run(&mut db, r#"{"type":"moveRow","from":"live","to":"other","rowId":1}"#).unwrap_err(),
+            "Schema mismatch: other doesn't have the columns of live"
+        );
+    }
+
+
+    #[test]
+    fn version_reports_the_package_and_protocol_versions() {
+        let mut db = db();
+        assert_eq!(
+            values(run(&mut db, r#"{"type":"version"}"#)),
+            [[
+                Value::Text(env!("CARGO_PKG_VERSION").into()),
+                Value::Int(protocol::PROTOCOL_VERSION as i64),
+                Value::Int(protocol::MIN_PROTOCOL_VERSION as i64),
+            ]]
+        );
+    }
+
+
+    #[test]
+    fn list_indexes_gives_each_index_and_its_kind() {
+        let mut db = db();
+        run(&mut db, r#"{"type":"createTable","table":"t","columns":[["k","int"],["n","int"],["s","text"]],"key":["k","n"]}"#).unwrap();
+        let list = r#"{"type":"listIndexes","table":"t"}"#;
+        let index = |column: &str, kind: &str| vec![Value::Text(column.into()), Value::Text(kind.into())];
+        assert_eq!(values(run(&mut db, list)), [index("k,n", "unique")]);
+
+        run(&mut db, r#"{"type":"createTextIndex","table":"t","column":"s"}"#).unwrap();
+        run(&mut db, r#"{"type":"createIndex","table":"t","column":"n"}"#).unwrap();
+        assert_eq!(values(run(&mut db, list)), [index("n", "btree"), index("s", "fulltext"), index("k,n", "unique")]);
+        assert_eq!(run(&mut db, r#"{"type":"listIndexes","table":"missing"}"#).unwrap_err(), "Table not found");
+    }
+}
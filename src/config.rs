@@ -1,3 +1,66 @@
+use std::time::Duration;
+
 pub const DB_ADDRESS: &str = "127.0.0.1:8080";
 pub const CLIENT_SERVER: &str = "0.0.0.0:3000";
 pub const CLIENT_ADDRESS: &str = "http://localhost:3000";
+/// Database connections the web client shares between its sockets, and how
+/// long a command waits for one when all are in use
+pub const DB_POOL_SIZE: usize = 16;
+pub const DB_POOL_WAIT: Duration = Duration::from_secs(5);
+/// Attempts the web client makes to reconnect to the database after losing it,
+/// starting at the delay below and doubling it each time
+pub const DB_RECONNECT_ATTEMPTS: u32 = 8;
+pub const DB_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+/// How often the web client pings browsers to keep idle sockets open through
+/// proxies. A browser that misses a whole interval without answering is dropped.
+/// `None` disables pings
+pub const WS_PING_INTERVAL: Option<Duration> = Some(Duration::from_secs(30));
+/// Rows the web client fetches per SelectAll when exporting a table
+pub const EXPORT_PAGE_ROWS: u32 = 1000;
+pub const MAX_CONNECTIONS: usize = 256;
+/// Commands queued for the database loop before clients have to wait
+pub const COMMAND_QUEUE_SIZE: usize = 1024;
+/// When the queue is full, answer "Server busy" instead of waiting for room
+pub const REJECT_WHEN_BUSY: bool = false;
+/// Largest frame accepted on any connection, in bytes. Must be between 1 KiB and 256 MiB
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+/// A write repeated byte for byte on the same connection within this window,
+/// with nothing in between, isn't run again; the client gets the first one's
+/// response. Catches clients that double-send. `None` runs every command
+pub const REPEATED_WRITE_WINDOW: Option<Duration> = None;
+pub const CASE_INSENSITIVE_TABLES: bool = false;
+/// List tables in the order they were created rather than by name
+pub const TABLES_IN_CREATION_ORDER: bool = false;
+/// Principals and their tokens. A connection authenticates with the session
+/// option `auth`, set to `principal:token`, and is then checked against table ACLs
+pub const USERS: &[(&str, &str)] = &[];
+/// Principals allowed to set table ACLs and subscribe to logs. Empty lets any
+/// connection do both
+pub const ADMINS: &[&str] = &[];
+/// Token a follower has to present to receive this server's writes, and
+/// presents to its primary when following. `None` refuses every follower
+pub const REPLICATION_TOKEN: Option<&str> = None;
+/// Limit table and column names to ASCII letters, digits and underscores. When
+/// unset, anything without control characters, commas or quotes is allowed
+pub const STRICT_NAMES: bool = true;
+/// Longest table or column name, in characters
+pub const MAX_NAME_LENGTH: usize = 64;
+pub const MAX_SELECT_ROWS: usize = 10_000;
+pub const ALLOW_RESET: bool = false;
+pub const SNAPSHOT_PATH: Option<&str> = Some("rust_db.snapshot");
+pub const SNAPSHOT_INTERVAL: Option<Duration> = Some(Duration::from_secs(60));
+/// Gzip snapshot files. Compressed and plain snapshots both load either way
+pub const COMPRESS_SNAPSHOTS: bool = false;
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+pub const ENFORCE_FOREIGN_KEYS: bool = true;
+/// Deleted rows remembered per table for ChangesSince. Clients that fall
+/// further behind have to sync the whole table again
+pub const MAX_TOMBSTONES: usize = 100_000;
+/// Number of SelectAll responses to cache. `None` disables the cache
+pub const QUERY_CACHE_SIZE: Option<usize> = None;
+pub const SLOW_QUERY_THRESHOLD: Option<Duration> = Some(Duration::from_millis(100));
+pub const DEFAULT_LOG_FILTER: &str = "info";
+/// Log events sent to each log subscriber per second. The rest are dropped and
+/// the subscriber is told how many it missed
+pub const LOG_STREAM_RATE: u32 = 200;
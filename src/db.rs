@@ -1,45 +1,798 @@
 use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
 use tokio::sync::mpsc::Receiver;
+use tracing::{Instrument, debug, error, field, info_span, warn};
 
 use crate::{Command, protocol};
 use crate::commands::{DbCommand, DbResult};
-use crate::db_types::Table;
+use crate::db_types::{IdempotencyCache, OnDelete, QueryCache, RowCursor, Table};
+use crate::session::Session;
+use crate::snapshot::SnapshotState;
 
 #[derive(Debug, Default)]
 pub struct Database {
     pub tables: HashMap<String, Table>,
+    pub cursors: HashMap<u64, RowCursor>,
+    pub next_cursor_id: u64,
+    pub idempotency_keys: IdempotencyCache,
+    /// When set, table names are lowercased on create and on every lookup.
+    pub case_insensitive_tables: bool,
+    /// GetTables and GetSchema list tables oldest first instead of by name.
+    pub tables_in_creation_order: bool,
+    /// Cap on rows returned by SelectAll and SelectWhere. `None` means unlimited.
+    pub max_select_rows: Option<usize>,
+    /// Whether the Reset command may wipe the database. Meant for test setups only.
+    pub allow_reset: bool,
+    /// Refuses writes from clients. Replicated writes are still applied.
+    pub read_only: bool,
+    /// Applied writes are published here for followers.
+    pub replication: Option<broadcast::Sender<Vec<u8>>>,
+    /// Where Snapshot writes to. `None` disables snapshots.
+    pub snapshot_path: Option<PathBuf>,
+    /// Gzip snapshots as they're written.
+    pub compress_snapshots: bool,
+    /// Which tables the next snapshot has to rewrite.
+    pub snapshot_state: SnapshotState,
+    /// Reject writes that break a foreign key. When unset, violations are only logged.
+    pub enforce_foreign_keys: bool,
+    /// When the server started, for the uptime reported by ServerInfo.
+    pub started_at: Option<Instant>,
+    /// Commands taking longer than this are logged as slow. `None` turns the log off.
+    pub slow_query_threshold: Option<Duration>,
+    /// Responses of repeated SelectAll commands. `None` disables caching.
+    pub query_cache: Option<QueryCache>,
+    /// Principals allowed to set table ACLs. Empty lets anyone set them.
+    pub admins: Vec<String>,
 }
 
 impl Database {
     
     pub async  fn run(&mut self, mut rec: Receiver<Command>) -> () {
            while let Some(cmd) = rec.recv().await {
-            let response = match protocol::parse_command(&cmd.data) {
-                Ok(db_cmd) => match self.execute(db_cmd) {
-                    Ok(result) => protocol::encode_result(&result),
-                    Err(e) => protocol::encode_error(&e),
-                },
-                Err(e) => protocol::encode_error(&format!("Protocol error: {}", e)),
+            let span = info_span!("command", opcode = cmd.data.first().copied(), table = field::Empty);
+
+            let parsed = protocol::parse_command(&cmd.data).map(|mut c| {
+                if self.case_insensitive_tables {
+                    c.lowercase_table_names();
+                }
+                cmd.session.apply(&mut c);
+                c
+            });
+            if let Ok(c) = &parsed
+                && let Some(table) = c.table()
+            {
+                span.record("table", table);
+            }
+            let name = parsed.as_ref().map_or("invalid", DbCommand::name);
+            let started = Instant::now();
+
+            let principal = cmd.session.principal.as_deref();
+            let response = match parsed {
+                // Files are written on a blocking thread so the runtime can keep
+                // serving connections; commands still wait until it's done
+                Ok(DbCommand::Snapshot {}) if cmd.replicated || self.check_access(principal, &DbCommand::Snapshot {}).is_ok() => {
+                    match self.snapshot_off_thread().instrument(span.clone()).await {
+                        Ok(result) => protocol::encode_result(&result),
+                        Err(e) => protocol::encode_error(&e),
+                    }
+                }
+                parsed => {
+                    let _entered = span.enter();
+                    // A panic fails only the command that caused it. Whatever the command
+                    // changed before panicking stays changed, which beats every client hanging.
+                    panic::catch_unwind(AssertUnwindSafe(|| match parsed {
+                        // Writes from a primary were checked there
+                        Ok(db_cmd) if !cmd.replicated
+                            && let Err(e) = self.check_access(principal, &db_cmd) =>
+                        {
+                            protocol::encode_error(&e)
+                        }
+                        // Encoded straight from the table so large selects aren't cloned first
+                        Ok(DbCommand::SelectAll { table, with_types, limit, cursor, exclude_metadata }) => {
+                            // Session options rewrite the command, so only plain commands are cached by their bytes
+                            let cacheable = cmd.session == Session::default();
+                            let cached = match &mut self.query_cache {
+                                Some(cache) if cacheable => cache.get(&cmd.data).cloned(),
+                                _ => None,
+                            };
+                            match (cached, self.tables.get(&table)) {
+                                (Some(hit), _) => hit,
+                                (None, Some(t)) => {
+                                    let response = protocol::encode_table(t, self.row_cap(), with_types, limit, cursor.as_deref(), !exclude_metadata)
+                                        .unwrap_or_else(|e| protocol::encode_error(&e));
+                                    if cacheable && let Some(cache) = &mut self.query_cache {
+                                        cache.insert(cmd.data.clone(), table, response.clone());
+                                    }
+                                    response
+                                }
+                                (None, None) => protocol::encode_error("Table not found"),
+                            }
+                        }
+                        Ok(db_cmd) if self.read_only && !cmd.replicated && db_cmd.is_write() => {
+                            protocol::encode_error("Server is read-only")
+                        }
+                        Ok(db_cmd) => {
+                            // Followers replay the command as it ran, with session options already applied
+                            let replicated_data = db_cmd.is_write().then(|| {
+                                if cmd.session == Session::default() {
+                                    cmd.data.clone()
+                                } else {
+                                    let mut data = Vec::new();
+                                    protocol::encode_command_into(&mut data, &db_cmd);
+                                    data
+                                }
+                            });
+                            if db_cmd.is_write()
+                                && let Some(cache) = &mut self.query_cache
+                            {
+                                match db_cmd.table() {
+                                    // Cascading deletes can reach other tables
+                                    Some(table) if !deletes(&db_cmd) => cache.invalidate(table),
+                                    _ => cache.clear(),
+                                }
+                            }
+                            if db_cmd.is_write() {
+                                self.mark_dirty(&db_cmd);
+                            }
+                            let result = self.execute(db_cmd);
+                            if let Some(data) = replicated_data
+                                && result.is_ok()
+                                && let Some(replication) = &self.replication
+                            {
+                                // Nobody may be following; that's not an error
+                                let _ = replication.send(data);
+                            }
+                            match result {
+                                Ok(result) => protocol::encode_result(&result),
+                                Err(e) => protocol::encode_error(&e),
+                            }
+                        }
+                        Err(e) => protocol::encode_error(&format!("Protocol error: {}", e)),
+                    }))
+                    .unwrap_or_else(|panic| {
+                        let message = panic
+                            .downcast_ref::<&str>()
+                            .copied()
+                            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                            .unwrap_or("unknown cause");
+                        error!(command = name, panic = message, "Command panicked");
+                        protocol::encode_error(&format!("Internal error: {}", message))
+                    })
+                }
             };
+            let _entered = span.enter();
+            let elapsed = started.elapsed();
+            if self.slow_query_threshold.is_some_and(|t| elapsed >= t) {
+                warn!(command = name, elapsed_ms = elapsed.as_millis() as u64, "Slow command");
+            }
+            debug!(command = name, response_bytes = response.len(), "Executed command");
             let _ = cmd.respond_to.send(response);
         }
     }
+
+    /// Notes the tables a write may change for the next snapshot, before it
+    /// runs. Deletes can cascade, so they also mark every table with a foreign key.
+    fn mark_dirty(&mut self, cmd: &DbCommand) {
+        let state = &mut self.snapshot_state;
+        cmd.clone().map_table_names(&mut |t| state.mark_dirty(t));
+        if deletes(cmd) {
+            for t in self.tables.values().filter(|t| !t.foreign_keys.is_empty()) {
+                state.mark_dirty(&t.name);
+            }
+        }
+    }
+
+    /// Rejects the command if `principal` may not use one of the tables it
+    /// touches. Table listings like GetTables aren't restricted.
+    fn check_access(&self, principal: Option<&str>, cmd: &DbCommand) -> Result<(), String> {
+        let table_access = |table: &str, write: bool| match self.tables.get(table).and_then(|t| t.acl.as_ref()) {
+            Some(acl) if !acl.allows(principal, write) => Err(format!("Access denied to table {}", table)),
+            _ => Ok(()),
+        };
+        let listed_admin = principal.is_some_and(|p| self.admins.iter().any(|a| a == p));
+        match cmd {
+            // Admins can always change an ACL, even one that shuts them out
+            DbCommand::SetAcl { .. } => {
+                if self.admins.is_empty() || listed_admin {
+                    Ok(())
+                } else {
+                    Err("Access denied: only admins can set ACLs".into())
+                }
+            }
+            // These reach every table at once, or the disk, so they need an admin
+            // or a principal who could write to every table anyway
+            DbCommand::Reset {} | DbCommand::Snapshot {} | DbCommand::SetMemoryLimit { .. } => {
+                let writes_everything = self
+                    .tables
+                    .values()
+                    .all(|t| t.acl.as_ref().is_none_or(|acl| acl.allows(principal, true)));
+                if listed_admin || writes_everything {
+                    Ok(())
+                } else {
+                    Err(format!("Access denied: only admins can run {}", cmd.name()))
+                }
+            }
+            // A cursor is read under its table's ACL, whoever opened it
+            DbCommand::Fetch { cursor_id, .. } => match self.cursors.get(cursor_id) {
+                Some(cursor) => table_access(&cursor.table, false),
+                None => Ok(()),
+            },
+            DbCommand::Batch { commands, .. } | DbCommand::Atomic { commands } => {
+                commands.iter().try_for_each(|c| self.check_access(principal, c))
+            }
+            DbCommand::Explain { inner } | DbCommand::Validate { inner } | DbCommand::Timed { inner } => {
+                self.check_access(principal, inner)
+            }
+            // The copy's source is only read
+            DbCommand::CopyTable { from, to, .. } => {
+                table_access(from, false)?;
+                table_access(to, true)
+            }
+            _ if self.tables.values().all(|t| t.acl.is_none()) => Ok(()),
+            _ => {
+                let mut tables = Vec::new();
+                cmd.clone().map_table_names(&mut |t| tables.push(t.clone()));
+                // Deleted rows take the rows referencing them along, from tables of their own
+                if let DbCommand::DeleteRow { table, .. } | DbCommand::Dedup { table, .. } | DbCommand::MoveRow { from: table, .. } = cmd {
+                    tables.extend(self.cascades_from(table));
+                }
+                tables.iter().try_for_each(|t| table_access(t, cmd.is_write()))
+            }
+        }
+    }
+
+    /// Tables that deleting rows of `table` can cascade to, through any number of foreign keys.
+    fn cascades_from(&self, table: &str) -> Vec<String> {
+        let mut reached = vec![table.to_string()];
+        let mut next = 0;
+        while let Some(parent) = reached.get(next).cloned() {
+            next += 1;
+            for t in self.tables.values() {
+                let cascades = t.foreign_keys.iter().any(|fk| fk.parent == parent && fk.on_delete == OnDelete::Cascade);
+                if cascades && !reached.contains(&t.name) {
+                    reached.push(t.name.clone());
+                }
+            }
+        }
+        reached.split_off(1)
+    }
+
     pub fn execute(&mut self, cmd: DbCommand) -> Result<DbResult, String> {
         match cmd {
-            DbCommand::CreateTable { table, columns } =>
-                self.create_table(table, columns),
+            DbCommand::CreateTable { table, columns, key, foreign_keys } =>
+                self.create_table(table, columns, key, foreign_keys),
 
-            DbCommand::InsertRow { table, values } =>
-                self.insert_row(table, values),
+            DbCommand::InsertRow { table, values, idempotency_key } =>
+                self.insert_row(table, values, idempotency_key),
 
-            DbCommand::UpdateRow { table, row_id, updates } =>
-                self.update_row(table, row_id, updates),
+            DbCommand::UpdateRow { table, row_id, updates, expected_version } =>
+                self.update_row(table, row_id, updates, expected_version),
 
-            DbCommand::SelectAll { table } =>
-                self.select_all(table),
+            DbCommand::SelectAll { table, with_types, limit, cursor, exclude_metadata } =>
+                self.select_all(table, with_types, limit, cursor, exclude_metadata),
                 
             DbCommand::GetTables {} =>
                 self.get_tables(),
+
+            DbCommand::Ping {} =>
+                Ok(DbResult::Ok),
+
+            DbCommand::SelectCursor { table } =>
+                self.open_cursor(table),
+
+            DbCommand::Fetch { cursor_id, n } =>
+                self.fetch(cursor_id, n),
+
+            DbCommand::SelectWhere { table, filter, limit, offset, order_by, exclude_metadata } =>
+                self.select_where(table, filter, limit, offset, order_by, exclude_metadata),
+
+            DbCommand::CreateTextIndex { table, column } =>
+                self.create_text_index(table, column),
+
+            DbCommand::SearchText { table, column, query } =>
+                self.search_text(table, column, query),
+
+            DbCommand::Explain { inner } =>
+                self.explain(*inner),
+
+            DbCommand::Join { left, right, left_col, right_col } =>
+                self.join(left, right, left_col, right_col),
+
+            DbCommand::GroupCount { table, column } =>
+                self.group_count(table, column),
+
+            DbCommand::Batch { commands, ignore_errors } =>
+                self.batch(commands, ignore_errors),
+
+            DbCommand::ListIds { table } =>
+                self.list_ids(table),
+
+            DbCommand::SelectByIds { table, ids, ignore_missing } =>
+                self.select_by_ids(table, ids, ignore_missing),
+
+            DbCommand::Reset {} =>
+                self.reset(),
+
+            DbCommand::Migrate { table, steps } =>
+                self.migrate(table, steps),
+
+            DbCommand::Snapshot {} =>
+                self.snapshot(),
+
+            DbCommand::DeleteRow { table, row_id } =>
+                self.delete_row(table, row_id),
+
+            DbCommand::CreateIndex { table, column } =>
+                self.create_index(table, column),
+
+            DbCommand::ServerInfo {} =>
+                self.server_info(),
+
+            DbCommand::Validate { inner } =>
+                self.validate(*inner),
+
+            DbCommand::Compact { table } =>
+                self.compact(table),
+
+            DbCommand::GetSchema {} =>
+                self.get_schema(),
+
+            DbCommand::ReserveIds { table, count } =>
+                self.reserve_ids(table, count),
+
+            DbCommand::InsertWithId { table, row_id, values } =>
+                self.insert_with_id(table, row_id, values),
+
+            DbCommand::CopyTable { from, to, preserve_ids } =>
+                self.copy_table(from, to, preserve_ids),
+
+            DbCommand::SchemaHash { table } =>
+                self.schema_hash(table),
+
+            DbCommand::SetMemoryLimit { table, max_rows } =>
+                self.set_memory_limit(table, max_rows),
+
+            DbCommand::ColumnHistogram { table, column, limit } =>
+                self.column_histogram(table, column, limit),
+
+            DbCommand::Increment { table, row_id, column, by } =>
+                self.increment(table, row_id, column, by),
+
+            DbCommand::CompareAndSwap { table, row_id, column, expected, new } =>
+                self.compare_and_swap(table, row_id, column, expected, new),
+
+            DbCommand::Tail { table, n } =>
+                self.tail(table, n),
+
+            DbCommand::SetAcl { table, acl } =>
+                self.set_acl(table, acl),
+
+            DbCommand::ResetSequence { table, start } =>
+                self.reset_sequence(table, start),
+
+            DbCommand::IdBounds { table } =>
+                self.id_bounds(table),
+
+            DbCommand::Atomic { commands } =>
+                self.atomic(commands),
+
+            DbCommand::Exists { table, row_id } =>
+                self.exists(table, row_id),
+
+            DbCommand::ChangesSince { table, since_version } =>
+                self.changes_since(table, since_version),
+
+            DbCommand::CreateTables { tables } =>
+                self.create_tables(tables),
+
+            DbCommand::Sample { table, n, seed } =>
+                self.sample(table, n, seed),
+
+            DbCommand::Columns { table } =>
+                self.column_names(table),
+
+            DbCommand::Timed { inner } =>
+                {
+                    let started = Instant::now();
+                    let result = self.execute(*inner)?;
+                    Ok(DbResult::Timed { result: Box::new(result), micros: started.elapsed().as_micros() as u64 })
+                },
+
+            DbCommand::Dedup { table, columns } =>
+                self.dedup(table, columns),
+
+            DbCommand::SwapIds { table, a, b } =>
+                self.swap_ids(table, a, b),
+
+            DbCommand::MoveRow { from, to, row_id } =>
+                self.move_row(from, to, row_id),
+
+            DbCommand::Version {} =>
+                self.version(),
+
+            DbCommand::ListIndexes { table } =>
+                self.list_indexes(table),
+
+            DbCommand::Set { .. } =>
+                Err("Set can only be sent on its own".into()),
+        }
+    }
+}
+
+/// Whether the command, or any command it wraps, deletes rows. Deletes can
+/// cascade through foreign keys to tables the command doesn't name.
+fn deletes(cmd: &DbCommand) -> bool {
+    match cmd {
+        DbCommand::DeleteRow { .. } | DbCommand::Dedup { .. } | DbCommand::MoveRow { .. } => true,
+        DbCommand::Batch { commands, .. } | DbCommand::Atomic { commands } => commands.iter().any(deletes),
+        DbCommand::Timed { inner } => deletes(inner),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_types::Acl;
+    use tokio::sync::{mpsc, oneshot};
+
+    fn command(json: &str) -> DbCommand {
+        serde_json::from_str(json).unwrap()
+    }
+
+    /// Runs `db` on its own loop, as the server does, and returns its queue.
+    fn start(db: Database) -> mpsc::Sender<Command> {
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            let mut db = db;
+            db.run(rx).await;
+        });
+        tx
+    }
+
+    async fn send_with(tx: &mpsc::Sender<Command>, json: &str, session: Session, replicated: bool) -> Result<DbResult, String> {
+        let mut data = Vec::new();
+        protocol::encode_command_into(&mut data, &command(json));
+        let (respond_to, response) = oneshot::channel();
+        tx.send(Command { data, respond_to, replicated, session }).await.unwrap();
+        protocol::decode_response(&response.await.unwrap())
+    }
+
+    async fn send(tx: &mpsc::Sender<Command>, json: &str) -> Result<DbResult, String> {
+        send_with(tx, json, Session::default(), false).await
+    }
+
+    /// A database with table `secret`, readable and writable only by `alice`.
+    fn db_with_secret_table() -> Database {
+        let mut db = Database { allow_reset: true, ..Database::default() };
+        db.execute(command(r#"{"type":"createTable","table":"secret","columns":[["a","int"]]}"#)).unwrap();
+        db.execute(command(r#"{"type":"insert","table":"secret","values":[1]}"#)).unwrap();
+        db.execute(DbCommand::SetAcl {
+            table: "secret".into(),
+            acl: Some(Acl { readers: Default::default(), writers: ["alice".to_string()].into() }),
+        })
+        .unwrap();
+        db
+    }
+
+    #[test]
+    fn fetch_is_checked_against_the_cursor_table() {
+        let mut db = db_with_secret_table();
+        let open = command(r#"{"type":"selectCursor","table":"secret"}"#);
+        db.check_access(Some("alice"), &open).unwrap();
+        let Ok(DbResult::CursorOpened { cursor_id }) = db.execute(open) else { panic!("no cursor") };
+
+        let fetch = DbCommand::Fetch { cursor_id, n: 10 };
+        assert!(db.check_access(Some("mallory"), &fetch).is_err());
+        assert!(db.check_access(None, &fetch).is_err());
+        assert!(db.check_access(Some("alice"), &fetch).is_ok());
+    }
+
+    #[test]
+    fn reset_snapshot_and_memory_limit_need_an_admin() {
+        let mut db = db_with_secret_table();
+        db.admins = vec!["root".into()];
+        for json in [
+            r#"{"type":"reset"}"#,
+            r#"{"type":"snapshot"}"#,
+            r#"{"type":"setMemoryLimit","table":"secret","maxRows":1}"#,
+        ] {
+            let cmd = command(json);
+            assert!(db.check_access(Some("mallory"), &cmd).is_err(), "{}", json);
+            assert!(db.check_access(Some("root"), &cmd).is_ok(), "{}", json);
+            // Writing every table already lets alice wipe them one by one
+            assert!(db.check_access(Some("alice"), &cmd).is_ok(), "{}", json);
+        }
+    }
+
+    fn tables(result: Result<DbResult, String>) -> Vec<String> {
+        match result {
+            Ok(DbResult::Rows { rows, .. }) => rows.into_iter().map(|(_, values)| crate::commands::format_value(&values[0])).collect(),
+            other => panic!("expected rows, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn table_names_can_be_case_insensitive() {
+        let tx = start(Database { case_insensitive_tables: true, ..Database::default() });
+        send(&tx, r#"{"type":"createTable","table":"Users","columns":[["a","int"]]}"#).await.unwrap();
+        send(&tx, r#"{"type":"insert","table":"USERS","values":[1]}"#).await.unwrap();
+        send(&tx, r#"{"type":"update","table":"users","rowId":1,"updates":{"a":2}}"#).await.unwrap();
+        assert!(matches!(send(&tx, r#"{"type":"selectAll","table":"uSeRs"}"#).await, Ok(DbResult::Rows { rows, .. }) if rows.len() == 1));
+        assert_eq!(tables(send(&tx, r#"{"type":"getTables"}"#).await), [r#""users""#]);
+        assert_eq!(
+            send(&tx, r#"{"type":"createTable","table":"users","columns":[["a","int"]]}"#).await.unwrap_err(),
+            "Table already exists"
+        );
+    }
+
+    #[tokio::test]
+    async fn table_names_are_case_sensitive_by_default() {
+        let tx = start(Database::default());
+        send(&tx, r#"{"type":"createTable","table":"Users","columns":[["a","int"]]}"#).await.unwrap();
+        assert_eq!(send(&tx, r#"{"type":"insert","table":"users","values":[1]}"#).await.unwrap_err(), "Table not found");
+        send(&tx, r#"{"type":"createTable","table":"users","columns":[["a","int"]]}"#).await.unwrap();
+        assert_eq!(tables(send(&tx, r#"{"type":"getTables"}"#).await), [r#""Users""#, r#""users""#]);
+    }
+
+    #[tokio::test]
+    async fn select_all_from_the_loop_is_flagged_truncated() {
+        let tx = start(Database { max_select_rows: Some(2), ..Database::default() });
+        send(&tx, r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).await.unwrap();
+        for a in 0..3 {
+            send(&tx, &format!(r#"{{"type":"insert","table":"t","values":[{}]}}"#, a)).await.unwrap();
+        }
+        let Ok(DbResult::Rows { rows, truncated, .. }) = send(&tx, r#"{"type":"selectAll","table":"t"}"#).await else {
+            panic!("expected rows")
+        };
+        assert_eq!((rows.len(), truncated), (2, true));
+    }
+
+    /// Collects what the fmt subscriber prints while installed with `capture`.
+    #[derive(Clone, Default)]
+    struct Logs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Logs {
+        fn capture(&self) -> tracing::subscriber::DefaultGuard {
+            let logs = self.clone();
+            let subscriber = tracing_subscriber::fmt()
+                .with_writer(move || logs.clone())
+                .with_max_level(tracing::Level::DEBUG)
+                .with_ansi(false)
+                .finish();
+            tracing::subscriber::set_default(subscriber)
+        }
+
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).lines().map(String::from).collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn executed_commands_are_logged_with_their_table() {
+        let logs = Logs::default();
+        let _guard = logs.capture();
+        let tx = start(Database::default());
+        send(&tx, r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).await.unwrap();
+
+        let lines = logs.lines();
+        let line = lines.iter().find(|l| l.contains("Executed command")).unwrap();
+        assert!(line.contains("DEBUG"), "{}", line);
+        assert!(line.contains("table=\"t\""), "{}", line);
+        assert!(line.contains("command=\"createTable\""), "{}", line);
+    }
+
+    #[tokio::test]
+    async fn commands_over_the_threshold_are_logged_as_slow() {
+        let logs = Logs::default();
+        let _guard = logs.capture();
+        let tx = start(Database { slow_query_threshold: Some(Duration::from_secs(60)), ..Database::default() });
+        send(&tx, r#"{"type":"createTable","table":"fast","columns":[["a","int"]]}"#).await.unwrap();
+        assert!(!logs.lines().iter().any(|l| l.contains("Slow command")));
+
+        // Every command takes at least no time at all
+        let tx = start(Database { slow_query_threshold: Some(Duration::ZERO), ..Database::default() });
+        send(&tx, r#"{"type":"createTable","table":"slow","columns":[["a","int"]]}"#).await.unwrap();
+        let lines = logs.lines();
+        let line = lines.iter().find(|l| l.contains("Slow command")).unwrap();
+        assert!(line.contains("WARN"), "{}", line);
+        assert!(line.contains("table=\"slow\""), "{}", line);
+        assert!(line.contains("command=\"createTable\""), "{}", line);
+        assert!(line.contains("elapsed_ms="), "{}", line);
+    }
+
+    #[tokio::test]
+    async fn cached_selects_are_identical_until_their_table_changes() {
+        let db = Database { query_cache: Some(QueryCache::new(8)), ..Database::default() };
+        let (tx, rx) = mpsc::channel(16);
+        let server = tokio::spawn(async move {
+            let mut db = db;
+            db.run(rx).await;
+            db
+        });
+        let raw = |json: &str| {
+            let mut data = Vec::new();
+            protocol::encode_command_into(&mut data, &command(json));
+            data
+        };
+        let send_raw = |data: Vec<u8>| {
+            let tx = tx.clone();
+            async move {
+                let (respond_to, response) = oneshot::channel();
+                tx.send(Command { data, respond_to, replicated: false, session: Session::default() }).await.unwrap();
+                response.await.unwrap()
+            }
+        };
+        for table in ["t", "u"] {
+            send(&tx, &format!(r#"{{"type":"createTable","table":"{}","columns":[["a","int"]]}}"#, table)).await.unwrap();
+        }
+        let select_t = raw(r#"{"type":"selectAll","table":"t"}"#);
+        let select_u = raw(r#"{"type":"selectAll","table":"u"}"#);
+
+        let first = send_raw(select_t.clone()).await;
+        assert_eq!(send_raw(select_t.clone()).await, first);
+        send_raw(select_u.clone()).await;
+        send(&tx, r#"{"type":"insert","table":"t","values":[1]}"#).await.unwrap();
+        let Ok(DbResult::Rows { rows, .. }) = protocol::decode_response(&send_raw(select_t.clone()).await) else { panic!("expected rows") };
+        assert_eq!(rows.len(), 1);
+        send(&tx, r#"{"type":"insert","table":"t","values":[2]}"#).await.unwrap();
+
+        drop(tx);
+        let mut db = server.await.unwrap();
+        let cache = db.query_cache.as_mut().unwrap();
+        assert!(cache.get(&select_t).is_none());
+        assert!(cache.get(&select_u).is_some());
+    }
+
+    #[tokio::test]
+    async fn wrapped_deletes_clear_cached_selects_of_the_tables_they_cascade_to() {
+        let mut db = Database { query_cache: Some(QueryCache::new(8)), ..Database::default() };
+        for json in [
+            r#"{"type":"createTable","table":"p","columns":[["k","int"]],"key":["k"]}"#,
+            r#"{"type":"createTable","table":"c","columns":[["p","int"]],"foreignKeys":[{"column":"p","parent":"p","onDelete":"cascade"}]}"#,
+            r#"{"type":"insert","table":"p","values":[1]}"#,
+            r#"{"type":"insert","table":"c","values":[1]}"#,
+        ] {
+            db.execute(command(json)).unwrap();
+        }
+        let tx = start(db);
+        let select_c = r#"{"type":"selectAll","table":"c"}"#;
+        let Ok(DbResult::Rows { rows, .. }) = send(&tx, select_c).await else { panic!("expected rows") };
+        assert_eq!(rows.len(), 1);
+
+        send(&tx, r#"{"type":"timed","inner":{"type":"deleteRow","table":"p","rowId":1}}"#).await.unwrap();
+        let Ok(DbResult::Rows { rows, .. }) = send(&tx, select_c).await else { panic!("expected rows") };
+        assert!(rows.is_empty());
+    }
+
+    fn as_principal(principal: &str) -> Session {
+        Session { principal: Some(principal.into()), ..Session::default() }
+    }
+
+    #[tokio::test]
+    async fn acls_deny_reads_and_allow_listed_writers() {
+        let mut db = db_with_secret_table();
+        db.execute(command(r#"{"type":"createTable","table":"open","columns":[["a","int"]]}"#)).unwrap();
+        let tx = start(db);
+        let select = r#"{"type":"selectAll","table":"secret"}"#;
+        let insert = r#"{"type":"insert","table":"secret","values":[2]}"#;
+
+        for session in [as_principal("mallory"), Session::default()] {
+            assert_eq!(send_with(&tx, select, session.clone(), false).await.unwrap_err(), "Access denied to table secret");
+            assert!(send_with(&tx, insert, session, false).await.is_err());
+        }
+        assert!(matches!(send_with(&tx, insert, as_principal("alice"), false).await, Ok(DbResult::Inserted { row_id: 2 })));
+        let Ok(DbResult::Rows { rows, .. }) = send_with(&tx, select, as_principal("alice"), false).await else { panic!("expected rows") };
+        assert_eq!(rows.len(), 2);
+
+        // Tables without an ACL stay open to everyone
+        assert!(send_with(&tx, r#"{"type":"insert","table":"open","values":[1]}"#, as_principal("mallory"), false).await.is_ok());
+        // Writes replicated from a primary were checked there
+        assert!(send_with(&tx, insert, Session::default(), true).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn deletes_need_write_access_to_every_table_they_cascade_to() {
+        let mut db = Database::default();
+        for json in [
+            r#"{"type":"createTable","table":"p","columns":[["k","int"]],"key":["k"]}"#,
+            r#"{"type":"createTable","table":"c","columns":[["p","int"],["k","int"]],"key":["k"],"foreignKeys":[{"column":"p","parent":"p","onDelete":"cascade"}]}"#,
+            r#"{"type":"createTable","table":"g","columns":[["c","int"]],"foreignKeys":[{"column":"c","parent":"c","onDelete":"cascade"}]}"#,
+            r#"{"type":"insert","table":"p","values":[1]}"#,
+            r#"{"type":"insert","table":"c","values":[1,1]}"#,
+        ] {
+            db.execute(command(json)).unwrap();
+        }
+        // Only bob may write the grandchild, two cascades away from p
+        db.execute(DbCommand::SetAcl {
+            table: "g".into(),
+            acl: Some(Acl { readers: Default::default(), writers: ["bob".to_string()].into() }),
+        })
+        .unwrap();
+        let tx = start(db);
+
+        for json in [
+            r#"{"type":"deleteRow","table":"p","rowId":1}"#,
+            r#"{"type":"dedup","table":"p"}"#,
+            r#"{"type":"timed","inner":{"type":"deleteRow","table":"c","rowId":1}}"#,
+        ] {
+            assert_eq!(send_with(&tx, json, as_principal("alice"), false).await.unwrap_err(), "Access denied to table g", "{}", json);
+        }
+        let Ok(DbResult::Rows { rows, .. }) = send(&tx, r#"{"type":"selectAll","table":"c"}"#).await else { panic!("expected rows") };
+        assert_eq!(rows.len(), 1);
+        assert!(send_with(&tx, r#"{"type":"deleteRow","table":"p","rowId":1}"#, as_principal("bob"), false).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn read_only_servers_reject_writes_and_serve_reads() {
+        let mut db = Database { read_only: true, ..db_with_secret_table() };
+        db.execute(command(r#"{"type":"setAcl","table":"secret"}"#)).unwrap();
+        let tx = start(db);
+        for json in [
+            r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#,
+            r#"{"type":"insert","table":"secret","values":[2]}"#,
+            r#"{"type":"update","table":"secret","rowId":1,"updates":{"a":5}}"#,
+            r#"{"type":"deleteRow","table":"secret","rowId":1}"#,
+            r#"{"type":"migrate","table":"secret","steps":[{"op":"drop","name":"a"}]}"#,
+            r#"{"type":"batch","commands":[{"type":"ping"},{"type":"deleteRow","table":"secret","rowId":1}]}"#,
+        ] {
+            assert_eq!(send(&tx, json).await.unwrap_err(), "Server is read-only", "{}", json);
+        }
+
+        let Ok(DbResult::Rows { rows, .. }) = send(&tx, r#"{"type":"selectAll","table":"secret"}"#).await else { panic!("expected rows") };
+        assert_eq!(rows.len(), 1);
+        assert_eq!(tables(send(&tx, r#"{"type":"getTables"}"#).await), [r#""secret""#]);
+    }
+
+    #[tokio::test]
+    async fn a_panicking_command_fails_alone_and_the_loop_carries_on() {
+        let mut db = Database::default();
+        db.execute(command(r#"{"type":"createTable","table":"t","columns":[["a","int"],["b","int"]]}"#)).unwrap();
+        // A key column past the end of the schema, which no command can produce,
+        // makes an insert index past the end of its values
+        db.tables.get_mut("t").unwrap().key_columns = vec![2];
+        let tx = start(db);
+
+        let err = send(&tx, r#"{"type":"insert","table":"t","values":[1,2]}"#).await.unwrap_err();
+        assert!(err.starts_with("Internal error: index out of bounds"), "{}", err);
+        assert!(matches!(send(&tx, r#"{"type":"ping"}"#).await, Ok(DbResult::Ok)));
+        assert!(send(&tx, r#"{"type":"createTable","table":"u","columns":[["a","int"]]}"#).await.is_ok());
+    }
+
+
+    #[tokio::test]
+    async fn timed_commands_report_their_execution_time() {
+        let mut db = Database::default();
+        db.execute(command(r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#)).unwrap();
+        for n in 0..2000 {
+            db.execute(command(&format!(r#"{{"type":"insert","table":"t","values":[{}]}}"#, n))).unwrap();
+        }
+        let tx = start(db);
+
+        let sent = Instant::now();
+        let reply = send(&tx, r#"{"type":"timed","inner":{"type":"selectAll","table":"t"}}"#).await;
+        let round_trip = sent.elapsed().as_micros() as u64;
+        match reply {
+            Ok(DbResult::Timed { result, micros }) => {
+                assert!(matches!(*result, DbResult::Rows { ref rows, .. } if rows.len() == 2000));
+                // Selecting 2000 rows takes measurable time, but never longer than the whole round trip
+                assert!(micros > 0 && micros <= round_trip, "{}µs of a {}µs round trip", micros, round_trip);
+            }
+            other => panic!("expected a timed reply, got {:?}", other),
         }
     }
 }
@@ -1,7 +1,12 @@
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use serde::de::{self, Deserializer, Visitor};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::row_store::RowStore;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ColumnType {
     Int,
@@ -9,24 +14,670 @@ pub enum ColumnType {
     Bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl ColumnType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColumnType::Int => "int",
+            ColumnType::Text => "text",
+            ColumnType::Bool => "bool",
+        }
+    }
+
+    /// The inverse of `name`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "int" => Some(ColumnType::Int),
+            "text" => Some(ColumnType::Text),
+            "bool" => Some(ColumnType::Bool),
+            _ => None,
+        }
+    }
+}
+
+// There is no float variant, so equality is total and `Value` can key maps and sets
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
 #[serde(untagged)]
 pub enum Value {
     Bool(bool), 
     Int(i64),
     Text(String),
 }
-#[derive(Debug, Clone, Serialize)]
+
+impl Value {
+    /// Name of the column type this value belongs in, as shown in error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Text(_) => "text",
+        }
+    }
+
+    /// Total order used for sorting results: bools, then ints, then text.
+    pub fn compare(&self, other: &Value) -> Ordering {
+        fn rank(v: &Value) -> u8 {
+            match v {
+                Value::Bool(_) => 0,
+                Value::Int(_) => 1,
+                Value::Text(_) => 2,
+            }
+        }
+
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::Int(a), Value::Int(b)) => a.cmp(b),
+            (Value::Text(a), Value::Text(b)) => a.cmp(b),
+            _ => rank(self).cmp(&rank(other)),
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.compare(other)
+    }
+}
+
+// Deserialized by hand instead of `#[serde(untagged)]` so each JSON kind maps
+// to exactly one variant and out-of-range numbers get a real error message.
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ValueVisitor;
+
+        impl<'de> Visitor<'de> for ValueVisitor {
+            type Value = Value;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a bool, an integer or a string")
+            }
+
+            fn visit_bool<E: de::Error>(self, b: bool) -> Result<Value, E> {
+                Ok(Value::Bool(b))
+            }
+
+            fn visit_i64<E: de::Error>(self, i: i64) -> Result<Value, E> {
+                Ok(Value::Int(i))
+            }
+
+            fn visit_u64<E: de::Error>(self, u: u64) -> Result<Value, E> {
+                i64::try_from(u)
+                    .map(Value::Int)
+                    .map_err(|_| E::custom(format!("Integer {} is too large for i64", u)))
+            }
+
+            // JSON integers past the u64 range, and anything with a fraction or
+            // exponent, arrive here
+            fn visit_f64<E: de::Error>(self, f: f64) -> Result<Value, E> {
+                if f.fract() != 0.0 || !f.is_finite() {
+                    return Err(E::custom(format!("Expected an integer, got the decimal {}", f)));
+                }
+                // Bounds as f64: i64::MAX rounds up to 2^63, which is already out of range
+                if f >= -(2f64.powi(63)) && f < 2f64.powi(63) {
+                    Ok(Value::Int(f as i64))
+                } else {
+                    Err(E::custom(format!("Integer {} is out of range for i64", f)))
+                }
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Value, E> {
+                Ok(Value::Text(s.to_string()))
+            }
+
+            fn visit_string<E: de::Error>(self, s: String) -> Result<Value, E> {
+                Ok(Value::Text(s))
+            }
+        }
+
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Column {
     pub name: String,
     pub col_type: ColumnType,
 }
 
+/// Server-side cursor over a snapshot of a table's row ids, in id order.
+#[derive(Debug, Clone)]
+pub struct RowCursor {
+    pub table: String,
+    pub row_ids: VecDeque<u64>,
+}
+
+/// Inverted index from lowercased whitespace-separated tokens to row ids.
+#[derive(Debug, Default)]
+pub struct TextIndex {
+    pub tokens: HashMap<String, HashSet<u64>>,
+}
+
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split_whitespace().map(|t| t.to_lowercase())
+}
+
+impl TextIndex {
+    pub fn insert(&mut self, row_id: u64, text: &str) {
+        for token in tokenize(text) {
+            self.tokens.entry(token).or_default().insert(row_id);
+        }
+    }
+
+    pub fn remove(&mut self, row_id: u64, text: &str) {
+        for token in tokenize(text) {
+            if let Some(ids) = self.tokens.get_mut(&token) {
+                ids.remove(&row_id);
+                if ids.is_empty() {
+                    self.tokens.remove(&token);
+                }
+            }
+        }
+    }
+
+    /// Moves entries to new row ids; ids missing from `new_ids` stay put.
+    pub fn renumber(&mut self, new_ids: &HashMap<u64, u64>) {
+        for ids in self.tokens.values_mut() {
+            *ids = ids.iter().map(|id| new_ids.get(id).copied().unwrap_or(*id)).collect();
+        }
+    }
+
+    /// Row ids containing any token of `query`.
+    pub fn search(&self, query: &str) -> HashSet<u64> {
+        tokenize(query)
+            .filter_map(|t| self.tokens.get(&t))
+            .flatten()
+            .copied()
+            .collect()
+    }
+}
+
+/// Adding a column widens every row, so a row shorter than the schema means
+/// the stored data is inconsistent, e.g. a hand-edited snapshot. Reported
+/// rather than indexing past the end of the row.
+pub fn check_row_width(row_id: u64, row: &[Value], columns: usize) -> Result<(), String> {
+    if row.len() < columns {
+        return Err(format!(
+            "Stored row {} has {} values but the table has {} columns",
+            row_id,
+            row.len(),
+            columns
+        ));
+    }
+    Ok(())
+}
+
+/// Row ids ordered by the value of one column, ties broken by row id.
+#[derive(Debug, Default)]
+pub struct SortedIndex {
+    pub entries: BTreeMap<Value, BTreeSet<u64>>,
+}
+
+impl SortedIndex {
+    pub fn insert(&mut self, row_id: u64, value: &Value) {
+        self.entries.entry(value.clone()).or_default().insert(row_id);
+    }
+
+    pub fn remove(&mut self, row_id: u64, value: &Value) {
+        if let Some(ids) = self.entries.get_mut(value) {
+            ids.remove(&row_id);
+            if ids.is_empty() {
+                self.entries.remove(value);
+            }
+        }
+    }
+
+    /// Moves entries to new row ids; ids missing from `new_ids` stay put.
+    pub fn renumber(&mut self, new_ids: &HashMap<u64, u64>) {
+        for ids in self.entries.values_mut() {
+            *ids = ids.iter().map(|id| new_ids.get(id).copied().unwrap_or(*id)).collect();
+        }
+    }
+
+    /// Row ids in index order, or the exact reverse when `descending`.
+    pub fn ids(&self, descending: bool) -> Box<dyn Iterator<Item = u64> + '_> {
+        if descending {
+            Box::new(self.entries.values().rev().flat_map(|ids| ids.iter().rev().copied()))
+        } else {
+            Box::new(self.entries.values().flat_map(|ids| ids.iter().copied()))
+        }
+    }
+}
+
+/// Remembers the row id created for recent idempotency keys. Only the most
+/// recent `capacity` keys are retained; older ones are evicted first.
+#[derive(Debug, Clone, Default)]
+pub struct IdempotencyCache {
+    entries: HashMap<String, (String, u64)>,
+    order: VecDeque<String>,
+}
+
+impl IdempotencyCache {
+    pub fn get(&self, key: &str) -> Option<&(String, u64)> {
+        self.entries.get(key)
+    }
+
+    pub fn insert(&mut self, key: String, table: String, row_id: u64, capacity: usize) {
+        while self.order.len() >= capacity {
+            let Some(oldest) = self.order.pop_front() else { break };
+            self.entries.remove(&oldest);
+        }
+        self.order.push_back(key.clone());
+        self.entries.insert(key, (table, row_id));
+    }
+
+    /// Points keys for rows of `table` at their new ids after a compaction.
+    pub fn remap(&mut self, table: &str, new_ids: &HashMap<u64, u64>) {
+        for (t, row_id) in self.entries.values_mut() {
+            if t == table
+                && let Some(new_id) = new_ids.get(row_id)
+            {
+                *row_id = *new_id;
+            }
+        }
+    }
+}
+
+/// Encoded responses of recent selects, keyed by the encoded command.
+/// Holds at most `capacity` entries and evicts the least recently used first.
+#[derive(Debug)]
+pub struct QueryCache {
+    capacity: usize,
+    /// command bytes -> (table, response bytes, tick of last use)
+    entries: HashMap<Vec<u8>, (String, Vec<u8>, u64)>,
+    /// Commands by tick of last use, least recently used first
+    by_tick: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, entries: HashMap::new(), by_tick: BTreeMap::new(), tick: 0 }
+    }
+
+    pub fn get(&mut self, command: &[u8]) -> Option<&Vec<u8>> {
+        let (_, response, last_used) = self.entries.get_mut(command)?;
+        self.tick += 1;
+        let key = self.by_tick.remove(last_used).expect("cached command has a tick");
+        self.by_tick.insert(self.tick, key);
+        *last_used = self.tick;
+        Some(response)
+    }
+
+    pub fn insert(&mut self, command: Vec<u8>, table: String, response: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some((_, _, last_used)) = self.entries.remove(&command) {
+            self.by_tick.remove(&last_used);
+        }
+        while self.entries.len() >= self.capacity {
+            let Some((_, oldest)) = self.by_tick.pop_first() else { break };
+            self.entries.remove(&oldest);
+        }
+        self.tick += 1;
+        self.by_tick.insert(self.tick, command.clone());
+        self.entries.insert(command, (table, response, self.tick));
+    }
+
+    /// Drops every cached response read from `table`.
+    pub fn invalidate(&mut self, table: &str) {
+        let by_tick = &mut self.by_tick;
+        self.entries.retain(|_, (t, _, last_used)| {
+            let keep = t != table;
+            if !keep {
+                by_tick.remove(last_used);
+            }
+            keep
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.by_tick.clear();
+    }
+}
+
 #[derive(Debug)]
 pub struct Table {
     pub name: String,
     pub columns: Vec<Column>,
-    pub rows: HashMap<u64, Vec<Value>>,
+    pub rows: RowStore,
     pub next_row_id: u64,
+    /// Full-text indexes keyed by column name
+    pub text_indexes: HashMap<String, TextIndex>,
+    /// Ordered indexes keyed by column name
+    pub sorted_indexes: HashMap<String, SortedIndex>,
+    /// Per-row version, starting at 1 and bumped on every update
+    pub versions: HashMap<u64, u64>,
+    /// Starts at 1 and is bumped once per applied migration
+    pub schema_version: u64,
+    /// Positions of the columns forming the table's key, empty if it has none
+    pub key_columns: Vec<usize>,
+    /// Key tuples of all rows, used to reject duplicates
+    pub keys: HashSet<Vec<Value>>,
+    pub foreign_keys: Vec<ForeignKey>,
+    /// Who may use the table. `None` lets everyone in.
+    pub acl: Option<Acl>,
+    /// Position in creation order, starting at 1. Tables from snapshots
+    /// older than this field have 0.
+    pub created: u64,
+    pub changes: ChangeLog,
+}
+
+/// The principals allowed to read and to write a table. Writers may read too.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Acl {
+    #[serde(default)]
+    pub readers: HashSet<String>,
+    #[serde(default)]
+    pub writers: HashSet<String>,
+}
+
+impl Acl {
+    /// Connections that haven't authenticated are only allowed into tables without an ACL.
+    pub fn allows(&self, principal: Option<&str>, write: bool) -> bool {
+        principal.is_some_and(|p| self.writers.contains(p) || (!write && self.readers.contains(p)))
+    }
+}
+
+/// What ChangesSince reads. Every insert, update and delete of a row takes the
+/// table's next change number; rows remember the number of their last change,
+/// and deletes leave a tombstone.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChangeLog {
+    /// Last change number handed out, 0 before the first change
+    pub seq: u64,
+    /// Number of each row's last change
+    pub rows: HashMap<u64, u64>,
+    /// (change number, row id) of deletes, oldest first
+    pub tombstones: VecDeque<(u64, u64)>,
+    /// Last change number whose tombstone was dropped to make room
+    pub horizon: u64,
 }
 
+impl ChangeLog {
+    pub fn changed(&mut self, row_id: u64) {
+        self.seq += 1;
+        self.rows.insert(row_id, self.seq);
+    }
+
+    /// Keeps at most `MAX_TOMBSTONES`, dropping the oldest.
+    pub fn deleted(&mut self, row_id: u64) {
+        self.seq += 1;
+        self.rows.remove(&row_id);
+        self.tombstones.push_back((self.seq, row_id));
+        while self.tombstones.len() > crate::config::MAX_TOMBSTONES {
+            if let Some((seq, _)) = self.tombstones.pop_front() {
+                self.horizon = seq;
+            }
+        }
+    }
+}
+
+/// What deleting a parent row does to the rows referencing it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnDelete {
+    /// The delete is rejected while references remain
+    #[default]
+    Restrict,
+    /// Referencing rows are deleted too
+    Cascade,
+}
+
+/// Values in column `column` must exist in the single-column key of table `parent`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForeignKey {
+    pub column: usize,
+    pub parent: String,
+    #[serde(default)]
+    pub on_delete: OnDelete,
+}
+
+pub const VERSION_COLUMN: &str = "_version";
+/// Most columns a table may have. Column counts go over the wire as a single
+/// byte, and results list `_version` after the table's own columns.
+pub const MAX_COLUMNS: usize = u8::MAX as usize - 1;
+
+impl Table {
+    /// Column names for read results, including server-managed columns.
+    pub fn result_columns(&self) -> Vec<String> {
+        self.columns
+            .iter()
+            .map(|c| c.name.clone())
+            .chain(std::iter::once(VERSION_COLUMN.to_string()))
+            .collect()
+    }
+
+    /// Types matching `result_columns`.
+    pub fn result_column_types(&self) -> Vec<ColumnType> {
+        self.columns
+            .iter()
+            .map(|c| c.col_type.clone())
+            .chain(std::iter::once(ColumnType::Int))
+            .collect()
+    }
+
+    /// A full copy of the table, with its own spill file if it spills.
+    /// Indexes are rebuilt from the copied rows rather than cloned.
+    pub fn try_clone(&self) -> Result<Table, String> {
+        let mut copy = Table {
+            name: self.name.clone(),
+            columns: self.columns.clone(),
+            rows: self.rows.try_clone()?,
+            next_row_id: self.next_row_id,
+            text_indexes: self.text_indexes.keys().map(|c| (c.clone(), TextIndex::default())).collect(),
+            sorted_indexes: self.sorted_indexes.keys().map(|c| (c.clone(), SortedIndex::default())).collect(),
+            versions: self.versions.clone(),
+            schema_version: self.schema_version,
+            key_columns: self.key_columns.clone(),
+            keys: self.keys.clone(),
+            foreign_keys: self.foreign_keys.clone(),
+            acl: self.acl.clone(),
+            created: self.created,
+            changes: self.changes.clone(),
+        };
+        copy.rebuild_indexes()?;
+        Ok(copy)
+    }
+
+    /// Rebuilds every index from the stored rows.
+    pub fn rebuild_indexes(&mut self) -> Result<(), String> {
+        for (column, index) in self.text_indexes.iter_mut() {
+            *index = TextIndex::default();
+            let Some(col) = self.columns.iter().position(|c| c.name == *column) else { continue };
+            for row in self.rows.iter() {
+                let (row_id, values) = row?;
+                check_row_width(*row_id, &values, self.columns.len())?;
+                if let Value::Text(text) = &values[col] {
+                    index.insert(*row_id, text);
+                }
+            }
+        }
+        for (column, index) in self.sorted_indexes.iter_mut() {
+            *index = SortedIndex::default();
+            let Some(col) = self.columns.iter().position(|c| c.name == *column) else { continue };
+            for row in self.rows.iter() {
+                let (row_id, values) = row?;
+                check_row_width(*row_id, &values, self.columns.len())?;
+                index.insert(*row_id, &values[col]);
+            }
+        }
+        Ok(())
+    }
+
+    /// CRC32 of the column names and types in order. Unlike `schema_version`
+    /// it is the same for equal schemas in different tables and servers.
+    pub fn schema_hash(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        for column in &self.columns {
+            hasher.update(column.name.as_bytes());
+            hasher.update(&[0]);
+            hasher.update(column.col_type.name().as_bytes());
+            hasher.update(&[0]);
+        }
+        hasher.finalize()
+    }
+
+    /// Stores a new row under `row_id` at version 1 and indexes it. Checks are the caller's job.
+    pub fn insert_at(&mut self, row_id: u64, values: Vec<Value>, key: Option<Vec<Value>>) {
+        self.index_row(row_id, &values);
+        self.rows.insert(row_id, values);
+        self.versions.insert(row_id, 1);
+        self.changes.changed(row_id);
+        if let Some(key) = key {
+            self.keys.insert(key);
+        }
+    }
+
+    /// Adds a row's values to every index on the table.
+    pub fn index_row(&mut self, row_id: u64, values: &[Value]) {
+        for (column, value) in self.columns.iter().zip(values) {
+            if let (Some(index), Value::Text(text)) = (self.text_indexes.get_mut(&column.name), value) {
+                index.insert(row_id, text);
+            }
+            if let Some(index) = self.sorted_indexes.get_mut(&column.name) {
+                index.insert(row_id, value);
+            }
+        }
+    }
+
+    /// Removes a row's values from every index on the table.
+    pub fn unindex_row(&mut self, row_id: u64, values: &[Value]) {
+        for (column, value) in self.columns.iter().zip(values) {
+            if let (Some(index), Value::Text(text)) = (self.text_indexes.get_mut(&column.name), value) {
+                index.remove(row_id, text);
+            }
+            if let Some(index) = self.sorted_indexes.get_mut(&column.name) {
+                index.remove(row_id, value);
+            }
+        }
+    }
+
+    /// The key tuple of `values`, or `None` if the table has no key.
+    pub fn key_of(&self, values: &[Value]) -> Option<Vec<Value>> {
+        if self.key_columns.is_empty() {
+            return None;
+        }
+        Some(self.key_columns.iter().map(|&i| values[i].clone()).collect())
+    }
+
+    /// A row as returned by reads, with server-managed values appended.
+    pub fn result_row(&self, row_id: u64) -> Result<Option<(u64, Vec<Value>)>, String> {
+        let values = self.rows.get(&row_id)?;
+        Ok(values.map(|values| self.result_row_from(row_id, &values)))
+    }
+
+    /// Like `result_row`, for values the caller already read.
+    pub fn result_row_from(&self, row_id: u64, values: &[Value]) -> (u64, Vec<Value>) {
+        let version = self.versions.get(&row_id).copied().unwrap_or(1);
+        let mut out = Vec::with_capacity(values.len() + 1);
+        out.extend(values.iter().cloned());
+        out.push(Value::Int(version as i64));
+        (row_id, out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(json: &str) -> Result<Value, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn each_json_kind_deserializes_to_one_variant() {
+        assert!(matches!(value("true"), Ok(Value::Bool(true))));
+        assert!(matches!(value("false"), Ok(Value::Bool(false))));
+        assert!(matches!(value("42"), Ok(Value::Int(42))));
+        assert!(matches!(value("-7"), Ok(Value::Int(-7))));
+        assert!(matches!(value(r#""hi""#), Ok(Value::Text(s)) if s == "hi"));
+        // Strings that look like other kinds stay text
+        assert!(matches!(value(r#""true""#), Ok(Value::Text(s)) if s == "true"));
+        assert!(matches!(value(r#""42""#), Ok(Value::Text(s)) if s == "42"));
+    }
+
+    #[test]
+    fn integers_at_the_i64_bounds_deserialize() {
+        assert!(matches!(value("9223372036854775807"), Ok(Value::Int(i64::MAX))));
+        assert!(matches!(value("-9223372036854775808"), Ok(Value::Int(i64::MIN))));
+        assert!(value("9223372036854775808").is_err());
+    }
+
+    #[test]
+    fn values_serialize_back_to_plain_json() {
+        for json in ["true", "42", "-7", r#""hi""#] {
+            assert_eq!(serde_json::to_string(&value(json).unwrap()).unwrap(), json);
+        }
+    }
+
+    #[test]
+    fn idempotency_cache_forgets_the_oldest_keys_first() {
+        let mut cache = IdempotencyCache::default();
+        for (n, key) in ["a", "b", "c"].into_iter().enumerate() {
+            cache.insert(key.into(), "t".into(), n as u64 + 1, 2);
+        }
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.get("b"), Some(&("t".to_string(), 2)));
+        assert_eq!(cache.get("c"), Some(&("t".to_string(), 3)));
+    }
+
+    #[test]
+    fn equal_values_hash_equally_and_key_sets() {
+        use std::hash::BuildHasher;
+        let hasher = std::hash::RandomState::new();
+        assert_eq!(Value::Text("a".into()), Value::Text("a".into()));
+        assert_eq!(hasher.hash_one(Value::Text("a".into())), hasher.hash_one(Value::Text("a".into())));
+        assert_ne!(Value::Text("a".into()), Value::Text("b".into()));
+
+        // Values of different kinds never compare equal, even when they look alike
+        assert_ne!(Value::Int(1), Value::Text("1".into()));
+        assert_ne!(Value::Int(1), Value::Bool(true));
+
+        let set: HashSet<Value> =
+            [Value::Text("a".into()), Value::Text("b".into()), Value::Text("a".into()), Value::Int(0), Value::Bool(false)]
+                .into_iter()
+                .collect();
+        assert_eq!(set.len(), 4);
+        assert!(set.contains(&Value::Text("b".into())));
+        assert!(!set.contains(&Value::Text("c".into())));
+    }
+
+    #[test]
+    fn query_cache_evicts_the_least_recently_used() {
+        let mut cache = QueryCache::new(2);
+        cache.insert(b"a".to_vec(), "t".into(), b"1".to_vec());
+        cache.insert(b"b".to_vec(), "t".into(), b"2".to_vec());
+        assert_eq!(cache.get(b"a"), Some(&b"1".to_vec()));
+        cache.insert(b"c".to_vec(), "u".into(), b"3".to_vec());
+        assert!(cache.get(b"b").is_none());
+        assert!(cache.get(b"a").is_some());
+        assert!(cache.get(b"c").is_some());
+
+        // Re-inserting a command replaces it rather than taking a second slot
+        cache.insert(b"c".to_vec(), "u".into(), b"4".to_vec());
+        assert_eq!(cache.get(b"a"), Some(&b"1".to_vec()));
+        assert_eq!(cache.get(b"c"), Some(&b"4".to_vec()));
+
+        cache.invalidate("t");
+        assert!(cache.get(b"a").is_none());
+        cache.insert(b"d".to_vec(), "u".into(), b"5".to_vec());
+        assert!(cache.get(b"c").is_some() && cache.get(b"d").is_some());
+    }
+
+    #[test]
+    fn oversized_and_decimal_numbers_say_what_is_wrong() {
+        let message = |json: &str| value(json).unwrap_err().to_string();
+        assert_eq!(message("9223372036854775808"), "Integer 9223372036854775808 is too large for i64 at line 1 column 19");
+        assert_eq!(message("-1e30"), "Integer -1000000000000000000000000000000 is out of range for i64 at line 1 column 5");
+        assert_eq!(message("1.5"), "Expected an integer, got the decimal 1.5 at line 1 column 3");
+        // A whole number written as a decimal is still an integer
+        assert_eq!(value("3.0").unwrap(), Value::Int(3));
+    }
+}
@@ -0,0 +1,384 @@
+use std::fmt;
+use serde::Deserialize;
+
+use crate::commands::{format_value, value_matches_type};
+use crate::db_types::{Column, ColumnType, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TextMatch {
+    Contains,
+    StartsWith,
+    EndsWith,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum RangeOp {
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = ">=")]
+    Ge,
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "<=")]
+    Le,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Filter {
+    Text {
+        column: String,
+        mode: TextMatch,
+        pattern: String,
+        #[serde(default, rename = "caseInsensitive")]
+        case_insensitive: bool,
+    },
+    Range {
+        column: String,
+        op: RangeOp,
+        value: i64,
+    },
+    /// Null checks. Columns can't hold null yet, so for now `IsNull` matches
+    /// no row and `IsNotNull` every row. Once they can, an equality test
+    /// against null will match the null values, the same as `IsNull`, rather
+    /// than matching nothing as in SQL.
+    IsNull {
+        column: String,
+    },
+    IsNotNull {
+        column: String,
+    },
+    /// Matches rows whose value in `column` equals any of `values`.
+    In {
+        column: String,
+        values: Vec<Value>,
+    },
+    Eq {
+        column: String,
+        value: Value,
+    },
+    /// Matches rows every filter matches; with no filters, every row.
+    And {
+        filters: Vec<Filter>,
+    },
+    /// Matches rows any filter matches; with no filters, no row.
+    Or {
+        filters: Vec<Filter>,
+    },
+    Not {
+        filter: Box<Filter>,
+    },
+}
+
+/// Deepest nesting of And, Or and Not accepted, so hostile filters can't
+/// overflow the stack while being parsed or evaluated.
+pub const MAX_FILTER_DEPTH: usize = 32;
+
+fn column_index(columns: &[Column], name: &str) -> Result<usize, String> {
+    columns
+        .iter()
+        .position(|c| c.name == name)
+        .ok_or_else(|| format!("Column not found: {}", name))
+}
+
+impl Filter {
+    /// Checks the filter against a table schema so bad filters fail even on empty tables.
+    pub fn validate(&self, columns: &[Column]) -> Result<(), String> {
+        self.validate_at(columns, 0)
+    }
+
+    fn validate_at(&self, columns: &[Column], depth: usize) -> Result<(), String> {
+        match self {
+            Filter::Text { column, .. } => {
+                let index = column_index(columns, column)?;
+                if !matches!(columns[index].col_type, ColumnType::Text) {
+                    return Err(format!("Text filter on non-text column {}", column));
+                }
+                Ok(())
+            }
+            Filter::Range { column, .. } => {
+                let index = column_index(columns, column)?;
+                if !matches!(columns[index].col_type, ColumnType::Int) {
+                    return Err(format!("Range filter on non-int column {}", column));
+                }
+                Ok(())
+            }
+            Filter::IsNull { column } | Filter::IsNotNull { column } => column_index(columns, column).map(|_| ()),
+            Filter::In { column, values } => {
+                let col_type = &columns[column_index(columns, column)?].col_type;
+                match values.iter().find(|v| !value_matches_type(v, col_type)) {
+                    Some(value) => Err(format!(
+                        "Type mismatch for column {}: expected {}, got {}",
+                        column,
+                        col_type.name(),
+                        value.type_name()
+                    )),
+                    None => Ok(()),
+                }
+            }
+            Filter::Eq { column, value } => {
+                let col_type = &columns[column_index(columns, column)?].col_type;
+                if !value_matches_type(value, col_type) {
+                    return Err(format!(
+                        "Type mismatch for column {}: expected {}, got {}",
+                        column,
+                        col_type.name(),
+                        value.type_name()
+                    ));
+                }
+                Ok(())
+            }
+            Filter::And { filters } | Filter::Or { filters } => {
+                check_depth(depth)?;
+                filters.iter().try_for_each(|f| f.validate_at(columns, depth + 1))
+            }
+            Filter::Not { filter } => {
+                check_depth(depth)?;
+                filter.validate_at(columns, depth + 1)
+            }
+        }
+    }
+
+    /// Assumes `validate` has already passed for `columns`.
+    pub fn matches(&self, columns: &[Column], row: &[Value]) -> bool {
+        match self {
+            Filter::Text { column, mode, pattern, case_insensitive } => {
+                let Ok(index) = column_index(columns, column) else { return false };
+                let Some(Value::Text(text)) = row.get(index) else { return false };
+
+                if *case_insensitive {
+                    text_matches(&text.to_lowercase(), *mode, &pattern.to_lowercase())
+                } else {
+                    text_matches(text, *mode, pattern)
+                }
+            }
+            Filter::Range { column, op, value } => {
+                let Ok(index) = column_index(columns, column) else { return false };
+                let Some(Value::Int(i)) = row.get(index) else { return false };
+
+                match op {
+                    RangeOp::Gt => i > value,
+                    RangeOp::Ge => i >= value,
+                    RangeOp::Lt => i < value,
+                    RangeOp::Le => i <= value,
+                }
+            }
+            Filter::IsNull { .. } => false,
+            Filter::IsNotNull { .. } => true,
+            Filter::In { column, values } => {
+                let Ok(index) = column_index(columns, column) else { return false };
+                row.get(index).is_some_and(|v| values.contains(v))
+            }
+            Filter::Eq { column, value } => {
+                let Ok(index) = column_index(columns, column) else { return false };
+                row.get(index) == Some(value)
+            }
+            Filter::And { filters } => filters.iter().all(|f| f.matches(columns, row)),
+            Filter::Or { filters } => filters.iter().any(|f| f.matches(columns, row)),
+            Filter::Not { filter } => !filter.matches(columns, row),
+        }
+    }
+}
+
+pub fn check_depth(depth: usize) -> Result<(), String> {
+    if depth >= MAX_FILTER_DEPTH {
+        return Err(format!("Filter nested deeper than {} levels", MAX_FILTER_DEPTH));
+    }
+    Ok(())
+}
+
+fn text_matches(text: &str, mode: TextMatch, pattern: &str) -> bool {
+    match mode {
+        TextMatch::Contains => text.contains(pattern),
+        TextMatch::StartsWith => text.starts_with(pattern),
+        TextMatch::EndsWith => text.ends_with(pattern),
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Filter::Text { column, mode, pattern, case_insensitive } => {
+                let mode = match mode {
+                    TextMatch::Contains => "contains",
+                    TextMatch::StartsWith => "starts with",
+                    TextMatch::EndsWith => "ends with",
+                };
+                write!(f, "{} {} {:?}", column, mode, pattern)?;
+                if *case_insensitive {
+                    write!(f, " (case-insensitive)")?;
+                }
+                Ok(())
+            }
+            Filter::Range { column, op, value } => {
+                let op = match op {
+                    RangeOp::Gt => ">",
+                    RangeOp::Ge => ">=",
+                    RangeOp::Lt => "<",
+                    RangeOp::Le => "<=",
+                };
+                write!(f, "{} {} {}", column, op, value)
+            }
+            Filter::IsNull { column } => write!(f, "{} is null", column),
+            Filter::IsNotNull { column } => write!(f, "{} is not null", column),
+            Filter::In { column, values } => {
+                let values: Vec<String> = values.iter().map(format_value).collect();
+                write!(f, "{} in ({})", column, values.join(", "))
+            }
+            Filter::Eq { column, value } => write!(f, "{} = {}", column, format_value(value)),
+            Filter::And { filters } | Filter::Or { filters } => {
+                let joiner = if matches!(self, Filter::And { .. }) { " and " } else { " or " };
+                let parts: Vec<String> = filters.iter().map(|f| f.to_string()).collect();
+                write!(f, "({})", parts.join(joiner))
+            }
+            Filter::Not { filter } => write!(f, "not {}", filter),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns() -> Vec<Column> {
+        vec![
+            Column { name: "n".into(), col_type: ColumnType::Int },
+            Column { name: "s".into(), col_type: ColumnType::Text },
+        ]
+    }
+
+    fn filter(json: &str) -> Filter {
+        serde_json::from_str(json).unwrap()
+    }
+
+    /// Values of `s` in the rows `f` matches.
+    fn matching(f: &Filter, texts: &[&str]) -> Vec<String> {
+        let columns = columns();
+        f.validate(&columns).unwrap();
+        texts
+            .iter()
+            .enumerate()
+            .map(|(n, s)| (n, vec![Value::Int(n as i64), Value::Text(s.to_string())]))
+            .filter(|(_, row)| f.matches(&columns, row))
+            .map(|(n, _)| texts[n].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn text_filters_match_by_mode() {
+        let texts = ["apple pie", "Pineapple", "grape", "apple"];
+        let text = |mode: &str, pattern: &str| {
+            filter(&format!(r#"{{"kind":"text","column":"s","mode":"{}","pattern":"{}"}}"#, mode, pattern))
+        };
+        assert_eq!(matching(&text("contains", "apple"), &texts), ["apple pie", "Pineapple", "apple"]);
+        assert_eq!(matching(&text("startsWith", "apple"), &texts), ["apple pie", "apple"]);
+        assert_eq!(matching(&text("endsWith", "apple"), &texts), ["Pineapple", "apple"]);
+    }
+
+    #[test]
+    fn text_filters_are_case_sensitive_unless_asked() {
+        let texts = ["Pineapple", "pineapple", "PINE"];
+        let sensitive = filter(r#"{"kind":"text","column":"s","mode":"startsWith","pattern":"pine"}"#);
+        assert_eq!(matching(&sensitive, &texts), ["pineapple"]);
+        let insensitive =
+            filter(r#"{"kind":"text","column":"s","mode":"startsWith","pattern":"pine","caseInsensitive":true}"#);
+        assert_eq!(matching(&insensitive, &texts), ["Pineapple", "pineapple", "PINE"]);
+    }
+
+    #[test]
+    fn text_filters_reject_non_text_columns() {
+        let f = filter(r#"{"kind":"text","column":"n","mode":"contains","pattern":"1"}"#);
+        assert_eq!(f.validate(&columns()).unwrap_err(), "Text filter on non-text column n");
+    }
+
+    #[test]
+    fn null_checks_find_no_null_values_yet() {
+        let texts = ["a", "", "c"];
+        assert!(matching(&filter(r#"{"kind":"isNull","column":"s"}"#), &texts).is_empty());
+        assert_eq!(matching(&filter(r#"{"kind":"isNotNull","column":"s"}"#), &texts), texts);
+        // An empty string is a value, not a null
+        let not = filter(r#"{"kind":"not","filter":{"kind":"isNull","column":"s"}}"#);
+        assert_eq!(matching(&not, &texts), texts);
+        assert!(filter(r#"{"kind":"isNull","column":"missing"}"#).validate(&columns()).is_err());
+        assert_eq!(filter(r#"{"kind":"isNotNull","column":"n"}"#).to_string(), "n is not null");
+    }
+
+    #[test]
+    fn in_filters_match_any_listed_value() {
+        let texts = ["a", "b", "c", "d", "e", "f"];
+        let f = filter(r#"{"kind":"in","column":"n","values":[1,4,9]}"#);
+        assert_eq!(matching(&f, &texts), ["b", "e"]);
+        assert!(matching(&filter(r#"{"kind":"in","column":"n","values":[]}"#), &texts).is_empty());
+        assert!(filter(r#"{"kind":"in","column":"n","values":[1,"two"]}"#).validate(&columns()).is_err());
+
+        // The value list survives the trip through the binary protocol
+        let select = crate::commands::DbCommand::SelectWhere {
+            table: "t".into(),
+            filter: f,
+            limit: None,
+            offset: None,
+            order_by: None,
+            exclude_metadata: false,
+        };
+        let mut frame = Vec::new();
+        crate::protocol::encode_command_into(&mut frame, &select);
+        let crate::commands::DbCommand::SelectWhere { filter: parsed, .. } = crate::protocol::parse_command(&frame).unwrap() else {
+            panic!("expected a select")
+        };
+        assert_eq!(matching(&parsed, &texts), ["b", "e"]);
+    }
+
+    /// Rows of (a, b) pairs, as text so `matching` can name them.
+    fn pairs() -> (Vec<Column>, Vec<Vec<Value>>) {
+        let columns = vec![
+            Column { name: "a".into(), col_type: ColumnType::Int },
+            Column { name: "b".into(), col_type: ColumnType::Int },
+        ];
+        let rows = [(1, 2), (1, 3), (2, 2), (3, 3)].iter().map(|&(a, b)| vec![Value::Int(a), Value::Int(b)]).collect();
+        (columns, rows)
+    }
+
+    fn matching_pairs(json: &str) -> Vec<(i64, i64)> {
+        let (columns, rows) = pairs();
+        let f = filter(json);
+        f.validate(&columns).unwrap();
+        rows.iter()
+            .filter(|row| f.matches(&columns, row))
+            .map(|row| match row[..] {
+                [Value::Int(a), Value::Int(b)] => (a, b),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn and_or_and_not_combine_conditions() {
+        let a1 = r#"{"kind":"eq","column":"a","value":1}"#;
+        let a2 = r#"{"kind":"eq","column":"a","value":2}"#;
+        let b2 = r#"{"kind":"eq","column":"b","value":2}"#;
+        assert_eq!(matching_pairs(&format!(r#"{{"kind":"and","filters":[{},{}]}}"#, a1, b2)), [(1, 2)]);
+        assert_eq!(matching_pairs(&format!(r#"{{"kind":"or","filters":[{},{}]}}"#, a1, a2)), [(1, 2), (1, 3), (2, 2)]);
+        assert_eq!(matching_pairs(&format!(r#"{{"kind":"not","filter":{}}}"#, b2)), [(1, 3), (3, 3)]);
+        // Empty lists follow the usual identities
+        assert_eq!(matching_pairs(r#"{"kind":"and","filters":[]}"#).len(), 4);
+        assert!(matching_pairs(r#"{"kind":"or","filters":[]}"#).is_empty());
+    }
+
+    #[test]
+    fn filters_nested_too_deep_are_rejected() {
+        let nested = |depth: usize| {
+            let mut json = r#"{"kind":"eq","column":"a","value":1}"#.to_string();
+            for _ in 0..depth {
+                json = format!(r#"{{"kind":"not","filter":{}}}"#, json);
+            }
+            json
+        };
+        let (columns, _) = pairs();
+        assert!(filter(&nested(MAX_FILTER_DEPTH)).validate(&columns).is_ok());
+        assert_eq!(
+            filter(&nested(MAX_FILTER_DEPTH + 1)).validate(&columns).unwrap_err(),
+            format!("Filter nested deeper than {} levels", MAX_FILTER_DEPTH)
+        );
+    }
+}
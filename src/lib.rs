@@ -0,0 +1,30 @@
+//! The database server. `main.rs` runs it; the library exists so benchmarks
+//! can reach the same code.
+
+use tokio::sync::oneshot;
+
+pub mod client;
+pub mod commands;
+pub mod config;
+pub mod db;
+pub mod db_types;
+pub mod filter;
+pub mod listener;
+pub mod log_stream;
+pub mod migration;
+pub mod pool;
+pub mod protocol;
+pub mod replication;
+pub mod row_store;
+pub mod session;
+pub mod snapshot;
+
+/// A command on its way to the database loop, with the channel its response goes back on.
+pub struct Command {
+    data: Vec<u8>,
+    respond_to: oneshot::Sender<Vec<u8>>,
+    /// Set for writes replayed from a primary, which a read-only follower still applies
+    replicated: bool,
+    /// Options of the connection the command came from
+    session: session::Session,
+}
@@ -1,56 +1,654 @@
-use tokio::{net::TcpListener, sync::mpsc, sync::oneshot};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::{sync::Semaphore, sync::broadcast, sync::mpsc, sync::oneshot};
+use tracing::{Instrument, error, info, info_span, warn};
 
-use crate::{Command, protocol};
+use crate::commands::DbResult;
+use crate::session::Session;
+use crate::{Command, log_stream, protocol, replication};
+
+/// Responses a connection may owe its client at once. A client pipelining
+/// further ahead waits until earlier responses are written.
+const MAX_PIPELINED: usize = 64;
+
+/// Pause after a failed accept before trying again.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
 
 pub struct Listener {
     listener: TcpListener,
+    connections: Arc<Semaphore>,
+    command_timeout: Duration,
+    /// Refuse commands while the database queue is full instead of waiting for room
+    reject_when_busy: bool,
+    /// How long an identical consecutive write is answered from the first one's response
+    repeated_write_window: Option<Duration>,
+    /// What a follower has to present to get the replication stream. `None` refuses followers
+    replication_token: Option<String>,
+    /// Commands sent to the database whose response hasn't been written back yet
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Counts one command as in flight for as long as it's alive.
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl InFlightGuard {
+    fn start(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Self(counter.clone())
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl Listener {
-    pub async fn new(address: &str) -> anyhow::Result<Self> {
+    pub async fn new(
+        address: &str,
+        max_connections: usize,
+        command_timeout: Duration,
+        reject_when_busy: bool,
+        repeated_write_window: Option<Duration>,
+        replication_token: Option<String>,
+    ) -> anyhow::Result<Self> {
         let listener = TcpListener::bind(address).await?;
-        println!("Database server on {}", address);
-        Ok(Self { listener })
+        info!(%address, max_connections, "Database server listening");
+        Ok(Self {
+            listener,
+            connections: Arc::new(Semaphore::new(max_connections)),
+            command_timeout,
+            reject_when_busy,
+            repeated_write_window,
+            replication_token,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        })
     }
 
-    pub async fn accept(&self, tx: mpsc::Sender<Command>) {
+    pub async fn accept(&self, tx: mpsc::Sender<Command>, writes: broadcast::Sender<Vec<u8>>, logs: broadcast::Sender<String>) {
         loop {
-            let (mut socket, addr) = self.listener.accept().await.unwrap();
-            println!("Client connected: {}", addr);
-            let tx = tx.clone();
+            let (socket, addr) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                // Usually out of file descriptors; they free up as clients leave
+                Err(e) => {
+                    error!(error = %e, "Failed to accept connection");
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            // Excess connections are refused outright rather than queued
+            let Ok(permit) = self.connections.clone().try_acquire_owned() else {
+                warn!(client_addr = %addr, "Connection limit reached, refusing client");
+                drop(socket);
+                continue;
+            };
+            info!(client_addr = %addr, "Client connected");
+            // Everything logged by the connection task carries the client's address
+            let span = info_span!("connection", client_addr = %addr);
+            let connection = Connection {
+                tx: tx.clone(),
+                writes: writes.clone(),
+                logs: logs.clone(),
+                in_flight: self.in_flight.clone(),
+                command_timeout: self.command_timeout,
+                reject_when_busy: self.reject_when_busy,
+                repeated_write_window: self.repeated_write_window,
+                replication_token: self.replication_token.clone(),
+            };
             tokio::spawn(async move {
-                loop {
-                    let frame = match protocol::read_frame(&mut socket).await {
-                        Ok(Some(f)) => f,
-                        Ok(None) => break,
-                        Err(e) => {
-                            eprintln!("Client {} error: {}", addr, e);
-                            break;
-                        }
-                    };
-
-                    let (resp_tx, resp_rx) = oneshot::channel();
-
-                    if tx
-                        .send(Command {
-                            data: frame,
-                            respond_to: resp_tx,
-                        })
-                        .await
-                        .is_err()
-                    {
+                let _permit = permit;
+                connection.run(socket, addr).await;
+            }
+            .instrument(span));
+        }
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Waits for every in-flight command to get its response, up to `timeout`.
+    /// Returns false if commands were still running when it gave up.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let wait = async {
+            while self.in_flight.load(Ordering::SeqCst) > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(timeout, wait).await.is_ok()
+    }
+}
+
+/// A response the writer sends once it's available, in the order the commands arrived.
+enum Pending {
+    /// Answered by the connection itself, without the database
+    Ready(Vec<u8>),
+    /// Waiting on the database until the deadline, set when the command was
+    /// queued; the guard keeps the command counted until it's written
+    Waiting(oneshot::Receiver<Vec<u8>>, InFlightGuard, tokio::time::Instant),
+    /// Repeats the response to the command before it
+    Repeat,
+    /// Acknowledges a log subscription, after which the writer only streams log events
+    Logs(broadcast::Receiver<String>),
+}
+
+/// What a connection task needs from the listener.
+struct Connection {
+    tx: mpsc::Sender<Command>,
+    writes: broadcast::Sender<Vec<u8>>,
+    logs: broadcast::Sender<String>,
+    in_flight: Arc<AtomicUsize>,
+    command_timeout: Duration,
+    reject_when_busy: bool,
+    repeated_write_window: Option<Duration>,
+    replication_token: Option<String>,
+}
+
+impl Connection {
+    /// Reads frames and hands them to the database without waiting for earlier
+    /// responses, so a client can pipeline. A separate writer task sends the
+    /// responses back in request order as they complete.
+    async fn run(self, mut socket: TcpStream, addr: SocketAddr) {
+        let mut version = protocol::DEFAULT_PROTOCOL_VERSION;
+        let mut frame_opts = protocol::FrameOptions::default();
+
+        let first = match protocol::read_frame(&mut socket).await {
+            Ok(Some(f)) => f,
+            Ok(None) => return,
+            Err(e) => {
+                error!(error = %e, "Client read error");
+                return;
+            }
+        };
+
+        // A follower takes over the connection for the replication stream, which
+        // carries every table's writes and so needs the replication token
+        if let Some(token) = protocol::parse_replicate_request(&first) {
+            if self.replication_token.as_deref().is_none_or(|t| t.as_bytes() != token) {
+                warn!("Follower refused: invalid replication token");
+                let _ = protocol::write_frame(&mut socket, &protocol::encode_error("Access denied: invalid replication token")).await;
+                return;
+            }
+            // Subscribed before the acknowledgement, so no write after it is missed
+            let writes = self.writes.subscribe();
+            if protocol::write_frame(&mut socket, &protocol::encode_result(&DbResult::Ok)).await.is_err() {
+                return;
+            }
+            info!("Follower connected");
+            replication::serve_follower(&mut socket, writes, addr).await;
+            return;
+        }
+
+        // Only the first frame may negotiate a version
+        let mut next = Some(first);
+        if let Some(requested) = next.as_deref().and_then(protocol::parse_handshake) {
+            let agreed = requested
+                .map_err(|e| format!("Protocol error: {}", e))
+                .and_then(|(v, opts)| protocol::negotiate(v, opts));
+            let (response, agreed) = match agreed {
+                Ok((v, opts)) => (protocol::encode_handshake_response(v, opts), Some((v, opts))),
+                Err(e) => (protocol::encode_error(&e), None),
+            };
+            // The handshake reply itself is always sent uncompressed
+            if let Err(e) = protocol::write_frame(&mut socket, &response).await {
+                error!(error = %e, "Client write error");
+                return;
+            }
+            if let Some((v, opts)) = agreed {
+                version = v;
+                frame_opts = opts;
+            }
+            next = None;
+        }
+
+        let (mut reader, writer) = socket.into_split();
+        let (pending_tx, pending_rx) = mpsc::channel(MAX_PIPELINED);
+        let writer = tokio::spawn(write_responses(writer, pending_rx, frame_opts, self.command_timeout).in_current_span());
+
+        let mut session = Session::default();
+        // Hash of the previous frame and when it arrived, if it was a write sent to the database
+        let mut last_write: Option<(u64, Instant)> = None;
+        loop {
+            let frame = match next.take() {
+                Some(f) => f,
+                None => match protocol::read_frame_with(&mut reader, frame_opts).await {
+                    Ok(Some(f)) => f,
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!(error = %e, "Client read error");
                         break;
                     }
+                },
+            };
 
-                    if let Ok(response) = resp_rx.await {
-                        if let Err(e) = protocol::write_frame(&mut socket, &response).await {
-                            eprintln!("Client {} write error: {}", addr, e);
-                            break;
-                        }
-                    }
+            let write_hash = self.repeated_write_window.and_then(|_| write_hash(&frame));
+            let repeated = last_write.take().is_some_and(|(hash, at)| {
+                Some(hash) == write_hash && self.repeated_write_window.is_some_and(|w| at.elapsed() <= w)
+            });
+            let pending = if repeated {
+                info!("Identical write repeated, answering with the previous response");
+                Pending::Repeat
+            } else {
+                match self.submit(frame, &mut session).await {
+                    Some(pending) => pending,
+                    None => break,
+                }
+            };
+            // A write the database never saw, e.g. one refused as busy, may be retried for real
+            if matches!(pending, Pending::Waiting(..) | Pending::Repeat) {
+                last_write = write_hash.map(|hash| (hash, Instant::now()));
+            }
+            let subscribed = matches!(pending, Pending::Logs(_));
+            // The writer is gone once the client stops reading
+            if pending_tx.send(pending).await.is_err() {
+                break;
+            }
+            if subscribed {
+                info!("Log subscriber connected");
+                // A subscriber sends nothing more that matters; stream until it hangs up
+                while let Ok(Some(_)) = protocol::read_frame_with(&mut reader, frame_opts).await {}
+                writer.abort();
+                break;
+            }
+        }
+
+        // Let the writer flush whatever is still owed to the client
+        drop(pending_tx);
+        let _ = writer.await;
+        info!(protocol_version = version, "Client disconnected");
+    }
+
+    /// Sends one frame on to the database, or answers it directly. Returns
+    /// `None` once the database has shut down.
+    async fn submit(&self, frame: Vec<u8>, session: &mut Session) -> Option<Pending> {
+        if protocol::is_subscribe_logs_request(&frame) {
+            return Some(if session.is_admin() {
+                Pending::Logs(self.logs.subscribe())
+            } else {
+                Pending::Ready(protocol::encode_error("Access denied: only admins can subscribe to logs"))
+            });
+        }
+
+        // Session options belong to this connection and never reach the database
+        if let Some(set) = protocol::parse_set(&frame) {
+            let applied = set
+                .map_err(|e| format!("Protocol error: {}", e))
+                .and_then(|(key, value)| session.set(&key, &value));
+            return Some(Pending::Ready(match applied {
+                Ok(()) => protocol::encode_result(&DbResult::Ok),
+                Err(e) => protocol::encode_error(&e),
+            }));
+        }
+
+        let guard = InFlightGuard::start(&self.in_flight);
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let command = Command {
+            data: frame,
+            respond_to: resp_tx,
+            replicated: false,
+            session: session.clone(),
+        };
+        // Time spent behind earlier commands counts towards the timeout
+        let deadline = tokio::time::Instant::now() + self.command_timeout;
+        let sent = if self.reject_when_busy {
+            self.tx.try_send(command)
+        } else {
+            self.tx.send(command).await.map_err(|e| TrySendError::Closed(e.0))
+        };
+        match sent {
+            Ok(()) => Some(Pending::Waiting(resp_rx, guard, deadline)),
+            // Tell the client to back off rather than stalling it
+            Err(TrySendError::Full(_)) => {
+                warn!("Command queue full, rejecting command");
+                Some(Pending::Ready(protocol::encode_error("Server busy, try again later")))
+            }
+            Err(TrySendError::Closed(_)) => None,
+        }
+    }
+}
+
+async fn write_responses(
+    mut writer: OwnedWriteHalf,
+    mut pending: mpsc::Receiver<Pending>,
+    frame_opts: protocol::FrameOptions,
+    command_timeout: Duration,
+) {
+    let mut write_buf = Vec::new();
+    let mut last_response = Vec::new();
+    while let Some(next) = pending.recv().await {
+        let (response, _guard) = match next {
+            Pending::Ready(response) => (response, None),
+            Pending::Repeat => (last_response.clone(), None),
+            Pending::Logs(events) => {
+                let ack = protocol::encode_result(&DbResult::Ok);
+                if protocol::write_frame_buffered(&mut writer, &ack, frame_opts, &mut write_buf).await.is_ok() {
+                    log_stream::serve_subscriber(&mut writer, events, frame_opts).await;
+                }
+                return;
+            }
+            // A stalled logic loop shouldn't leave the client hanging forever
+            Pending::Waiting(rx, guard, deadline) => match tokio::time::timeout_at(deadline, rx).await {
+                Ok(Ok(response)) => (response, Some(guard)),
+                Ok(Err(_)) => return,
+                Err(_) => {
+                    warn!(timeout = ?command_timeout, "Command timed out");
+                    (protocol::encode_error("timeout"), Some(guard))
                 }
-                println!("Client disconnected: {}", addr);
+            },
+        };
+
+        if let Err(e) = protocol::write_frame_buffered(&mut writer, &response, frame_opts, &mut write_buf).await {
+            error!(error = %e, "Client write error");
+            return;
+        }
+        last_response = response;
+    }
+}
+
+/// Hashes a frame holding a write, for spotting a client sending it twice.
+/// Anything else, including frames that don't parse, gives `None`.
+fn write_hash(frame: &[u8]) -> Option<u64> {
+    if !protocol::parse_command(frame).is_ok_and(|cmd| cmd.is_write()) {
+        return None;
+    }
+    let mut hasher = DefaultHasher::new();
+    frame.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::commands::DbCommand;
+    use crate::db::Database;
+
+    /// A listener for tests, on a free local port. Fields left at their
+    /// defaults get a listener like the server's, with fresh channels.
+    pub(crate) struct TestListener {
+        pub max_connections: usize,
+        pub command_timeout: Duration,
+        pub reject_when_busy: bool,
+        /// Room in the database queue
+        pub queue_size: usize,
+        pub repeated_write_window: Option<Duration>,
+        pub replication_token: Option<String>,
+        pub writes: Option<broadcast::Sender<Vec<u8>>>,
+        pub logs: Option<broadcast::Sender<String>>,
+    }
+
+    impl Default for TestListener {
+        fn default() -> Self {
+            TestListener {
+                max_connections: 4,
+                command_timeout: Duration::from_secs(5),
+                reject_when_busy: false,
+                queue_size: 16,
+                repeated_write_window: None,
+                replication_token: None,
+                writes: None,
+                logs: None,
+            }
+        }
+    }
+
+    impl TestListener {
+        /// Starts accepting connections whose commands go to `tx`.
+        pub async fn start_with(self, tx: mpsc::Sender<Command>) -> (Arc<Listener>, SocketAddr) {
+            let listener = Listener::new(
+                "127.0.0.1:0",
+                self.max_connections,
+                self.command_timeout,
+                self.reject_when_busy,
+                self.repeated_write_window,
+                self.replication_token,
+            )
+            .await
+            .unwrap();
+            let listener = Arc::new(listener);
+            let addr = listener.local_addr().unwrap();
+            let writes = self.writes.unwrap_or_else(|| broadcast::channel(16).0);
+            let logs = self.logs.unwrap_or_else(|| broadcast::channel(16).0);
+            tokio::spawn({
+                let listener = listener.clone();
+                async move { listener.accept(tx, writes, logs).await }
             });
+            (listener, addr)
         }
+
+        /// Starts accepting connections, and returns the queue their commands
+        /// arrive on for the caller to serve.
+        pub async fn start(self) -> (Arc<Listener>, SocketAddr, mpsc::Receiver<Command>) {
+            let (tx, rx) = mpsc::channel(self.queue_size);
+            let (listener, addr) = self.start_with(tx).await;
+            (listener, addr, rx)
+        }
+
+        /// Serves `db` and returns the address.
+        pub async fn serve(self, db: Database) -> SocketAddr {
+            let (_, addr, rx) = self.start().await;
+            tokio::spawn(async move {
+                let mut db = db;
+                db.run(rx).await;
+            });
+            addr
+        }
+    }
+
+    /// Serves a fresh database on a free local port and returns its address.
+    pub(crate) async fn database() -> String {
+        TestListener::default().serve(Database::default()).await.to_string()
+    }
+
+    /// Sends one command and waits for its response; `None` if the server hung up.
+    async fn send(socket: &mut TcpStream, json: &str) -> Option<Vec<u8>> {
+        let cmd: DbCommand = serde_json::from_str(json).unwrap();
+        let mut frame = Vec::new();
+        protocol::encode_command_into(&mut frame, &cmd);
+        protocol::write_frame(socket, &frame).await.ok()?;
+        protocol::read_frame(socket).await.ok().flatten()
+    }
+
+    #[tokio::test]
+    async fn connections_past_the_limit_are_refused() {
+        let addr = TestListener { max_connections: 1, ..TestListener::default() }.serve(Database::default()).await;
+        let ok = protocol::encode_result(&DbResult::Ok);
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(send(&mut first, r#"{"type":"ping"}"#).await, Some(ok.clone()));
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(protocol::read_frame(&mut second).await.ok().flatten(), None);
+
+        // The slot frees up once the first client leaves
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut third = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(send(&mut third, r#"{"type":"ping"}"#).await, Some(ok));
+    }
+
+    #[tokio::test]
+    async fn commands_time_out_from_when_they_were_queued() {
+        let timeout = Duration::from_millis(200);
+        let (_, addr, mut rx) = TestListener { max_connections: 1, command_timeout: timeout, ..TestListener::default() }.start().await;
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+
+        // A database that never answers
+        let started = Instant::now();
+        assert_eq!(send(&mut socket, r#"{"type":"ping"}"#).await, Some(protocol::encode_error("timeout")));
+        assert!(started.elapsed() >= timeout);
+        let stalled = rx.recv().await.unwrap();
+
+        // Two pipelined commands share the wait: the second one's clock started
+        // when it was queued, not once the first one timed out
+        let mut frame = Vec::new();
+        protocol::encode_command_into(&mut frame, &DbCommand::Ping {});
+        let started = Instant::now();
+        protocol::write_frame(&mut socket, &frame).await.unwrap();
+        protocol::write_frame(&mut socket, &frame).await.unwrap();
+        for _ in 0..2 {
+            let response = protocol::read_frame(&mut socket).await.unwrap().unwrap();
+            assert_eq!(response, protocol::encode_error("timeout"));
+        }
+        assert!(started.elapsed() < timeout * 2, "took {:?}", started.elapsed());
+        drop(stalled);
+    }
+
+    #[tokio::test]
+    async fn drain_waits_for_commands_in_flight() {
+        let (listener, addr, mut rx) = TestListener::default().start().await;
+        assert!(listener.drain(Duration::from_millis(10)).await);
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let mut frame = Vec::new();
+        protocol::encode_command_into(&mut frame, &DbCommand::Ping {});
+        protocol::write_frame(&mut socket, &frame).await.unwrap();
+        // The database holds on to the command, as a slow one would
+        let slow = rx.recv().await.unwrap();
+        assert!(!listener.drain(Duration::from_millis(50)).await);
+
+        let drained = tokio::spawn({
+            let listener = listener.clone();
+            async move { listener.drain(Duration::from_secs(5)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!drained.is_finished());
+        slow.respond_to.send(protocol::encode_result(&DbResult::Ok)).unwrap();
+        assert!(drained.await.unwrap());
+        assert_eq!(protocol::read_frame(&mut socket).await.unwrap(), Some(protocol::encode_result(&DbResult::Ok)));
+    }
+
+    #[tokio::test]
+    async fn session_options_apply_to_their_own_connection_only() {
+        let addr = TestListener::default().serve(Database::default()).await;
+        let mut prefixed = TcpStream::connect(addr).await.unwrap();
+        let mut other = TcpStream::connect(addr).await.unwrap();
+        let ok = protocol::encode_result(&DbResult::Ok);
+        let select = r#"{"type":"selectAll","table":"t"}"#;
+
+        assert_eq!(send(&mut prefixed, r#"{"type":"set","key":"table_prefix","value":"a_"}"#).await, Some(ok.clone()));
+        assert_eq!(send(&mut prefixed, r#"{"type":"createTable","table":"t","columns":[["n","int"]]}"#).await, Some(ok));
+        assert!(protocol::decode_response(&send(&mut prefixed, select).await.unwrap()).is_ok());
+
+        // The table went in under the prefix, which the other connection doesn't have
+        assert!(protocol::decode_response(&send(&mut other, select).await.unwrap()).is_err());
+        assert!(protocol::decode_response(&send(&mut other, r#"{"type":"selectAll","table":"a_t"}"#).await.unwrap()).is_ok());
+
+        // Nor does a new connection from the same client
+        drop(prefixed);
+        let mut reconnected = TcpStream::connect(addr).await.unwrap();
+        assert!(protocol::decode_response(&send(&mut reconnected, select).await.unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_full_queue_answers_busy_when_rejecting() {
+        let busy = TestListener { max_connections: 1, reject_when_busy: true, queue_size: 1, ..TestListener::default() };
+        let (_, addr, mut rx) = busy.start().await;
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let ok = protocol::encode_result(&DbResult::Ok);
+
+        // The first command fills the queue while nothing serves it
+        let mut frame = Vec::new();
+        protocol::encode_command_into(&mut frame, &DbCommand::Ping {});
+        for _ in 0..3 {
+            protocol::write_frame(&mut socket, &frame).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let queued = rx.recv().await.unwrap();
+        queued.respond_to.send(ok.clone()).unwrap();
+
+        let busy = protocol::encode_error("Server busy, try again later");
+        for expected in [&ok, &busy, &busy] {
+            assert_eq!(protocol::read_frame(&mut socket).await.unwrap().as_ref(), Some(expected));
+        }
+
+        // Once there's room again commands go through
+        let reply = tokio::spawn(async move { send(&mut socket, r#"{"type":"ping"}"#).await });
+        rx.recv().await.unwrap().respond_to.send(ok.clone()).unwrap();
+        assert_eq!(reply.await.unwrap(), Some(ok));
+    }
+
+    #[tokio::test]
+    async fn pipelined_commands_all_get_responses_in_order() {
+        let addr = TestListener { max_connections: 1, ..TestListener::default() }.serve(Database::default()).await;
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        assert!(send(&mut socket, r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).await.is_some());
+
+        // Every frame goes out before any response is read
+        let mut frames = Vec::new();
+        for i in 0..50 {
+            let cmd: DbCommand = serde_json::from_str(&format!(r#"{{"type":"insert","table":"t","values":[{}]}}"#, i)).unwrap();
+            let mut frame = Vec::new();
+            protocol::encode_command_into(&mut frame, &cmd);
+            frames.push(frame);
+        }
+        for frame in &frames {
+            protocol::write_frame(&mut socket, frame).await.unwrap();
+        }
+        for expected in 1..=50 {
+            let response = protocol::read_frame(&mut socket).await.unwrap().unwrap();
+            assert!(matches!(protocol::decode_response(&response), Ok(DbResult::Inserted { row_id }) if row_id == expected));
+        }
+    }
+
+
+    #[tokio::test]
+    async fn log_subscribers_receive_events_from_other_connections() {
+        use tracing_subscriber::prelude::*;
+
+        let (logs, _) = broadcast::channel(log_stream::LOG_STREAM_BUFFER);
+        // The test runtime runs every task on this thread, so they all log here
+        let _logging = tracing_subscriber::registry().with(log_stream::LogLayer::new(logs.clone())).set_default();
+        let addr = TestListener { logs: Some(logs), ..TestListener::default() }.serve(Database::default()).await;
+
+        let mut subscriber = TcpStream::connect(addr).await.unwrap();
+        protocol::write_frame(&mut subscriber, &protocol::encode_subscribe_logs_request()).await.unwrap();
+        let ack = protocol::read_frame(&mut subscriber).await.unwrap();
+        assert_eq!(ack, Some(protocol::encode_result(&DbResult::Ok)));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        send(&mut client, r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).await.unwrap();
+        let executed = async {
+            loop {
+                let frame = protocol::read_frame(&mut subscriber).await.unwrap().expect("log stream closed");
+                let line = String::from_utf8(frame).unwrap();
+                if line.contains("Executed command") {
+                    return line;
+                }
+            }
+        };
+        let line = tokio::time::timeout(Duration::from_secs(5), executed).await.expect("no event for the command");
+        assert!(line.starts_with("DEBUG rust_db::db:"), "{}", line);
+        assert!(line.contains(r#"command="createTable""#), "{}", line);
+    }
+
+
+    #[tokio::test]
+    async fn an_identical_consecutive_write_is_answered_without_running_again() {
+        let window = Duration::from_millis(200);
+        let addr = TestListener { repeated_write_window: Some(window), ..TestListener::default() }.serve(Database::default()).await;
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        send(&mut socket, r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).await.unwrap();
+
+        let insert = r#"{"type":"insert","table":"t","values":[1]}"#;
+        let first = send(&mut socket, insert).await.unwrap();
+        assert_eq!(send(&mut socket, insert).await.unwrap(), first);
+        let row_count = |reply: Vec<u8>| match protocol::decode_response(&reply) {
+            Ok(DbResult::Rows { rows, .. }) => rows.len(),
+            other => panic!("expected rows, got {:?}", other),
+        };
+        let select = r#"{"type":"selectAll","table":"t"}"#;
+        assert_eq!(row_count(send(&mut socket, select).await.unwrap()), 1);
+
+        // Anything in between, or a pause past the window, makes it a new write
+        send(&mut socket, insert).await.unwrap();
+        tokio::time::sleep(window + Duration::from_millis(50)).await;
+        send(&mut socket, insert).await.unwrap();
+        assert_eq!(row_count(send(&mut socket, select).await.unwrap()), 3);
     }
 }
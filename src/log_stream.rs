@@ -0,0 +1,132 @@
+use std::fmt::Write as _;
+use std::time::{Duration, Instant};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::config::LOG_STREAM_RATE;
+use crate::protocol;
+
+/// Log events kept for subscribers that fall behind.
+pub const LOG_STREAM_BUFFER: usize = 1024;
+
+/// Forwards every log event, formatted as one line, to the connections that
+/// subscribed to logs. Does nothing while nobody is subscribed.
+pub struct LogLayer {
+    events: broadcast::Sender<String>,
+}
+
+impl LogLayer {
+    pub fn new(events: broadcast::Sender<String>) -> Self {
+        Self { events }
+    }
+}
+
+/// A span's fields, formatted once when it's created.
+struct SpanFields(String);
+
+impl<S> Layer<S> for LogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = String::new();
+        attrs.record(&mut LineVisitor(&mut fields));
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(fields));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if self.events.receiver_count() == 0 {
+            return;
+        }
+        let meta = event.metadata();
+        let mut line = format!("{} {}:", meta.level(), meta.target());
+        // Fields of the enclosing spans, such as the connection's client address
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                    line.push_str(fields);
+                }
+            }
+        }
+        event.record(&mut LineVisitor(&mut line));
+        let _ = self.events.send(line);
+    }
+}
+
+struct LineVisitor<'a>(&'a mut String);
+
+impl Visit for LineVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            let _ = write!(self.0, " {}", value);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, " {:?}", value);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Streams log events to a subscribed connection as UTF-8 text frames until
+/// it goes away. At most `LOG_STREAM_RATE` events are sent per second; when
+/// some were dropped, the next frame after the limit resets says how many.
+pub async fn serve_subscriber(
+    writer: &mut OwnedWriteHalf,
+    mut events: broadcast::Receiver<String>,
+    frame_opts: protocol::FrameOptions,
+) {
+    let mut write_buf = Vec::new();
+    let mut window_start = Instant::now();
+    let mut sent = 0;
+    let mut dropped = 0u64;
+    loop {
+        let line = match events.recv().await {
+            Ok(line) => line,
+            // Unlike a follower, a log subscriber can carry on after missing events
+            Err(RecvError::Lagged(missed)) => {
+                dropped += missed;
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        if window_start.elapsed() >= Duration::from_secs(1) {
+            window_start = Instant::now();
+            sent = 0;
+        }
+        if sent >= LOG_STREAM_RATE {
+            dropped += 1;
+            continue;
+        }
+
+        let mut frames = Vec::with_capacity(2);
+        if dropped > 0 {
+            frames.push(format!("{} log events dropped", dropped));
+            dropped = 0;
+        }
+        frames.push(line);
+        for frame in frames {
+            // Logging a failed write would only feed more events into this stream
+            if protocol::write_frame_buffered(writer, frame.as_bytes(), frame_opts, &mut write_buf)
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+        sent += 1;
+    }
+}
@@ -1,38 +1,120 @@
+use std::time::Instant;
 use anyhow::Result;
-use tokio::sync::{mpsc, oneshot};
-mod client;
-mod commands;
-mod config;
-mod db;
-mod db_types;
-mod listener;
-mod protocol;
-use crate::db::Database;
-
-struct Command {
-    data: Vec<u8>,
-    respond_to: oneshot::Sender<Vec<u8>>,
-}
+use tokio::sync::{broadcast, mpsc};
+use tracing::{error, info, warn};
+use tracing::Level;
+use tracing_subscriber::filter::{EnvFilter, Targets};
+use tracing_subscriber::prelude::*;
+use rust_db::db::Database;
+use rust_db::db_types::QueryCache;
+use rust_db::{Command, client, config, listener, log_stream, replication, snapshot};
 
 const ADDRESS: &str = concat!("0.0.0.0", ":", "8080");
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let (tx, rx) = mpsc::channel::<Command>(1024);
+    // RUST_LOG overrides the default filter, e.g. RUST_LOG=rust_db=debug to log every command
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(config::DEFAULT_LOG_FILTER));
+    // Log subscribers see the server's own events down to debug, whatever RUST_LOG says
+    let (log_tx, _) = broadcast::channel(log_stream::LOG_STREAM_BUFFER);
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(filter))
+        .with(log_stream::LogLayer::new(log_tx.clone()).with_filter(Targets::new().with_target("rust_db", Level::DEBUG)))
+        .init();
+
+    // `--follow <primary>` runs a read-only follower; `--listen <addr>` overrides the bind address;
+    // `--read-only` serves the snapshot without accepting writes
+    let mut follow = None;
+    let mut read_only = false;
+    let mut address = ADDRESS.to_string();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--follow" => follow = Some(args.next().ok_or_else(|| anyhow::anyhow!("--follow needs an address"))?),
+            "--listen" => address = args.next().ok_or_else(|| anyhow::anyhow!("--listen needs an address"))?,
+            "--read-only" => read_only = true,
+            _ => anyhow::bail!("Unknown argument {}", arg),
+        }
+    }
+
+    let (tx, rx) = mpsc::channel::<Command>(config::COMMAND_QUEUE_SIZE);
+    let (replication_tx, _) = broadcast::channel(replication::REPLICATION_BUFFER);
+
+    // Followers get their data from the primary, not from disk
+    let snapshot_path = config::SNAPSHOT_PATH.filter(|_| follow.is_none()).map(std::path::PathBuf::from);
 
-    let mut db = Database::default();
+    let mut db = Database {
+        case_insensitive_tables: config::CASE_INSENSITIVE_TABLES,
+        tables_in_creation_order: config::TABLES_IN_CREATION_ORDER,
+        max_select_rows: Some(config::MAX_SELECT_ROWS),
+        // Clients can't write to a follower, so only the primary's setting matters there
+        allow_reset: config::ALLOW_RESET || follow.is_some(),
+        read_only: read_only || follow.is_some(),
+        replication: Some(replication_tx.clone()),
+        snapshot_path: snapshot_path.clone(),
+        compress_snapshots: config::COMPRESS_SNAPSHOTS,
+        enforce_foreign_keys: config::ENFORCE_FOREIGN_KEYS,
+        started_at: Some(Instant::now()),
+        slow_query_threshold: config::SLOW_QUERY_THRESHOLD,
+        query_cache: config::QUERY_CACHE_SIZE.map(QueryCache::new),
+        admins: config::ADMINS.iter().map(|a| a.to_string()).collect(),
+        ..Database::default()
+    };
+
+    if let Some(path) = &snapshot_path
+        && path.exists()
+    {
+        (db.tables, db.snapshot_state) = snapshot::load(path).map_err(|e| anyhow::anyhow!(e))?;
+        info!("Loaded {} tables from {}", db.tables.len(), path.display());
+    }
 
     // Database logic loop
     tokio::spawn(async move {
         db.run(rx).await;
     });
 
-    tokio::spawn(async move {
-        client::run().await;
-    });
+    // Nothing can change in read-only mode, so there's nothing new to save
+    if let (Some(_), Some(interval), false) = (&snapshot_path, config::SNAPSHOT_INTERVAL, read_only) {
+        let tx = tx.clone();
+        tokio::spawn(snapshot::run_periodic(interval, tx));
+    }
+
+    match follow {
+        Some(primary) => {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let token = config::REPLICATION_TOKEN.unwrap_or_default().to_string();
+                if let Err(e) = replication::follow(primary, token, tx).await {
+                    error!(error = %e, "Replication stopped");
+                }
+            });
+        }
+        None => {
+            tokio::spawn(async move {
+                client::run().await;
+            });
+        }
+    }
 
-    let listener = listener::Listener::new(ADDRESS).await?;
-    listener.accept(tx).await;
+    let listener = listener::Listener::new(
+        &address,
+        config::MAX_CONNECTIONS,
+        config::COMMAND_TIMEOUT,
+        config::REJECT_WHEN_BUSY,
+        config::REPEATED_WRITE_WINDOW,
+        config::REPLICATION_TOKEN.map(String::from),
+    )
+    .await?;
+    tokio::select! {
+        _ = listener.accept(tx, replication_tx, log_tx) => {}
+        _ = tokio::signal::ctrl_c() => {
+            // Stop accepting, then let commands already sent finish before exiting
+            info!("Shutting down, waiting for in-flight commands");
+            if !listener.drain(config::DRAIN_TIMEOUT).await {
+                warn!("Gave up waiting for in-flight commands after {:?}", config::DRAIN_TIMEOUT);
+            }
+        }
+    }
 
     Ok(())
 }
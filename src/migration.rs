@@ -0,0 +1,222 @@
+use serde::Deserialize;
+
+use crate::commands::{check_name, value_matches_type};
+use crate::db_types::{Column, ColumnType, MAX_COLUMNS, Table, Value};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum MigrationStep {
+    /// Existing rows get `default` for the new column.
+    Add {
+        name: String,
+        #[serde(rename = "type")]
+        col_type: ColumnType,
+        default: Value,
+    },
+    Drop {
+        name: String,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+}
+
+impl MigrationStep {
+    /// Checks the step against `columns` and the key column names, and updates
+    /// both to the schema the step would produce, so later steps are checked
+    /// against earlier ones.
+    pub fn check(&self, columns: &mut Vec<Column>, key: &mut [String]) -> Result<(), String> {
+        match self {
+            MigrationStep::Add { name, col_type, default } => {
+                check_new_name(columns, name)?;
+                if columns.len() >= MAX_COLUMNS {
+                    return Err(format!("Table can't have more than {} columns", MAX_COLUMNS));
+                }
+                if !value_matches_type(default, col_type) {
+                    return Err(format!(
+                        "Default for column {} must be {}, got {}",
+                        name,
+                        col_type.name(),
+                        default.type_name()
+                    ));
+                }
+                columns.push(Column { name: name.clone(), col_type: col_type.clone() });
+            }
+            MigrationStep::Drop { name } => {
+                let index = column_index(columns, name)?;
+                if key.contains(name) {
+                    return Err(format!("Cannot drop key column {}", name));
+                }
+                if columns.len() == 1 {
+                    return Err("Table must have at least one column".into());
+                }
+                columns.remove(index);
+            }
+            MigrationStep::Rename { from, to } => {
+                let index = column_index(columns, from)?;
+                check_new_name(columns, to)?;
+                columns[index].name = to.clone();
+                if let Some(k) = key.iter_mut().find(|k| *k == from) {
+                    *k = to.clone();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies steps that have already passed `check`. Rows are rewritten in
+    /// a single pass for all of them, so a spilled row that can't be read back
+    /// leaves the table as it was.
+    pub fn apply_all(steps: Vec<MigrationStep>, table: &mut Table) -> Result<(), String> {
+        // Column positions as each step finds them, after the steps before it
+        let mut names: Vec<&str> = table.columns.iter().map(|c| c.name.as_str()).collect();
+        let mut edits = Vec::new();
+        for step in &steps {
+            match step {
+                MigrationStep::Add { name, default, .. } => {
+                    edits.push(RowEdit::Push(default.clone()));
+                    names.push(name);
+                }
+                MigrationStep::Drop { name } => {
+                    if let Some(index) = names.iter().position(|n| n == name) {
+                        edits.push(RowEdit::Remove(index));
+                        names.remove(index);
+                    }
+                }
+                MigrationStep::Rename { from, to } => {
+                    if let Some(n) = names.iter_mut().find(|n| *n == from) {
+                        *n = to;
+                    }
+                }
+            }
+        }
+        table.rows.update_all(|row| {
+            for edit in &edits {
+                match edit {
+                    RowEdit::Push(value) => row.push(value.clone()),
+                    RowEdit::Remove(index) => {
+                        row.remove(*index);
+                    }
+                }
+            }
+        })?;
+
+        for step in steps {
+            step.apply_to_schema(table);
+        }
+        Ok(())
+    }
+
+    /// The schema side of `apply_all`, once the rows have been rewritten.
+    fn apply_to_schema(self, table: &mut Table) {
+        match self {
+            MigrationStep::Add { name, col_type, .. } => {
+                table.columns.push(Column { name, col_type });
+            }
+            MigrationStep::Drop { name } => {
+                let Some(index) = table.columns.iter().position(|c| c.name == name) else { return };
+                table.columns.remove(index);
+                table.text_indexes.remove(&name);
+                table.sorted_indexes.remove(&name);
+                for k in &mut table.key_columns {
+                    if *k > index {
+                        *k -= 1;
+                    }
+                }
+                // Dropping a referencing column drops its constraint with it
+                table.foreign_keys.retain(|fk| fk.column != index);
+                for fk in &mut table.foreign_keys {
+                    if fk.column > index {
+                        fk.column -= 1;
+                    }
+                }
+            }
+            MigrationStep::Rename { from, to } => {
+                let Some(column) = table.columns.iter_mut().find(|c| c.name == from) else { return };
+                column.name = to.clone();
+                if let Some(index) = table.text_indexes.remove(&from) {
+                    table.text_indexes.insert(to.clone(), index);
+                }
+                if let Some(index) = table.sorted_indexes.remove(&from) {
+                    table.sorted_indexes.insert(to, index);
+                }
+            }
+        }
+    }
+}
+
+/// What a migration does to each stored row.
+enum RowEdit {
+    Push(Value),
+    Remove(usize),
+}
+
+fn column_index(columns: &[Column], name: &str) -> Result<usize, String> {
+    columns
+        .iter()
+        .position(|c| c.name == name)
+        .ok_or_else(|| format!("Column not found: {}", name))
+}
+
+fn check_new_name(columns: &[Column], name: &str) -> Result<(), String> {
+    check_name("Column", name)?;
+    if columns.iter().any(|c| c.name == name) {
+        return Err(format!("Duplicate column name {}", name));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+
+    fn add(name: &str) -> MigrationStep {
+        MigrationStep::Add { name: name.into(), col_type: ColumnType::Int, default: Value::Int(0) }
+    }
+
+    #[test]
+    fn add_stops_at_the_column_limit() {
+        let mut columns: Vec<Column> =
+            (1..MAX_COLUMNS).map(|i| Column { name: format!("c{}", i), col_type: ColumnType::Int }).collect();
+        add("last").check(&mut columns, &mut []).unwrap();
+        assert_eq!(columns.len(), MAX_COLUMNS);
+        let err = add("one_too_many").check(&mut columns, &mut []).unwrap_err();
+        assert!(err.contains("more than"), "{}", err);
+    }
+
+    fn migrate(db: &mut Database, steps: &str) -> Result<(), String> {
+        let json = format!(r#"{{"type":"migrate","table":"t","steps":{}}}"#, steps);
+        db.execute(serde_json::from_str(&json).unwrap()).map(|_| ())
+    }
+
+    fn column_names(db: &Database) -> Vec<&str> {
+        db.tables["t"].columns.iter().map(|c| c.name.as_str()).collect()
+    }
+
+    #[test]
+    fn migrations_apply_every_step_under_one_version() {
+        let mut db = Database::default();
+        db.execute(serde_json::from_str(r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).unwrap()).unwrap();
+        db.execute(serde_json::from_str(r#"{"type":"insert","table":"t","values":[7]}"#).unwrap()).unwrap();
+        let version = db.tables["t"].schema_version;
+
+        migrate(&mut db, r#"[{"op":"add","name":"b","type":"text","default":"x"},{"op":"rename","from":"a","to":"c"}]"#).unwrap();
+        assert_eq!(db.tables["t"].schema_version, version + 1);
+        assert_eq!(column_names(&db), ["c", "b"]);
+        assert_eq!(db.tables["t"].rows.row(&1).unwrap().to_vec(), [Value::Int(7), Value::Text("x".into())]);
+    }
+
+    #[test]
+    fn failed_migrations_change_nothing() {
+        let mut db = Database::default();
+        db.execute(serde_json::from_str(r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).unwrap()).unwrap();
+        let version = db.tables["t"].schema_version;
+
+        let err = migrate(&mut db, r#"[{"op":"add","name":"b","type":"int","default":0},{"op":"drop","name":"missing"}]"#);
+        assert!(err.is_err());
+        assert_eq!(db.tables["t"].schema_version, version);
+        assert_eq!(column_names(&db), ["a"]);
+    }
+}
@@ -0,0 +1,164 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+
+use crate::protocol::{self, FrameOptions};
+
+/// A handshaken connection to the database.
+pub struct Conn {
+    pub tcp: TcpStream,
+    pub frame_opts: FrameOptions,
+}
+
+/// Database connections shared by all of the web client's sockets. At most
+/// `size` exist at once; each command checks one out and hands it back after
+/// reading its response.
+pub struct Pool {
+    /// Where the database listens
+    address: String,
+    idle: Mutex<Vec<Conn>>,
+    permits: Arc<Semaphore>,
+    /// How long `get` waits for a connection before giving up
+    wait: Duration,
+}
+
+/// A connection checked out of a pool. It goes back to the pool when dropped,
+/// unless retired.
+pub struct PooledConn {
+    conn: Option<Conn>,
+    pool: Arc<Pool>,
+    reusable: bool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Pool {
+    pub fn new(address: &str, size: usize, wait: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            address: address.to_string(),
+            idle: Mutex::new(Vec::new()),
+            permits: Arc::new(Semaphore::new(size)),
+            wait,
+        })
+    }
+
+    /// Reuses an idle connection or opens a new one. Fails with "Connection
+    /// pool exhausted" if none frees up within the pool's wait time.
+    pub async fn get(self: &Arc<Self>) -> Result<PooledConn, String> {
+        let permit = time::timeout(self.wait, self.permits.clone().acquire_owned())
+            .await
+            .map_err(|_| "Connection pool exhausted".to_string())?
+            .expect("pool semaphore is never closed");
+
+        let idle = self.idle.lock().unwrap().pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => connect(&self.address).await?,
+        };
+        Ok(PooledConn { conn: Some(conn), pool: self.clone(), reusable: true, _permit: permit })
+    }
+
+    /// Closes the idle connections, e.g. after one of them turned out to be
+    /// broken, which usually means the database restarted.
+    pub fn clear_idle(&self) {
+        self.idle.lock().unwrap().clear();
+    }
+}
+
+impl PooledConn {
+    /// Closes the connection on drop instead of returning it to the pool.
+    pub fn retire(&mut self) {
+        self.reusable = false;
+    }
+}
+
+impl Deref for PooledConn {
+    type Target = Conn;
+
+    fn deref(&self) -> &Conn {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConn {
+    fn deref_mut(&mut self) -> &mut Conn {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConn {
+    fn drop(&mut self) {
+        if self.reusable
+            && let Some(conn) = self.conn.take()
+        {
+            self.pool.idle.lock().unwrap().push(conn);
+        }
+    }
+}
+
+/// Connects to the database and completes the handshake.
+async fn connect(address: &str) -> Result<Conn, String> {
+    let mut tcp = TcpStream::connect(address)
+        .await
+        .map_err(|e| format!("Failed to connect to database: {}", e))?;
+    let frame_opts = handshake(&mut tcp).await.map_err(|e| format!("Handshake failed: {}", e))?;
+    Ok(Conn { tcp, frame_opts })
+}
+
+async fn handshake(tcp: &mut TcpStream) -> Result<FrameOptions, String> {
+    let wanted = FrameOptions {
+        compression: true,
+        checksum: true,
+        ..Default::default()
+    };
+    let request = protocol::encode_handshake(protocol::PROTOCOL_VERSION, wanted);
+    protocol::write_frame(tcp, &request).await.map_err(|e| e.to_string())?;
+    match protocol::read_frame(tcp).await {
+        Ok(Some(response)) => protocol::decode_handshake_response(&response).map(|(_, opts)| opts),
+        Ok(None) => Err("Connection closed".into()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::tests::database;
+
+    #[tokio::test]
+    async fn connections_are_reused_and_capped() {
+        let pool = Pool::new(&database().await, 2, Duration::from_millis(100));
+        let first = pool.get().await.unwrap();
+        let second = pool.get().await.unwrap();
+        let first_port = first.tcp.local_addr().unwrap().port();
+        assert_eq!(pool.get().await.err().unwrap(), "Connection pool exhausted");
+
+        // A returned connection is handed out again rather than a new one opened
+        drop(first);
+        let again = pool.get().await.unwrap();
+        assert_eq!(again.tcp.local_addr().unwrap().port(), first_port);
+
+        // A retired one isn't, but its slot frees up
+        let mut retired = second;
+        retired.retire();
+        let retired_port = retired.tcp.local_addr().unwrap().port();
+        drop(retired);
+        assert_ne!(pool.get().await.unwrap().tcp.local_addr().unwrap().port(), retired_port);
+    }
+
+    #[tokio::test]
+    async fn a_waiting_get_takes_the_next_returned_connection() {
+        let pool = Pool::new(&database().await, 1, Duration::from_secs(5));
+        let held = pool.get().await.unwrap();
+        let waiter = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.get().await.map(|conn| conn.tcp.local_addr().unwrap().port()) }
+        });
+        time::sleep(Duration::from_millis(20)).await;
+        let port = held.tcp.local_addr().unwrap().port();
+        drop(held);
+        assert_eq!(waiter.await.unwrap(), Ok(port));
+    }
+}
@@ -1,22 +1,186 @@
-use std::collections::HashMap;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use crate::db_types::{Column, ColumnType, Value};
-use crate::commands::{DbCommand, DbResult};
+use std::borrow::{Borrow, Cow};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::config;
+use crate::db_types::{Acl, Column, ColumnType, OnDelete, Table, Value};
+use crate::commands::{self, DbCommand, DbResult, ForeignKeyDef, OrderBy, TableSchema};
+use crate::filter::{self, Filter, RangeOp, TextMatch};
+use crate::migration::MigrationStep;
 // Command opcodes
 const OP_CREATE_TABLE: u8 = 0x01;
 const OP_INSERT_ROW: u8 = 0x02;
 const OP_UPDATE_ROW: u8 = 0x03;
 const OP_SELECT_ALL: u8 = 0x04;
 const OP_GET_TABLES: u8 = 0x05;
+// Keepalive with no side effects; idle clients should send one about every 30s
+// so middleboxes don't drop the connection.
+const OP_PING: u8 = 0x06;
+const OP_SELECT_CURSOR: u8 = 0x07;
+const OP_FETCH: u8 = 0x08;
+const OP_SELECT_WHERE: u8 = 0x09;
+const OP_CREATE_TEXT_INDEX: u8 = 0x0A;
+const OP_SEARCH_TEXT: u8 = 0x0B;
+const OP_EXPLAIN: u8 = 0x0C;
+const OP_JOIN: u8 = 0x0D;
+const OP_GROUP_COUNT: u8 = 0x0E;
+const OP_BATCH: u8 = 0x0F;
+const OP_LIST_IDS: u8 = 0x10;
+const OP_SELECT_BY_IDS: u8 = 0x11;
+const OP_RESET: u8 = 0x12;
+const OP_MIGRATE: u8 = 0x13;
+const OP_SNAPSHOT: u8 = 0x14;
+const OP_DELETE_ROW: u8 = 0x15;
+const OP_CREATE_INDEX: u8 = 0x16;
+const OP_SERVER_INFO: u8 = 0x17;
+const OP_SET: u8 = 0x18;
+const OP_VALIDATE: u8 = 0x19;
+const OP_COMPACT: u8 = 0x1A;
+const OP_GET_SCHEMA: u8 = 0x1B;
+const OP_RESERVE_IDS: u8 = 0x1C;
+const OP_INSERT_WITH_ID: u8 = 0x1D;
+const OP_COPY_TABLE: u8 = 0x1E;
+const OP_SCHEMA_HASH: u8 = 0x1F;
+const OP_SET_MEMORY_LIMIT: u8 = 0x20;
+const OP_COLUMN_HISTOGRAM: u8 = 0x21;
+const OP_INCREMENT: u8 = 0x22;
+const OP_COMPARE_AND_SWAP: u8 = 0x23;
+const OP_TAIL: u8 = 0x24;
+const OP_SET_ACL: u8 = 0x25;
+const OP_RESET_SEQUENCE: u8 = 0x26;
+const OP_ID_BOUNDS: u8 = 0x27;
+const OP_ATOMIC: u8 = 0x28;
+const OP_EXISTS: u8 = 0x29;
+const OP_CHANGES_SINCE: u8 = 0x2A;
+const OP_CREATE_TABLES: u8 = 0x2B;
+const OP_SAMPLE: u8 = 0x2C;
+const OP_COLUMNS: u8 = 0x2D;
+const OP_TIMED: u8 = 0x2E;
+const OP_DEDUP: u8 = 0x2F;
+const OP_SWAP_IDS: u8 = 0x30;
+const OP_MOVE_ROW: u8 = 0x31;
+const OP_VERSION: u8 = 0x32;
+const OP_LIST_INDEXES: u8 = 0x33;
+// Connection-level opcodes, handled by the listener rather than the database
+const OP_HANDSHAKE: u8 = 0x80;
+const OP_REPLICATE: u8 = 0x81;
+const OP_SUBSCRIBE_LOGS: u8 = 0x82;
 // Value/Column type opcodes
 const TYPE_INT: u8 = 0x01;
 const TYPE_TEXT: u8 = 0x02;
 const TYPE_BOOL: u8 = 0x03;
 
+// Filter opcodes
+const FILTER_TEXT: u8 = 0x01;
+const FILTER_RANGE: u8 = 0x02;
+const FILTER_IS_NULL: u8 = 0x03;
+const FILTER_IS_NOT_NULL: u8 = 0x04;
+const FILTER_IN: u8 = 0x05;
+const FILTER_EQ: u8 = 0x06;
+const FILTER_AND: u8 = 0x07;
+const FILTER_OR: u8 = 0x08;
+const FILTER_NOT: u8 = 0x09;
+
+// Foreign key on-delete actions
+const ON_DELETE_RESTRICT: u8 = 0x00;
+const ON_DELETE_CASCADE: u8 = 0x01;
+
+// Migration step kinds
+const STEP_ADD_COLUMN: u8 = 0x01;
+const STEP_DROP_COLUMN: u8 = 0x02;
+const STEP_RENAME_COLUMN: u8 = 0x03;
+
+const MATCH_CONTAINS: u8 = 0x01;
+const MATCH_STARTS_WITH: u8 = 0x02;
+const MATCH_ENDS_WITH: u8 = 0x03;
+
+const RANGE_GT: u8 = 0x01;
+const RANGE_GE: u8 = 0x02;
+const RANGE_LT: u8 = 0x03;
+const RANGE_LE: u8 = 0x04;
+
 // Response opcodes
 const RESP_OK: u8 = 0x00;
 const RESP_ERR: u8 = 0x01;
+const RESP_INSERTED: u8 = 0x02;
+const RESP_HANDSHAKE: u8 = 0x03;
+const RESP_CURSOR: u8 = 0x04;
+const RESP_BATCH: u8 = 0x05;
+const RESP_AFFECTED: u8 = 0x06;
+/// Rows response with each column's type after the column names
+const RESP_ROWS_TYPED: u8 = 0x07;
+const RESP_SCHEMA: u8 = 0x08;
+const RESP_CHANGES: u8 = 0x09;
+const RESP_TIMED: u8 = 0x0A;
+
+// Protocol versions. Connections that never send a handshake speak version 1.
+pub const PROTOCOL_VERSION: u16 = 1;
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+pub const DEFAULT_PROTOCOL_VERSION: u16 = 1;
+
+// Handshake feature flags
+const FLAG_COMPRESSION: u8 = 0x01;
+const FLAG_CHECKSUM: u8 = 0x02;
+const SUPPORTED_FLAGS: u8 = FLAG_COMPRESSION | FLAG_CHECKSUM;
+
+// Per-frame encoding marker, only present once compression is negotiated
+const FRAME_RAW: u8 = 0x00;
+const FRAME_DEFLATE: u8 = 0x01;
+
+/// Bounds for the configured frame size. Frames must fit a handshake and an
+/// error message, and are buffered whole in memory.
+const MIN_FRAME_SIZE: usize = 1024;
+const MAX_FRAME_SIZE_LIMIT: usize = 256 * 1024 * 1024;
+const _: () = assert!(
+    config::MAX_FRAME_SIZE >= MIN_FRAME_SIZE && config::MAX_FRAME_SIZE <= MAX_FRAME_SIZE_LIMIT,
+    "MAX_FRAME_SIZE must be between 1 KiB and 256 MiB"
+);
+// Payloads at or below this size are sent raw, compressing them isn't worth it
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Framing options agreed on during the handshake.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameOptions {
+    pub compression: bool,
+    /// Append a CRC32 of the payload to every frame
+    pub checksum: bool,
+    /// Largest payload accepted, after decompression. Not negotiated; each side enforces its own.
+    pub max_frame_size: usize,
+}
+
+impl Default for FrameOptions {
+    fn default() -> Self {
+        Self {
+            compression: false,
+            checksum: false,
+            max_frame_size: config::MAX_FRAME_SIZE,
+        }
+    }
+}
+
+impl FrameOptions {
+    fn from_flags(flags: u8) -> Self {
+        Self {
+            compression: flags & FLAG_COMPRESSION != 0,
+            checksum: flags & FLAG_CHECKSUM != 0,
+            ..Self::default()
+        }
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.compression {
+            flags |= FLAG_COMPRESSION;
+        }
+        if self.checksum {
+            flags |= FLAG_CHECKSUM;
+        }
+        flags
+    }
+}
 
 
 pub struct Cursor<'a> {
@@ -38,6 +202,14 @@ impl<'a> Cursor<'a> {
         Ok(slice)
     }
 
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len().saturating_sub(self.pos)
+    }
+
     fn u8(&mut self) -> anyhow::Result<u8> {
         Ok(self.take(1)?[0])
     }
@@ -46,6 +218,10 @@ impl<'a> Cursor<'a> {
         Ok(u16::from_be_bytes(self.take(2)?.try_into()?))
     }
 
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
     fn u64(&mut self) -> anyhow::Result<u64> {
         Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
     }
@@ -56,8 +232,23 @@ impl<'a> Cursor<'a> {
         Ok(String::from_utf8(bytes.to_vec())?)
     }
 }
+/// How deeply commands may nest inside Explain, Validate and the like. Deeper
+/// frames are rejected before they can exhaust the stack.
+pub const MAX_COMMAND_DEPTH: usize = 32;
+
 pub fn parse_command(buf: &[u8]) -> anyhow::Result<DbCommand> {
-    let mut c = Cursor::new(buf);
+    parse_command_from(&mut Cursor::new(buf), 0)
+}
+
+/// Parses a command wrapped by another one at `depth`.
+fn parse_nested(c: &mut Cursor, depth: usize) -> anyhow::Result<DbCommand> {
+    if depth >= MAX_COMMAND_DEPTH {
+        anyhow::bail!("Command nested deeper than {} levels", MAX_COMMAND_DEPTH);
+    }
+    parse_command_from(c, depth + 1)
+}
+
+fn parse_command_from(c: &mut Cursor, depth: usize) -> anyhow::Result<DbCommand> {
     let opcode = c.u8()?;
 
     match opcode {
@@ -68,16 +259,35 @@ pub fn parse_command(buf: &[u8]) -> anyhow::Result<DbCommand> {
 
             for _ in 0..count {
                 let name = c.string()?;
-                let ty = match c.u8()? {
-                    TYPE_INT => ColumnType::Int,
-                    TYPE_TEXT => ColumnType::Text,
-                    TYPE_BOOL => ColumnType::Bool,
-                    _ => anyhow::bail!("Unknown column type"),
-                };
+                let ty = parse_column_type(c)?;
                 columns.push((name, ty));
             }
 
-            Ok(DbCommand::CreateTable { table, columns })
+            // Older clients end the frame after the columns and declare no keys
+            let mut key = Vec::new();
+            if !c.is_empty() {
+                let key_count = c.u8()? as usize;
+                for _ in 0..key_count {
+                    key.push(c.string()?);
+                }
+            }
+
+            let mut foreign_keys = Vec::new();
+            if !c.is_empty() {
+                let fk_count = c.u8()? as usize;
+                for _ in 0..fk_count {
+                    let column = c.string()?;
+                    let parent = c.string()?;
+                    let on_delete = match c.u8()? {
+                        ON_DELETE_RESTRICT => OnDelete::Restrict,
+                        ON_DELETE_CASCADE => OnDelete::Cascade,
+                        _ => anyhow::bail!("Unknown on-delete action"),
+                    };
+                    foreign_keys.push(ForeignKeyDef { column, parent, on_delete });
+                }
+            }
+
+            Ok(DbCommand::CreateTable { table, columns, key, foreign_keys })
         }
         OP_INSERT_ROW => {
             let table = c.string()?;
@@ -85,10 +295,17 @@ pub fn parse_command(buf: &[u8]) -> anyhow::Result<DbCommand> {
             let mut values = Vec::with_capacity(count);
 
             for _ in 0..count {
-                values.push(parse_value(&mut c)?);
+                values.push(parse_value(c)?);
             }
 
-            Ok(DbCommand::InsertRow { table, values })
+            // Older clients end the frame here and send no key byte
+            let idempotency_key = if c.is_empty() || c.u8()? == 0 {
+                None
+            } else {
+                Some(c.string()?)
+            };
+
+            Ok(DbCommand::InsertRow { table, values, idempotency_key })
         }
         OP_UPDATE_ROW => {
             let table = c.string()?;
@@ -98,72 +315,662 @@ pub fn parse_command(buf: &[u8]) -> anyhow::Result<DbCommand> {
 
             for _ in 0..count {
                 let name = c.string()?;
-                let val = parse_value(&mut c)?;
+                let val = parse_value(c)?;
                 updates.insert(name, val);
             }
 
+            // Older clients end the frame here and send no version byte
+            let expected_version = if c.is_empty() || c.u8()? == 0 {
+                None
+            } else {
+                Some(c.u64()?)
+            };
+
             Ok(DbCommand::UpdateRow {
                 table,
                 row_id,
                 updates,
+                expected_version,
             })
         }
         OP_SELECT_ALL => {
             let table = c.string()?;
-            Ok(DbCommand::SelectAll { table })
+            // Older clients end the frame after the table name, or after the types flag
+            let with_types = !c.is_empty() && c.u8()? != 0;
+            let (limit, cursor) = if c.is_empty() {
+                (None, None)
+            } else {
+                let limit = parse_opt_u32(c)?;
+                let cursor = if c.u8()? == 0 { None } else { Some(c.string()?) };
+                (limit, cursor)
+            };
+            let exclude_metadata = !c.is_empty() && c.u8()? != 0;
+            Ok(DbCommand::SelectAll { table, with_types, limit, cursor, exclude_metadata })
         }
         OP_GET_TABLES => {
             Ok(DbCommand::GetTables {})
         }
+        OP_PING => Ok(DbCommand::Ping {}),
+        OP_SELECT_CURSOR => {
+            let table = c.string()?;
+            Ok(DbCommand::SelectCursor { table })
+        }
+        OP_FETCH => {
+            let cursor_id = c.u64()?;
+            let n = c.u32()?;
+            Ok(DbCommand::Fetch { cursor_id, n })
+        }
+        OP_SELECT_WHERE => {
+            let table = c.string()?;
+            let filter = parse_filter(c)?;
+            let limit = parse_opt_u32(c)?;
+            let offset = parse_opt_u32(c)?;
+            // Older clients end the frame here and never order results
+            let order_by = if c.is_empty() || c.u8()? == 0 {
+                None
+            } else {
+                let column = c.string()?;
+                let descending = c.u8()? != 0;
+                Some(OrderBy { column, descending })
+            };
+            let exclude_metadata = !c.is_empty() && c.u8()? != 0;
+            Ok(DbCommand::SelectWhere { table, filter, limit, offset, order_by, exclude_metadata })
+        }
+        OP_CREATE_TEXT_INDEX => {
+            let table = c.string()?;
+            let column = c.string()?;
+            Ok(DbCommand::CreateTextIndex { table, column })
+        }
+        OP_SEARCH_TEXT => {
+            let table = c.string()?;
+            let column = c.string()?;
+            let query = c.string()?;
+            Ok(DbCommand::SearchText { table, column, query })
+        }
+        OP_EXPLAIN => {
+            let inner = Box::new(parse_nested(c, depth)?);
+            Ok(DbCommand::Explain { inner })
+        }
+        OP_VALIDATE => {
+            let inner = Box::new(parse_nested(c, depth)?);
+            Ok(DbCommand::Validate { inner })
+        }
+        OP_JOIN => {
+            let left = c.string()?;
+            let right = c.string()?;
+            let left_col = c.string()?;
+            let right_col = c.string()?;
+            Ok(DbCommand::Join { left, right, left_col, right_col })
+        }
+        OP_GROUP_COUNT => {
+            let table = c.string()?;
+            let column = c.string()?;
+            Ok(DbCommand::GroupCount { table, column })
+        }
+        OP_BATCH => {
+            let ignore_errors = c.u8()? != 0;
+            let count = c.u16()? as usize;
+            let mut commands = Vec::with_capacity(count);
+
+            for _ in 0..count {
+                commands.push(parse_nested(c, depth)?);
+            }
+
+            Ok(DbCommand::Batch { commands, ignore_errors })
+        }
+        OP_LIST_IDS => {
+            let table = c.string()?;
+            Ok(DbCommand::ListIds { table })
+        }
+        OP_SELECT_BY_IDS => {
+            let table = c.string()?;
+            let ignore_missing = c.u8()? != 0;
+            let count = c.u32()? as usize;
+            // Don't trust the count for preallocation beyond what the frame can hold
+            let mut ids = Vec::with_capacity(count.min(c.remaining() / 8));
+
+            for _ in 0..count {
+                ids.push(c.u64()?);
+            }
+
+            Ok(DbCommand::SelectByIds { table, ids, ignore_missing })
+        }
+        OP_RESET => Ok(DbCommand::Reset {}),
+        OP_SNAPSHOT => Ok(DbCommand::Snapshot {}),
+        OP_SERVER_INFO => Ok(DbCommand::ServerInfo {}),
+        OP_SET => {
+            let key = c.string()?;
+            let value = c.string()?;
+            Ok(DbCommand::Set { key, value })
+        }
+        OP_CREATE_INDEX => {
+            let table = c.string()?;
+            let column = c.string()?;
+            Ok(DbCommand::CreateIndex { table, column })
+        }
+        OP_DELETE_ROW => {
+            let table = c.string()?;
+            let row_id = c.u64()?;
+            Ok(DbCommand::DeleteRow { table, row_id })
+        }
+        OP_MIGRATE => {
+            let table = c.string()?;
+            let count = c.u8()? as usize;
+            let mut steps = Vec::with_capacity(count);
+
+            for _ in 0..count {
+                steps.push(parse_migration_step(c)?);
+            }
+
+            Ok(DbCommand::Migrate { table, steps })
+        }
+        OP_COMPACT => {
+            let table = c.string()?;
+            Ok(DbCommand::Compact { table })
+        }
+        OP_GET_SCHEMA => Ok(DbCommand::GetSchema {}),
+        OP_RESERVE_IDS => {
+            let table = c.string()?;
+            let count = c.u64()?;
+            Ok(DbCommand::ReserveIds { table, count })
+        }
+        OP_INSERT_WITH_ID => {
+            let table = c.string()?;
+            let row_id = c.u64()?;
+            let count = c.u8()? as usize;
+            let mut values = Vec::with_capacity(count);
+
+            for _ in 0..count {
+                values.push(parse_value(c)?);
+            }
+
+            Ok(DbCommand::InsertWithId { table, row_id, values })
+        }
+        OP_COPY_TABLE => {
+            let from = c.string()?;
+            let to = c.string()?;
+            let preserve_ids = c.u8()? != 0;
+            Ok(DbCommand::CopyTable { from, to, preserve_ids })
+        }
+        OP_SCHEMA_HASH => {
+            let table = c.string()?;
+            Ok(DbCommand::SchemaHash { table })
+        }
+        OP_SET_MEMORY_LIMIT => {
+            let table = c.string()?;
+            let max_rows = if c.u8()? == 0 { None } else { Some(c.u64()?) };
+            Ok(DbCommand::SetMemoryLimit { table, max_rows })
+        }
+        OP_COLUMN_HISTOGRAM => {
+            let table = c.string()?;
+            let column = c.string()?;
+            let limit = parse_opt_u32(c)?;
+            Ok(DbCommand::ColumnHistogram { table, column, limit })
+        }
+        OP_INCREMENT => {
+            let table = c.string()?;
+            let row_id = c.u64()?;
+            let column = c.string()?;
+            let by = c.u64()? as i64;
+            Ok(DbCommand::Increment { table, row_id, column, by })
+        }
+        OP_COMPARE_AND_SWAP => {
+            let table = c.string()?;
+            let row_id = c.u64()?;
+            let column = c.string()?;
+            let expected = parse_value(c)?;
+            let new = parse_value(c)?;
+            Ok(DbCommand::CompareAndSwap { table, row_id, column, expected, new })
+        }
+        OP_TAIL => {
+            let table = c.string()?;
+            let n = c.u32()?;
+            Ok(DbCommand::Tail { table, n })
+        }
+        OP_SET_ACL => {
+            let table = c.string()?;
+            let acl = if c.u8()? == 0 {
+                None
+            } else {
+                Some(Acl { readers: parse_principals(c)?, writers: parse_principals(c)? })
+            };
+            Ok(DbCommand::SetAcl { table, acl })
+        }
+        OP_RESET_SEQUENCE => {
+            let table = c.string()?;
+            let start = c.u64()?;
+            Ok(DbCommand::ResetSequence { table, start })
+        }
+        OP_ID_BOUNDS => {
+            let table = c.string()?;
+            Ok(DbCommand::IdBounds { table })
+        }
+        OP_ATOMIC => {
+            let count = c.u16()? as usize;
+            let mut commands = Vec::with_capacity(count);
+            for _ in 0..count {
+                commands.push(parse_nested(c, depth)?);
+            }
+            Ok(DbCommand::Atomic { commands })
+        }
+        OP_EXISTS => {
+            let table = c.string()?;
+            let row_id = if c.u8()? == 0 { None } else { Some(c.u64()?) };
+            Ok(DbCommand::Exists { table, row_id })
+        }
+        OP_CHANGES_SINCE => {
+            let table = c.string()?;
+            let since_version = c.u64()?;
+            Ok(DbCommand::ChangesSince { table, since_version })
+        }
+        OP_CREATE_TABLES => {
+            let count = c.u16()? as usize;
+            let mut tables = Vec::with_capacity(count);
+            for _ in 0..count {
+                let table = c.string()?;
+                let column_count = c.u8()? as usize;
+                let mut columns = Vec::with_capacity(column_count);
+                for _ in 0..column_count {
+                    let name = c.string()?;
+                    columns.push((name, parse_column_type(c)?));
+                }
+                tables.push((table, columns));
+            }
+            Ok(DbCommand::CreateTables { tables })
+        }
+        OP_SAMPLE => {
+            let table = c.string()?;
+            let n = c.u32()?;
+            let seed = if c.u8()? == 0 { None } else { Some(c.u64()?) };
+            Ok(DbCommand::Sample { table, n, seed })
+        }
+        OP_COLUMNS => Ok(DbCommand::Columns { table: c.string()? }),
+        OP_TIMED => {
+            let inner = Box::new(parse_nested(c, depth)?);
+            Ok(DbCommand::Timed { inner })
+        }
+        OP_DEDUP => {
+            let table = c.string()?;
+            let count = c.u8()? as usize;
+            let columns = (0..count).map(|_| c.string()).collect::<anyhow::Result<_>>()?;
+            Ok(DbCommand::Dedup { table, columns })
+        }
+        OP_SWAP_IDS => {
+            let table = c.string()?;
+            let a = c.u64()?;
+            let b = c.u64()?;
+            Ok(DbCommand::SwapIds { table, a, b })
+        }
+        OP_MOVE_ROW => {
+            let from = c.string()?;
+            let to = c.string()?;
+            let row_id = c.u64()?;
+            Ok(DbCommand::MoveRow { from, to, row_id })
+        }
+        OP_VERSION => Ok(DbCommand::Version {}),
+        OP_LIST_INDEXES => Ok(DbCommand::ListIndexes { table: c.string()? }),
         _ => anyhow::bail!("Unknown command opcode"),
     }
 }
 
-pub fn encode_command(cmd: &DbCommand) -> Vec<u8> {
-    let mut buf = Vec::new();
-
+/// Appends the encoded command to `buf`, so callers can reuse one buffer across commands.
+pub fn encode_command_into(buf: &mut Vec<u8>, cmd: &DbCommand) {
     match cmd {
         DbCommand::GetTables {} => {
             buf.push(OP_GET_TABLES);
         }
-        DbCommand::CreateTable { table, columns } => {
+        DbCommand::Ping {} => {
+            buf.push(OP_PING);
+        }
+        DbCommand::Reset {} => {
+            buf.push(OP_RESET);
+        }
+        DbCommand::Snapshot {} => {
+            buf.push(OP_SNAPSHOT);
+        }
+        DbCommand::ServerInfo {} => {
+            buf.push(OP_SERVER_INFO);
+        }
+        DbCommand::Set { key, value } => {
+            buf.push(OP_SET);
+            write_string(buf, key);
+            write_string(buf, value);
+        }
+        DbCommand::CreateIndex { table, column } => {
+            buf.push(OP_CREATE_INDEX);
+            write_string(buf, table);
+            write_string(buf, column);
+        }
+        DbCommand::DeleteRow { table, row_id } => {
+            buf.push(OP_DELETE_ROW);
+            write_string(buf, table);
+            buf.extend_from_slice(&row_id.to_be_bytes());
+        }
+        DbCommand::Migrate { table, steps } => {
+            buf.push(OP_MIGRATE);
+            write_string(buf, table);
+            buf.push(steps.len() as u8);
+            for step in steps {
+                encode_migration_step(buf, step);
+            }
+        }
+        DbCommand::SelectCursor { table } => {
+            buf.push(OP_SELECT_CURSOR);
+            write_string(buf, table);
+        }
+        DbCommand::Fetch { cursor_id, n } => {
+            buf.push(OP_FETCH);
+            buf.extend_from_slice(&cursor_id.to_be_bytes());
+            buf.extend_from_slice(&n.to_be_bytes());
+        }
+        DbCommand::SelectWhere { table, filter, limit, offset, order_by, exclude_metadata } => {
+            buf.push(OP_SELECT_WHERE);
+            write_string(buf, table);
+            encode_filter(buf, filter);
+            write_opt_u32(buf, *limit);
+            write_opt_u32(buf, *offset);
+            match order_by {
+                Some(order) => {
+                    buf.push(1);
+                    write_string(buf, &order.column);
+                    buf.push(if order.descending { 1 } else { 0 });
+                }
+                None => buf.push(0),
+            }
+            buf.push(if *exclude_metadata { 1 } else { 0 });
+        }
+        DbCommand::CreateTextIndex { table, column } => {
+            buf.push(OP_CREATE_TEXT_INDEX);
+            write_string(buf, table);
+            write_string(buf, column);
+        }
+        DbCommand::SearchText { table, column, query } => {
+            buf.push(OP_SEARCH_TEXT);
+            write_string(buf, table);
+            write_string(buf, column);
+            write_string(buf, query);
+        }
+        DbCommand::Explain { inner } => {
+            buf.push(OP_EXPLAIN);
+            encode_command_into(buf, inner);
+        }
+        DbCommand::Validate { inner } => {
+            buf.push(OP_VALIDATE);
+            encode_command_into(buf, inner);
+        }
+        DbCommand::Join { left, right, left_col, right_col } => {
+            buf.push(OP_JOIN);
+            write_string(buf, left);
+            write_string(buf, right);
+            write_string(buf, left_col);
+            write_string(buf, right_col);
+        }
+        DbCommand::GroupCount { table, column } => {
+            buf.push(OP_GROUP_COUNT);
+            write_string(buf, table);
+            write_string(buf, column);
+        }
+        DbCommand::Batch { commands, ignore_errors } => {
+            buf.push(OP_BATCH);
+            buf.push(if *ignore_errors { 1 } else { 0 });
+            buf.extend_from_slice(&(commands.len() as u16).to_be_bytes());
+            for cmd in commands {
+                encode_command_into(buf, cmd);
+            }
+        }
+        DbCommand::SelectByIds { table, ids, ignore_missing } => {
+            buf.push(OP_SELECT_BY_IDS);
+            write_string(buf, table);
+            buf.push(if *ignore_missing { 1 } else { 0 });
+            buf.extend_from_slice(&(ids.len() as u32).to_be_bytes());
+            for id in ids {
+                buf.extend_from_slice(&id.to_be_bytes());
+            }
+        }
+        DbCommand::ListIds { table } => {
+            buf.push(OP_LIST_IDS);
+            write_string(buf, table);
+        }
+        DbCommand::CreateTable { table, columns, key, foreign_keys } => {
             buf.push(OP_CREATE_TABLE);
-            write_string(&mut buf, table);
+            write_string(buf, table);
             buf.push(columns.len() as u8);
             for (name, col_type) in columns {
-                write_string(&mut buf, name);
-                buf.push(match col_type {
-                    ColumnType::Int => TYPE_INT,
-                    ColumnType::Text => TYPE_TEXT,
-                    ColumnType::Bool => TYPE_BOOL,
+                write_string(buf, name);
+                encode_column_type(buf, col_type);
+            }
+            buf.push(key.len() as u8);
+            for name in key {
+                write_string(buf, name);
+            }
+            buf.push(foreign_keys.len() as u8);
+            for fk in foreign_keys {
+                write_string(buf, &fk.column);
+                write_string(buf, &fk.parent);
+                buf.push(match fk.on_delete {
+                    OnDelete::Restrict => ON_DELETE_RESTRICT,
+                    OnDelete::Cascade => ON_DELETE_CASCADE,
                 });
             }
         }
-        DbCommand::InsertRow { table, values } => {
+        DbCommand::InsertRow { table, values, idempotency_key } => {
             buf.push(OP_INSERT_ROW);
-            write_string(&mut buf, table);
+            write_string(buf, table);
             buf.push(values.len() as u8);
             for v in values {
-                encode_value(&mut buf, v);
+                encode_value(buf, v);
+            }
+            match idempotency_key {
+                Some(key) => {
+                    buf.push(1);
+                    write_string(buf, key);
+                }
+                None => buf.push(0),
             }
         }
-        DbCommand::UpdateRow { table, row_id, updates } => {
+        DbCommand::UpdateRow { table, row_id, updates, expected_version } => {
             buf.push(OP_UPDATE_ROW);
-            write_string(&mut buf, table);
+            write_string(buf, table);
             buf.extend_from_slice(&row_id.to_be_bytes());
             buf.push(updates.len() as u8);
             for (col, val) in updates {
-                write_string(&mut buf, col);
-                encode_value(&mut buf, val);
+                write_string(buf, col);
+                encode_value(buf, val);
+            }
+            match expected_version {
+                Some(v) => {
+                    buf.push(1);
+                    buf.extend_from_slice(&v.to_be_bytes());
+                }
+                None => buf.push(0),
             }
         }
-        DbCommand::SelectAll { table } => {
+        DbCommand::SelectAll { table, with_types, limit, cursor, exclude_metadata } => {
             buf.push(OP_SELECT_ALL);
-            write_string(&mut buf, table);
+            write_string(buf, table);
+            buf.push(if *with_types { 1 } else { 0 });
+            write_opt_u32(buf, *limit);
+            match cursor {
+                Some(cursor) => {
+                    buf.push(1);
+                    write_string(buf, cursor);
+                }
+                None => buf.push(0),
+            }
+            buf.push(if *exclude_metadata { 1 } else { 0 });
+        }
+        DbCommand::Compact { table } => {
+            buf.push(OP_COMPACT);
+            write_string(buf, table);
+        }
+        DbCommand::GetSchema {} => {
+            buf.push(OP_GET_SCHEMA);
+        }
+        DbCommand::ReserveIds { table, count } => {
+            buf.push(OP_RESERVE_IDS);
+            write_string(buf, table);
+            buf.extend_from_slice(&count.to_be_bytes());
+        }
+        DbCommand::InsertWithId { table, row_id, values } => {
+            buf.push(OP_INSERT_WITH_ID);
+            write_string(buf, table);
+            buf.extend_from_slice(&row_id.to_be_bytes());
+            buf.push(values.len() as u8);
+            for v in values {
+                encode_value(buf, v);
+            }
+        }
+        DbCommand::CopyTable { from, to, preserve_ids } => {
+            buf.push(OP_COPY_TABLE);
+            write_string(buf, from);
+            write_string(buf, to);
+            buf.push(*preserve_ids as u8);
+        }
+        DbCommand::SchemaHash { table } => {
+            buf.push(OP_SCHEMA_HASH);
+            write_string(buf, table);
+        }
+        DbCommand::SetMemoryLimit { table, max_rows } => {
+            buf.push(OP_SET_MEMORY_LIMIT);
+            write_string(buf, table);
+            match max_rows {
+                Some(max_rows) => {
+                    buf.push(1);
+                    buf.extend_from_slice(&max_rows.to_be_bytes());
+                }
+                None => buf.push(0),
+            }
+        }
+        DbCommand::ColumnHistogram { table, column, limit } => {
+            buf.push(OP_COLUMN_HISTOGRAM);
+            write_string(buf, table);
+            write_string(buf, column);
+            write_opt_u32(buf, *limit);
+        }
+        DbCommand::Increment { table, row_id, column, by } => {
+            buf.push(OP_INCREMENT);
+            write_string(buf, table);
+            buf.extend_from_slice(&row_id.to_be_bytes());
+            write_string(buf, column);
+            buf.extend_from_slice(&by.to_be_bytes());
+        }
+        DbCommand::CompareAndSwap { table, row_id, column, expected, new } => {
+            buf.push(OP_COMPARE_AND_SWAP);
+            write_string(buf, table);
+            buf.extend_from_slice(&row_id.to_be_bytes());
+            write_string(buf, column);
+            encode_value(buf, expected);
+            encode_value(buf, new);
+        }
+        DbCommand::Tail { table, n } => {
+            buf.push(OP_TAIL);
+            write_string(buf, table);
+            buf.extend_from_slice(&n.to_be_bytes());
+        }
+        DbCommand::SetAcl { table, acl } => {
+            buf.push(OP_SET_ACL);
+            write_string(buf, table);
+            match acl {
+                Some(acl) => {
+                    buf.push(1);
+                    write_principals(buf, &acl.readers);
+                    write_principals(buf, &acl.writers);
+                }
+                None => buf.push(0),
+            }
+        }
+        DbCommand::ResetSequence { table, start } => {
+            buf.push(OP_RESET_SEQUENCE);
+            write_string(buf, table);
+            buf.extend_from_slice(&start.to_be_bytes());
+        }
+        DbCommand::IdBounds { table } => {
+            buf.push(OP_ID_BOUNDS);
+            write_string(buf, table);
+        }
+        DbCommand::Atomic { commands } => {
+            buf.push(OP_ATOMIC);
+            buf.extend_from_slice(&(commands.len() as u16).to_be_bytes());
+            for cmd in commands {
+                encode_command_into(buf, cmd);
+            }
+        }
+        DbCommand::Exists { table, row_id } => {
+            buf.push(OP_EXISTS);
+            write_string(buf, table);
+            match row_id {
+                Some(id) => {
+                    buf.push(1);
+                    buf.extend_from_slice(&id.to_be_bytes());
+                }
+                None => buf.push(0),
+            }
+        }
+        DbCommand::ChangesSince { table, since_version } => {
+            buf.push(OP_CHANGES_SINCE);
+            write_string(buf, table);
+            buf.extend_from_slice(&since_version.to_be_bytes());
+        }
+        DbCommand::CreateTables { tables } => {
+            buf.push(OP_CREATE_TABLES);
+            buf.extend_from_slice(&(tables.len() as u16).to_be_bytes());
+            for (table, columns) in tables {
+                write_string(buf, table);
+                buf.push(columns.len() as u8);
+                for (name, col_type) in columns {
+                    write_string(buf, name);
+                    encode_column_type(buf, col_type);
+                }
+            }
+        }
+        DbCommand::Sample { table, n, seed } => {
+            buf.push(OP_SAMPLE);
+            write_string(buf, table);
+            buf.extend_from_slice(&n.to_be_bytes());
+            match seed {
+                Some(seed) => {
+                    buf.push(1);
+                    buf.extend_from_slice(&seed.to_be_bytes());
+                }
+                None => buf.push(0),
+            }
+        }
+        DbCommand::Columns { table } => {
+            buf.push(OP_COLUMNS);
+            write_string(buf, table);
+        }
+        DbCommand::Timed { inner } => {
+            buf.push(OP_TIMED);
+            encode_command_into(buf, inner);
+        }
+        DbCommand::Dedup { table, columns } => {
+            buf.push(OP_DEDUP);
+            write_string(buf, table);
+            buf.push(columns.len() as u8);
+            for column in columns {
+                write_string(buf, column);
+            }
+        }
+        DbCommand::SwapIds { table, a, b } => {
+            buf.push(OP_SWAP_IDS);
+            write_string(buf, table);
+            buf.extend_from_slice(&a.to_be_bytes());
+            buf.extend_from_slice(&b.to_be_bytes());
+        }
+        DbCommand::MoveRow { from, to, row_id } => {
+            buf.push(OP_MOVE_ROW);
+            write_string(buf, from);
+            write_string(buf, to);
+            buf.extend_from_slice(&row_id.to_be_bytes());
+        }
+        DbCommand::Version {} => {
+            buf.push(OP_VERSION);
+        }
+        DbCommand::ListIndexes { table } => {
+            buf.push(OP_LIST_INDEXES);
+            write_string(buf, table);
         }
     }
-
-    buf
 }
 
 pub fn decode_response(data: &[u8]) -> Result<DbResult, String> {
@@ -172,73 +979,179 @@ pub fn decode_response(data: &[u8]) -> Result<DbResult, String> {
     }
 
     match data[0] {
-        RESP_OK => {
+        RESP_OK | RESP_ROWS_TYPED => {
             if data.len() == 1 {
                 return Ok(DbResult::Ok);
             }
 
-            let mut pos = 1;
-            let col_count = data[pos] as usize;
-            pos += 1;
-
-            let mut columns = Vec::with_capacity(col_count);
-            for _ in 0..col_count {
-                let len = u16::from_be_bytes([data[pos], data[pos + 1]]) as usize;
-                pos += 2;
-                columns.push(String::from_utf8_lossy(&data[pos..pos + len]).to_string());
-                pos += len;
-            }
-
-            let row_count = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
-            pos += 4;
-
-            let mut rows = Vec::with_capacity(row_count);
-            for _ in 0..row_count {
-                let row_id = u64::from_be_bytes(data[pos..pos + 8].try_into().unwrap());
-                pos += 8;
-
-                let mut values = Vec::with_capacity(col_count);
-                for _ in 0..col_count {
-                    let val_type = data[pos];
-                    pos += 1;
-
-                    let val = match val_type {
-                        TYPE_INT => {
-                            let i = i64::from_be_bytes(data[pos..pos + 8].try_into().unwrap());
-                            pos += 8;
-                            Value::Int(i)
-                        }
-                        TYPE_TEXT => {
-                            let len = u16::from_be_bytes([data[pos], data[pos + 1]]) as usize;
-                            pos += 2;
-                            let s = String::from_utf8_lossy(&data[pos..pos + len]).to_string();
-                            pos += len;
-                            Value::Text(s)
-                        }
-                        TYPE_BOOL => {
-                            let b = data[pos] != 0;
-                            pos += 1;
-                            Value::Bool(b)
-                        }
-                        _ => return Err("Unknown value type".into()),
-                    };
-                    values.push(val);
+            decode_rows(&mut Cursor::new(&data[1..]), data[0] == RESP_ROWS_TYPED).map_err(|e| e.to_string())
+        }
+        RESP_INSERTED => {
+            if data.len() < 9 {
+                return Err("Truncated insert response".into());
+            }
+            let row_id = u64::from_be_bytes(data[1..9].try_into().unwrap());
+            Ok(DbResult::Inserted { row_id })
+        }
+        RESP_CURSOR => {
+            if data.len() < 9 {
+                return Err("Truncated cursor response".into());
+            }
+            let cursor_id = u64::from_be_bytes(data[1..9].try_into().unwrap());
+            Ok(DbResult::CursorOpened { cursor_id })
+        }
+        RESP_AFFECTED => {
+            if data.len() < 9 {
+                return Err("Truncated affected response".into());
+            }
+            let count = u64::from_be_bytes(data[1..9].try_into().unwrap());
+            Ok(DbResult::Affected(count))
+        }
+        RESP_SCHEMA => decode_schema(&mut Cursor::new(&data[1..])).map_err(|e| e.to_string()),
+        RESP_CHANGES => {
+            let mut c = Cursor::new(&data[1..]);
+            let version = c.u64().map_err(|e| e.to_string())?;
+            let count = c.u32().map_err(|e| e.to_string())? as usize;
+            let deleted = (0..count).map(|_| c.u64()).collect::<anyhow::Result<Vec<_>>>().map_err(|e| e.to_string())?;
+            // The rows follow as a complete rows response
+            if c.u8().map_err(|e| e.to_string())? != RESP_OK {
+                return Err("Malformed changes response".into());
+            }
+            match decode_rows(&mut c, false).map_err(|e| e.to_string())? {
+                DbResult::Rows { columns, rows, truncated, .. } => {
+                    Ok(DbResult::Changes { columns, rows, deleted, version, truncated })
                 }
+                _ => Err("Malformed changes response".into()),
+            }
+        }
+        RESP_TIMED => {
+            // The duration trails the inner result
+            if data.len() < 10 {
+                return Err("Truncated timed response".into());
+            }
+            let (inner, micros) = data[1..].split_at(data.len() - 9);
+            let result = Box::new(decode_response(inner)?);
+            Ok(DbResult::Timed { result, micros: u64::from_be_bytes(micros.try_into().unwrap()) })
+        }
+        RESP_BATCH => {
+            let mut c = Cursor::new(&data[1..]);
+            let count = c.u16().map_err(|e| e.to_string())? as usize;
+            let mut results = Vec::with_capacity(count);
 
-                rows.push((row_id, values));
+            for _ in 0..count {
+                let len = c.u32().map_err(|e| e.to_string())? as usize;
+                let item = c.take(len).map_err(|e| e.to_string())?;
+                results.push(decode_response(item));
             }
 
-            Ok(DbResult::Rows { columns, rows })
-        }
-        RESP_ERR => {
-            let len = u16::from_be_bytes([data[1], data[2]]) as usize;
-            let msg = String::from_utf8_lossy(&data[3..3 + len]).to_string();
-            Err(msg)
+            Ok(DbResult::Batch { results })
         }
+        RESP_ERR => Err(Cursor::new(&data[1..])
+            .string()
+            .unwrap_or_else(|e| format!("Malformed error response: {}", e))),
         _ => Err("Unknown response type".into()),
     }
 }
 
+/// The body of a rows response, after its type byte.
+fn decode_rows(c: &mut Cursor, typed: bool) -> anyhow::Result<DbResult> {
+    let col_count = c.u8()? as usize;
+    let columns = (0..col_count).map(|_| c.string()).collect::<anyhow::Result<Vec<_>>>()?;
+    let column_types = if typed {
+        Some((0..col_count).map(|_| parse_column_type(c)).collect::<anyhow::Result<Vec<_>>>()?)
+    } else {
+        None
+    };
+
+    let row_count = c.u32()? as usize;
+    // Every row takes at least its id, so a bogus count can't reserve much
+    let mut rows = Vec::with_capacity(row_count.min(c.remaining() / 8));
+    for _ in 0..row_count {
+        let row_id = c.u64()?;
+        let values = (0..col_count).map(|_| parse_value(c)).collect::<anyhow::Result<Vec<_>>>()?;
+        rows.push((row_id, values));
+    }
+
+    // Older servers don't send the truncation flag or the cursor
+    let truncated = !c.is_empty() && c.u8()? != 0;
+    let next_cursor = if !c.is_empty() && c.u8()? == 1 { Some(c.string()?) } else { None };
+
+    Ok(DbResult::Rows { columns, rows, truncated, column_types, next_cursor })
+}
+
+fn decode_schema(c: &mut Cursor) -> anyhow::Result<DbResult> {
+    let count = c.u16()? as usize;
+    let mut tables = Vec::with_capacity(count);
+    for _ in 0..count {
+        let name = c.string()?;
+        let schema_version = c.u64()?;
+        let col_count = c.u8()? as usize;
+        let mut columns = Vec::with_capacity(col_count);
+        for _ in 0..col_count {
+            let name = c.string()?;
+            let col_type = parse_column_type(c)?;
+            columns.push(Column { name, col_type });
+        }
+        tables.push(TableSchema { name, schema_version, columns });
+    }
+    Ok(DbResult::Schema { tables })
+}
+
+fn parse_column_type(c: &mut Cursor) -> anyhow::Result<ColumnType> {
+    match c.u8()? {
+        TYPE_INT => Ok(ColumnType::Int),
+        TYPE_TEXT => Ok(ColumnType::Text),
+        TYPE_BOOL => Ok(ColumnType::Bool),
+        _ => anyhow::bail!("Unknown column type"),
+    }
+}
+
+fn encode_column_type(buf: &mut Vec<u8>, col_type: &ColumnType) {
+    buf.push(match col_type {
+        ColumnType::Int => TYPE_INT,
+        ColumnType::Text => TYPE_TEXT,
+        ColumnType::Bool => TYPE_BOOL,
+    });
+}
+
+fn parse_migration_step(c: &mut Cursor) -> anyhow::Result<MigrationStep> {
+    match c.u8()? {
+        STEP_ADD_COLUMN => {
+            let name = c.string()?;
+            let col_type = parse_column_type(c)?;
+            let default = parse_value(c)?;
+            Ok(MigrationStep::Add { name, col_type, default })
+        }
+        STEP_DROP_COLUMN => Ok(MigrationStep::Drop { name: c.string()? }),
+        STEP_RENAME_COLUMN => {
+            let from = c.string()?;
+            let to = c.string()?;
+            Ok(MigrationStep::Rename { from, to })
+        }
+        _ => anyhow::bail!("Unknown migration step"),
+    }
+}
+
+fn encode_migration_step(buf: &mut Vec<u8>, step: &MigrationStep) {
+    match step {
+        MigrationStep::Add { name, col_type, default } => {
+            buf.push(STEP_ADD_COLUMN);
+            write_string(buf, name);
+            encode_column_type(buf, col_type);
+            encode_value(buf, default);
+        }
+        MigrationStep::Drop { name } => {
+            buf.push(STEP_DROP_COLUMN);
+            write_string(buf, name);
+        }
+        MigrationStep::Rename { from, to } => {
+            buf.push(STEP_RENAME_COLUMN);
+            write_string(buf, from);
+            write_string(buf, to);
+        }
+    }
+}
+
 fn parse_value(c: &mut Cursor) -> anyhow::Result<Value> {
     match c.u8()? {
         TYPE_INT => Ok(Value::Int(c.u64()? as i64)),
@@ -248,44 +1161,435 @@ fn parse_value(c: &mut Cursor) -> anyhow::Result<Value> {
     }
 }
 
+fn parse_filter(c: &mut Cursor) -> anyhow::Result<Filter> {
+    parse_filter_at(c, 0)
+}
+
+fn parse_filter_at(c: &mut Cursor, depth: usize) -> anyhow::Result<Filter> {
+    match c.u8()? {
+        FILTER_TEXT => {
+            let column = c.string()?;
+            let mode = match c.u8()? {
+                MATCH_CONTAINS => TextMatch::Contains,
+                MATCH_STARTS_WITH => TextMatch::StartsWith,
+                MATCH_ENDS_WITH => TextMatch::EndsWith,
+                _ => anyhow::bail!("Unknown text match mode"),
+            };
+            let pattern = c.string()?;
+            let case_insensitive = c.u8()? != 0;
+            Ok(Filter::Text { column, mode, pattern, case_insensitive })
+        }
+        FILTER_RANGE => {
+            let column = c.string()?;
+            let op = match c.u8()? {
+                RANGE_GT => RangeOp::Gt,
+                RANGE_GE => RangeOp::Ge,
+                RANGE_LT => RangeOp::Lt,
+                RANGE_LE => RangeOp::Le,
+                _ => anyhow::bail!("Unknown range operator"),
+            };
+            let value = c.u64()? as i64;
+            Ok(Filter::Range { column, op, value })
+        }
+        FILTER_IS_NULL => Ok(Filter::IsNull { column: c.string()? }),
+        FILTER_IS_NOT_NULL => Ok(Filter::IsNotNull { column: c.string()? }),
+        FILTER_IN => {
+            let column = c.string()?;
+            let count = c.u16()? as usize;
+            let values = (0..count).map(|_| parse_value(c)).collect::<anyhow::Result<_>>()?;
+            Ok(Filter::In { column, values })
+        }
+        FILTER_EQ => {
+            let column = c.string()?;
+            let value = parse_value(c)?;
+            Ok(Filter::Eq { column, value })
+        }
+        kind @ (FILTER_AND | FILTER_OR) => {
+            filter::check_depth(depth).map_err(anyhow::Error::msg)?;
+            let count = c.u16()? as usize;
+            let filters = (0..count).map(|_| parse_filter_at(c, depth + 1)).collect::<anyhow::Result<_>>()?;
+            Ok(if kind == FILTER_AND { Filter::And { filters } } else { Filter::Or { filters } })
+        }
+        FILTER_NOT => {
+            filter::check_depth(depth).map_err(anyhow::Error::msg)?;
+            Ok(Filter::Not { filter: Box::new(parse_filter_at(c, depth + 1)?) })
+        }
+        _ => anyhow::bail!("Unknown filter type"),
+    }
+}
+
+fn encode_filter(buf: &mut Vec<u8>, filter: &Filter) {
+    match filter {
+        Filter::Text { column, mode, pattern, case_insensitive } => {
+            buf.push(FILTER_TEXT);
+            write_string(buf, column);
+            buf.push(match mode {
+                TextMatch::Contains => MATCH_CONTAINS,
+                TextMatch::StartsWith => MATCH_STARTS_WITH,
+                TextMatch::EndsWith => MATCH_ENDS_WITH,
+            });
+            write_string(buf, pattern);
+            buf.push(if *case_insensitive { 1 } else { 0 });
+        }
+        Filter::Range { column, op, value } => {
+            buf.push(FILTER_RANGE);
+            write_string(buf, column);
+            buf.push(match op {
+                RangeOp::Gt => RANGE_GT,
+                RangeOp::Ge => RANGE_GE,
+                RangeOp::Lt => RANGE_LT,
+                RangeOp::Le => RANGE_LE,
+            });
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+        Filter::IsNull { column } => {
+            buf.push(FILTER_IS_NULL);
+            write_string(buf, column);
+        }
+        Filter::IsNotNull { column } => {
+            buf.push(FILTER_IS_NOT_NULL);
+            write_string(buf, column);
+        }
+        Filter::In { column, values } => {
+            buf.push(FILTER_IN);
+            write_string(buf, column);
+            buf.extend_from_slice(&(values.len() as u16).to_be_bytes());
+            for value in values {
+                encode_value(buf, value);
+            }
+        }
+        Filter::Eq { column, value } => {
+            buf.push(FILTER_EQ);
+            write_string(buf, column);
+            encode_value(buf, value);
+        }
+        Filter::And { filters } | Filter::Or { filters } => {
+            buf.push(if matches!(filter, Filter::And { .. }) { FILTER_AND } else { FILTER_OR });
+            buf.extend_from_slice(&(filters.len() as u16).to_be_bytes());
+            for f in filters {
+                encode_filter(buf, f);
+            }
+        }
+        Filter::Not { filter } => {
+            buf.push(FILTER_NOT);
+            encode_filter(buf, filter);
+        }
+    }
+}
+
+fn parse_opt_u32(c: &mut Cursor) -> anyhow::Result<Option<u32>> {
+    match c.u8()? {
+        0 => Ok(None),
+        _ => Ok(Some(c.u32()?)),
+    }
+}
+
+fn write_opt_u32(buf: &mut Vec<u8>, v: Option<u32>) {
+    match v {
+        Some(v) => {
+            buf.push(1);
+            buf.extend_from_slice(&v.to_be_bytes());
+        }
+        None => buf.push(0),
+    }
+}
+
 pub fn encode_result(result: &DbResult) -> Vec<u8> {
+    let mut buf = Vec::new();
+    encode_result_into(&mut buf, result);
+    buf
+}
+
+/// Appends the encoded result to `buf`, so callers can reuse one buffer across responses.
+pub fn encode_result_into(buf: &mut Vec<u8>, result: &DbResult) {
     match result {
-        DbResult::Ok => vec![RESP_OK],
-        DbResult::Rows { columns, rows } => encode_rows(columns, rows),
+        DbResult::Ok => buf.push(RESP_OK),
+        DbResult::Rows { columns, rows, truncated, column_types, next_cursor } => encode_rows_into(
+            buf,
+            columns,
+            column_types.as_deref(),
+            rows.len(),
+            rows.iter().map(|(id, values)| (*id, values)),
+            *truncated,
+            next_cursor.as_deref(),
+        ),
+        DbResult::Inserted { row_id } => {
+            buf.push(RESP_INSERTED);
+            buf.extend_from_slice(&row_id.to_be_bytes());
+        }
+        DbResult::CursorOpened { cursor_id } => {
+            buf.push(RESP_CURSOR);
+            buf.extend_from_slice(&cursor_id.to_be_bytes());
+        }
+        DbResult::Affected(count) => {
+            buf.push(RESP_AFFECTED);
+            buf.extend_from_slice(&count.to_be_bytes());
+        }
+        DbResult::Schema { tables } => {
+            buf.push(RESP_SCHEMA);
+            buf.extend_from_slice(&(tables.len() as u16).to_be_bytes());
+            for table in tables {
+                write_string(buf, &table.name);
+                buf.extend_from_slice(&table.schema_version.to_be_bytes());
+                buf.push(table.columns.len() as u8);
+                for column in &table.columns {
+                    write_string(buf, &column.name);
+                    encode_column_type(buf, &column.col_type);
+                }
+            }
+        }
+        DbResult::Changes { columns, rows, deleted, version, truncated } => {
+            buf.push(RESP_CHANGES);
+            buf.extend_from_slice(&version.to_be_bytes());
+            buf.extend_from_slice(&(deleted.len() as u32).to_be_bytes());
+            for id in deleted {
+                buf.extend_from_slice(&id.to_be_bytes());
+            }
+            encode_rows_into(buf, columns, None, rows.len(), rows.iter().map(|(id, values)| (*id, values)), *truncated, None);
+        }
+        DbResult::Timed { result, micros } => {
+            buf.push(RESP_TIMED);
+            encode_result_into(buf, result);
+            buf.extend_from_slice(&micros.to_be_bytes());
+        }
+        DbResult::Batch { results } => {
+            buf.push(RESP_BATCH);
+            buf.extend_from_slice(&(results.len() as u16).to_be_bytes());
+            for result in results {
+                // Length prefix is patched in once the item is encoded
+                let len_pos = buf.len();
+                buf.extend_from_slice(&[0; 4]);
+                match result {
+                    Ok(r) => encode_result_into(buf, r),
+                    Err(e) => encode_error_into(buf, e),
+                }
+                let len = (buf.len() - len_pos - 4) as u32;
+                buf[len_pos..len_pos + 4].copy_from_slice(&len.to_be_bytes());
+            }
+        }
     }
 }
 
 pub fn encode_error(msg: &str) -> Vec<u8> {
-    let mut buf = vec![RESP_ERR];
+    let mut buf = Vec::new();
+    encode_error_into(&mut buf, msg);
+    buf
+}
+
+pub fn encode_error_into(buf: &mut Vec<u8>, msg: &str) {
+    buf.push(RESP_ERR);
     let bytes = msg.as_bytes();
     buf.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
     buf.extend_from_slice(bytes);
+}
+
+/// Returns `None` when the frame is not a handshake, so callers can treat it as a command.
+/// The feature flags byte is optional; version-only handshakes request no features.
+pub fn parse_handshake(buf: &[u8]) -> Option<anyhow::Result<(u16, FrameOptions)>> {
+    if buf.first() != Some(&OP_HANDSHAKE) {
+        return None;
+    }
+    Some(read_handshake(&mut Cursor::new(&buf[1..])))
+}
+
+fn read_handshake(c: &mut Cursor) -> anyhow::Result<(u16, FrameOptions)> {
+    let version = c.u16()?;
+    let flags = if c.is_empty() { 0 } else { c.u8()? };
+    Ok((version, FrameOptions::from_flags(flags)))
+}
+
+/// Returns the key and value of a Set frame, which the connection handles itself.
+pub fn parse_set(buf: &[u8]) -> Option<anyhow::Result<(String, String)>> {
+    if buf.first() != Some(&OP_SET) {
+        return None;
+    }
+    Some(read_set(&mut Cursor::new(&buf[1..])))
+}
+
+fn read_set(c: &mut Cursor) -> anyhow::Result<(String, String)> {
+    let key = c.string()?;
+    let value = c.string()?;
+    Ok((key, value))
+}
+
+/// A follower opens its connection with this frame, carrying the primary's
+/// replication token, to receive the primary's writes.
+pub fn encode_replicate_request(token: &str) -> Vec<u8> {
+    let mut buf = vec![OP_REPLICATE];
+    buf.extend_from_slice(token.as_bytes());
     buf
 }
 
-fn encode_rows(
+/// Returns the token of a replicate request, or `None` for any other frame.
+pub fn parse_replicate_request(buf: &[u8]) -> Option<&[u8]> {
+    buf.strip_prefix(&[OP_REPLICATE])
+}
+
+/// An admin sends this frame to turn its connection into a stream of log events.
+pub fn encode_subscribe_logs_request() -> Vec<u8> {
+    vec![OP_SUBSCRIBE_LOGS]
+}
+
+pub fn is_subscribe_logs_request(buf: &[u8]) -> bool {
+    buf == [OP_SUBSCRIBE_LOGS]
+}
+
+/// Whether the frame is one of the connection-level requests above, which
+/// change what a connection is rather than running a command on it.
+pub fn is_connection_request(buf: &[u8]) -> bool {
+    matches!(buf.first(), Some(&(OP_HANDSHAKE | OP_REPLICATE | OP_SUBSCRIBE_LOGS)))
+}
+
+pub fn negotiate(requested: u16, options: FrameOptions) -> Result<(u16, FrameOptions), String> {
+    if requested < MIN_PROTOCOL_VERSION {
+        return Err(format!(
+            "Unsupported protocol version {} (server supports {}-{})",
+            requested, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    let flags = options.flags() & SUPPORTED_FLAGS;
+    Ok((requested.min(PROTOCOL_VERSION), FrameOptions::from_flags(flags)))
+}
+
+pub fn encode_handshake(version: u16, options: FrameOptions) -> Vec<u8> {
+    let mut buf = vec![OP_HANDSHAKE];
+    buf.extend_from_slice(&version.to_be_bytes());
+    buf.push(options.flags());
+    buf
+}
+
+pub fn encode_handshake_response(version: u16, options: FrameOptions) -> Vec<u8> {
+    let mut buf = vec![RESP_HANDSHAKE];
+    buf.extend_from_slice(&version.to_be_bytes());
+    buf.push(options.flags());
+    buf
+}
+
+pub fn decode_handshake_response(data: &[u8]) -> Result<(u16, FrameOptions), String> {
+    match data.first() {
+        Some(&RESP_HANDSHAKE) if data.len() >= 3 => {
+            let version = u16::from_be_bytes([data[1], data[2]]);
+            let flags = data.get(3).copied().unwrap_or(0);
+            Ok((version, FrameOptions::from_flags(flags)))
+        }
+        Some(&RESP_ERR) => match decode_response(data) {
+            Err(e) => Err(e),
+            Ok(_) => Err("Invalid handshake response".into()),
+        },
+        _ => Err("Invalid handshake response".into()),
+    }
+}
+
+pub fn encode_rows_into<R, V>(
+    buf: &mut Vec<u8>,
     columns: &[String],
-    rows: &[(u64, Vec<Value>)],
-) -> Vec<u8> {
-    let mut buf = vec![RESP_OK];
+    column_types: Option<&[ColumnType]>,
+    row_count: usize,
+    rows: impl Iterator<Item = (u64, R)>,
+    truncated: bool,
+    next_cursor: Option<&str>,
+)
+where
+    R: IntoIterator<Item = V>,
+    V: Borrow<Value>,
+{
+    buf.push(if column_types.is_some() { RESP_ROWS_TYPED } else { RESP_OK });
 
     buf.push(columns.len() as u8);
     for c in columns {
-        write_string(&mut buf, c);
+        write_string(buf, c);
+    }
+    for t in column_types.unwrap_or_default() {
+        encode_column_type(buf, t);
     }
 
-    buf.extend_from_slice(&(rows.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&(row_count as u32).to_be_bytes());
 
     for (row_id, values) in rows {
         buf.extend_from_slice(&row_id.to_be_bytes());
         for v in values {
-            encode_value(&mut buf, v);
+            encode_value(buf, v.borrow());
         }
     }
 
-    buf
+    buf.push(if truncated { 1 } else { 0 });
+    match next_cursor {
+        Some(cursor) => {
+            buf.push(1);
+            write_string(buf, cursor);
+        }
+        None => buf.push(0),
+    }
 }
 
+/// Encodes a whole table as a rows response straight from storage, without
+/// cloning it into a `DbResult` first. Produces the same bytes as encoding
+/// the result of `Database::select_all`.
+pub fn encode_table(
+    table: &Table,
+    max_rows: usize,
+    with_types: bool,
+    limit: Option<u32>,
+    cursor: Option<&str>,
+    metadata: bool,
+) -> Result<Vec<u8>, String> {
+    let (ids, truncated, next_cursor) = commands::select_page(table, cursor, limit, max_rows)?;
+
+    // Read up front so a spilled row that can't be read fails the whole response
+    let rows = ids
+        .iter()
+        .map(|id| {
+            let version = table.versions.get(id).copied().unwrap_or(1);
+            let metadata_values = metadata.then_some(Cow::Owned(Value::Int(version as i64)));
+            Ok((*id, table.rows.row_values(id)?.chain(metadata_values)))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let mut buf = Vec::new();
+    let mut columns = table.result_columns();
+    let mut column_types = with_types.then(|| table.result_column_types());
+    if !metadata {
+        columns.truncate(table.columns.len());
+        if let Some(types) = &mut column_types {
+            types.truncate(table.columns.len());
+        }
+    }
+    encode_rows_into(
+        &mut buf,
+        &columns,
+        column_types.as_deref(),
+        ids.len(),
+        rows.into_iter(),
+        truncated,
+        next_cursor.as_deref(),
+    );
+    Ok(buf)
+}
+
+/// Page cursors are opaque to clients; they hold the last row id of the page.
+pub fn encode_page_cursor(last_id: u64) -> String {
+    format!("{:016x}", last_id)
+}
+
+pub fn decode_page_cursor(cursor: &str) -> Result<u64, String> {
+    if cursor.len() != 16 {
+        return Err("Invalid cursor".into());
+    }
+    u64::from_str_radix(cursor, 16).map_err(|_| "Invalid cursor".to_string())
+}
+
+
+fn parse_principals(c: &mut Cursor) -> anyhow::Result<HashSet<String>> {
+    let count = c.u16()?;
+    (0..count).map(|_| c.string()).collect()
+}
+
+fn write_principals(buf: &mut Vec<u8>, principals: &HashSet<String>) {
+    buf.extend_from_slice(&(principals.len() as u16).to_be_bytes());
+    for p in principals {
+        write_string(buf, p);
+    }
+}
 
 fn write_string(buf: &mut Vec<u8>, s: &str) {
     let bytes = s.as_bytes();
@@ -310,7 +1614,14 @@ fn encode_value(buf: &mut Vec<u8>, v: &Value) {
 }
 
 
-pub async fn read_frame(stream: &mut TcpStream) -> std::io::Result<Option<Vec<u8>>> {
+pub async fn read_frame(stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Option<Vec<u8>>> {
+    read_frame_with(stream, FrameOptions::default()).await
+}
+
+pub async fn read_frame_with(
+    stream: &mut (impl AsyncRead + Unpin),
+    options: FrameOptions,
+) -> std::io::Result<Option<Vec<u8>>> {
     let mut len_buf = [0u8; 4];
 
     if stream.read_exact(&mut len_buf).await.is_err() {
@@ -319,19 +1630,485 @@ pub async fn read_frame(stream: &mut TcpStream) -> std::io::Result<Option<Vec<u8
 
     let len = u32::from_be_bytes(len_buf) as usize;
 
-    if len > 1024 * 1024 {
-        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Frame too large"));
+    if len > options.max_frame_size {
+        return Err(invalid_data("Frame too large"));
     }
 
     let mut data = vec![0u8; len];
     stream.read_exact(&mut data).await?;
 
+    if options.checksum {
+        if data.len() < 4 {
+            return Err(invalid_data("Frame too short for checksum"));
+        }
+        let crc_bytes = data.split_off(data.len() - 4);
+        let expected = u32::from_be_bytes(crc_bytes.try_into().unwrap());
+        if crc32fast::hash(&data) != expected {
+            return Err(invalid_data("Frame checksum mismatch"));
+        }
+    }
+
+    if options.compression {
+        data = unwrap_compressed(&data, options.max_frame_size)?;
+    }
+
     Ok(Some(data))
 }
 
-pub async fn write_frame(stream: &mut TcpStream, data: &[u8]) -> std::io::Result<()> {
-    let len = (data.len() as u32).to_be_bytes();
-    stream.write_all(&len).await?;
-    stream.write_all(data).await?;
+pub async fn write_frame(stream: &mut (impl AsyncWrite + Unpin), data: &[u8]) -> std::io::Result<()> {
+    write_frame_with(stream, data, FrameOptions::default()).await
+}
+
+pub async fn write_frame_with(
+    stream: &mut (impl AsyncWrite + Unpin),
+    data: &[u8],
+    options: FrameOptions,
+) -> std::io::Result<()> {
+    write_frame_buffered(stream, data, options, &mut Vec::new()).await
+}
+
+/// Assembles the whole frame in `scratch` and writes it in one call. Long-lived
+/// connections pass the same buffer every time so its allocation is reused.
+pub async fn write_frame_buffered(
+    stream: &mut (impl AsyncWrite + Unpin),
+    data: &[u8],
+    options: FrameOptions,
+    scratch: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    scratch.clear();
+    scratch.extend_from_slice(&[0; 4]);
+
+    if options.compression {
+        write_compressed(scratch, data)?;
+    } else {
+        scratch.extend_from_slice(data);
+    }
+
+    if options.checksum {
+        let crc = crc32fast::hash(&scratch[4..]);
+        scratch.extend_from_slice(&crc.to_be_bytes());
+    }
+
+    let len = (scratch.len() - 4) as u32;
+    scratch[..4].copy_from_slice(&len.to_be_bytes());
+    stream.write_all(scratch).await
+}
+
+fn write_compressed(buf: &mut Vec<u8>, data: &[u8]) -> std::io::Result<()> {
+    if data.len() <= COMPRESSION_THRESHOLD {
+        buf.push(FRAME_RAW);
+        buf.extend_from_slice(data);
+        return Ok(());
+    }
+
+    buf.push(FRAME_DEFLATE);
+    let mut encoder = DeflateEncoder::new(buf, Compression::fast());
+    encoder.write_all(data)?;
+    encoder.finish()?;
     Ok(())
 }
+
+fn unwrap_compressed(data: &[u8], max_frame_size: usize) -> std::io::Result<Vec<u8>> {
+    match data.first() {
+        Some(&FRAME_RAW) => Ok(data[1..].to_vec()),
+        Some(&FRAME_DEFLATE) => {
+            let mut out = Vec::new();
+            // Cap the inflated size so a small frame can't expand without bound
+            DeflateDecoder::new(&data[1..])
+                .take(max_frame_size as u64 + 1)
+                .read_to_end(&mut out)?;
+            if out.len() > max_frame_size {
+                return Err(invalid_data("Frame too large"));
+            }
+            Ok(out)
+        }
+        _ => Err(invalid_data("Unknown frame encoding")),
+    }
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+
+    fn typed_rows() -> DbResult {
+        DbResult::Rows {
+            columns: vec!["n".into(), "s".into(), "b".into()],
+            rows: vec![(1, vec![Value::Int(-7), Value::Text("hi".into()), Value::Bool(true)])],
+            truncated: true,
+            column_types: Some(vec![ColumnType::Int, ColumnType::Text, ColumnType::Bool]),
+            next_cursor: Some("2".into()),
+        }
+    }
+
+    #[test]
+    fn typed_rows_round_trip() {
+        let encoded = encode_result(&typed_rows());
+        assert_eq!(encoded[0], RESP_ROWS_TYPED);
+        let decoded = decode_response(&encoded).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", typed_rows()));
+    }
+
+    #[test]
+    fn truncated_responses_are_errors_not_panics() {
+        let encoded = encode_result(&typed_rows());
+        // Cut anywhere before the end of the last row
+        let rows_end = encoded.len() - 1 - 1 - 3;
+        for len in 2..rows_end {
+            assert!(decode_response(&encoded[..len]).is_err(), "prefix of {} bytes", len);
+        }
+        assert!(decode_response(&[RESP_ERR, 0]).is_err());
+    }
+
+    #[test]
+    fn changes_round_trip_and_reject_a_missing_rows_part() {
+        let changes = DbResult::Changes {
+            columns: vec!["a".into()],
+            rows: vec![(4, vec![Value::Int(1)])],
+            deleted: vec![2, 3],
+            version: 9,
+            truncated: false,
+        };
+        let encoded = encode_result(&changes);
+        assert_eq!(format!("{:?}", decode_response(&encoded).unwrap()), format!("{:?}", changes));
+        // Header and deleted ids only, with no rows response after them
+        let header = 1 + 8 + 4 + 2 * 8;
+        assert!(decode_response(&encoded[..header]).is_err());
+    }
+
+    #[test]
+    fn deeply_nested_explain_is_rejected() {
+        let mut frame = vec![OP_EXPLAIN; 1_000_000];
+        frame.push(OP_GET_TABLES);
+        let err = parse_command(&frame).unwrap_err();
+        assert!(err.to_string().contains("nested deeper"), "{}", err);
+    }
+
+    #[test]
+    fn deeply_nested_batch_is_rejected() {
+        let mut frame = Vec::new();
+        for _ in 0..100_000 {
+            frame.extend_from_slice(&[OP_BATCH, 0, 0, 1]);
+        }
+        frame.push(OP_GET_TABLES);
+        let err = parse_command(&frame).unwrap_err();
+        assert!(err.to_string().contains("nested deeper"), "{}", err);
+    }
+
+    #[test]
+    fn deeply_nested_atomic_is_rejected() {
+        let mut frame = Vec::new();
+        for _ in 0..100_000 {
+            frame.extend_from_slice(&[OP_ATOMIC, 0, 1]);
+        }
+        frame.push(OP_GET_TABLES);
+        let err = parse_command(&frame).unwrap_err();
+        assert!(err.to_string().contains("nested deeper"), "{}", err);
+    }
+
+    #[test]
+    fn deeply_nested_timed_is_rejected() {
+        let mut frame = vec![OP_TIMED; 1_000_000];
+        frame.push(OP_GET_TABLES);
+        let err = parse_command(&frame).unwrap_err();
+        assert!(err.to_string().contains("nested deeper"), "{}", err);
+    }
+
+    #[test]
+    fn nesting_up_to_the_limit_parses() {
+        let mut frame = vec![OP_VALIDATE; MAX_COMMAND_DEPTH];
+        frame.push(OP_GET_TABLES);
+        assert!(parse_command(&frame).is_ok());
+        frame.insert(0, OP_EXPLAIN);
+        assert!(parse_command(&frame).is_err());
+    }
+
+    #[test]
+    fn inserted_row_ids_round_trip() {
+        for row_id in [1, 2, u64::MAX] {
+            let decoded = decode_response(&encode_result(&DbResult::Inserted { row_id })).unwrap();
+            assert!(matches!(decoded, DbResult::Inserted { row_id: id } if id == row_id));
+        }
+    }
+
+    #[test]
+    fn ping_round_trips_to_an_ok() {
+        let mut frame = Vec::new();
+        encode_command_into(&mut frame, &DbCommand::Ping {});
+        assert_eq!(frame, [OP_PING]);
+        let cmd = parse_command(&frame).unwrap();
+        assert!(matches!(cmd, DbCommand::Ping {}));
+        assert!(!cmd.is_write());
+
+        let response = encode_result(&Database::default().execute(cmd).unwrap());
+        assert_eq!(response, [RESP_OK]);
+        assert!(matches!(decode_response(&response), Ok(DbResult::Ok)));
+    }
+
+    #[test]
+    fn handshake_agrees_on_a_supported_version() {
+        let frame = encode_handshake(PROTOCOL_VERSION, FrameOptions::default());
+        let (requested, options) = parse_handshake(&frame).unwrap().unwrap();
+        let (version, agreed) = negotiate(requested, options).unwrap();
+        assert_eq!(version, PROTOCOL_VERSION);
+        let response = encode_handshake_response(version, agreed);
+        let (decoded_version, decoded) = decode_handshake_response(&response).unwrap();
+        assert_eq!((decoded_version, decoded.flags()), (version, agreed.flags()));
+
+        // A newer client is answered with the newest version the server speaks
+        assert_eq!(negotiate(PROTOCOL_VERSION + 1, FrameOptions::default()).unwrap().0, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn handshake_rejects_an_unsupported_version() {
+        let error = negotiate(MIN_PROTOCOL_VERSION - 1, FrameOptions::default()).unwrap_err();
+        assert!(error.starts_with("Unsupported protocol version 0"), "{}", error);
+        assert_eq!(decode_handshake_response(&encode_error(&error)).unwrap_err(), error);
+    }
+
+    #[test]
+    fn commands_are_not_handshakes() {
+        let mut frame = Vec::new();
+        encode_command_into(&mut frame, &DbCommand::GetTables {});
+        assert!(parse_handshake(&frame).is_none());
+        // Version-only handshakes from older clients request no features
+        let (version, options) = parse_handshake(&[OP_HANDSHAKE, 0, 1]).unwrap().unwrap();
+        assert_eq!((version, options.flags()), (1, 0));
+    }
+
+    async fn frame_round_trip(payload: &[u8], options: FrameOptions) -> Vec<u8> {
+        let mut wire = Vec::new();
+        write_frame_with(&mut wire, payload, options).await.unwrap();
+        let read = read_frame_with(&mut wire.as_slice(), options).await.unwrap().unwrap();
+        assert_eq!(read, payload);
+        wire
+    }
+
+    #[tokio::test]
+    async fn large_frames_are_compressed_and_small_ones_are_not() {
+        let options = FrameOptions { compression: true, ..FrameOptions::default() };
+        let rows = (1..=1000).map(|id| (id, vec![Value::Int(id as i64), Value::Text("some repeated text".into())]));
+        let result = DbResult::Rows {
+            columns: vec!["n".into(), "s".into()],
+            rows: rows.collect(),
+            truncated: false,
+            column_types: None,
+            next_cursor: None,
+        };
+        let large = encode_result(&result);
+        let wire = frame_round_trip(&large, options).await;
+        assert_eq!(wire[4], FRAME_DEFLATE);
+        assert!(wire.len() < large.len() / 2);
+        assert_eq!(format!("{:?}", decode_response(&large).unwrap()), format!("{:?}", result));
+
+        let small = encode_result(&DbResult::Affected(1));
+        let wire = frame_round_trip(&small, options).await;
+        assert_eq!(wire[4], FRAME_RAW);
+        assert_eq!(&wire[5..], small);
+    }
+
+    #[tokio::test]
+    async fn checksums_catch_a_flipped_byte() {
+        let options = FrameOptions { checksum: true, ..FrameOptions::default() };
+        let payload = encode_result(&DbResult::Affected(7));
+        let mut wire = frame_round_trip(&payload, options).await;
+
+        let last = wire.len() - 5;
+        wire[last] ^= 0x01;
+        let error = read_frame_with(&mut wire.as_slice(), options).await.unwrap_err();
+        assert_eq!(error.to_string(), "Frame checksum mismatch");
+
+        // Without the checksum flag the trailing CRC would just be payload
+        let plain = frame_round_trip(&payload, FrameOptions::default()).await;
+        assert_eq!(plain.len() + 4, wire.len());
+    }
+
+    #[test]
+    fn range_filters_round_trip_with_their_operator() {
+        for op in [">", ">=", "<", "<="] {
+            let json = format!(
+                r#"{{"type":"selectWhere","table":"t","filter":{{"kind":"range","column":"n","op":"{}","value":-3}},"limit":5}}"#,
+                op
+            );
+            let cmd: DbCommand = serde_json::from_str(&json).unwrap();
+            let mut frame = Vec::new();
+            encode_command_into(&mut frame, &cmd);
+            assert_eq!(format!("{:?}", parse_command(&frame).unwrap()), format!("{:?}", cmd));
+        }
+    }
+
+    #[test]
+    fn batches_round_trip() {
+        let cmd: DbCommand = serde_json::from_str(
+            r#"{"type":"batch","ignoreErrors":true,"commands":[{"type":"ping"},{"type":"deleteRow","table":"t","rowId":4}]}"#,
+        )
+        .unwrap();
+        let mut frame = Vec::new();
+        encode_command_into(&mut frame, &cmd);
+        assert_eq!(format!("{:?}", parse_command(&frame).unwrap()), format!("{:?}", cmd));
+
+        let result = DbResult::Batch { results: vec![Ok(DbResult::Affected(1)), Err("Row not found".into())] };
+        assert_eq!(format!("{:?}", decode_response(&encode_result(&result)).unwrap()), format!("{:?}", result));
+    }
+
+    #[test]
+    fn tables_encode_like_their_select_all_result() {
+        let mut db = Database::default();
+        let create = r#"{"type":"createTable","table":"t","columns":[["n","int"],["s","text"]]}"#;
+        db.execute(serde_json::from_str(create).unwrap()).unwrap();
+        for n in 1..=5 {
+            let insert = format!(r#"{{"type":"insert","table":"t","values":[{},"s{}"]}}"#, n, n);
+            db.execute(serde_json::from_str(&insert).unwrap()).unwrap();
+        }
+        db.execute(serde_json::from_str(r#"{"type":"deleteRow","table":"t","rowId":2}"#).unwrap()).unwrap();
+
+        for (with_types, limit, exclude_metadata) in [(false, None, false), (true, Some(2), false), (false, Some(10), true)] {
+            let result = db.select_all("t".into(), with_types, limit, None, exclude_metadata).unwrap();
+            let direct = encode_table(&db.tables["t"], db.row_cap(), with_types, limit, None, !exclude_metadata).unwrap();
+            assert_eq!(direct, encode_result(&result));
+        }
+
+        // A later page, from the cursor the first one returned
+        let Ok(DbResult::Rows { next_cursor: Some(cursor), .. }) = db.select_all("t".into(), false, Some(2), None, false) else {
+            panic!("no next page")
+        };
+        let result = db.select_all("t".into(), false, Some(2), Some(cursor.clone()), false).unwrap();
+        let direct = encode_table(&db.tables["t"], db.row_cap(), false, Some(2), Some(&cursor), true).unwrap();
+        assert_eq!(direct, encode_result(&result));
+    }
+
+    #[tokio::test]
+    async fn reused_buffers_encode_the_same_bytes() {
+        let commands: Vec<DbCommand> = [
+            r#"{"type":"insert","table":"t","values":[1,"a long enough string"]}"#,
+            r#"{"type":"ping"}"#,
+            r#"{"type":"deleteRow","table":"t","rowId":9}"#,
+        ]
+        .iter()
+        .map(|json| serde_json::from_str(json).unwrap())
+        .collect();
+        let options = FrameOptions { checksum: true, compression: true, ..FrameOptions::default() };
+
+        let (mut buf, mut scratch, mut reused) = (Vec::new(), Vec::new(), Vec::new());
+        let mut fresh = Vec::new();
+        for cmd in &commands {
+            // A longer command first leaves stale bytes behind if clearing is missed
+            buf.clear();
+            encode_command_into(&mut buf, cmd);
+            write_frame_buffered(&mut reused, &buf, options, &mut scratch).await.unwrap();
+
+            let mut own = Vec::new();
+            encode_command_into(&mut own, cmd);
+            assert_eq!(buf, own);
+            write_frame_with(&mut fresh, &own, options).await.unwrap();
+        }
+        assert_eq!(reused, fresh);
+    }
+
+    #[test]
+    fn select_by_ids_round_trips() {
+        let cmd: DbCommand = serde_json::from_str(r#"{"type":"selectByIds","table":"t","ids":[3,1,18446744073709551615],"ignoreMissing":true}"#).unwrap();
+        let mut frame = Vec::new();
+        encode_command_into(&mut frame, &cmd);
+        assert_eq!(format!("{:?}", parse_command(&frame).unwrap()), format!("{:?}", cmd));
+    }
+
+    #[test]
+    fn affected_counts_round_trip() {
+        for count in [0, 1, 70_000, u64::MAX] {
+            let decoded = decode_response(&encode_result(&DbResult::Affected(count))).unwrap();
+            assert!(matches!(decoded, DbResult::Affected(n) if n == count));
+        }
+    }
+
+    #[test]
+    fn create_table_keys_round_trip() {
+        let cmd: DbCommand = serde_json::from_str(
+            r#"{"type":"createTable","table":"t","columns":[["a","int"],["b","text"]],"key":["b","a"]}"#,
+        )
+        .unwrap();
+        let mut frame = Vec::new();
+        encode_command_into(&mut frame, &cmd);
+        assert_eq!(format!("{:?}", parse_command(&frame).unwrap()), format!("{:?}", cmd));
+    }
+
+    #[test]
+    fn select_all_carries_column_types_only_when_asked() {
+        let mut db = Database::default();
+        let create: DbCommand = serde_json::from_str(r#"{"type":"createTable","table":"t","columns":[["n","int"],["s","text"]]}"#).unwrap();
+        db.execute(create).unwrap();
+        db.execute(serde_json::from_str(r#"{"type":"insert","table":"t","values":[1,"true"]}"#).unwrap()).unwrap();
+
+        let select = |json: &str| {
+            let mut frame = Vec::new();
+            encode_command_into(&mut frame, &serde_json::from_str(json).unwrap());
+            parse_command(&frame).unwrap()
+        };
+        let typed = db.execute(select(r#"{"type":"selectAll","table":"t","withTypes":true}"#)).unwrap();
+        let encoded = encode_result(&typed);
+        assert_eq!(encoded[0], RESP_ROWS_TYPED);
+        let Ok(DbResult::Rows { column_types, .. }) = decode_response(&encoded) else { panic!("expected rows") };
+        assert_eq!(column_types, Some(vec![ColumnType::Int, ColumnType::Text, ColumnType::Int]));
+        let json = serde_json::to_value(&typed).unwrap();
+        assert_eq!(json["columnTypes"], serde_json::json!(["int", "text", "int"]));
+
+        let untyped = db.execute(select(r#"{"type":"selectAll","table":"t"}"#)).unwrap();
+        let Ok(DbResult::Rows { column_types, .. }) = decode_response(&encode_result(&untyped)) else { panic!("expected rows") };
+        assert_eq!(column_types, None);
+        assert!(serde_json::to_value(&untyped).unwrap().get("columnTypes").is_none());
+    }
+
+    #[tokio::test]
+    async fn frames_past_the_configured_size_are_rejected() {
+        let options = FrameOptions { max_frame_size: 2048, ..FrameOptions::default() };
+        frame_round_trip(&[7; 2048], options).await;
+
+        let mut wire = Vec::new();
+        write_frame(&mut wire, &[7; 2049]).await.unwrap();
+        let err = read_frame_with(&mut wire.as_slice(), options).await.unwrap_err();
+        assert_eq!(err.to_string(), "Frame too large");
+
+        // The limit applies to the payload once decompressed, not to what was sent
+        let compressed = FrameOptions { compression: true, ..options };
+        let mut wire = Vec::new();
+        write_frame_with(&mut wire, &[7; 4096], FrameOptions { max_frame_size: 4096, ..compressed }).await.unwrap();
+        assert!(wire.len() < 2048);
+        assert!(read_frame_with(&mut wire.as_slice(), compressed).await.is_err());
+    }
+
+    #[test]
+    fn get_schema_round_trips_column_types() {
+        let mut db = Database::default();
+        for json in [
+            r#"{"type":"createTable","table":"b","columns":[["flag","bool"]]}"#,
+            r#"{"type":"createTable","table":"a","columns":[["n","int"],["s","text"]]}"#,
+            r#"{"type":"migrate","table":"a","steps":[{"op":"add","name":"ok","type":"bool","default":true}]}"#,
+        ] {
+            db.execute(serde_json::from_str(json).unwrap()).unwrap();
+        }
+
+        let mut frame = Vec::new();
+        encode_command_into(&mut frame, &DbCommand::GetSchema {});
+        assert_eq!(frame, [OP_GET_SCHEMA]);
+        let encoded = encode_result(&db.execute(parse_command(&frame).unwrap()).unwrap());
+        assert_eq!(encoded[0], RESP_SCHEMA);
+        let Ok(DbResult::Schema { tables }) = decode_response(&encoded) else { panic!("expected a schema") };
+
+        let names: Vec<(&str, u64)> = tables.iter().map(|t| (t.name.as_str(), t.schema_version)).collect();
+        assert_eq!(names, [("a", 2), ("b", 1)]);
+        let columns = |i: usize| -> Vec<(&str, ColumnType)> {
+            tables[i].columns.iter().map(|c| (c.name.as_str(), c.col_type.clone())).collect()
+        };
+        assert_eq!(columns(0), [("n", ColumnType::Int), ("s", ColumnType::Text), ("ok", ColumnType::Bool)]);
+        assert_eq!(columns(1), [("flag", ColumnType::Bool)]);
+
+        // The text listing is still there for older clients
+        let mut frame = Vec::new();
+        encode_command_into(&mut frame, &DbCommand::GetTables {});
+        assert!(matches!(decode_response(&encode_result(&db.execute(parse_command(&frame).unwrap()).unwrap())), Ok(DbResult::Rows { .. })));
+    }
+}
@@ -0,0 +1,149 @@
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info, warn};
+
+use crate::session::Session;
+use crate::{Command, protocol};
+
+/// Writes kept for followers that fall behind before they are dropped.
+pub const REPLICATION_BUFFER: usize = 1024;
+
+/// Streams every applied write to a connected follower until either side goes away.
+/// A follower that falls too far behind is disconnected, since it can no longer
+/// catch up from the stream alone.
+pub async fn serve_follower(socket: &mut TcpStream, mut writes: broadcast::Receiver<Vec<u8>>, addr: std::net::SocketAddr) {
+    loop {
+        let command = match writes.recv().await {
+            Ok(command) => command,
+            Err(RecvError::Lagged(missed)) => {
+                warn!(follower_addr = %addr, missed, "Follower fell behind, disconnecting");
+                return;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        if let Err(e) = protocol::write_frame(socket, &command).await {
+            error!(follower_addr = %addr, error = %e, "Follower write error");
+            return;
+        }
+    }
+}
+
+/// Connects to `primary`, presenting its replication `token`, and replays its
+/// writes into the local database. Only writes made after the follower
+/// connects are received, so a follower should be started alongside a fresh primary.
+pub async fn follow(primary: String, token: String, tx: mpsc::Sender<Command>) -> anyhow::Result<()> {
+    let mut stream = TcpStream::connect(&primary).await?;
+    protocol::write_frame(&mut stream, &protocol::encode_replicate_request(&token)).await?;
+    // The primary acknowledges the request before streaming, or says why it refused
+    let ack = protocol::read_frame(&mut stream)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Primary {} closed the replication stream", primary))?;
+    if let Err(e) = protocol::decode_response(&ack) {
+        anyhow::bail!("Primary {} refused replication: {}", primary, e);
+    }
+    info!(%primary, "Following primary");
+
+    while let Some(frame) = protocol::read_frame(&mut stream).await? {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(Command {
+            data: frame,
+            respond_to: resp_tx,
+            replicated: true,
+            session: Session::default(),
+        })
+        .await?;
+
+        if let Ok(response) = resp_rx.await
+            && let Err(e) = protocol::decode_response(&response)
+        {
+            error!(error = %e, "Replicated command failed");
+        }
+    }
+
+    anyhow::bail!("Primary {} closed the replication stream", primary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{DbCommand, DbResult};
+    use crate::db::Database;
+    use crate::listener::tests::TestListener;
+    use std::time::Duration;
+
+    fn start(db: Database) -> mpsc::Sender<Command> {
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            let mut db = db;
+            db.run(rx).await;
+        });
+        tx
+    }
+
+    /// Sends a command the way a client connection would.
+    async fn send(tx: &mpsc::Sender<Command>, json: &str) -> Result<DbResult, String> {
+        let cmd: DbCommand = serde_json::from_str(json).unwrap();
+        let mut data = Vec::new();
+        protocol::encode_command_into(&mut data, &cmd);
+        let (respond_to, response) = oneshot::channel();
+        tx.send(Command { data, respond_to, replicated: false, session: Session::default() }).await.unwrap();
+        protocol::decode_response(&response.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn writes_on_the_primary_reach_the_follower() {
+        let (writes, _) = broadcast::channel(REPLICATION_BUFFER);
+        let primary = start(Database { replication: Some(writes.clone()), ..Database::default() });
+        let listener = TestListener { replication_token: Some("secret".into()), writes: Some(writes), ..TestListener::default() };
+        let (_, address) = listener.start_with(primary.clone()).await;
+
+        let follower = start(Database { read_only: true, ..Database::default() });
+        tokio::spawn(follow(address.to_string(), "secret".into(), follower.clone()));
+        // Only writes made after the follower subscribes are streamed
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        send(&primary, r#"{"type":"createTable","table":"t","columns":[["a","int"]]}"#).await.unwrap();
+        send(&primary, r#"{"type":"insert","table":"t","values":[42]}"#).await.unwrap();
+
+        let mut rows = Vec::new();
+        for _ in 0..50 {
+            if let Ok(DbResult::Rows { rows: found, .. }) = send(&follower, r#"{"type":"selectAll","table":"t"}"#).await
+                && !found.is_empty()
+            {
+                rows = found;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].1[0], crate::db_types::Value::Int(42));
+
+        assert_eq!(
+            send(&follower, r#"{"type":"insert","table":"t","values":[1]}"#).await.unwrap_err(),
+            "Server is read-only"
+        );
+    }
+
+    #[tokio::test]
+    async fn followers_without_the_token_are_refused() {
+        let (writes, _) = broadcast::channel(REPLICATION_BUFFER);
+        let primary = start(Database { replication: Some(writes.clone()), ..Database::default() });
+        let listener = TestListener { replication_token: Some("secret".into()), writes: Some(writes), ..TestListener::default() };
+        let (_, address) = listener.start_with(primary).await;
+
+        for token in ["", "guess"] {
+            let mut socket = TcpStream::connect(address).await.unwrap();
+            protocol::write_frame(&mut socket, &protocol::encode_replicate_request(token)).await.unwrap();
+            let refusal = protocol::read_frame(&mut socket).await.unwrap().unwrap();
+            assert_eq!(protocol::decode_response(&refusal).unwrap_err(), "Access denied: invalid replication token");
+            // The connection is closed rather than left to stream writes
+            assert_eq!(protocol::read_frame(&mut socket).await.ok().flatten(), None);
+        }
+
+        let follower = start(Database { read_only: true, ..Database::default() });
+        let refused = follow(address.to_string(), "guess".into(), follower).await.unwrap_err();
+        assert!(refused.to_string().ends_with("refused replication: Access denied: invalid replication token"), "{}", refused);
+    }
+}
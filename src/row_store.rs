@@ -0,0 +1,469 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::warn;
+
+use crate::db_types::Value;
+
+/// Spill files are rewritten once this much of them is dead space.
+const SPILL_COMPACT_BYTES: u64 = 1024 * 1024;
+
+static NEXT_SPILL_FILE: AtomicU64 = AtomicU64::new(1);
+
+/// The rows of one table, keyed by row id. Every row is kept in memory unless
+/// a memory limit is set; then the least recently used rows past the limit are
+/// moved to a spill file and read back from it on access.
+///
+/// Reads of spilled rows don't bring them back into memory, since they only
+/// have `&self`; `get_mut` and `insert` do. A spilled row that can't be read
+/// back is an error for the caller to report, and stays in the spill file.
+#[derive(Debug, Default)]
+pub struct RowStore {
+    hot: HashMap<u64, Vec<Value>>,
+    spill: Option<Spill>,
+}
+
+#[derive(Debug)]
+struct Spill {
+    max_rows: usize,
+    file: File,
+    path: PathBuf,
+    /// Offset and length of each spilled row in the file
+    cold: HashMap<u64, (u64, u64)>,
+    /// Where the next spilled row is written
+    end: u64,
+    /// Bytes of the file still belonging to a spilled row
+    live: u64,
+    /// Updated by reads, which only borrow the store
+    recency: RefCell<Recency>,
+}
+
+/// In-memory row ids ordered by last use.
+#[derive(Debug, Default)]
+struct Recency {
+    tick: u64,
+    by_tick: BTreeMap<u64, u64>,
+    by_id: HashMap<u64, u64>,
+}
+
+impl Recency {
+    fn touch(&mut self, id: u64) {
+        self.tick += 1;
+        if let Some(old) = self.by_id.insert(id, self.tick) {
+            self.by_tick.remove(&old);
+        }
+        self.by_tick.insert(self.tick, id);
+    }
+
+    fn forget(&mut self, id: u64) {
+        if let Some(old) = self.by_id.remove(&id) {
+            self.by_tick.remove(&old);
+        }
+    }
+
+    fn pop_oldest(&mut self) -> Option<u64> {
+        let (_, id) = self.by_tick.pop_first()?;
+        self.by_id.remove(&id);
+        Some(id)
+    }
+}
+
+impl Spill {
+    fn create(max_rows: usize) -> io::Result<Spill> {
+        // `create_new` never opens a file or symlink someone else put at the path;
+        // a name that's taken, say by a crashed process with the same pid, is skipped
+        let (file, path) = loop {
+            let n = NEXT_SPILL_FILE.fetch_add(1, Ordering::Relaxed);
+            let path = std::env::temp_dir().join(format!("rust_db-{}-{}.spill", std::process::id(), n));
+            match OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+                Ok(file) => break (file, path),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        // Unlinked right away so the file goes with the process however it exits.
+        // Where open files can't be removed, Drop tries again.
+        let _ = fs::remove_file(&path);
+        Ok(Spill {
+            max_rows,
+            file,
+            path,
+            cold: HashMap::new(),
+            end: 0,
+            live: 0,
+            recency: RefCell::default(),
+        })
+    }
+
+    fn read(&self, (offset, len): (u64, u64)) -> io::Result<Vec<Value>> {
+        let mut buf = vec![0; len as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buf)?;
+        serde_json::from_slice(&buf).map_err(io::Error::other)
+    }
+
+    /// Like `read`, with the error as reported to clients.
+    fn try_read(&self, slot: (u64, u64)) -> Result<Vec<Value>, String> {
+        self.read(slot)
+            .map_err(|e| format!("Failed to read spilled row from {}: {}", self.path.display(), e))
+    }
+
+    fn write(&mut self, values: &[Value]) -> io::Result<(u64, u64)> {
+        let data = serde_json::to_vec(values).map_err(io::Error::other)?;
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&data)?;
+        let slot = (self.end, data.len() as u64);
+        self.end += slot.1;
+        self.live += slot.1;
+        Ok(slot)
+    }
+
+    /// Reads a spilled row and forgets its slot. The slot is kept if the read fails.
+    fn take(&mut self, id: u64) -> Result<Option<Vec<Value>>, String> {
+        let Some(&slot) = self.cold.get(&id) else { return Ok(None) };
+        let values = self.try_read(slot)?;
+        self.cold.remove(&id);
+        self.live -= slot.1;
+        Ok(Some(values))
+    }
+
+    /// Rewrites the file without the space left behind by rows that were
+    /// loaded back or removed.
+    fn compact(&mut self) -> io::Result<()> {
+        let mut fresh = Spill::create(self.max_rows)?;
+        for (id, slot) in &self.cold {
+            let values = self.read(*slot)?;
+            let slot = fresh.write(&values)?;
+            fresh.cold.insert(*id, slot);
+        }
+        fresh.recency = std::mem::take(&mut self.recency);
+        // The old file is removed when `fresh` replaces it
+        *self = fresh;
+        Ok(())
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl RowStore {
+    pub fn len(&self) -> usize {
+        self.hot.len() + self.spill.as_ref().map_or(0, |s| s.cold.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains_key(&self, id: &u64) -> bool {
+        self.hot.contains_key(id) || self.spill.as_ref().is_some_and(|s| s.cold.contains_key(id))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &u64> {
+        self.hot.keys().chain(self.spill.iter().flat_map(|s| s.cold.keys()))
+    }
+
+    pub fn get(&self, id: &u64) -> Result<Option<Cow<'_, [Value]>>, String> {
+        if let Some(values) = self.hot.get(id) {
+            if let Some(spill) = &self.spill {
+                spill.recency.borrow_mut().touch(*id);
+            }
+            return Ok(Some(Cow::Borrowed(values.as_slice())));
+        }
+        let Some(spill) = &self.spill else { return Ok(None) };
+        spill.cold.get(id).map(|slot| spill.try_read(*slot).map(Cow::Owned)).transpose()
+    }
+
+    /// Like `get`, for a row the caller knows exists.
+    pub fn row(&self, id: &u64) -> Result<Cow<'_, [Value]>, String> {
+        self.get(id)?.ok_or_else(|| format!("Row {} not found", id))
+    }
+
+    /// The values of row `id`, borrowed when the row is in memory.
+    pub fn row_values(&self, id: &u64) -> Result<impl Iterator<Item = Cow<'_, Value>>, String> {
+        let (borrowed, owned) = match self.row(id)? {
+            Cow::Borrowed(values) => (Some(values.iter().map(Cow::Borrowed)), None),
+            Cow::Owned(values) => (None, Some(values.into_iter().map(Cow::Owned))),
+        };
+        Ok(borrowed.into_iter().flatten().chain(owned.into_iter().flatten()))
+    }
+
+    /// Loads the row back into memory if it was spilled.
+    pub fn get_mut(&mut self, id: &u64) -> Result<Option<&mut Vec<Value>>, String> {
+        if let Some(spill) = &mut self.spill {
+            if let Some(values) = spill.take(*id)? {
+                self.hot.insert(*id, values);
+            }
+            if self.hot.contains_key(id) {
+                spill.recency.get_mut().touch(*id);
+            }
+        }
+        self.evict(Some(*id));
+        Ok(self.hot.get_mut(id))
+    }
+
+    pub fn insert(&mut self, id: u64, values: Vec<Value>) {
+        self.hot.insert(id, values);
+        if let Some(spill) = &mut self.spill {
+            // A spilled copy of the row is stale now
+            if let Some((_, len)) = spill.cold.remove(&id) {
+                spill.live -= len;
+            }
+            spill.recency.get_mut().touch(id);
+        }
+        self.evict(Some(id));
+    }
+
+    /// Drops row `id` without reading it back, for callers that already
+    /// have its values. Returns whether there was such a row.
+    pub fn delete(&mut self, id: &u64) -> bool {
+        let Some(spill) = &mut self.spill else { return self.hot.remove(id).is_some() };
+        spill.recency.get_mut().forget(*id);
+        if self.hot.remove(id).is_some() {
+            return true;
+        }
+        match spill.cold.remove(id) {
+            Some((_, len)) => {
+                spill.live -= len;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Rows in memory come first, in no particular order, then spilled rows.
+    /// Iterating doesn't count as use, so a full scan doesn't evict the hot set.
+    /// Spilled rows that can't be read back come out as errors.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(&u64, Cow<'_, [Value]>), String>> {
+        let hot = self.hot.iter().map(|(id, values)| Ok((id, Cow::Borrowed(values.as_slice()))));
+        let cold = self
+            .spill
+            .iter()
+            .flat_map(|s| s.cold.iter().map(move |(id, slot)| s.try_read(*slot).map(|values| (id, Cow::Owned(values)))));
+        hot.chain(cold)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = Result<Cow<'_, [Value]>, String>> {
+        self.iter().map(|row| row.map(|(_, values)| values))
+    }
+
+    /// Applies `f` to every row. Spilled rows are rewritten in the spill file.
+    /// If a spilled row can't be read back, no row is changed.
+    pub fn update_all(&mut self, mut f: impl FnMut(&mut Vec<Value>)) -> Result<(), String> {
+        if let Some(spill) = &mut self.spill {
+            // The new copies go after the old ones, which stay in use until every row is done
+            let mut slots = HashMap::with_capacity(spill.cold.len());
+            let mut unspilled = Vec::new();
+            let ids: Vec<u64> = spill.cold.keys().copied().collect();
+            for id in ids {
+                let mut values = spill.try_read(spill.cold[&id])?;
+                f(&mut values);
+                match spill.write(&values) {
+                    Ok(slot) => {
+                        slots.insert(id, slot);
+                    }
+                    Err(e) => {
+                        warn!(error = %e, "Failed to spill row, keeping it in memory");
+                        unspilled.push((id, values));
+                    }
+                }
+            }
+            spill.live = slots.values().map(|(_, len)| len).sum();
+            spill.cold = slots;
+            for (id, values) in unspilled {
+                spill.recency.get_mut().touch(id);
+                self.hot.insert(id, values);
+            }
+        }
+        self.hot.values_mut().for_each(&mut f);
+        Ok(())
+    }
+
+    /// Moves every row to its new id in `new_ids`; ids not in the map keep theirs.
+    pub fn renumber(&mut self, new_ids: &HashMap<u64, u64>) {
+        let new_id = |id: u64| new_ids.get(&id).copied().unwrap_or(id);
+        self.hot = self.hot.drain().map(|(id, values)| (new_id(id), values)).collect();
+        let Some(spill) = &mut self.spill else { return };
+        spill.cold = spill.cold.drain().map(|(id, slot)| (new_id(id), slot)).collect();
+        let recency = spill.recency.get_mut();
+        recency.by_tick.values_mut().for_each(|id| *id = new_id(*id));
+        recency.by_id = recency.by_tick.iter().map(|(tick, id)| (*id, *tick)).collect();
+    }
+
+    /// A copy with the same memory limit and its own spill file. Which rows
+    /// are in memory isn't copied.
+    pub fn try_clone(&self) -> Result<RowStore, String> {
+        let Some(limit) = self.memory_limit() else {
+            return Ok(RowStore { hot: self.hot.clone(), spill: None });
+        };
+        let mut copy = RowStore::default();
+        copy.set_memory_limit(Some(limit))?;
+        for row in self.iter() {
+            let (id, values) = row?;
+            copy.insert(*id, values.into_owned());
+        }
+        Ok(copy)
+    }
+
+    /// Most rows kept in memory, or `None` if the table is entirely in memory.
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.spill.as_ref().map(|s| s.max_rows)
+    }
+
+    /// Setting a limit spills rows past it right away. Clearing it loads
+    /// every spilled row back and deletes the spill file.
+    pub fn set_memory_limit(&mut self, max_rows: Option<usize>) -> Result<(), String> {
+        match (max_rows, &mut self.spill) {
+            (Some(0), _) => return Err("Memory limit must be at least 1 row".into()),
+            (Some(max_rows), Some(spill)) => spill.max_rows = max_rows,
+            (Some(max_rows), None) => {
+                let spill = Spill::create(max_rows).map_err(|e| format!("Failed to create spill file: {}", e))?;
+                let mut ids: Vec<u64> = self.hot.keys().copied().collect();
+                ids.sort_unstable();
+                ids.into_iter().for_each(|id| spill.recency.borrow_mut().touch(id));
+                self.spill = Some(spill);
+            }
+            (None, Some(spill)) => {
+                // Every row is read before any is moved, so a failed read leaves the limit in place
+                let cold = spill
+                    .cold
+                    .iter()
+                    .map(|(id, slot)| Ok((*id, spill.try_read(*slot)?)))
+                    .collect::<Result<Vec<_>, String>>()?;
+                self.hot.extend(cold);
+                self.spill = None;
+                return Ok(());
+            }
+            (None, None) => return Ok(()),
+        }
+        self.evict(None);
+        Ok(())
+    }
+
+    /// Spills least recently used rows until the limit holds. `keep` is the
+    /// row being handed out, which must stay in memory.
+    fn evict(&mut self, keep: Option<u64>) {
+        let Some(spill) = &mut self.spill else { return };
+        while self.hot.len() > spill.max_rows {
+            let Some(id) = spill.recency.get_mut().pop_oldest() else { break };
+            if Some(id) == keep {
+                spill.recency.get_mut().touch(id);
+                continue;
+            }
+            let Some(values) = self.hot.remove(&id) else { continue };
+            match spill.write(&values) {
+                Ok(slot) => {
+                    spill.cold.insert(id, slot);
+                }
+                Err(e) => {
+                    warn!(error = %e, "Failed to spill row, keeping it in memory");
+                    spill.recency.get_mut().touch(id);
+                    self.hot.insert(id, values);
+                    return;
+                }
+            }
+        }
+        if spill.end - spill.live > SPILL_COMPACT_BYTES.max(spill.live)
+            && let Err(e) = spill.compact()
+        {
+            warn!(error = %e, "Failed to compact spill file");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(n: i64) -> Vec<Value> {
+        vec![Value::Int(n), Value::Text(format!("row {}", n))]
+    }
+
+    fn cold_ids(store: &RowStore) -> Vec<u64> {
+        let mut ids: Vec<u64> = store.spill.as_ref().map_or(Vec::new(), |s| s.cold.keys().copied().collect());
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn rows_past_the_limit_spill_and_read_back() {
+        let mut store = RowStore::default();
+        store.set_memory_limit(Some(2)).unwrap();
+        for id in 1..=10 {
+            store.insert(id, row(id as i64));
+        }
+        assert_eq!(store.len(), 10);
+        assert_eq!(store.hot.len(), 2);
+        assert_eq!(cold_ids(&store), (1..=8).collect::<Vec<u64>>());
+
+        // Reads come from the file and leave the row there
+        assert_eq!(store.get(&3).unwrap().unwrap().as_ref(), row(3));
+        assert_eq!(cold_ids(&store).len(), 8);
+        let mut all: Vec<(u64, Vec<Value>)> = store.iter().map(|r| r.map(|(id, values)| (*id, values.into_owned()))).collect::<Result<_, _>>().unwrap();
+        all.sort_unstable_by_key(|(id, _)| *id);
+        assert_eq!(all, (1..=10).map(|id| (id, row(id as i64))).collect::<Vec<_>>());
+
+        // Writing to a spilled row loads it and pushes out the oldest one in memory
+        store.get_mut(&1).unwrap().unwrap()[0] = Value::Int(100);
+        assert!(store.hot.contains_key(&1) && !store.hot.contains_key(&9));
+        assert_eq!(store.get(&1).unwrap().unwrap()[0], Value::Int(100));
+        assert!(store.delete(&5));
+        assert!(!store.contains_key(&5));
+
+        // Lifting the limit loads everything back
+        store.set_memory_limit(None).unwrap();
+        assert_eq!(store.hot.len(), 9);
+        assert_eq!(store.get(&1).unwrap().unwrap()[0], Value::Int(100));
+        assert!(store.set_memory_limit(Some(0)).is_err());
+    }
+
+    #[test]
+    fn unreadable_spilled_rows_are_errors_and_stay_stored() {
+        let mut store = RowStore::default();
+        store.set_memory_limit(Some(1)).unwrap();
+        store.insert(1, row(1));
+        store.insert(2, row(2));
+        assert_eq!(cold_ids(&store), vec![1]);
+        store.spill.as_ref().unwrap().file.set_len(0).unwrap();
+
+        assert!(store.get(&1).is_err());
+        assert!(store.row(&1).is_err());
+        assert!(store.get_mut(&1).is_err());
+        assert!(store.iter().any(|r| r.is_err()));
+        assert!(store.update_all(|row| row.push(Value::Int(0))).is_err());
+        assert!(store.set_memory_limit(None).is_err());
+
+        // Nothing was dropped or half-applied along the way
+        assert!(store.contains_key(&1));
+        assert_eq!(cold_ids(&store), vec![1]);
+        assert_eq!(store.get(&2).unwrap().unwrap().as_ref(), row(2));
+        assert!(store.delete(&1));
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn spill_files_never_reuse_an_existing_path() {
+        let next = NEXT_SPILL_FILE.load(Ordering::Relaxed);
+        let taken: Vec<PathBuf> = (next..next + 3)
+            .map(|n| std::env::temp_dir().join(format!("rust_db-{}-{}.spill", std::process::id(), n)))
+            .collect();
+        for path in &taken {
+            fs::write(path, b"not ours").unwrap();
+        }
+
+        let spill = Spill::create(1).unwrap();
+        for path in &taken {
+            assert_eq!(fs::read(path).unwrap(), b"not ours");
+            let _ = fs::remove_file(path);
+        }
+        assert!(!taken.contains(&spill.path));
+    }
+}
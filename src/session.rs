@@ -0,0 +1,56 @@
+use crate::commands::DbCommand;
+use crate::config::{ADMINS, USERS};
+
+/// Options a client sets for its own connection with the Set command.
+/// They last until the connection closes and never affect other clients.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Session {
+    /// Lowercases table names, like the server-wide setting but for this connection only.
+    pub case_insensitive: bool,
+    /// Prepended to every table name the connection refers to.
+    pub table_prefix: Option<String>,
+    /// Who the connection authenticated as, checked against table ACLs.
+    pub principal: Option<String>,
+}
+
+impl Session {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "case_insensitive" => {
+                self.case_insensitive = match value {
+                    "true" | "on" | "1" => true,
+                    "false" | "off" | "0" => false,
+                    _ => return Err(format!("Invalid value for case_insensitive: {}", value)),
+                };
+            }
+            "table_prefix" => {
+                self.table_prefix = (!value.is_empty()).then(|| value.to_string());
+            }
+            "auth" => {
+                let (principal, token) = value.split_once(':').unwrap_or((value, ""));
+                if !USERS.contains(&(principal, token)) {
+                    return Err("Authentication failed".into());
+                }
+                self.principal = Some(principal.to_string());
+            }
+            _ => return Err(format!("Unknown session option: {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Whether the connection may use admin-only features. Anyone may when
+    /// no admins are configured.
+    pub fn is_admin(&self) -> bool {
+        ADMINS.is_empty() || self.principal.as_deref().is_some_and(|p| ADMINS.contains(&p))
+    }
+
+    /// Rewrites the command's table names according to the session's options.
+    pub fn apply(&self, cmd: &mut DbCommand) {
+        if let Some(prefix) = &self.table_prefix {
+            cmd.map_table_names(&mut |t| t.insert_str(0, prefix));
+        }
+        if self.case_insensitive {
+            cmd.lowercase_table_names();
+        }
+    }
+}
@@ -0,0 +1,476 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tracing::error;
+
+use crate::commands::DbCommand;
+use crate::db_types::{Acl, ChangeLog, Column, ForeignKey, SortedIndex, Table, TextIndex, Value};
+use crate::row_store::RowStore;
+use crate::session::Session;
+use crate::{Command, protocol};
+
+/// On-disk form of a table. Indexes and key tuples aren't stored; they're
+/// rebuilt from the rows on load.
+#[derive(Serialize, Deserialize)]
+struct TableSnapshot {
+    name: String,
+    columns: Vec<Column>,
+    next_row_id: u64,
+    schema_version: u64,
+    text_indexes: Vec<String>,
+    #[serde(default)]
+    sorted_indexes: Vec<String>,
+    #[serde(default)]
+    key_columns: Vec<usize>,
+    #[serde(default)]
+    foreign_keys: Vec<ForeignKey>,
+    /// Rows kept in memory before the rest spill to disk
+    #[serde(default)]
+    memory_limit: Option<usize>,
+    #[serde(default)]
+    acl: Option<Acl>,
+    #[serde(default)]
+    created: u64,
+    #[serde(default)]
+    changes: ChangeLog,
+    /// (row id, row version, values)
+    rows: Vec<(u64, u64, Vec<Value>)>,
+}
+
+// Leading bytes of a gzip stream. Plain snapshot files are JSON.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Written at the snapshot path. Each table lives in a file of its own in
+/// the directory next to it, so a snapshot only rewrites the tables that changed.
+#[derive(Serialize, Deserialize)]
+struct SnapshotIndex {
+    /// Bumped by every snapshot, and part of the names of the files it writes
+    generation: u64,
+    /// File of each table, by table name
+    tables: BTreeMap<String, String>,
+}
+
+/// What the last snapshot wrote, and which tables changed since.
+#[derive(Debug, Default)]
+pub struct SnapshotState {
+    generation: u64,
+    files: HashMap<String, String>,
+    dirty: HashSet<String>,
+}
+
+impl SnapshotState {
+    /// Makes the next snapshot rewrite the table's file.
+    pub fn mark_dirty(&mut self, table: &str) {
+        self.dirty.insert(table.to_string());
+    }
+}
+
+/// Where the table files of the snapshot at `path` go.
+fn tables_dir(path: &Path) -> PathBuf {
+    let mut dir = path.as_os_str().to_owned();
+    dir.push(".tables");
+    PathBuf::from(dir)
+}
+
+/// Writes the tables that changed since the last snapshot, or were never
+/// written, to new files, gzipped if `compress` is set, then swaps in an
+/// index pointing at them. Files are never overwritten and the index is
+/// renamed into place, so a crash mid-write leaves the previous snapshot intact.
+pub fn save(tables: &HashMap<String, Table>, path: &Path, compress: bool, state: &mut SnapshotState) -> Result<(), String> {
+    let saved = prepare(tables, path, compress, state)?.write()?;
+    state.saved(saved);
+    Ok(())
+}
+
+/// A snapshot copied out of the database, so its files can be written
+/// without holding on to the tables. See `save`.
+pub struct SnapshotJob {
+    path: PathBuf,
+    compress: bool,
+    generation: u64,
+    /// Table name, its file and, for tables to rewrite, their contents
+    tables: Vec<(String, String, Option<TableSnapshot>)>,
+    /// Files of the previous snapshot
+    old_files: Vec<String>,
+}
+
+/// What a finished job wrote, for updating the snapshot state.
+pub struct Saved {
+    generation: u64,
+    files: HashMap<String, String>,
+}
+
+impl SnapshotState {
+    /// Records a finished job as the latest snapshot.
+    pub fn saved(&mut self, saved: Saved) {
+        self.generation = saved.generation;
+        self.files = saved.files;
+        self.dirty.clear();
+    }
+}
+
+/// Copies the tables `save` would write. Only changed tables are copied.
+pub fn prepare(tables: &HashMap<String, Table>, path: &Path, compress: bool, state: &SnapshotState) -> Result<SnapshotJob, String> {
+    let generation = state.generation + 1;
+    let mut names: Vec<&String> = tables.keys().collect();
+    names.sort();
+    let tables = names
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| match state.files.get(name) {
+            Some(file) if !state.dirty.contains(name) => Ok((name.clone(), file.clone(), None)),
+            _ => Ok((name.clone(), format!("{}-{}.table", generation, i), Some(table_snapshot(&tables[name])?))),
+        })
+        .collect::<Result<_, String>>()?;
+    Ok(SnapshotJob {
+        path: path.to_path_buf(),
+        compress,
+        generation,
+        tables,
+        old_files: state.files.values().cloned().collect(),
+    })
+}
+
+impl SnapshotJob {
+    /// Does the file IO of `save`, flushing every file to disk before the
+    /// index that points at them replaces the old one. Blocks; in async code
+    /// run it with `spawn_blocking`.
+    pub fn write(self) -> Result<Saved, String> {
+        let write_error = |e: std::io::Error| format!("Snapshot write error: {}", e);
+        let dir = tables_dir(&self.path);
+        fs::create_dir_all(&dir).map_err(write_error)?;
+
+        let mut files = BTreeMap::new();
+        for (name, file, snapshot) in self.tables {
+            if let Some(snapshot) = snapshot {
+                write_synced(&dir.join(&file), &encode(&snapshot, self.compress)?).map_err(write_error)?;
+            }
+            files.insert(name, file);
+        }
+        sync_dir(&dir);
+
+        let index = SnapshotIndex { generation: self.generation, tables: files };
+        let data = serde_json::to_vec(&index).map_err(|e| format!("Snapshot encode error: {}", e))?;
+        let tmp = self.path.with_extension("tmp");
+        write_synced(&tmp, &data).map_err(write_error)?;
+        fs::rename(&tmp, &self.path).map_err(write_error)?;
+        if let Some(parent) = self.path.parent() {
+            sync_dir(parent);
+        }
+
+        // Only now are the replaced files unused
+        let kept: HashSet<&String> = index.tables.values().collect();
+        for file in self.old_files.iter().filter(|f| !kept.contains(f)) {
+            let _ = fs::remove_file(dir.join(file));
+        }
+        Ok(Saved { generation: self.generation, files: index.tables.into_iter().collect() })
+    }
+}
+
+/// Writes `data` to a new file at `path` and waits until it's on disk.
+fn write_synced(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// Makes renames and new files in `dir` durable. Not every platform can open
+/// a directory for this, so failures are ignored.
+fn sync_dir(dir: &Path) {
+    if let Ok(dir) = fs::File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+/// Reads tables back from a snapshot written by `save`, or from a
+/// single-file snapshot written before tables got files of their own.
+pub fn load(path: &Path) -> Result<(HashMap<String, Table>, SnapshotState), String> {
+    let data = read(path)?;
+    if data.first() == Some(&b'[') {
+        let snapshot: Vec<TableSnapshot> =
+            serde_json::from_slice(&data).map_err(|e| format!("Snapshot decode error: {}", e))?;
+        let mut tables = HashMap::with_capacity(snapshot.len());
+        for s in snapshot {
+            let table = restore(s)?;
+            tables.insert(table.name.clone(), table);
+        }
+        // With no files known, the next snapshot writes every table
+        return Ok((tables, SnapshotState::default()));
+    }
+
+    let index: SnapshotIndex = serde_json::from_slice(&data).map_err(|e| format!("Snapshot decode error: {}", e))?;
+    let dir = tables_dir(path);
+    let mut tables = HashMap::with_capacity(index.tables.len());
+    for file in index.tables.values() {
+        let s: TableSnapshot = serde_json::from_slice(&read(&dir.join(file))?)
+            .map_err(|e| format!("Snapshot decode error in {}: {}", file, e))?;
+        let table = restore(s)?;
+        tables.insert(table.name.clone(), table);
+    }
+    let state = SnapshotState {
+        generation: index.generation,
+        files: index.tables.into_iter().collect(),
+        dirty: HashSet::new(),
+    };
+    Ok((tables, state))
+}
+
+/// Reads a snapshot file, unzipping it if it's gzipped.
+fn read(path: &Path) -> Result<Vec<u8>, String> {
+    let data = fs::read(path).map_err(|e| format!("Snapshot read error: {}", e))?;
+    if !data.starts_with(&GZIP_MAGIC) {
+        return Ok(data);
+    }
+    let mut plain = Vec::new();
+    GzDecoder::new(data.as_slice())
+        .read_to_end(&mut plain)
+        .map_err(|e| format!("Snapshot decode error: {}", e))?;
+    Ok(plain)
+}
+
+fn encode(snapshot: &TableSnapshot, compress: bool) -> Result<Vec<u8>, String> {
+    let data = serde_json::to_vec(snapshot).map_err(|e| format!("Snapshot encode error: {}", e))?;
+    if !compress {
+        return Ok(data);
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&data).map_err(|e| format!("Snapshot encode error: {}", e))?;
+    encoder.finish().map_err(|e| format!("Snapshot encode error: {}", e))
+}
+
+fn table_snapshot(t: &Table) -> Result<TableSnapshot, String> {
+    Ok(TableSnapshot {
+        name: t.name.clone(),
+        columns: t.columns.clone(),
+        next_row_id: t.next_row_id,
+        schema_version: t.schema_version,
+        text_indexes: t.text_indexes.keys().cloned().collect(),
+        sorted_indexes: t.sorted_indexes.keys().cloned().collect(),
+        key_columns: t.key_columns.clone(),
+        foreign_keys: t.foreign_keys.clone(),
+        memory_limit: t.rows.memory_limit(),
+        acl: t.acl.clone(),
+        created: t.created,
+        changes: t.changes.clone(),
+        rows: t
+            .rows
+            .iter()
+            .map(|row| row.map(|(id, values)| (*id, t.versions.get(id).copied().unwrap_or(1), values.into_owned())))
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+fn restore(s: TableSnapshot) -> Result<Table, String> {
+    let mut table = Table {
+        name: s.name,
+        columns: s.columns,
+        rows: RowStore::default(),
+        next_row_id: s.next_row_id,
+        text_indexes: HashMap::new(),
+        sorted_indexes: HashMap::new(),
+        versions: HashMap::with_capacity(s.rows.len()),
+        schema_version: s.schema_version,
+        key_columns: s.key_columns,
+        keys: HashSet::new(),
+        foreign_keys: s.foreign_keys,
+        acl: s.acl,
+        created: s.created,
+        changes: s.changes,
+    };
+
+    // Set before the rows go in, so rows past the limit spill as they're loaded
+    table.rows.set_memory_limit(s.memory_limit)?;
+    for (id, version, values) in s.rows {
+        if let Some(key) = table.key_of(&values) {
+            table.keys.insert(key);
+        }
+        table.rows.insert(id, values);
+        table.versions.insert(id, version);
+    }
+    // Snapshots from before change tracking have no change numbers; count
+    // every row as changed once so ChangesSince still returns them
+    if table.changes.seq == 0 {
+        let ids: Vec<u64> = table.rows.keys().copied().collect();
+        for id in ids {
+            table.changes.changed(id);
+        }
+    }
+
+    for column in s.text_indexes {
+        table.text_indexes.insert(column, TextIndex::default());
+    }
+    for column in s.sorted_indexes {
+        table.sorted_indexes.insert(column, SortedIndex::default());
+    }
+    table.rebuild_indexes()?;
+    Ok(table)
+}
+
+/// Asks the database loop for a snapshot every `interval`, through the same
+/// channel client commands use so it never races with a write.
+pub async fn run_periodic(interval: Duration, tx: mpsc::Sender<Command>) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick fires immediately; there's nothing worth saving yet
+    ticker.tick().await;
+
+    let mut data = Vec::new();
+    protocol::encode_command_into(&mut data, &DbCommand::Snapshot {});
+
+    loop {
+        ticker.tick().await;
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let command = Command {
+            data: data.clone(),
+            respond_to: resp_tx,
+            replicated: false,
+            session: Session::default(),
+        };
+        if tx.send(command).await.is_err() {
+            return;
+        }
+
+        if let Ok(response) = resp_rx.await
+            && let Err(e) = protocol::decode_response(&response)
+        {
+            error!(error = %e, "Periodic snapshot failed");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+
+    fn command(json: &str) -> DbCommand {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn temp_snapshot_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rust_db-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("db.snapshot")
+    }
+
+    fn db_with_tables(path: &Path) -> Database {
+        let mut db = Database { snapshot_path: Some(path.to_path_buf()), ..Database::default() };
+        for table in ["a", "b"] {
+            db.execute(command(&format!(r#"{{"type":"createTable","table":"{}","columns":[["n","int"]]}}"#, table)))
+                .unwrap();
+            db.execute(command(&format!(r#"{{"type":"insert","table":"{}","values":[1]}}"#, table))).unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn only_changed_tables_are_rewritten() {
+        let path = temp_snapshot_path("rewrite");
+        let mut db = db_with_tables(&path);
+        db.snapshot().unwrap();
+        let first = db.snapshot_state.files.clone();
+
+        db.execute(command(r#"{"type":"insert","table":"a","values":[2]}"#)).unwrap();
+        db.snapshot_state.mark_dirty("a");
+        db.snapshot().unwrap();
+        let second = db.snapshot_state.files.clone();
+        assert_ne!(first["a"], second["a"]);
+        assert_eq!(first["b"], second["b"]);
+        // The replaced file is removed once the new index is in place
+        assert!(!tables_dir(&path).join(&first["a"]).exists());
+
+        let (tables, state) = load(&path).unwrap();
+        assert_eq!(tables["a"].rows.len(), 2);
+        assert_eq!(tables["b"].rows.len(), 1);
+        assert_eq!(state.files, second);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn snapshot_off_thread_matches_snapshot() {
+        let path = temp_snapshot_path("off-thread");
+        let mut db = db_with_tables(&path);
+        db.snapshot_off_thread().await.unwrap();
+        assert_eq!(db.snapshot_state.generation, 1);
+        assert!(db.snapshot_state.dirty.is_empty());
+
+        let (tables, _) = load(&path).unwrap();
+        assert_eq!(tables.len(), 2);
+        assert!(!path.with_extension("tmp").exists());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn periodic_snapshots_are_written_and_reload() {
+        let path = temp_snapshot_path("periodic");
+        let db = db_with_tables(&path);
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            let mut db = db;
+            db.run(rx).await;
+        });
+        tokio::spawn(run_periodic(Duration::from_millis(20), tx));
+
+        let mut loaded = None;
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            if path.exists() {
+                loaded = Some(load(&path).unwrap());
+                break;
+            }
+        }
+        let (tables, _) = loaded.expect("no snapshot written");
+        assert_eq!(tables.len(), 2);
+        assert_eq!(tables["a"].rows.len(), 1);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    /// A table as a snapshot would store it, in an order that doesn't
+    /// depend on how it was built.
+    fn contents(t: &Table) -> serde_json::Value {
+        let mut s = table_snapshot(t).unwrap();
+        s.rows.sort_by_key(|(id, _, _)| *id);
+        s.text_indexes.sort();
+        s.sorted_indexes.sort();
+        serde_json::to_value(s).unwrap()
+    }
+
+    #[test]
+    fn compressed_snapshots_load_back_identical() {
+        let path = temp_snapshot_path("compressed");
+        let mut db = db_with_tables(&path);
+        db.compress_snapshots = true;
+        db.execute(command(r#"{"type":"createTable","table":"notes","columns":[["s","text"]]}"#)).unwrap();
+        db.execute(command(r#"{"type":"createTextIndex","table":"notes","column":"s"}"#)).unwrap();
+        db.execute(command(r#"{"type":"createIndex","table":"a","column":"n"}"#)).unwrap();
+        for _ in 0..200 {
+            db.execute(command(r#"{"type":"insert","table":"notes","values":["the same words over and over"]}"#)).unwrap();
+        }
+        db.execute(command(r#"{"type":"update","table":"notes","rowId":3,"updates":{"s":"changed"}}"#)).unwrap();
+        db.snapshot().unwrap();
+
+        let dir = tables_dir(&path);
+        for file in db.snapshot_state.files.values() {
+            assert!(fs::read(dir.join(file)).unwrap().starts_with(&GZIP_MAGIC), "{} isn't gzipped", file);
+        }
+        let (tables, _) = load(&path).unwrap();
+        assert_eq!(tables.len(), db.tables.len());
+        for (name, table) in &db.tables {
+            assert_eq!(contents(&tables[name]), contents(table), "table {}", name);
+        }
+
+        // Repetitive text shrinks well below its plain size
+        let zipped = fs::metadata(dir.join(&db.snapshot_state.files["notes"])).unwrap().len() as usize;
+        let plain = serde_json::to_vec(&table_snapshot(&db.tables["notes"]).unwrap()).unwrap().len();
+        assert!(zipped * 4 < plain, "{} bytes gzipped, {} plain", zipped, plain);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -22,10 +22,23 @@ class DbClient {
         };
 
         this.ws.onmessage = (event) => {
+            const message = JSON.parse(event.data);
+            // Status updates about the server's own database connection answer no command
+            if (message.status) {
+                const status = document.getElementById('connectionStatus');
+                if (message.status === 'reconnecting') {
+                    status.className = 'status disconnected';
+                    status.textContent = `Database unavailable - Reconnecting (attempt ${message.attempt})...`;
+                } else {
+                    status.className = 'status connected';
+                    status.textContent = 'Connected';
+                }
+                return;
+            }
             if (this.pendingResolve) {
                 const resolve = this.pendingResolve;
                 this.pendingResolve = null;
-                resolve(JSON.parse(event.data));
+                resolve(message);
             }
         };
     }
@@ -37,25 +50,218 @@ class DbClient {
         });
     }
     
-    createTable(table, columns) {
-        return this.send({ type: 'createTable', table, columns });
+    createTable(table, columns, key = [], foreignKeys = []) {
+        return this.send({ type: 'createTable', table, columns, key, foreignKeys });
+    }
+
+    insert(table, values, idempotencyKey) {
+        return this.send({ type: 'insert', table, values, idempotencyKey });
     }
 
-    insert(table, values) {
-        return this.send({ type: 'insert', table, values });
+    // Values keyed by column name; a missing table is created from their types
+    insertObject(table, values, idempotencyKey) {
+        return this.send({ type: 'insert', table, values, idempotencyKey, inferSchema: true });
     }
 
-    update(table, rowId, updates) {
-        return this.send({ type: 'update', table, rowId, updates });
+    update(table, rowId, updates, expectedVersion) {
+        return this.send({ type: 'update', table, rowId, updates, expectedVersion });
     }
 
-    selectAll(table) {
-        return this.send({ type: 'selectAll', table });
+    // Pass a limit to page through the table; each page's nextCursor gets the next one
+    selectAll(table, withTypes = false, limit, cursor, excludeMetadata = false) {
+        return this.send({ type: 'selectAll', table, withTypes, limit, cursor, excludeMetadata });
     }
 
     getTables() {
         return this.send({ type: 'getTables' });
     }
+
+    selectCursor(table) {
+        return this.send({ type: 'selectCursor', table });
+    }
+
+    fetch(cursorId, n) {
+        return this.send({ type: 'fetch', cursorId, n });
+    }
+
+    selectWhere(table, filter, limit, offset, orderBy, excludeMetadata = false) {
+        return this.send({ type: 'selectWhere', table, filter, limit, offset, orderBy, excludeMetadata });
+    }
+
+    createTextIndex(table, column) {
+        return this.send({ type: 'createTextIndex', table, column });
+    }
+
+    searchText(table, column, query) {
+        return this.send({ type: 'searchText', table, column, query });
+    }
+
+    explain(inner) {
+        return this.send({ type: 'explain', inner });
+    }
+
+    join(left, right, leftCol, rightCol) {
+        return this.send({ type: 'join', left, right, leftCol, rightCol });
+    }
+
+    groupCount(table, column) {
+        return this.send({ type: 'groupCount', table, column });
+    }
+
+    batch(commands, ignoreErrors = false) {
+        return this.send({ type: 'batch', commands, ignoreErrors });
+    }
+
+    listIds(table) {
+        return this.send({ type: 'listIds', table });
+    }
+
+    selectByIds(table, ids, ignoreMissing = false) {
+        return this.send({ type: 'selectByIds', table, ids, ignoreMissing });
+    }
+
+    reset() {
+        return this.send({ type: 'reset' });
+    }
+
+    migrate(table, steps) {
+        return this.send({ type: 'migrate', table, steps });
+    }
+
+    snapshot() {
+        return this.send({ type: 'snapshot' });
+    }
+
+    serverInfo() {
+        return this.send({ type: 'serverInfo' });
+    }
+
+    validate(inner) {
+        return this.send({ type: 'validate', inner });
+    }
+
+    compact(table) {
+        return this.send({ type: 'compact', table });
+    }
+
+    getSchema() {
+        return this.send({ type: 'getSchema' });
+    }
+
+    reserveIds(table, count) {
+        return this.send({ type: 'reserveIds', table, count });
+    }
+
+    insertWithId(table, rowId, values) {
+        return this.send({ type: 'insertWithId', table, rowId, values });
+    }
+
+    copyTable(from, to, preserveIds) {
+        return this.send({ type: 'copyTable', from, to, preserveIds });
+    }
+
+    schemaHash(table) {
+        return this.send({ type: 'schemaHash', table });
+    }
+
+    setMemoryLimit(table, maxRows) {
+        return this.send({ type: 'setMemoryLimit', table, maxRows });
+    }
+
+    columnHistogram(table, column, limit) {
+        return this.send({ type: 'columnHistogram', table, column, limit });
+    }
+
+    increment(table, rowId, column, by = 1) {
+        return this.send({ type: 'increment', table, rowId, column, by });
+    }
+
+    compareAndSwap(table, rowId, column, expected, newValue) {
+        return this.send({ type: 'compareAndSwap', table, rowId, column, expected, new: newValue });
+    }
+
+    tail(table, n) {
+        return this.send({ type: 'tail', table, n });
+    }
+
+    // acl is { readers: [...], writers: [...] }, or null to open the table to everyone
+    setAcl(table, acl) {
+        return this.send({ type: 'setAcl', table, acl });
+    }
+
+    resetSequence(table, start = 1) {
+        return this.send({ type: 'resetSequence', table, start });
+    }
+
+    idBounds(table) {
+        return this.send({ type: 'idBounds', table });
+    }
+
+    // commands are plain command objects, e.g. { type: 'insert', table, values }
+    atomic(commands) {
+        return this.send({ type: 'atomic', commands });
+    }
+
+    exists(table, rowId) {
+        return this.send({ type: 'exists', table, rowId });
+    }
+
+    // Pass the version of the previous reply to get only what changed since
+    changesSince(table, sinceVersion = 0) {
+        return this.send({ type: 'changesSince', table, sinceVersion });
+    }
+
+    // tables: [[name, [[column, type], ...]], ...]
+    createTables(tables) {
+        return this.send({ type: 'createTables', tables });
+    }
+
+    // Pass a seed to get the same sample again
+    sample(table, n, seed) {
+        return this.send({ type: 'sample', table, n, seed });
+    }
+
+    columns(table) {
+        return this.send({ type: 'columns', table });
+    }
+
+    // Resolves to the command's result plus durationMicros, its execution time on the server
+    timed(command) {
+        return this.send({ type: 'timed', inner: command });
+    }
+
+    // Leave columns out to compare whole rows
+    dedup(table, columns = []) {
+        return this.send({ type: 'dedup', table, columns });
+    }
+
+    swapIds(table, a, b) {
+        return this.send({ type: 'swapIds', table, a, b });
+    }
+
+    moveRow(from, to, rowId) {
+        return this.send({ type: 'moveRow', from, to, rowId });
+    }
+
+    version() {
+        return this.send({ type: 'version' });
+    }
+
+    listIndexes(table) {
+        return this.send({ type: 'listIndexes', table });
+    }
+
+    set(key, value) {
+        return this.send({ type: 'set', key, value: String(value) });
+    }
+
+    deleteRow(table, rowId) {
+        return this.send({ type: 'deleteRow', table, rowId });
+    }
+
+    createIndex(table, column) {
+        return this.send({ type: 'createIndex', table, column });
+    }
 }
 
 const client = new DbClient();
@@ -217,6 +423,12 @@ function parseValue(str) {
     return str;
 }
 
+// Number() rather than parseInt() so decimals and huge values reach the
+// server and get a proper error instead of being silently truncated
+function parseInteger(str) {
+    return str.trim() === '' ? 0 : Number(str);
+}
+
 async function insertRow() {
     const tableName = document.getElementById('insertTableName').value;
 
@@ -232,7 +444,7 @@ async function insertRow() {
         if (type === 'bool') {
             values.push(input.checked);
         } else if (type === 'int') {
-            values.push(parseInt(input.value) || 0);
+            values.push(parseInteger(input.value));
         } else {
             values.push(input.value);
         }
@@ -240,6 +452,9 @@ async function insertRow() {
 
     const result = await client.insert(tableName, values);
     showResult('insertResult', result);
+    if (result.ok) {
+        document.getElementById('insertResult').textContent = `Inserted row ${result.rowId}`;
+    }
 }
 
 async function updateRow() {
@@ -264,7 +479,7 @@ async function updateRow() {
         if (type === 'bool') {
             updates[col] = input.checked;
         } else if (type === 'int') {
-            updates[col] = parseInt(input.value) || 0;
+            updates[col] = parseInteger(input.value);
         } else {
             updates[col] = input.value;
         }
@@ -277,6 +492,9 @@ async function updateRow() {
 
     const result = await client.update(tableName, rowId, updates);
     showResult('updateResult', result);
+    if (result.ok) {
+        document.getElementById('updateResult').textContent = `Updated ${result.affected} row(s)`;
+    }
 }
 
 async function selectAll() {
@@ -296,6 +514,10 @@ async function selectAll() {
     }
 
     showResult('selectResult', { ok: true });
+    if (result.truncated) {
+        document.getElementById('selectResult').textContent =
+            `Showing the first ${result.rows.length} rows. Use selectWhere with limit/offset or a cursor to read the rest.`;
+    }
 
     if (!result.rows || result.rows.length === 0) {
         document.getElementById('results').innerHTML = '<p>No rows found</p>';